@@ -1,4 +1,4 @@
-use sarus::{jit, parser};
+use sarus::{frontend::Diagnostics, jit, parser};
 use std::{env, fs, mem};
 
 fn main() -> anyhow::Result<()> {
@@ -10,11 +10,17 @@ fn main() -> anyhow::Result<()> {
         let mut jit = jit::JIT::default();
         jit.add_math_constants()?;
 
-        // Generate AST from string
-        let ast = parser::program(&code)?;
-
-        // Pass the AST to the JIT to compile
-        jit.translate(ast)?;
+        // Generate AST from string, then pass the AST to the JIT to compile
+        let compiled = parser::program(&code)
+            .map_err(anyhow::Error::from)
+            .and_then(|ast| jit.translate(ast));
+        if let Err(e) = compiled {
+            match e.downcast_ref::<Diagnostics>() {
+                Some(diags) => eprint!("{}", diags.render(&code)),
+                None => eprintln!("error: {:?}", e),
+            }
+            std::process::exit(1);
+        }
 
         //Get the function, returns a raw pointer to machine code.
         let func_ptr = jit.get_func("main")?;
@@ -22,7 +28,7 @@ fn main() -> anyhow::Result<()> {
         // Cast the raw pointer to a typed function pointer. This is unsafe, because
         // this is the critical point where you have to trust that the generated code
         // is safe to be called.
-        let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+        let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
 
         println!("the answer is: {}", func(100.0f64, 200.0f64));
     } else {
@@ -23,7 +23,7 @@ fn add(a, b) -> (c) {
     // Cast the raw pointer to a typed function pointer. This is unsafe, because
     // this is the critical point where you have to trust that the generated code
     // is safe to be called.
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
 
     println!("the answer is: {}", func(3.0f64, 5.0f64));
 
@@ -38,7 +38,7 @@ fn mult(a, b) -> (c) {
 
     jit.translate(ast)?;
     let func_ptr = jit.get_func("mult")?;
-    let func = unsafe { mem::transmute::<_, fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, fn(f64, f64) -> f64>(func_ptr) };
     println!("the answer is: {}", func(3.0f64, 5.0f64));
 
     // TODO allow validator to look at previously compiled strings to allow this:
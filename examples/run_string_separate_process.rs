@@ -4,6 +4,10 @@ use sarus::{jit, parser};
 
 use mitosis::{self, JoinHandle};
 
+// Traps, like an integer division by zero, can be caught in the same process
+// with `TypedFunc::try_call` or `JIT::call`. A separate process also survives
+// anything else going wrong, like a host function crashing.
+
 // NOTE: this method won't work in a VST plugin.
 // It will try to open the whole DAW in a second instance
 
@@ -24,7 +28,7 @@ fn subprocess_code<I, O>(input: (String, I)) -> Result<O, String> {
     let func_ptr = jit
         .get_func("main")
         .map_err(|e| format!("get_func failed: {}", e))?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(I) -> O>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(I) -> O>(func_ptr) };
     Ok(func(values))
 }
 
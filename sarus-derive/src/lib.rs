@@ -0,0 +1,156 @@
+//! `#[derive(SarusStruct)]` for `#[repr(C)]` structs that are shared with sarus code.
+//! See `sarus::sarus_struct` for the traits this implements.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields};
+
+#[proc_macro_derive(SarusStruct)]
+pub fn derive_sarus_struct(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    // Any other representation is free to reorder fields, so sarus couldn't match it
+    if !is_repr_c(input) {
+        return Err(Error::new_spanned(
+            &input.ident,
+            "SarusStruct requires #[repr(C)]",
+        ));
+    }
+    if !input.generics.params.is_empty() {
+        return Err(Error::new_spanned(
+            &input.generics,
+            "SarusStruct can't be derived for generic structs",
+        ));
+    }
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "SarusStruct requires named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "SarusStruct can only be derived for structs",
+            ))
+        }
+    };
+
+    let ident = &input.ident;
+    let name = ident.to_string();
+    let field_idents = fields
+        .iter()
+        .map(|field| field.ident.as_ref().unwrap())
+        .collect::<Vec<_>>();
+    let field_names = field_idents
+        .iter()
+        .map(|ident| ident.to_string())
+        .collect::<Vec<_>>();
+    let field_types = fields.iter().map(|field| &field.ty).collect::<Vec<_>>();
+    let field_indices = 0..fields.len();
+    let offset_messages = field_names.iter().map(|field_name| {
+        format!(
+            "field {} of {} isn't at the offset sarus gives it",
+            field_name, name
+        )
+    });
+    let size_message = format!("{} isn't the size or alignment sarus gives it", name);
+    // The size and alignment of each field in sarus
+    let sarus_fields = quote! {
+        &[#((
+            <#field_types as ::sarus::sarus_struct::SarusType>::SIZE,
+            <#field_types as ::sarus::sarus_struct::SarusType>::ALIGN,
+        )),*]
+    };
+
+    Ok(quote! {
+        // Fails to compile if Rust puts anything somewhere else than sarus does,
+        // like with `packed` or `align` on top of `repr(C)`
+        const _: () = {
+            const FIELDS: &[(usize, usize)] = #sarus_fields;
+            #(
+                assert!(
+                    ::std::mem::offset_of!(#ident, #field_idents)
+                        == ::sarus::sarus_struct::c_offset(FIELDS, #field_indices),
+                    #offset_messages
+                );
+            )*
+            assert!(
+                ::std::mem::size_of::<#ident>()
+                    == ::sarus::sarus_struct::c_offset(FIELDS, FIELDS.len())
+                    && ::std::mem::align_of::<#ident>() == ::sarus::sarus_struct::c_align(FIELDS),
+                #size_message
+            );
+        };
+
+        impl ::sarus::sarus_struct::SarusType for #ident {
+            const SIZE: usize = ::sarus::sarus_struct::c_offset(#sarus_fields, usize::MAX);
+            const ALIGN: usize = ::sarus::sarus_struct::c_align(#sarus_fields);
+
+            fn expr_type() -> ::sarus::validator::ExprType {
+                ::sarus::validator::ExprType::Struct(::std::boxed::Box::new(#name.to_string()))
+            }
+        }
+
+        impl ::sarus::sarus_struct::SarusStruct for #ident {
+            fn declaration() -> ::sarus::frontend::Struct {
+                ::sarus::frontend::Struct {
+                    name: #name.to_string(),
+                    fields: vec![#(
+                        ::sarus::frontend::Arg {
+                            name: #field_names.to_string(),
+                            expr_type: Some(
+                                <#field_types as ::sarus::sarus_struct::SarusType>::expr_type(),
+                            ),
+                            span: ::sarus::frontend::Span::default(),
+                        }
+                    ),*],
+                    extern_struct: true,
+                    span: ::sarus::frontend::Span::default(),
+                }
+            }
+
+            fn layout() -> ::sarus::sarus_struct::StructLayout {
+                let value = ::std::mem::MaybeUninit::<#ident>::uninit();
+                let base = value.as_ptr();
+                ::sarus::sarus_struct::StructLayout {
+                    size: ::std::mem::size_of::<#ident>(),
+                    align: ::std::mem::align_of::<#ident>(),
+                    fields: vec![#(
+                        (
+                            #field_names.to_string(),
+                            // Only the address is taken, the uninitialized field is never read
+                            unsafe { ::std::ptr::addr_of!((*base).#field_idents) } as usize
+                                - base as usize,
+                        )
+                    ),*],
+                }
+            }
+        }
+    })
+}
+
+fn is_repr_c(input: &DeriveInput) -> bool {
+    input
+        .attrs
+        .iter()
+        .filter(|attr| attr.path.is_ident("repr"))
+        .any(|attr| {
+            attr.parse_args_with(
+                syn::punctuated::Punctuated::<syn::Meta, syn::Token![,]>::parse_terminated,
+            )
+            .map(|reprs| reprs.iter().any(|repr| repr.path().is_ident("C")))
+            .unwrap_or(false)
+        })
+}
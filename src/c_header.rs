@@ -0,0 +1,195 @@
+//! Generating a C header for functions compiled into an object file, see
+//! `JIT::new_object`. Structs are laid out with the C rules already, so they
+//! translate to plain C structs.
+
+use crate::frontend::{Arg, Function};
+use crate::jit::StructDef;
+use crate::sarus_std_lib;
+use crate::validator::ExprType;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use target_lexicon::{Architecture, CallingConvention, Triple};
+
+pub fn c_header(
+    funcs: &HashMap<String, Function>,
+    structs: &HashMap<String, StructDef>,
+    symbols: &HashMap<String, String>,
+    triple: &Triple,
+) -> anyhow::Result<String> {
+    let mut header = String::new();
+    writeln!(header, "// Generated by sarus")?;
+    writeln!(header, "#pragma once")?;
+    writeln!(header, "#include <stdbool.h>")?;
+    writeln!(header, "#include <stdint.h>")?;
+
+    let mut struct_names = structs.keys().collect::<Vec<_>>();
+    struct_names.sort();
+    let mut written = HashSet::new();
+    for name in struct_names {
+        write_struct(&mut header, name, structs, &mut written)?;
+    }
+
+    writeln!(header)?;
+    writeln!(
+        header,
+        "// Called before trapping on an out of bounds index, to be provided by the program"
+    )?;
+    writeln!(
+        header,
+        "void {}(int64_t index, int64_t len);",
+        sarus_std_lib::INDEX_OUT_OF_BOUNDS
+    )?;
+
+    let mut funcs = funcs
+        .values()
+        .filter(|func| !func.extern_func)
+        .collect::<Vec<_>>();
+    funcs.sort_by_key(|func| (func.span.start, &func.name));
+    for func in funcs {
+        let symbol = symbols.get(&func.name).unwrap_or(&func.name);
+        writeln!(header)?;
+        write_function(&mut header, func, symbol, triple)?;
+    }
+    Ok(header)
+}
+
+/// Write the struct after the structs it contains, which C needs to be complete.
+fn write_struct(
+    header: &mut String,
+    name: &str,
+    structs: &HashMap<String, StructDef>,
+    written: &mut HashSet<String>,
+) -> anyhow::Result<()> {
+    if !written.insert(name.to_string()) {
+        return Ok(());
+    }
+    let struct_def = &structs[name];
+    let mut fields = struct_def.fields.values().collect::<Vec<_>>();
+    fields.sort_by_key(|field| field.offset);
+    for field in &fields {
+        if let ExprType::Struct(field_struct) = &field.expr_type {
+            write_struct(header, field_struct, structs, written)?;
+        }
+    }
+    writeln!(header)?;
+    writeln!(header, "typedef struct {} {{", name)?;
+    for field in &fields {
+        writeln!(header, "    {} {};", c_type(&field.expr_type)?, field.name)?;
+    }
+    writeln!(header, "}} {};", name)?;
+    Ok(())
+}
+
+/// Write the prototype of `func`, passing values the way it takes them at the
+/// machine level.
+fn write_function(
+    header: &mut String,
+    func: &Function,
+    symbol: &str,
+    triple: &Triple,
+) -> anyhow::Result<()> {
+    let c_name = c_identifier(symbol);
+    let mut params = Vec::new();
+    let mut returns = Vec::new();
+    for ret in &func.returns {
+        match arg_type(ret) {
+            ExprType::Struct(struct_name) => params.push(format!("{}* {}", struct_name, ret.name)),
+            t => returns.push((c_type(&t)?, &ret.name)),
+        }
+    }
+    for param in &func.params {
+        match arg_type(param) {
+            ExprType::Struct(struct_name) => {
+                params.push(format!("{}* {}", struct_name, param.name))
+            }
+            ExprType::Slice(elem) => {
+                params.push(format!("{}* {}", c_type(&elem)?, param.name));
+                params.push(format!("int64_t {}_len", param.name));
+            }
+            t => params.push(format!("{} {}", c_type(&t)?, param.name)),
+        }
+    }
+    let params = if params.is_empty() {
+        "void".to_string()
+    } else {
+        params.join(", ")
+    };
+
+    let return_type = match &returns[..] {
+        [] => "void".to_string(),
+        [(c_type, _)] => c_type.to_string(),
+        // Two values are returned in the registers C uses for a struct of two,
+        // but only on System V x86-64 and when each has an eightbyte of its own
+        [(a_type, a_name), (b_type, b_name)] => {
+            if !pair_returned_as_struct(triple, a_type, b_type) {
+                anyhow::bail!(
+                    "{} returns ({}, {}), which C can't receive on {}",
+                    func.name,
+                    a_type,
+                    b_type,
+                    triple
+                )
+            }
+            let return_type = format!("{}_returns", c_name);
+            writeln!(
+                header,
+                "typedef struct {{ {} {}; {} {}; }} {};",
+                a_type, a_name, b_type, b_name, return_type
+            )?;
+            return_type
+        }
+        _ => {
+            writeln!(
+                header,
+                "// {} returns more than two values, which C can't receive",
+                func.name
+            )?;
+            return Ok(());
+        }
+    };
+
+    // Methods and redefined functions have symbols that aren't valid in C
+    if c_name == symbol {
+        writeln!(header, "{} {}({});", return_type, c_name, params)?;
+    } else {
+        writeln!(
+            header,
+            "{} {}({}) __asm__(\"{}\");",
+            return_type, c_name, params, symbol
+        )?;
+    }
+    Ok(())
+}
+
+/// Whether C returns a struct of `a_type` and `b_type` in the registers
+/// Cranelift returns two values in, one for each. Two bools would share a
+/// register in the struct.
+fn pair_returned_as_struct(triple: &Triple, a_type: &str, b_type: &str) -> bool {
+    triple.architecture == Architecture::X86_64
+        && triple.default_calling_convention() == Ok(CallingConvention::SystemV)
+        && !(a_type == "bool" && b_type == "bool")
+}
+
+fn arg_type(arg: &Arg) -> ExprType {
+    arg.expr_type.clone().unwrap_or(ExprType::F64)
+}
+
+fn c_type(expr_type: &ExprType) -> anyhow::Result<String> {
+    Ok(match expr_type {
+        ExprType::Bool => "bool".to_string(),
+        ExprType::F64 => "double".to_string(),
+        ExprType::I64 => "int64_t".to_string(),
+        ExprType::UnboundedArrayF64 => "double*".to_string(),
+        ExprType::UnboundedArrayI64 => "int64_t*".to_string(),
+        ExprType::Address => "void*".to_string(),
+        ExprType::Struct(struct_name) => struct_name.to_string(),
+        t => anyhow::bail!("{} has no equivalent C type", t),
+    })
+}
+
+fn c_identifier(symbol: &str) -> String {
+    symbol
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
@@ -0,0 +1,467 @@
+//! Machine code of compiled functions saved to disk, so translating the same
+//! program again can skip codegen, see `JIT::cache_dir`. Each function is kept
+//! in an object file written by `ObjectModule`, the same way `JIT::new_object`
+//! compiles a program, with the sarus functions it calls referred to by name
+//! rather than by the symbol of the version that was current. What an object
+//! file has no place for, the signatures and where the code can trap, goes in
+//! its `.sarus` section.
+
+use crate::jit::MachineCode;
+use cranelift::codegen::binemit::Reloc;
+use cranelift::codegen::ir::{types, AbiParam, ExternalName, LibCall, Signature, TrapCode, Type};
+use cranelift::codegen::isa::{self, CallConv, TargetIsa};
+use cranelift_module::{DataId, FuncId, Linkage, Module, RelocRecord};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use object::{
+    BinaryFormat, Object, ObjectSection, ObjectSymbol, RelocationEncoding, RelocationKind,
+    RelocationTarget, SectionKind,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Bumped whenever what's saved changes meaning.
+const CACHE_VERSION: u32 = 3;
+
+/// The section of the object file with what describes the function besides its code.
+const METADATA_SECTION: &str = ".sarus";
+
+/// Stable across runs and platforms, unlike `DefaultHasher`.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Self {
+        let mut hasher = Fnv1a(0xcbf2_9ce4_8422_2325);
+        hasher.write(&CACHE_VERSION.to_le_bytes());
+        hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+pub(crate) struct CachedFunction {
+    code: Vec<u8>,
+    relocs: Vec<CachedReloc>,
+    metadata: Metadata,
+}
+
+struct CachedReloc {
+    offset: u32,
+    reloc: Reloc,
+    addend: i64,
+    target: RelocTarget,
+}
+
+enum RelocTarget {
+    /// By the name of the sarus function, which is the same for every version of it
+    Function(String),
+    Data(String),
+    LibCall(LibCall),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    signature: CachedSignature,
+    /// Every function the code refers to, by the name it has in the object file
+    functions: BTreeMap<String, CachedSignature>,
+    data: Vec<String>,
+    traps: Vec<CachedTrap>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedTrap {
+    offset: u32,
+    /// As `TrapCode` displays it, like `int_divz`
+    code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedSignature {
+    params: Vec<String>,
+    returns: Vec<String>,
+    call_conv: String,
+}
+
+fn path(dir: &Path, key: u64, fn_name: &str) -> PathBuf {
+    dir.join(format!("{:016x}-{}.o", key, fn_name))
+}
+
+impl CachedFunction {
+    /// Describe a compiled function. `fn_names` gives the function name for the
+    /// symbols of sarus functions.
+    pub(crate) fn new(
+        module: &dyn Module,
+        machine_code: &MachineCode,
+        fn_names: &HashMap<&str, &str>,
+    ) -> anyhow::Result<Self> {
+        let mut functions = BTreeMap::new();
+        let mut data = Vec::new();
+        let mut relocs = Vec::new();
+        for reloc in &machine_code.relocs {
+            let target = match &reloc.name {
+                ExternalName::User { namespace: 0, .. } => {
+                    let decl = module
+                        .declarations()
+                        .get_function_decl(FuncId::from_name(&reloc.name));
+                    let name = fn_names
+                        .get(decl.name.as_str())
+                        .copied()
+                        .unwrap_or(&decl.name);
+                    functions.insert(name.to_string(), CachedSignature::new(&decl.signature));
+                    RelocTarget::Function(name.to_string())
+                }
+                ExternalName::User { namespace: 1, .. } => {
+                    let decl = module
+                        .declarations()
+                        .get_data_decl(DataId::from_name(&reloc.name));
+                    if !data.contains(&decl.name) {
+                        data.push(decl.name.clone());
+                    }
+                    RelocTarget::Data(decl.name.clone())
+                }
+                ExternalName::LibCall(libcall) => RelocTarget::LibCall(*libcall),
+                name => anyhow::bail!("can't cache a relocation to {}", name),
+            };
+            relocs.push(CachedReloc {
+                offset: reloc.offset,
+                reloc: reloc.reloc,
+                addend: reloc.addend,
+                target,
+            });
+        }
+        Ok(CachedFunction {
+            code: machine_code.code.clone(),
+            relocs,
+            metadata: Metadata {
+                signature: CachedSignature::new(&machine_code.signature),
+                functions,
+                data,
+                traps: machine_code
+                    .traps
+                    .iter()
+                    .map(|(offset, code)| CachedTrap {
+                        offset: *offset,
+                        code: code.to_string(),
+                    })
+                    .collect(),
+            },
+        })
+    }
+
+    /// The function saved for `key`, if there is one that can be read. A file
+    /// that isn't what `save` writes is the same as none.
+    pub(crate) fn load(dir: &Path, key: u64, fn_name: &str) -> Option<Self> {
+        let bytes = fs::read(path(dir, key, fn_name)).ok()?;
+        CachedFunction::from_object(&bytes, fn_name).ok()
+    }
+
+    /// Write the function as an object file for `isa`. It's written to a
+    /// temporary file first, so a translate running at the same time never
+    /// reads half of one.
+    pub(crate) fn save(
+        &self,
+        isa: &dyn TargetIsa,
+        dir: &Path,
+        key: u64,
+        fn_name: &str,
+    ) -> anyhow::Result<()> {
+        static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+        let bytes = self.to_object(isa, fn_name)?;
+        fs::create_dir_all(dir)?;
+        let temp = dir.join(format!(
+            ".{:016x}-{}.{}-{}.tmp",
+            key,
+            fn_name,
+            std::process::id(),
+            TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temp, bytes)?;
+        if let Err(e) = fs::rename(&temp, path(dir, key, fn_name)) {
+            let _ = fs::remove_file(&temp);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    fn to_object(&self, isa: &dyn TargetIsa, fn_name: &str) -> anyhow::Result<Vec<u8>> {
+        // Only the target matters to `define_function_bytes`, not the CPU features
+        let isa = isa::lookup(isa.triple().clone())
+            .map_err(|e| anyhow::anyhow!("{}", e))?
+            .finish(isa.flags().clone());
+        let builder = ObjectBuilder::new(isa, fn_name, cranelift_module::default_libcall_names())?;
+        let mut module = ObjectModule::new(builder);
+        // Local, so the calls it makes to itself stay relocations against its symbol
+        let id = module.declare_function(
+            fn_name,
+            Linkage::Local,
+            &self.metadata.signature.to_signature()?,
+        )?;
+        let mut relocs = Vec::new();
+        for reloc in &self.relocs {
+            let name = match &reloc.target {
+                RelocTarget::Function(name) => module
+                    .declare_function(
+                        name,
+                        Linkage::Import,
+                        &self.metadata.functions[name].to_signature()?,
+                    )?
+                    .into(),
+                RelocTarget::Data(name) => module
+                    .declare_data(name, Linkage::Import, true, false)?
+                    .into(),
+                RelocTarget::LibCall(libcall) => ExternalName::LibCall(*libcall),
+            };
+            relocs.push(RelocRecord {
+                offset: reloc.offset,
+                reloc: reloc.reloc,
+                name,
+                addend: reloc.addend,
+            });
+        }
+        module.define_function_bytes(id, &self.code, &relocs)?;
+
+        let mut product = module.finish();
+        let section = product.object.add_section(
+            Vec::new(),
+            METADATA_SECTION.as_bytes().to_vec(),
+            SectionKind::Other,
+        );
+        // Going through a `Value` puts plain values ahead of tables, as toml needs
+        let metadata = toml::to_string(&toml::Value::try_from(&self.metadata)?)?;
+        product
+            .object
+            .append_section_data(section, metadata.as_bytes(), 1);
+        product.emit().map_err(|e| anyhow::anyhow!("{}", e))
+    }
+
+    fn from_object(bytes: &[u8], fn_name: &str) -> anyhow::Result<Self> {
+        let file = object::File::parse(bytes)?;
+        let metadata = match file.section_by_name(METADATA_SECTION) {
+            Some(section) => toml::from_slice::<Metadata>(section.data()?)?,
+            None => anyhow::bail!("no {} section", METADATA_SECTION),
+        };
+        let function = file
+            .symbols()
+            .find(|symbol| {
+                symbol.is_definition() && symbol_name(&file, symbol).ok() == Some(fn_name)
+            })
+            .ok_or_else(|| anyhow::anyhow!("no function {}", fn_name))?;
+        let section = match function.section_index() {
+            Some(index) => file.section_by_index(index)?,
+            None => anyhow::bail!("function {} has no section", fn_name),
+        };
+        let data = section.data()?;
+        let start = function
+            .address()
+            .checked_sub(section.address())
+            .ok_or_else(|| anyhow::anyhow!("function {} is outside its section", fn_name))?;
+        // Mach-O doesn't record sizes, the function is all there is in the section
+        let end = match function.size() {
+            0 => data.len() as u64,
+            size => start + size,
+        };
+        let code = match data.get(start as usize..end as usize) {
+            Some(code) => code.to_vec(),
+            None => anyhow::bail!("function {} is outside its section", fn_name),
+        };
+
+        let libcall_names = cranelift_module::default_libcall_names();
+        let mut relocs = Vec::new();
+        for (offset, reloc) in section.relocations() {
+            if offset < start || offset >= end {
+                continue;
+            }
+            let offset = (offset - start) as u32;
+            let symbol = match reloc.target() {
+                RelocationTarget::Symbol(index) => file.symbol_by_index(index)?,
+                target => anyhow::bail!("can't load a relocation to {:?}", target),
+            };
+            let name = symbol_name(&file, &symbol)?;
+            let target = if metadata.functions.contains_key(name) {
+                RelocTarget::Function(name.to_string())
+            } else if metadata.data.iter().any(|data| data == name) {
+                RelocTarget::Data(name.to_string())
+            } else {
+                match LibCall::all_libcalls()
+                    .iter()
+                    .find(|libcall| libcall_names(**libcall) == name)
+                {
+                    Some(libcall) => RelocTarget::LibCall(*libcall),
+                    None => anyhow::bail!("relocation to unknown symbol {}", name),
+                }
+            };
+            let mut addend = reloc.addend();
+            // COFF and Mach-O keep the addend in the code, which the relocation overwrites
+            if reloc.has_implicit_addend() {
+                addend += implicit_addend(&code, offset as usize, reloc.size())?;
+            }
+            relocs.push(CachedReloc {
+                offset,
+                reloc: reloc_kind(reloc.kind(), reloc.encoding(), reloc.size())?,
+                addend,
+                target,
+            });
+        }
+        Ok(CachedFunction {
+            code,
+            relocs,
+            metadata,
+        })
+    }
+
+    /// The machine code to define the function with, declaring what the
+    /// relocations refer to in `module`. Sarus functions are referred to by
+    /// their current symbol.
+    pub(crate) fn to_module(
+        &self,
+        module: &mut dyn Module,
+        symbols: &HashMap<String, String>,
+    ) -> anyhow::Result<MachineCode> {
+        let mut relocs = Vec::new();
+        for reloc in &self.relocs {
+            let name = match &reloc.target {
+                RelocTarget::Function(fn_name) => {
+                    let symbol = symbols.get(fn_name).unwrap_or(fn_name);
+                    let signature = self.metadata.functions[fn_name].to_signature()?;
+                    module
+                        .declare_function(symbol, Linkage::Import, &signature)?
+                        .into()
+                }
+                RelocTarget::Data(data_name) => {
+                    // The same as `translate_global_data_addr` declares it
+                    module
+                        .declare_data(data_name, Linkage::Export, true, false)?
+                        .into()
+                }
+                RelocTarget::LibCall(libcall) => ExternalName::LibCall(*libcall),
+            };
+            relocs.push(RelocRecord {
+                offset: reloc.offset,
+                reloc: reloc.reloc,
+                name,
+                addend: reloc.addend,
+            });
+        }
+        let mut traps = Vec::new();
+        for trap in &self.metadata.traps {
+            let code = TrapCode::from_str(&trap.code)
+                .map_err(|_| anyhow::anyhow!("unknown trap code {}", trap.code))?;
+            traps.push((trap.offset, code));
+        }
+        Ok(MachineCode {
+            signature: self.metadata.signature.to_signature()?,
+            code: self.code.clone(),
+            relocs,
+            traps,
+        })
+    }
+}
+
+impl CachedSignature {
+    fn new(signature: &Signature) -> Self {
+        let value_types = |params: &[AbiParam]| {
+            params
+                .iter()
+                .map(|param| param.value_type.to_string())
+                .collect()
+        };
+        CachedSignature {
+            params: value_types(&signature.params),
+            returns: value_types(&signature.returns),
+            call_conv: signature.call_conv.to_string(),
+        }
+    }
+
+    fn to_signature(&self) -> anyhow::Result<Signature> {
+        let call_conv = CallConv::from_str(&self.call_conv)
+            .map_err(|_| anyhow::anyhow!("unknown calling convention {}", self.call_conv))?;
+        let mut signature = Signature::new(call_conv);
+        for param in &self.params {
+            signature.params.push(AbiParam::new(value_type(param)?));
+        }
+        for ret in &self.returns {
+            signature.returns.push(AbiParam::new(value_type(ret)?));
+        }
+        Ok(signature)
+    }
+}
+
+/// The types sarus passes values as.
+fn value_type(name: &str) -> anyhow::Result<Type> {
+    [
+        types::B1,
+        types::I8,
+        types::I16,
+        types::I32,
+        types::I64,
+        types::F32,
+        types::F64,
+    ]
+    .iter()
+    .find(|t| t.to_string() == name)
+    .copied()
+    .ok_or_else(|| anyhow::anyhow!("unknown value type {}", name))
+}
+
+/// The name a symbol was declared with, without the prefix Mach-O adds.
+fn symbol_name<'data>(
+    file: &object::File<'data>,
+    symbol: &impl ObjectSymbol<'data>,
+) -> anyhow::Result<&'data str> {
+    let name = symbol.name()?;
+    Ok(match file.format() {
+        BinaryFormat::MachO => name.strip_prefix('_').unwrap_or(name),
+        _ => name,
+    })
+}
+
+/// The relocation `ObjectModule` writes as `kind`, `encoding` and `size`, as
+/// they are read back.
+fn reloc_kind(
+    kind: RelocationKind,
+    encoding: RelocationEncoding,
+    size: u8,
+) -> anyhow::Result<Reloc> {
+    Ok(match (kind, encoding, size) {
+        (RelocationKind::Absolute, _, 32) => Reloc::Abs4,
+        (RelocationKind::Absolute, _, 64) => Reloc::Abs8,
+        (RelocationKind::Relative, RelocationEncoding::X86Branch, 32) => Reloc::X86CallPCRel4,
+        (RelocationKind::Relative, _, 32) => Reloc::X86PCRel4,
+        (RelocationKind::PltRelative, RelocationEncoding::AArch64Call, 26) => Reloc::Arm64Call,
+        (RelocationKind::PltRelative, _, 32) => Reloc::X86CallPLTRel4,
+        (RelocationKind::GotRelative, _, 32) => Reloc::X86GOTPCRel4,
+        (kind, encoding, size) => anyhow::bail!(
+            "unknown relocation {:?} {:?} of {} bits",
+            kind,
+            encoding,
+            size
+        ),
+    })
+}
+
+fn implicit_addend(code: &[u8], offset: usize, size: u8) -> anyhow::Result<i64> {
+    let bytes = code.get(offset..offset + size as usize / 8);
+    Ok(match bytes {
+        Some(&[a, b, c, d]) => i32::from_le_bytes([a, b, c, d]) as i64,
+        Some(bytes) if bytes.len() == 8 => {
+            let mut addend = [0; 8];
+            addend.copy_from_slice(bytes);
+            i64::from_le_bytes(addend)
+        }
+        _ => anyhow::bail!("no {} bit addend at {}", size, offset),
+    })
+}
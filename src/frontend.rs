@@ -1,7 +1,195 @@
+// The code peg generates for `precedence!` rules calls each action as a closure
+#![allow(clippy::redundant_closure_call)]
+
 use crate::validator::ExprType;
 use std::fmt::Display;
 
 use std::fmt::Write;
+use thiserror::Error;
+
+/// Location of an AST node in the source it was parsed from. `line` and `col`
+/// are 1-based, a `line` of 0 means the node was generated rather than parsed.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl Span {
+    /// Span covering both `self` and `other`, `other` is expected to come later in the source.
+    pub fn join(self, other: Span) -> Span {
+        if self.line == 0 {
+            return other;
+        }
+        if other.line == 0 {
+            return self;
+        }
+        Span {
+            end: other.end.max(self.end),
+            ..self
+        }
+    }
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.line == 0 {
+            write!(f, "<generated>")
+        } else {
+            write!(f, "{}:{}", self.line, self.col)
+        }
+    }
+}
+
+/// An error pointing at a location in the source.
+#[derive(Debug, Clone, Error)]
+#[error("{span}: {message}")]
+pub struct Diagnostic {
+    pub span: Span,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<S: Into<String>>(span: Span, message: S) -> Self {
+        Diagnostic {
+            span,
+            message: message.into(),
+        }
+    }
+
+    /// Render the message along with the offending source line, underlining the span.
+    pub fn render(&self, code: &str) -> String {
+        let mut f = String::new();
+        writeln!(f, "error: {}", self.message).unwrap();
+        if self.span.line == 0 {
+            return f;
+        }
+        let line_text = code.lines().nth(self.span.line - 1).unwrap_or("");
+        let col = (self.span.col - 1).min(line_text.len());
+        let len = self
+            .span
+            .end
+            .saturating_sub(self.span.start)
+            .min(line_text.len() - col)
+            .max(1);
+        // Keep tabs so the underline lines up with the source line
+        let indent = line_text[..col]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect::<String>();
+        let gutter = " ".repeat(self.span.line.to_string().len());
+        writeln!(f, "{}--> {}", gutter, self.span).unwrap();
+        writeln!(f, "{} |", gutter).unwrap();
+        writeln!(f, "{} | {}", self.span.line, line_text).unwrap();
+        writeln!(f, "{} | {}{}", gutter, indent, "^".repeat(len)).unwrap();
+        f
+    }
+}
+
+/// All of the problems found in one pass over the source, in the order they were found.
+#[derive(Debug, Clone, Default)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl Diagnostics {
+    /// Render every diagnostic, see [`Diagnostic::render`].
+    pub fn render(&self, code: &str) -> String {
+        self.0.iter().map(|d| d.render(code)).collect()
+    }
+
+    /// Add the error to the list, flattening it if it already holds diagnostics. Errors
+    /// without a location of their own are attributed to `span`.
+    pub fn push_error(&mut self, span: Span, e: anyhow::Error) {
+        match e.downcast::<Diagnostics>() {
+            Ok(diagnostics) => self.0.extend(diagnostics.0),
+            Err(e) => match e.downcast::<Diagnostic>() {
+                Ok(diagnostic) => self.0.push(diagnostic),
+                Err(e) => self.0.push(Diagnostic::new(span, e.to_string())),
+            },
+        }
+    }
+}
+
+impl std::ops::Deref for Diagnostics {
+    type Target = [Diagnostic];
+
+    fn deref(&self) -> &[Diagnostic] {
+        &self.0
+    }
+}
+
+impl From<Diagnostic> for Diagnostics {
+    fn from(d: Diagnostic) -> Self {
+        Diagnostics(vec![d])
+    }
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, d) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", d)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Diagnostics {}
+
+/// Byte offsets at which each line of the source starts, used to turn parser
+/// positions into line/column pairs without rescanning the source.
+struct LineIndex {
+    starts: Vec<usize>,
+    /// Offset of the first character on each line that isn't a space or tab
+    firsts: Vec<usize>,
+}
+
+impl LineIndex {
+    fn new(code: &str) -> Self {
+        let mut starts = vec![0];
+        let mut firsts = vec![];
+        let mut indented = true;
+        for (i, c) in code.char_indices() {
+            if indented && c != ' ' && c != '\t' {
+                firsts.push(i);
+                indented = false;
+            }
+            if c == '\n' {
+                starts.push(i + 1);
+                indented = true;
+            }
+        }
+        if indented {
+            firsts.push(code.len());
+        }
+        LineIndex { starts, firsts }
+    }
+
+    fn line(&self, pos: usize) -> usize {
+        match self.starts.binary_search(&pos) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        }
+    }
+
+    fn span(&self, start: usize, end: usize) -> Span {
+        let line = self.line(start);
+        Span {
+            start,
+            end,
+            line: line + 1,
+            col: start - self.starts[line] + 1,
+        }
+    }
+
+    /// Whether nothing but spaces and tabs comes before `pos` on its line.
+    fn starts_line(&self, pos: usize) -> bool {
+        self.firsts[self.line(pos)] == pos
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 pub enum Unaryop {
@@ -69,40 +257,127 @@ type NV<T> = non_empty_vec::NonEmpty<T>;
 /// The AST node for expressions.
 #[derive(Debug, Clone)]
 pub enum Expr {
-    LiteralFloat(String),
-    LiteralInt(String),
-    LiteralBool(bool),
-    LiteralString(String),
-    Identifier(String),
-    Binop(Binop, Box<Expr>, Box<Expr>),
-    Unaryop(Unaryop, Box<Expr>),
-    Compare(Cmp, Box<Expr>, Box<Expr>),
-    IfThen(Box<Expr>, Vec<Expr>),
-    IfElse(Box<Expr>, Vec<Expr>, Vec<Expr>),
-    Assign(NV<String>, NV<Expr>),
-    AssignOp(Binop, Box<String>, Box<Expr>),
-    NewStruct(String, Vec<StructAssignField>),
-    WhileLoop(Box<Expr>, Vec<Expr>), //Should this take a block instead of Vec<Expr>?
-    Block(Vec<Expr>),
-    Call(String, Vec<Expr>, bool),
-    GlobalDataAddr(String),
-    Parentheses(Box<Expr>),
-    ArrayGet(String, Box<Expr>),
-    ArraySet(String, Box<Expr>, Box<Expr>),
+    LiteralFloat(Span, String),
+    LiteralInt(Span, String),
+    LiteralBool(Span, bool),
+    LiteralString(Span, String),
+    Identifier(Span, String),
+    Binop(Span, Binop, Box<Expr>, Box<Expr>),
+    Unaryop(Span, Unaryop, Box<Expr>),
+    Compare(Span, Cmp, Box<Expr>, Box<Expr>),
+    IfThen(Span, Box<Expr>, Vec<Expr>),
+    IfElse(Span, Box<Expr>, Vec<Expr>, Vec<Expr>),
+    Assign(Span, NV<String>, NV<Expr>),
+    AssignOp(Span, Binop, Box<String>, Box<Expr>),
+    NewStruct(Span, String, Vec<StructAssignField>),
+    WhileLoop(Span, Box<Expr>, Vec<Expr>), //Should this take a block instead of Vec<Expr>?
+    ForLoop(Span, String, Box<Expr>, Vec<Expr>), //Iterates over a Range, or an ArrayGet indexed by one
+    Range(Span, Box<Expr>, Box<Expr>),
+    Block(Span, Vec<Expr>),
+    Call(Span, String, Vec<Expr>, bool),
+    GlobalDataAddr(Span, String),
+    Parentheses(Span, Box<Expr>),
+    ArrayGet(Span, String, Box<Expr>),
+    ArraySet(Span, String, Box<Expr>, Box<Expr>),
+    ArrayLiteral(Span, Vec<Expr>),               //[1.0, 2.0, 3.0]
+    ArrayRepeat(Span, Box<Expr>, usize),         //[0.0; 16]
+    DeclareArray(Span, String, ExprType, usize), //buf: [f64; 64], zero initialized
+    Return(Span),
+    Break(Span),
+    Continue(Span),
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::LiteralFloat(span, ..)
+            | Expr::LiteralInt(span, ..)
+            | Expr::LiteralBool(span, ..)
+            | Expr::LiteralString(span, ..)
+            | Expr::Identifier(span, ..)
+            | Expr::Binop(span, ..)
+            | Expr::Unaryop(span, ..)
+            | Expr::Compare(span, ..)
+            | Expr::IfThen(span, ..)
+            | Expr::IfElse(span, ..)
+            | Expr::Assign(span, ..)
+            | Expr::AssignOp(span, ..)
+            | Expr::NewStruct(span, ..)
+            | Expr::WhileLoop(span, ..)
+            | Expr::ForLoop(span, ..)
+            | Expr::Range(span, ..)
+            | Expr::Block(span, ..)
+            | Expr::Call(span, ..)
+            | Expr::GlobalDataAddr(span, ..)
+            | Expr::Parentheses(span, ..)
+            | Expr::ArrayGet(span, ..)
+            | Expr::ArraySet(span, ..)
+            | Expr::ArrayLiteral(span, ..)
+            | Expr::ArrayRepeat(span, ..)
+            | Expr::DeclareArray(span, ..)
+            | Expr::Return(span)
+            | Expr::Break(span)
+            | Expr::Continue(span) => *span,
+        }
+    }
+
+    /// Whether control never continues past this expression.
+    pub fn diverges(&self) -> bool {
+        matches!(self, Expr::Return(_) | Expr::Break(_) | Expr::Continue(_))
+    }
+
+    /// The expressions directly nested in this one, in source order.
+    pub fn children(&self) -> Vec<&Expr> {
+        match self {
+            Expr::LiteralFloat(..)
+            | Expr::LiteralInt(..)
+            | Expr::LiteralBool(..)
+            | Expr::LiteralString(..)
+            | Expr::Identifier(..)
+            | Expr::GlobalDataAddr(..)
+            | Expr::DeclareArray(..)
+            | Expr::Return(_)
+            | Expr::Break(_)
+            | Expr::Continue(_) => vec![],
+            Expr::Unaryop(_, _, e)
+            | Expr::AssignOp(_, _, _, e)
+            | Expr::Parentheses(_, e)
+            | Expr::ArrayGet(_, _, e)
+            | Expr::ArrayRepeat(_, e, _) => vec![e],
+            Expr::Binop(_, _, a, b)
+            | Expr::Compare(_, _, a, b)
+            | Expr::Range(_, a, b)
+            | Expr::ArraySet(_, _, a, b) => vec![a, b],
+            Expr::IfThen(_, cond, body)
+            | Expr::WhileLoop(_, cond, body)
+            | Expr::ForLoop(_, _, cond, body) => {
+                std::iter::once(&**cond).chain(body.iter()).collect()
+            }
+            Expr::IfElse(_, cond, then_body, else_body) => std::iter::once(&**cond)
+                .chain(then_body.iter())
+                .chain(else_body.iter())
+                .collect(),
+            Expr::Assign(_, _, exprs) => exprs.iter().collect(),
+            Expr::NewStruct(_, _, fields) => fields.iter().map(|field| &field.expr).collect(),
+            Expr::Block(_, exprs) | Expr::Call(_, _, exprs, _) | Expr::ArrayLiteral(_, exprs) => {
+                exprs.iter().collect()
+            }
+        }
+    }
 }
 
 //TODO indentation, tests
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Expr::LiteralFloat(s) => write!(f, "{}", s),
-            Expr::LiteralInt(s) => write!(f, "{}", s),
-            Expr::LiteralString(s) => write!(f, "\"{}\"", s),
-            Expr::Identifier(s) => write!(f, "{}", s),
-            Expr::Binop(op, e1, e2) => write!(f, "{} {} {}", e1, op, e2),
-            Expr::Unaryop(op, e1) => write!(f, "{} {}", op, e1),
-            Expr::Compare(cmp, e1, e2) => write!(f, "{} {} {}", e1, cmp, e2),
-            Expr::IfThen(e, body) => {
+            Expr::LiteralFloat(_, s) => write!(f, "{}", s),
+            Expr::LiteralInt(_, s) => write!(f, "{}", s),
+            Expr::LiteralString(_, s) => write!(f, "\"{}\"", s),
+            Expr::Identifier(_, s) => write!(f, "{}", s),
+            Expr::Binop(_, op, e1, e2) => write!(f, "{} {} {}", e1, op, e2),
+            Expr::Unaryop(_, op, e1) => write!(f, "{} {}", op, e1),
+            Expr::Compare(_, cmp, e1, e2) => write!(f, "{} {} {}", e1, cmp, e2),
+            Expr::IfThen(_, e, body) => {
                 writeln!(f, "if {} {{", e)?;
                 for expr in body.iter() {
                     writeln!(f, "{}", expr)?;
@@ -110,7 +385,7 @@ impl Display for Expr {
                 write!(f, "}}")?;
                 Ok(())
             }
-            Expr::IfElse(e, body, else_body) => {
+            Expr::IfElse(_, e, body, else_body) => {
                 writeln!(f, "if {} {{", e)?;
                 for expr in body.iter() {
                     writeln!(f, "{}", expr)?;
@@ -122,7 +397,7 @@ impl Display for Expr {
                 write!(f, "}}")?;
                 Ok(())
             }
-            Expr::Assign(vars, exprs) => {
+            Expr::Assign(_, vars, exprs) => {
                 for (i, var) in vars.iter().enumerate() {
                     write!(f, "{}", var)?;
                     let len: usize = vars.len().into();
@@ -140,8 +415,8 @@ impl Display for Expr {
                 }
                 Ok(())
             }
-            Expr::AssignOp(op, s, e) => write!(f, "{} {}= {}", s, op, e),
-            Expr::NewStruct(struct_name, args) => {
+            Expr::AssignOp(_, op, s, e) => write!(f, "{} {}= {}", s, op, e),
+            Expr::NewStruct(_, struct_name, args) => {
                 writeln!(f, "{}{{", struct_name)?;
                 for arg in args.iter() {
                     writeln!(f, "{},", arg)?;
@@ -149,7 +424,7 @@ impl Display for Expr {
                 writeln!(f, "}}")?;
                 Ok(())
             }
-            Expr::WhileLoop(eval, block) => {
+            Expr::WhileLoop(_, eval, block) => {
                 writeln!(f, "while {} {{", eval)?;
                 for expr in block.iter() {
                     writeln!(f, "{}", expr)?;
@@ -157,13 +432,22 @@ impl Display for Expr {
                 write!(f, "}}")?;
                 Ok(())
             }
-            Expr::Block(block) => {
+            Expr::ForLoop(_, var, iterable, block) => {
+                writeln!(f, "for {} in {} {{", var, iterable)?;
                 for expr in block.iter() {
                     writeln!(f, "{}", expr)?;
                 }
+                write!(f, "}}")?;
                 Ok(())
             }
-            Expr::Call(func_name, args, _impl_func) => {
+            Expr::Range(_, start, end) => write!(f, "{}..{}", start, end),
+            Expr::Block(_, block) => {
+                for expr in block.iter() {
+                    writeln!(f, "{}", expr)?;
+                }
+                Ok(())
+            }
+            Expr::Call(_, func_name, args, _impl_func) => {
                 //todo print this correctly
                 write!(f, "{}(", func_name)?;
                 for (i, arg) in args.iter().enumerate() {
@@ -175,11 +459,26 @@ impl Display for Expr {
                 write!(f, ")")?;
                 Ok(())
             }
-            Expr::GlobalDataAddr(e) => write!(f, "{}", e),
-            Expr::LiteralBool(b) => write!(f, "{}", b),
-            Expr::Parentheses(e) => write!(f, "({})", e),
-            Expr::ArrayGet(var, e) => write!(f, "{}[{}]", var, e),
-            Expr::ArraySet(var, idx_e, e) => write!(f, "{}[{}] = {}", var, idx_e, e),
+            Expr::GlobalDataAddr(_, e) => write!(f, "{}", e),
+            Expr::LiteralBool(_, b) => write!(f, "{}", b),
+            Expr::Parentheses(_, e) => write!(f, "({})", e),
+            Expr::ArrayGet(_, var, e) => write!(f, "{}[{}]", var, e),
+            Expr::ArraySet(_, var, idx_e, e) => write!(f, "{}[{}] = {}", var, idx_e, e),
+            Expr::ArrayLiteral(_, items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}", item)?;
+                    if i < items.len() - 1 {
+                        write!(f, ", ")?;
+                    }
+                }
+                write!(f, "]")
+            }
+            Expr::ArrayRepeat(_, e, len) => write!(f, "[{}; {}]", e, len),
+            Expr::DeclareArray(_, var, elem, len) => write!(f, "{}: [{}; {}]", var, elem, len),
+            Expr::Return(_) => write!(f, "return"),
+            Expr::Break(_) => write!(f, "break"),
+            Expr::Continue(_) => write!(f, "continue"),
         }
     }
 }
@@ -207,7 +506,7 @@ impl Display for Declaration {
                 for word in head.iter() {
                     write!(f, "{}", word)?;
                 }
-                writeln!(f, "")?;
+                writeln!(f)?;
                 write!(f, "{}", body)?;
                 Ok(())
             }
@@ -219,7 +518,8 @@ impl Display for Declaration {
 #[derive(Debug, Clone)]
 pub struct Arg {
     pub name: String,
-    pub expr_type: Option<ExprType>, //Type is F64 if not specified
+    pub expr_type: Option<ExprType>, //Inferred from usage if not specified, otherwise F64
+    pub span: Span,
 }
 
 impl Display for Arg {
@@ -235,6 +535,7 @@ impl Display for Arg {
 pub struct StructAssignField {
     pub field_name: String,
     pub expr: Expr,
+    pub span: Span,
 }
 
 impl Display for StructAssignField {
@@ -250,6 +551,7 @@ pub struct Function {
     pub returns: Vec<Arg>,
     pub body: Vec<Expr>,
     pub extern_func: bool,
+    pub span: Span,
 }
 
 impl Display for Function {
@@ -262,8 +564,11 @@ impl Display for Function {
             }
         }
         write!(f, ") -> (")?;
-        for ret in self.returns.iter() {
+        for (i, ret) in self.returns.iter().enumerate() {
             write!(f, "{}", ret)?;
+            if i < self.returns.len() - 1 {
+                write!(f, ", ")?;
+            }
         }
         writeln!(f, ") {{")?;
         for expr in self.body.iter() {
@@ -279,10 +584,14 @@ pub struct Struct {
     pub name: String,
     pub fields: Vec<Arg>,
     pub extern_struct: bool,
+    pub span: Span,
 }
 
 impl Display for Struct {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.extern_struct {
+            write!(f, "extern ")?;
+        }
         write!(f, "struct {} {{", self.name)?;
         for param in &self.fields {
             writeln!(f, "{},", param)?;
@@ -320,7 +629,151 @@ pub fn pretty_indent(code: &str) -> String {
     f
 }
 
-peg::parser!(pub grammar parser() for str {
+pub mod parser {
+    use super::*;
+    use std::ops::Range;
+
+    /// Stop collecting syntax errors past this point, later ones are likely fallout.
+    const MAX_ERRORS: usize = 64;
+
+    /// Parse a program, reporting every syntax error with its line and column.
+    ///
+    /// After an error the offending statement (or the whole declaration, if the error
+    /// is outside of a block) is blanked out and parsing starts over, so a single call
+    /// lists the problems of the entire source.
+    pub fn program(code: &str) -> Result<Vec<Declaration>, Diagnostics> {
+        let lines = LineIndex::new(code);
+        let mut src = code.to_string();
+        let mut errors = Vec::new();
+        loop {
+            match sarus_parser::program(&src, &lines) {
+                Ok(ast) if errors.is_empty() => return Ok(ast),
+                Ok(_) => return Err(Diagnostics(errors)),
+                Err(e) => {
+                    let offset = e.location.offset;
+                    errors.push(Diagnostic::new(
+                        lines.span(offset, offset + 1),
+                        format!("expected {}", e.expected),
+                    ));
+                    let region = recovery_region(&src, offset);
+                    if errors.len() >= MAX_ERRORS || !blank_out(&mut src, region) {
+                        return Err(Diagnostics(errors));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Replace the region with whitespace, keeping newlines and byte offsets intact so
+    /// spans still point into the original source. Returns false if nothing changed.
+    fn blank_out(src: &mut String, region: Range<usize>) -> bool {
+        let text = &src[region.clone()];
+        if text.trim().is_empty() {
+            return false;
+        }
+        let blank = text
+            .chars()
+            .map(|c| match c {
+                '\n' => "\n".to_string(),
+                c => " ".repeat(c.len_utf8()),
+            })
+            .collect::<String>();
+        src.replace_range(region, &blank);
+        true
+    }
+
+    /// Brace nesting depth before each byte of `src`, ignoring comments and strings.
+    fn brace_depths(src: &str) -> Vec<usize> {
+        let bytes = src.as_bytes();
+        let mut depths = Vec::with_capacity(bytes.len() + 1);
+        let mut depth = 0usize;
+        let mut in_comment = false;
+        let mut in_string = false;
+        for (i, b) in bytes.iter().enumerate() {
+            depths.push(depth);
+            match b {
+                b'\n' => in_comment = false,
+                _ if in_comment => (),
+                b'"' => in_string = !in_string,
+                _ if in_string => (),
+                b'/' if bytes.get(i + 1) == Some(&b'/') => in_comment = true,
+                b'{' => depth += 1,
+                b'}' => depth = depth.saturating_sub(1),
+                _ => (),
+            }
+        }
+        depths.push(depth);
+        depths
+    }
+
+    /// The byte range to discard so parsing can resume after an error at `offset`.
+    fn recovery_region(src: &str, offset: usize) -> Range<usize> {
+        let depths = brace_depths(src);
+        let mut starts = vec![0];
+        starts.extend(src.match_indices('\n').map(|(i, _)| i + 1));
+        let line = match starts.binary_search(&offset) {
+            Ok(l) => l,
+            Err(l) => l - 1,
+        };
+        // First token of a line, skipping indentation
+        let token = |l: usize| src[starts[l]..].trim_start_matches([' ', '\t']);
+        let depth = depths[offset];
+
+        if depth > 0 {
+            // Find the line the current statement starts on, without leaving the block
+            let mut first = None;
+            for l in (0..=line).rev() {
+                if depths[starts[l]] < depth {
+                    break;
+                }
+                let t = token(l);
+                if depths[starts[l]] == depth
+                    && !(t.starts_with('\n') || t.starts_with('}') || t.starts_with("//"))
+                {
+                    first = Some(l);
+                    break;
+                }
+            }
+            if let Some(first) = first {
+                let start = starts[first];
+                // The statement ends at the next line in the same block, or at the
+                // brace closing the block
+                let next_line = (first + 1..starts.len())
+                    .map(|l| starts[l])
+                    .find(|&s| depths[s] == depth)
+                    .unwrap_or(src.len());
+                let block_end = (start..src.len())
+                    .find(|&i| src.as_bytes()[i] == b'}' && depths[i] == depth)
+                    .unwrap_or(src.len());
+                let end = next_line.min(block_end);
+                if !src[start..end].trim().is_empty() {
+                    return start..end;
+                }
+            }
+        }
+
+        // Otherwise skip the whole declaration
+        let is_decl = |l: usize| {
+            let t = token(l);
+            depths[starts[l]] == 0
+                && ["fn", "extern", "struct", "@"]
+                    .iter()
+                    .any(|k| t.starts_with(k))
+        };
+        let start = (0..=line)
+            .rev()
+            .find(|&l| is_decl(l))
+            .map(|l| starts[l])
+            .unwrap_or(starts[line]);
+        let end = (line + 1..starts.len())
+            .find(|&l| is_decl(l))
+            .map(|l| starts[l])
+            .unwrap_or(src.len());
+        start..end
+    }
+}
+
+peg::parser!(grammar sarus_parser(lines: &LineIndex) for str {
     pub rule program() -> Vec<Declaration>
         = (d:declaration() _ { d })*
 
@@ -330,7 +783,7 @@ peg::parser!(pub grammar parser() for str {
         / structdef()
 
     rule structdef() -> Declaration
-        = _ ext:("extern")? _ "struct" name:identifier() _ "{" _ fields:(a:arg() comma() {a})* _ "}" _ {Declaration::Struct(Struct{name, fields, extern_struct: if ext.is_some() {true} else {false}})}
+        = _ s:position!() ext:("extern")? _ "struct" name:identifier() _ "{" _ fields:(a:arg() comma() {a})* _ "}" e:position!() _ {Declaration::Struct(Struct{name, fields, extern_struct: ext.is_some(), span: lines.span(s, e)})}
 
     rule metadata() -> Declaration
         = _ "@" _ headings:(i:(metadata_identifier()** ([' ' | '\t'])) {i}) ([' ' | '\t'])* "\n" body:$[^'@']* "@" _ {Declaration::Metadata(headings, body.join(""))}
@@ -340,11 +793,11 @@ peg::parser!(pub grammar parser() for str {
         / expected!("identifier")
 
     rule function() -> Declaration
-        = _ ext:("extern")? _  "fn" name:identifier() _
+        = _ s:position!() ext:("extern")? _  "fn" name:identifier() _
         "(" params:(i:arg() ** comma()) ")" _
         "->" _
         "(" returns:(i:arg() ** comma()) _ ")"
-        body:block()
+        body:block() e:position!()
         {
             let mut name = name;
             if let Some(first_param) = params.first() {
@@ -358,12 +811,13 @@ peg::parser!(pub grammar parser() for str {
             params,
             returns,
             body,
-            extern_func: if ext.is_some() {true} else {false},
+            extern_func: ext.is_some(),
+            span: lines.span(s, e),
         }) }
 
     rule arg() -> Arg
-        = _ i:identifier() _ ":" _ t:type_label() _ { Arg {name: i.into(), expr_type: Some(t.into()) } }
-        / _ i:identifier() _ { Arg {name: i.into(), expr_type: None } }
+        = s:pos() i:identifier() _ ":" _ t:type_label() e:position!() _ { Arg {name: i, expr_type: Some(t), span: lines.span(s, e) } }
+        / s:pos() i:identifier() e:position!() _ { Arg {name: i, expr_type: None, span: lines.span(s, e) } }
 
     rule type_label() -> ExprType
         = _ n:$("f64") _ { ExprType::F64 }
@@ -371,6 +825,7 @@ peg::parser!(pub grammar parser() for str {
         / _ n:$("&[f64]") _ { ExprType::UnboundedArrayF64 }
         / _ n:$("&[i64]") _ { ExprType::UnboundedArrayI64 }
         / _ n:$("&") _ { ExprType::Address }
+        / _ "[" t:type_label() "]" _ { ExprType::Slice(Box::new(t)) }
         / _ n:$("bool") _ { ExprType::Bool }
         / _ n:identifier() _ { ExprType::Struct(Box::new(n)) }
 
@@ -379,7 +834,15 @@ peg::parser!(pub grammar parser() for str {
 
     rule statement() -> Expr
         //TODO allow for multiple expressions like: a, b, c returned from if/then/else, etc...
-        = while_loop() / assignment() / expression()
+        = while_loop() / for_loop() / control_flow() / declare_array() / assignment() / expression()
+
+    // Return values are passed through the named return variables
+    rule control_flow() -> Expr
+        = s:pos() "return" !ident_char() end:position!() { Expr::Return(lines.span(s, end)) }
+        / s:pos() "break" !ident_char() end:position!() { Expr::Break(lines.span(s, end)) }
+        / s:pos() "continue" !ident_char() end:position!() { Expr::Continue(lines.span(s, end)) }
+
+    rule ident_char() = ['a'..='z' | 'A'..='Z' | '0'..='9' | '_']
 
     rule expression() -> Expr
         = if_then()
@@ -390,76 +853,99 @@ peg::parser!(pub grammar parser() for str {
         / binary_op()
 
     rule if_then() -> Expr
-        = _ "if" _ e:expression() then_body:block() "\n"
-        { Expr::IfThen(Box::new(e), then_body) }
+        = s:pos() "if" _ e:expression() then_body:block() end:position!() "\n"
+        { Expr::IfThen(lines.span(s, end), Box::new(e), then_body) }
 
     rule if_else() -> Expr
-        = _ "if" e:expression() _ when_true:block() _ "else" when_false:block()
-        { Expr::IfElse(Box::new(e), when_true, when_false) }
+        = s:pos() "if" e:expression() _ when_true:block() _ "else" when_false:block() end:position!()
+        { Expr::IfElse(lines.span(s, end), Box::new(e), when_true, when_false) }
 
     rule while_loop() -> Expr
-        = _ "while" e:expression() body:block()
-        { Expr::WhileLoop(Box::new(e), body) }
+        = s:pos() "while" e:expression() body:block() end:position!()
+        { Expr::WhileLoop(lines.span(s, end), Box::new(e), body) }
+
+    rule for_loop() -> Expr
+        = s:pos() "for" !ident_char() v:identifier() _ "in" !ident_char() _ it:iterable() body:block() end:position!()
+        { Expr::ForLoop(lines.span(s, end), v, Box::new(it), body) }
+
+    rule iterable() -> Expr
+        = range()
+        / s:pos() i:var_identifier() _ "[" r:range() "]" end:position!() { Expr::ArrayGet(lines.span(s, end), i, Box::new(r)) }
+        / expression()
+
+    rule range() -> Expr
+        = a:binary_op() _ ".." _ b:binary_op() { Expr::Range(a.span().join(b.span()), Box::new(a), Box::new(b)) }
+
+    rule declare_array() -> Expr
+        = s:pos() i:identifier() _ ":" _ "[" t:type_label() ";" _ n:array_len() _ "]" end:position!()
+        { Expr::DeclareArray(lines.span(s, end), i, t, n) }
+
+    rule array_len() -> usize
+        = n:$(['0'..='9']+) {? n.parse().or(Err("array length")) }
 
     rule assignment() -> Expr
-        = assignments:((i:var_identifier() {i}) ** comma()) _ "=" args:((_ e:expression() _ {e}) ** comma()) {?
+        = s:pos() assignments:((i:var_identifier() {i}) ** comma()) _ "=" args:((_ e:expression() _ {e}) ** comma()) end:position!() {?
             make_nonempty(assignments)
                 .and_then(|assignments| make_nonempty(args)
-                .map(|args| Expr::Assign(assignments, args)))
+                .map(|args| Expr::Assign(lines.span(s, end), assignments, args)))
                 .ok_or("Cannot assign to/from empty tuple")
         }
 
     rule arrayset() -> Expr
-        = i:var_identifier() _ "[" idx:expression() "]" _ "=" _ e:expression() {Expr::ArraySet(i, Box::new(idx), Box::new(e))}
+        = s:pos() i:var_identifier() _ "[" idx:expression() "]" _ "=" _ e:expression() end:position!() {Expr::ArraySet(lines.span(s, end), i, Box::new(idx), Box::new(e))}
 
 
     rule unary_op() -> Expr = precedence!{
-        "!" e:expression() { Expr::Unaryop(Unaryop::Not, Box::new(e)) }
+        s:pos() "!" e:expression() { Expr::Unaryop(lines.span(s, e.span().end), Unaryop::Not, Box::new(e)) }
     }
 
     rule binary_op() -> Expr = precedence!{
-        a:@ _ "&&" _ b:(@) { Expr::Binop(Binop::LogicalAnd, Box::new(a), Box::new(b)) }
-        a:@ _ "||" _ b:(@) { Expr::Binop(Binop::LogicalOr, Box::new(a), Box::new(b)) }
+        a:(@) __ "&&" _ b:@ { Expr::Binop(a.span().join(b.span()), Binop::LogicalAnd, Box::new(a), Box::new(b)) }
+        a:(@) __ "||" _ b:@ { Expr::Binop(a.span().join(b.span()), Binop::LogicalOr, Box::new(a), Box::new(b)) }
         --
-        a:@ _ "==" b:(@) { Expr::Compare(Cmp::Eq, Box::new(a), Box::new(b)) }
-        a:@ _ "!=" b:(@) { Expr::Compare(Cmp::Ne, Box::new(a), Box::new(b)) }
-        a:@ _ "<"  b:(@) { Expr::Compare(Cmp::Lt, Box::new(a), Box::new(b)) }
-        a:@ _ "<=" b:(@) { Expr::Compare(Cmp::Le, Box::new(a), Box::new(b)) }
-        a:@ _ ">"  b:(@) { Expr::Compare(Cmp::Gt, Box::new(a), Box::new(b)) }
-        a:@ _ ">=" b:(@) { Expr::Compare(Cmp::Ge, Box::new(a), Box::new(b)) }
+        a:(@) __ "==" b:@ { Expr::Compare(a.span().join(b.span()), Cmp::Eq, Box::new(a), Box::new(b)) }
+        a:(@) __ "!=" b:@ { Expr::Compare(a.span().join(b.span()), Cmp::Ne, Box::new(a), Box::new(b)) }
+        a:(@) __ "<"  b:@ { Expr::Compare(a.span().join(b.span()), Cmp::Lt, Box::new(a), Box::new(b)) }
+        a:(@) __ "<=" b:@ { Expr::Compare(a.span().join(b.span()), Cmp::Le, Box::new(a), Box::new(b)) }
+        a:(@) __ ">"  b:@ { Expr::Compare(a.span().join(b.span()), Cmp::Gt, Box::new(a), Box::new(b)) }
+        a:(@) __ ">=" b:@ { Expr::Compare(a.span().join(b.span()), Cmp::Ge, Box::new(a), Box::new(b)) }
         --
-        a:@ _ "+" _ b:(@) { Expr::Binop(Binop::Add, Box::new(a), Box::new(b)) }
-        i:var_identifier() _ "+=" _ a:(@) { Expr::AssignOp(Binop::Add, Box::new(i), Box::new(a)) }
+        a:(@) __ "+" _ b:@ { Expr::Binop(a.span().join(b.span()), Binop::Add, Box::new(a), Box::new(b)) }
+        i:spanned_var() _ "+=" _ a:(@) { Expr::AssignOp(lines.span(i.0, a.span().end), Binop::Add, Box::new(i.1), Box::new(a)) }
 
-        a:@ _ "-" _ b:(@) { Expr::Binop(Binop::Sub, Box::new(a), Box::new(b)) }
-        i:var_identifier() _ "-=" _ a:(@) { Expr::AssignOp(Binop::Sub, Box::new(i), Box::new(a)) }
+        a:(@) __ "-" _ b:@ { Expr::Binop(a.span().join(b.span()), Binop::Sub, Box::new(a), Box::new(b)) }
+        i:spanned_var() _ "-=" _ a:(@) { Expr::AssignOp(lines.span(i.0, a.span().end), Binop::Sub, Box::new(i.1), Box::new(a)) }
         --
-        a:@ _ "*" _ b:(@) { Expr::Binop(Binop::Mul, Box::new(a), Box::new(b)) }
-        i:var_identifier() _ "*=" _ a:(@) { Expr::AssignOp(Binop::Mul, Box::new(i), Box::new(a)) }
+        a:(@) __ "*" _ b:@ { Expr::Binop(a.span().join(b.span()), Binop::Mul, Box::new(a), Box::new(b)) }
+        i:spanned_var() _ "*=" _ a:(@) { Expr::AssignOp(lines.span(i.0, a.span().end), Binop::Mul, Box::new(i.1), Box::new(a)) }
 
-        a:@ _ "/" _ b:(@) { Expr::Binop(Binop::Div, Box::new(a), Box::new(b)) }
-        i:var_identifier() _ "/=" _ a:(@) { Expr::AssignOp(Binop::Div, Box::new(i), Box::new(a)) }
+        a:(@) __ "/" _ b:@ { Expr::Binop(a.span().join(b.span()), Binop::Div, Box::new(a), Box::new(b)) }
+        i:spanned_var() _ "/=" _ a:(@) { Expr::AssignOp(lines.span(i.0, a.span().end), Binop::Div, Box::new(i.1), Box::new(a)) }
+        --
+        c:call() { c }
+        i:spanned_var() _ "{" args:((_ e:struct_assign_field() _ {e})*) "}" end:position!() { Expr::NewStruct(lines.span(i.0, end), i.1, args) }
+        i:spanned_var() __ "[" idx:expression() "]" end:position!() { Expr::ArrayGet(lines.span(i.0, end), i.1, Box::new(idx)) }
+        i:spanned_var() end:position!() { Expr::Identifier(lines.span(i.0, end), i.1) }
+        l:literal() { l }
         --
-        i:var_identifier() _ "(" args:((_ e:expression() _ {e}) ** comma()) ")" {
+        u:unary_op()  { u }
+        --
+        s:pos() "(" e:expression() ")" end:position!() { Expr::Parentheses(lines.span(s, end), Box::new(e)) }
+    }
+
+    rule call() -> Expr
+        = s:pos() i:var_identifier() __ "(" args:((_ e:expression() _ {e}) ** comma()) ")" end:position!() {
+            let span = lines.span(s, end);
             if i.contains(".") {
                 let mut parts = i.split(".").collect::<Vec<&str>>();
                 let mut args = args;
                 let func_name = parts.pop().unwrap().to_string();
-                args.insert(0, Expr::Identifier(parts.join(".")));
-                Expr::Call(func_name, args, true)
+                args.insert(0, Expr::Identifier(span, parts.join(".")));
+                Expr::Call(span, func_name, args, true)
             } else {
-                Expr::Call(i, args, false)
+                Expr::Call(span, i, args, false)
             }
         }
-        i:var_identifier() _ "{" args:((_ e:struct_assign_field() _ {e})*) "}" { Expr::NewStruct(i, args) }
-        i:var_identifier() _ "[" idx:expression() "]" { Expr::ArrayGet(i, Box::new(idx)) }
-        i:var_identifier() { Expr::Identifier(i) }
-        l:literal() { l }
-        --
-        u:unary_op()  { u }
-        --
-        "(" e:expression() ")" { Expr::Parentheses(Box::new(e)) }
-    }
 
     rule identifier() -> String
         = quiet!{ _ n:$((!"true"!"false")['a'..='z' | 'A'..='Z' | '_']['a'..='z' | 'A'..='Z' | '0'..='9' | '_']*) { n.into() } }
@@ -470,20 +956,29 @@ peg::parser!(pub grammar parser() for str {
         = i:(identifier() ++ ".") {i.join(".")}
         / identifier()
 
+    rule spanned_var() -> (usize, String)
+        = s:pos() i:var_identifier() { (s, i) }
+
     rule literal() -> Expr
-        = _ n:$(['-']*['0'..='9']+"."['0'..='9']+) { Expr::LiteralFloat(n.into()) }
-        / _ n:$(['-']*['0'..='9']+) { Expr::LiteralInt(n.into()) }
-        / "*" i:identifier() { Expr::GlobalDataAddr(i) }
-        / _ "true" _ { Expr::LiteralBool(true) }
-        / _ "false" _ { Expr::LiteralBool(false) }
-        / _ "\"" body:$[^'"']* "\"" _ { Expr::LiteralString(body.join("")) }
-        / _ "[" _ "\"" repstr:$[^'\"']* "\"" _ ";" _ len:$(['0'..='9']+) _ "]" _ {
+        = s:pos() n:$(['-']*['0'..='9']+"."['0'..='9']+) end:position!() { Expr::LiteralFloat(lines.span(s, end), n.into()) }
+        / s:pos() n:$(['-']*['0'..='9']+) end:position!() { Expr::LiteralInt(lines.span(s, end), n.into()) }
+        / s:pos() "*" i:identifier() end:position!() { Expr::GlobalDataAddr(lines.span(s, end), i) }
+        / s:pos() "true" end:position!() _ { Expr::LiteralBool(lines.span(s, end), true) }
+        / s:pos() "false" end:position!() _ { Expr::LiteralBool(lines.span(s, end), false) }
+        / s:pos() "\"" body:$[^'"']* "\"" end:position!() _ { Expr::LiteralString(lines.span(s, end), body.join("")) }
+        / s:pos() "[" _ "\"" repstr:$[^'\"']* "\"" _ ";" _ len:$(['0'..='9']+) _ "]" end:position!() _ {
             //Temp solution for creating empty strings
-            Expr::LiteralString(repstr.join("").repeat( len.parse().unwrap()))
+            Expr::LiteralString(lines.span(s, end), repstr.join("").repeat( len.parse().unwrap()))
         } //[" "; 10]
+        / s:pos() "[" _ e:expression() _ ";" _ n:array_len() _ "]" end:position!() { Expr::ArrayRepeat(lines.span(s, end), Box::new(e), n) }
+        / s:pos() "[" items:((_ e:expression() _ {e}) ** comma()) _ "]" end:position!() { Expr::ArrayLiteral(lines.span(s, end), items) }
 
     rule struct_assign_field() -> StructAssignField
-        = _ i:identifier() _ ":" _ e:expression() comma() _ { StructAssignField {field_name: i.into(), expr: e } }
+        = s:pos() i:identifier() _ ":" _ e:expression() end:position!() comma() _ { StructAssignField {field_name: i, expr: e, span: lines.span(s, end) } }
+
+    // Skip whitespace and return the position of the next token
+    rule pos() -> usize
+        = _ p:position!() { p }
 
     rule comment() -> ()
         = quiet!{"//" [^'\n']*"\n"}
@@ -491,4 +986,11 @@ peg::parser!(pub grammar parser() for str {
     rule comma() = _ ","
 
     rule _() =  quiet!{comment() / [' ' | '\t' | '\n']}*
+
+    // Before a binary operator, or the bracket of a call or index, which only
+    // continues an expression if it isn't the first thing on its line. There it
+    // starts the next statement, like the `-` of a negative literal
+    rule __() = quiet!{_ p:position!() {?
+        if lines.starts_line(p) { Err("operator") } else { Ok(()) }
+    }}
 });
@@ -3,7 +3,7 @@ use std::{collections::HashMap, fmt::Debug};
 use toposort_scc::IndexGraph;
 
 use crate::{
-    frontend::{make_nonempty, parser, Arg, Binop, Cmp, Declaration, Expr, Function},
+    frontend::{make_nonempty, parser, Arg, Declaration, Expr, Function, Span},
     jit, sarus_std_lib,
     validator::ExprType,
 };
@@ -53,7 +53,7 @@ impl Graph {
         for node_id in &node_execution_order {
             println!("{}", node_id);
         }
-        println!("");
+        println!();
 
         let graph_func_ast =
             build_graph_func(&connections, &nodes, &ast, block_size, node_execution_order)?;
@@ -76,10 +76,7 @@ impl Graph {
 
 fn order_connections(connections: &Vec<Connection>, nodes: &HashMap<String, Node>) -> Vec<String> {
     // TODO probably implement our own toposort
-    let node_indices = nodes
-        .iter()
-        .map(|(k, _v)| k.to_string())
-        .collect::<Vec<String>>();
+    let node_indices = nodes.keys().map(|k| k.to_string()).collect::<Vec<String>>();
     let mut node_map = HashMap::new();
     for (i, (k, _v)) in nodes.iter().enumerate() {
         node_map.insert(k, i);
@@ -102,9 +99,9 @@ fn order_connections(connections: &Vec<Connection>, nodes: &HashMap<String, Node
 }
 
 fn build_graph_func(
-    connections: &Vec<Connection>,
+    connections: &[Connection],
     nodes: &HashMap<String, Node>,
-    ast: &Vec<Declaration>,
+    ast: &[Declaration],
     block_size: usize,
     node_execution_order: Vec<String>,
 ) -> anyhow::Result<Declaration> {
@@ -118,18 +115,14 @@ fn build_graph_func(
     let mut main_body = Vec::new();
     let mut body = Vec::new();
 
-    main_body.push(Expr::Assign(
-        //i = 0
-        make_nonempty(vec!["i".to_string()]).unwrap(),
-        make_nonempty(vec![Expr::LiteralInt("0".to_string())]).unwrap(),
-    ));
-
     body.push(Expr::Assign(
+        Span::default(),
         //vINPUT_0 = audio[i]
         make_nonempty(vec!["vINPUT_src".to_string()]).unwrap(),
         make_nonempty(vec![Expr::ArrayGet(
+            Span::default(),
             "audio".to_string(),
-            Box::new(Expr::Identifier("i".to_string())),
+            Box::new(Expr::Identifier(Span::default(), "i".to_string())),
         )])
         .unwrap(),
     ));
@@ -152,71 +145,79 @@ fn build_graph_func(
         for param in node_src_ast.params.iter() {
             // find the connection that has this node and port as a dst
             let connection = connections
-                .into_iter()
+                .iter()
                 .filter(|c| c.dst_node == *node_id && c.dst_port == *param.name)
                 .collect::<Vec<&Connection>>();
 
-            if connection.len() > 0 {
+            if !connection.is_empty() {
                 // If a connection if found use the appropriate var name
                 let connection = connection.first().unwrap();
-                param_names.push(Expr::Identifier(format!(
-                    "v{}_{}",
-                    &connection.src_node, connection.src_port
-                )))
+                param_names.push(Expr::Identifier(
+                    Span::default(),
+                    format!("v{}_{}", &connection.src_node, connection.src_port),
+                ))
             } else {
-                println!("{}", format!("{}", node.port_defaults[&param.name]));
+                println!("{}", node.port_defaults[&param.name]);
                 // If there is no connection use the default val
-                param_names.push(Expr::LiteralFloat(format!(
-                    "{:.10}",
-                    node.port_defaults[&param.name]
-                )))
+                param_names.push(Expr::LiteralFloat(
+                    Span::default(),
+                    format!("{:.10}", node.port_defaults[&param.name]),
+                ))
                 //TODO arbitrary precision while always printing decimal?
             }
         }
 
         body.push(Expr::Assign(
+            Span::default(),
             make_nonempty(return_var_names).unwrap(),
-            make_nonempty(vec![Expr::Call(node.func_name.clone(), param_names, false)]).unwrap(),
+            make_nonempty(vec![Expr::Call(
+                Span::default(),
+                node.func_name.clone(),
+                param_names,
+                false,
+            )])
+            .unwrap(),
         ))
     }
 
     let last_node_id = node_execution_order.last().unwrap();
 
     let last_connection = connections
-        .into_iter()
+        .iter()
         .filter(|c| c.dst_node == *last_node_id)
         .collect::<Vec<&Connection>>();
 
     let last_connection = last_connection.first().unwrap();
 
     body.push(Expr::Assign(
+        Span::default(),
         //assign last node to output
         make_nonempty(vec![format!("v{}_dst", last_node_id)]).unwrap(),
-        make_nonempty(vec![Expr::Identifier(format!(
-            "v{}_{}",
-            &last_connection.src_node, last_connection.src_port
-        ))])
+        make_nonempty(vec![Expr::Identifier(
+            Span::default(),
+            format!(
+                "v{}_{}",
+                &last_connection.src_node, last_connection.src_port
+            ),
+        )])
         .unwrap(),
     ));
 
     body.push(Expr::ArraySet(
+        Span::default(),
         "audio".to_string(),
-        Box::new(Expr::Identifier("i".to_string())),
-        Box::new(Expr::Identifier("vOUTPUT_dst".to_string())),
-    ));
-
-    body.push(Expr::AssignOp(
-        //i += 1
-        Binop::Add,
-        Box::new("i".to_string()),
-        Box::new(Expr::LiteralInt("1".to_string())),
+        Box::new(Expr::Identifier(Span::default(), "i".to_string())),
+        Box::new(Expr::Identifier(Span::default(), "vOUTPUT_dst".to_string())),
     ));
 
-    main_body.push(Expr::WhileLoop(
-        Box::new(Expr::Compare(
-            Cmp::Le,
-            Box::new(Expr::Identifier("i".to_string())),
-            Box::new(Expr::LiteralInt(format!("{}", (block_size - 1) as f64))),
+    main_body.push(Expr::ForLoop(
+        Span::default(),
+        //for i in 0..block_size
+        "i".to_string(),
+        Box::new(Expr::Range(
+            Span::default(),
+            Box::new(Expr::LiteralInt(Span::default(), "0".to_string())),
+            Box::new(Expr::LiteralInt(Span::default(), block_size.to_string())),
         )),
         body,
     ));
@@ -226,9 +227,11 @@ fn build_graph_func(
         params: vec![Arg {
             name: "audio".into(),
             expr_type: Some(ExprType::UnboundedArrayF64),
+            span: Span::default(),
         }],
         returns: vec![],
         body: main_body,
         extern_func: false,
+        span: Span::default(),
     }))
 }
@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+
+use crate::{
+    frontend::{Arg, Binop, Declaration, Expr, Function, Span},
+    sarus_std_lib,
+    validator::{ExprType, TypeError},
+};
+
+/// Fill in the types of unannotated function parameters and returns.
+///
+/// Types flow from literals, annotated variables, struct fields and the
+/// signatures of called functions, both into the body of a function and into
+/// the parameters of the functions it calls. This is repeated until nothing
+/// changes, anything still unknown after that falls back to f64. Local
+/// variables take the type of their first assignment, the same way
+/// `declare_variables` types them during codegen.
+///
+/// Unannotated parameters take their types from the first call that
+/// determines them. A call passing other types gets its own copy of the
+/// function, named after the parameter types like `add_node__f64_f64`, which
+/// is added to `prog` and called instead. The copies made of each function are
+/// returned, with the types of their params.
+pub fn infer_types(prog: &mut Vec<Declaration>) -> Result<Specializations, Vec<TypeError>> {
+    let mut inference = Inference::new(prog);
+
+    // Falling back to f64 can make the arguments of more calls known, which
+    // may need copies of the functions they call
+    loop {
+        loop {
+            inference.changed = false;
+            inference.pass(prog);
+            if !inference.changed {
+                break;
+            }
+        }
+        if !inference.default_to_f64() {
+            break;
+        }
+    }
+    // The final pass only looks for conflicts, everything that can be
+    // inferred is known by then
+    inference.report = true;
+    inference.pass(prog);
+
+    if !inference.errors.is_empty() {
+        return Err(inference.errors);
+    }
+
+    let mut specializations = Specializations::new();
+    for ((func, param_types), name) in inference.specializations {
+        specializations
+            .entry(func)
+            .or_default()
+            .push((param_types, name));
+    }
+    for copies in specializations.values_mut() {
+        copies.sort_by(|a, b| a.1.cmp(&b.1));
+    }
+
+    for decl in prog.iter_mut() {
+        if let Declaration::Function(func) = decl {
+            let sig = &inference.sigs[&func.name];
+            for (arg, slot) in func.params.iter_mut().zip(sig.params.iter()) {
+                arg.expr_type = slot.expr_type.clone();
+            }
+            for (arg, slot) in func.returns.iter_mut().zip(sig.returns.iter()) {
+                arg.expr_type = slot.expr_type.clone();
+            }
+        }
+    }
+    Ok(specializations)
+}
+
+/// The name of each copy `infer_types` made of a function, with the types of
+/// its params, by the name of the function in the source.
+pub type Specializations = HashMap<String, Vec<(Vec<ExprType>, String)>>;
+
+/// Name of the function to call as `name` with params that `fits`: `name`
+/// itself if they fit its own, otherwise the copy of it they fit.
+pub(crate) fn resolve<'a>(
+    name: &'a str,
+    funcs: &'a HashMap<String, Function>,
+    specializations: &'a Specializations,
+    fits: impl Fn(&Function) -> bool,
+) -> &'a str {
+    match funcs.get(name) {
+        Some(func) if !fits(func) => specializations
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(|(_, copy)| copy.as_str())
+            .find(|copy| funcs.get(*copy).is_some_and(&fits))
+            .unwrap_or(name),
+        _ => name,
+    }
+}
+
+/// A function parameter or return value.
+#[derive(Debug, Clone)]
+struct Slot {
+    name: String,
+    expr_type: Option<ExprType>,
+    annotated: bool,
+    /// Where the type was inferred from
+    origin: Span,
+}
+
+#[derive(Debug, Clone)]
+struct Signature {
+    params: Vec<Slot>,
+    returns: Vec<Slot>,
+}
+
+impl Signature {
+    fn of(func: &Function) -> Self {
+        // The signature of an extern function is fixed by whatever implements it
+        let slot = |arg: &Arg| Slot {
+            name: arg.name.clone(),
+            expr_type: match &arg.expr_type {
+                None if func.extern_func => Some(ExprType::F64),
+                t => t.clone(),
+            },
+            annotated: arg.expr_type.is_some() || func.extern_func,
+            origin: arg.span,
+        };
+        Signature {
+            params: func.params.iter().map(slot).collect(),
+            returns: func.returns.iter().map(slot).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum SlotRef {
+    Param(usize),
+    Return(usize),
+}
+
+struct Scope<'a> {
+    func: &'a str,
+    locals: HashMap<String, ExprType>,
+    /// Induction variables of the enclosing for loops, innermost last
+    loop_vars: Vec<(String, Option<ExprType>)>,
+}
+
+struct Inference {
+    sigs: HashMap<String, Signature>,
+    /// Functions with unannotated parameters, as they were before any of their
+    /// calls were redirected, to copy for other parameter types
+    generics: HashMap<String, Function>,
+    /// Copies made during the current pass, they are added to the program after it
+    instances: Vec<Function>,
+    /// Name of the copy of each function for each list of param types
+    specializations: HashMap<(String, Vec<ExprType>), String>,
+    structs: HashMap<String, HashMap<String, ExprType>>,
+    constants: HashMap<String, f64>,
+    changed: bool,
+    report: bool,
+    errors: Vec<TypeError>,
+}
+
+impl Inference {
+    fn new(prog: &[Declaration]) -> Self {
+        let mut sigs = HashMap::new();
+        let mut generics = HashMap::new();
+        let mut structs = HashMap::new();
+        for decl in prog {
+            match decl {
+                Declaration::Function(func) => {
+                    sigs.insert(func.name.clone(), Signature::of(func));
+                    if !func.extern_func && func.params.iter().any(|p| p.expr_type.is_none()) {
+                        generics.insert(func.name.clone(), func.clone());
+                    }
+                }
+                Declaration::Struct(s) => {
+                    let fields = s
+                        .fields
+                        .iter()
+                        .map(|f| {
+                            let t = f.expr_type.clone().unwrap_or(ExprType::F64);
+                            (f.name.clone(), t)
+                        })
+                        .collect();
+                    structs.insert(s.name.clone(), fields);
+                }
+                Declaration::Metadata(..) => (),
+            }
+        }
+        Inference {
+            sigs,
+            generics,
+            instances: Vec::new(),
+            specializations: HashMap::new(),
+            structs,
+            constants: sarus_std_lib::get_constants(),
+            changed: false,
+            report: false,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Walk every function once, adding the copies made along the way to `prog`.
+    fn pass(&mut self, prog: &mut Vec<Declaration>) {
+        for decl in prog.iter_mut() {
+            if let Declaration::Function(func) = decl {
+                if !func.extern_func {
+                    let mut scope = Scope {
+                        func: &func.name,
+                        locals: HashMap::new(),
+                        loop_vars: Vec::new(),
+                    };
+                    for expr in &mut func.body {
+                        self.infer(&mut scope, expr, None);
+                    }
+                }
+            }
+        }
+        prog.extend(self.instances.drain(..).map(Declaration::Function));
+    }
+
+    /// Returns whether there was anything left to fall back to f64.
+    fn default_to_f64(&mut self) -> bool {
+        let mut defaulted = false;
+        for sig in self.sigs.values_mut() {
+            for slot in sig.params.iter_mut().chain(sig.returns.iter_mut()) {
+                if slot.expr_type.is_none() {
+                    slot.expr_type = Some(ExprType::F64);
+                    defaulted = true;
+                }
+            }
+        }
+        defaulted
+    }
+
+    fn find_slot(&self, func: &str, name: &str) -> Option<SlotRef> {
+        let sig = self.sigs.get(func)?;
+        if let Some(i) = sig.params.iter().position(|s| s.name == name) {
+            Some(SlotRef::Param(i))
+        } else {
+            sig.returns
+                .iter()
+                .position(|s| s.name == name)
+                .map(SlotRef::Return)
+        }
+    }
+
+    fn slot_type(&self, func: &str, r: SlotRef) -> Option<ExprType> {
+        let sig = &self.sigs[func];
+        match r {
+            SlotRef::Param(i) => sig.params[i].expr_type.clone(),
+            SlotRef::Return(i) => sig.returns[i].expr_type.clone(),
+        }
+    }
+
+    /// Record that the slot is used as `expr_type`, reporting a conflict with a
+    /// previously inferred type in the final pass.
+    fn constrain_slot(&mut self, func: &str, r: SlotRef, expr_type: &ExprType, span: Span) {
+        let sig = self.sigs.get_mut(func).unwrap();
+        let slot = match r {
+            SlotRef::Param(i) => &mut sig.params[i],
+            SlotRef::Return(i) => &mut sig.returns[i],
+        };
+        match &slot.expr_type {
+            None => {
+                slot.expr_type = Some(expr_type.clone());
+                slot.origin = span;
+                self.changed = true;
+            }
+            Some(first) => {
+                let compatible = first.accepts(expr_type) || expr_type.accepts(first);
+                if self.report && !slot.annotated && !compatible {
+                    self.errors.push(TypeError::InferenceConflict {
+                        span,
+                        func: func.to_string(),
+                        name: slot.name.clone(),
+                        first: first.clone(),
+                        first_span: slot.origin,
+                        second: expr_type.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    fn var_type(&self, scope: &Scope, name: &str) -> Option<ExprType> {
+        let mut parts = name.split(".");
+        let base = parts.next().unwrap();
+        let loop_var = scope.loop_vars.iter().rev().find(|(name, _)| name == base);
+        let mut expr_type = if let Some((_, t)) = loop_var {
+            t.clone()
+        } else if let Some(r) = self.find_slot(scope.func, base) {
+            self.slot_type(scope.func, r)
+        } else if let Some(t) = scope.locals.get(base) {
+            Some(t.clone())
+        } else if self.constants.contains_key(base) {
+            Some(ExprType::F64)
+        } else {
+            None
+        };
+        for field in parts {
+            expr_type = match expr_type {
+                Some(ExprType::Struct(s)) => self.structs.get(&*s)?.get(field).cloned(),
+                _ => None,
+            };
+        }
+        expr_type
+    }
+
+    /// Record that the variable holds a value of `expr_type`. Locals keep the
+    /// type of their first assignment.
+    fn constrain_var(&mut self, scope: &mut Scope, name: &str, expr_type: &ExprType, span: Span) {
+        if name.contains(".") {
+            // Struct fields always have a declared type
+        } else if scope.loop_vars.iter().any(|(v, _)| v == name) {
+            // Typed by what the loop iterates over
+        } else if let Some(r) = self.find_slot(scope.func, name) {
+            self.constrain_slot(scope.func, r, expr_type, span);
+        } else if !scope.locals.contains_key(name) && !self.constants.contains_key(name) {
+            scope.locals.insert(name.to_string(), expr_type.clone());
+        }
+    }
+
+    /// Work out the type of `expr`, pushing the `expected` type down into
+    /// variables and function parameters that don't have one yet.
+    fn infer(
+        &mut self,
+        scope: &mut Scope,
+        expr: &mut Expr,
+        expected: Option<&ExprType>,
+    ) -> Option<ExprType> {
+        match expr {
+            Expr::LiteralFloat(..) => Some(ExprType::F64),
+            Expr::LiteralInt(..) => Some(ExprType::I64),
+            Expr::LiteralBool(..) => Some(ExprType::Bool),
+            Expr::LiteralString(..) => Some(ExprType::UnboundedArrayI64),
+            Expr::GlobalDataAddr(..) => Some(ExprType::F64),
+            Expr::Return(..) | Expr::Break(..) | Expr::Continue(..) => Some(ExprType::Void),
+            Expr::Identifier(span, name) => {
+                let expr_type = self.var_type(scope, name);
+                if let Some(expected) = expected {
+                    if expr_type.is_none() || self.find_slot(scope.func, name).is_some() {
+                        self.constrain_var(scope, name, expected, *span);
+                    }
+                }
+                expr_type.or_else(|| expected.cloned())
+            }
+            Expr::Binop(_, Binop::LogicalAnd, l, r) | Expr::Binop(_, Binop::LogicalOr, l, r) => {
+                self.infer(scope, l, Some(&ExprType::Bool));
+                self.infer(scope, r, Some(&ExprType::Bool));
+                Some(ExprType::Bool)
+            }
+            Expr::Binop(_, _, l, r) => self.infer_same(scope, l, r, expected),
+            Expr::Unaryop(_, _, l) => {
+                self.infer(scope, l, Some(&ExprType::Bool));
+                Some(ExprType::Bool)
+            }
+            Expr::Compare(_, _, l, r) => {
+                self.infer_same(scope, l, r, None);
+                Some(ExprType::Bool)
+            }
+            Expr::IfThen(_, cond, then_body) => {
+                self.infer(scope, cond, Some(&ExprType::Bool));
+                self.infer_body(scope, then_body, None);
+                Some(ExprType::Void)
+            }
+            Expr::IfElse(_, cond, then_body, else_body) => {
+                self.infer(scope, cond, Some(&ExprType::Bool));
+                let then_type = self.infer_body(scope, then_body, expected);
+                let else_type = self.infer_body(scope, else_body, then_type.as_ref().or(expected));
+                if then_type.is_none() && else_type.is_some() {
+                    self.infer_body(scope, then_body, else_type.as_ref());
+                }
+                then_type.or(else_type)
+            }
+            Expr::WhileLoop(_, cond, loop_body) => {
+                self.infer(scope, cond, Some(&ExprType::Bool));
+                self.infer_body(scope, loop_body, None);
+                Some(ExprType::Void)
+            }
+            Expr::ForLoop(_, var, iterable, loop_body) => {
+                let var_type = match &mut **iterable {
+                    Expr::ArrayGet(_, name, range) => {
+                        self.infer(scope, range, None);
+                        self.var_type(scope, name).and_then(elem_of)
+                    }
+                    e => match self.infer(scope, e, None) {
+                        Some(ExprType::Slice(elem)) => Some(*elem),
+                        _ => Some(ExprType::I64),
+                    },
+                };
+                scope.loop_vars.push((var.to_string(), var_type));
+                self.infer_body(scope, loop_body, None);
+                scope.loop_vars.pop();
+                Some(ExprType::Void)
+            }
+            Expr::Range(_, start, end) => {
+                self.infer(scope, start, Some(&ExprType::I64));
+                self.infer(scope, end, Some(&ExprType::I64));
+                None
+            }
+            Expr::Block(_, body) => self.infer_body(scope, body, expected),
+            Expr::Parentheses(_, e) => self.infer(scope, e, expected),
+            Expr::Assign(span, vars, exprs) => {
+                if vars.len() == exprs.len() {
+                    let mut expr_types = Vec::new();
+                    for (var, e) in vars.iter().zip(exprs.iter_mut()) {
+                        let var_type = self.var_type(scope, var);
+                        let expr_type = self.infer(scope, e, var_type.as_ref());
+                        if let (None, Some(t)) = (&var_type, &expr_type) {
+                            self.constrain_var(scope, var, t, e.span());
+                        }
+                        expr_types.push(expr_type);
+                    }
+                    expr_types
+                        .into_iter()
+                        .collect::<Option<Vec<_>>>()
+                        .map(ExprType::Tuple)
+                } else {
+                    // Several values from a single expression
+                    let var_types = vars
+                        .iter()
+                        .map(|v| self.var_type(scope, v))
+                        .collect::<Option<Vec<_>>>()
+                        .map(ExprType::Tuple);
+                    let expr_type = self.infer(scope, &mut exprs[0], var_types.as_ref());
+                    if let Some(ExprType::Tuple(items)) = &expr_type {
+                        for (var, t) in vars.iter().zip(items.iter()) {
+                            if self.var_type(scope, var).is_none() {
+                                self.constrain_var(scope, var, t, *span);
+                            }
+                        }
+                    }
+                    expr_type
+                }
+            }
+            Expr::AssignOp(span, _, var, e) => {
+                let var_type = self.var_type(scope, var);
+                let expr_type = self.infer(scope, e, var_type.as_ref());
+                if let (None, Some(t)) = (&var_type, &expr_type) {
+                    self.constrain_var(scope, var, t, *span);
+                }
+                expr_type
+            }
+            Expr::Call(span, name, args, impl_func) => {
+                self.infer_call(scope, *span, name, args, *impl_func, expected)
+            }
+            Expr::ArrayGet(span, name, idx) => {
+                self.infer(scope, idx, None);
+                match self.var_type(scope, name) {
+                    Some(t) => elem_of(t),
+                    None => {
+                        let array_type = array_of(expected?)?;
+                        self.constrain_var(scope, name, &array_type, *span);
+                        expected.cloned()
+                    }
+                }
+            }
+            Expr::ArraySet(span, name, idx, e) => {
+                self.infer(scope, idx, None);
+                let elem_type = self.var_type(scope, name).and_then(elem_of);
+                let expr_type = self.infer(scope, e, elem_type.as_ref());
+                if let (None, Some(t)) = (&elem_type, &expr_type) {
+                    if let Some(array_type) = array_of(t) {
+                        self.constrain_var(scope, name, &array_type, *span);
+                    }
+                }
+                expr_type
+            }
+            Expr::ArrayLiteral(_, items) => {
+                let elem_type = match expected {
+                    Some(ExprType::Slice(elem)) => Some((**elem).clone()),
+                    _ => None,
+                };
+                let mut item_type = None;
+                for item in items {
+                    let t = self.infer(scope, item, elem_type.as_ref().or(item_type.as_ref()));
+                    item_type = item_type.or(t);
+                }
+                Some(ExprType::Slice(Box::new(item_type.or(elem_type)?)))
+            }
+            Expr::ArrayRepeat(_, e, _) => {
+                let elem_type = match expected {
+                    Some(ExprType::Slice(elem)) => Some(&**elem),
+                    _ => None,
+                };
+                let t = self.infer(scope, e, elem_type)?;
+                Some(ExprType::Slice(Box::new(t)))
+            }
+            Expr::DeclareArray(span, var, elem, _) => {
+                let array_type = ExprType::Slice(Box::new(elem.clone()));
+                if self.var_type(scope, var).is_none() {
+                    self.constrain_var(scope, var, &array_type, *span);
+                }
+                Some(ExprType::Void)
+            }
+            Expr::NewStruct(_, struct_name, fields) => {
+                for field in fields.iter_mut() {
+                    let field_type = self
+                        .structs
+                        .get(struct_name)
+                        .and_then(|s| s.get(&field.field_name))
+                        .cloned();
+                    self.infer(scope, &mut field.expr, field_type.as_ref());
+                }
+                Some(ExprType::Struct(Box::new(struct_name.to_string())))
+            }
+        }
+    }
+
+    /// Both sides of a binary operation share the type of the result.
+    fn infer_same(
+        &mut self,
+        scope: &mut Scope,
+        l: &mut Expr,
+        r: &mut Expr,
+        expected: Option<&ExprType>,
+    ) -> Option<ExprType> {
+        let l_type = self.infer(scope, l, expected);
+        let r_type = self.infer(scope, r, l_type.as_ref().or(expected));
+        if l_type.is_none() && r_type.is_some() {
+            self.infer(scope, l, r_type.as_ref());
+        }
+        l_type.or(r_type)
+    }
+
+    fn infer_body(
+        &mut self,
+        scope: &mut Scope,
+        body: &mut [Expr],
+        expected: Option<&ExprType>,
+    ) -> Option<ExprType> {
+        let mut expr_type = Some(ExprType::Void);
+        let len = body.len();
+        for (i, expr) in body.iter_mut().enumerate() {
+            let last = i == len - 1;
+            expr_type = self.infer(scope, expr, if last { expected } else { None });
+        }
+        // A body that returns or breaks doesn't produce a value of its own
+        if body.last().is_some_and(Expr::diverges) {
+            None
+        } else {
+            expr_type
+        }
+    }
+
+    fn infer_call(
+        &mut self,
+        scope: &mut Scope,
+        span: Span,
+        name: &mut String,
+        args: &mut [Expr],
+        impl_func: bool,
+        expected: Option<&ExprType>,
+    ) -> Option<ExprType> {
+        let mut fn_name = if impl_func {
+            match self.var_type(scope, &args[0].to_string()) {
+                Some(ExprType::Struct(s)) => format!("{}.{}", s, name),
+                _ => name.to_string(),
+            }
+        } else {
+            name.to_string()
+        };
+        let mut sig = match self.sigs.get(&fn_name) {
+            Some(sig) => sig.clone(),
+            None => {
+                for arg in args {
+                    self.infer(scope, arg, None);
+                }
+                // The built in len(arr)
+                return if fn_name == "len" {
+                    Some(ExprType::I64)
+                } else {
+                    None
+                };
+            }
+        };
+
+        let mut arg_types = Vec::new();
+        for (i, arg) in args.iter_mut().enumerate() {
+            let param_type = sig.params.get(i).and_then(|p| p.expr_type.clone());
+            arg_types.push(self.infer(scope, arg, param_type.as_ref()));
+        }
+        let instance = if impl_func {
+            None
+        } else {
+            self.specialize(&fn_name, &arg_types)
+        };
+        if let Some(instance) = instance {
+            *name = instance.clone();
+            fn_name = instance;
+            sig = self.sigs[&fn_name].clone();
+        } else {
+            for (i, (arg, arg_type)) in args.iter().zip(arg_types).enumerate() {
+                if let (Some(arg_type), true) = (arg_type, i < sig.params.len()) {
+                    self.constrain_slot(&fn_name, SlotRef::Param(i), &arg_type, arg.span());
+                }
+            }
+        }
+
+        match sig.returns.len() {
+            0 => Some(ExprType::Void),
+            1 => match (&sig.returns[0].expr_type, expected) {
+                (None, Some(expected)) => {
+                    self.constrain_slot(&fn_name, SlotRef::Return(0), expected, span);
+                    Some(expected.clone())
+                }
+                (t, _) => t.clone(),
+            },
+            _ => {
+                if let Some(ExprType::Tuple(items)) = expected {
+                    for (i, (slot, t)) in sig.returns.iter().zip(items.iter()).enumerate() {
+                        if slot.expr_type.is_none() {
+                            self.constrain_slot(&fn_name, SlotRef::Return(i), t, span);
+                        }
+                    }
+                }
+                self.sigs[&fn_name]
+                    .returns
+                    .iter()
+                    .map(|r| r.expr_type.clone())
+                    .collect::<Option<Vec<_>>>()
+                    .map(ExprType::Tuple)
+            }
+        }
+    }
+
+    /// Name of the copy of `func` to call for arguments of `arg_types`, if they
+    /// conflict with the types already inferred for its unannotated parameters.
+    fn specialize(&mut self, func: &str, arg_types: &[Option<ExprType>]) -> Option<String> {
+        let original = self.generics.get(func)?;
+        let sig = &self.sigs[func];
+        if arg_types.len() != sig.params.len() {
+            return None;
+        }
+        let conflict = sig.params.iter().zip(arg_types).any(|(slot, arg_type)| {
+            match (&slot.expr_type, arg_type) {
+                (Some(first), Some(t)) => !slot.annotated && !first.accepts(t) && !t.accepts(first),
+                _ => false,
+            }
+        });
+        if !conflict {
+            return None;
+        }
+        let param_types = sig
+            .params
+            .iter()
+            .zip(arg_types)
+            .map(|(slot, arg_type)| match (slot.annotated, arg_type) {
+                (false, Some(t)) => Some(t.clone()),
+                _ => slot.expr_type.clone(),
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let key = (func.to_string(), param_types);
+        if let Some(name) = self.specializations.get(&key) {
+            return Some(name.clone());
+        }
+        // A name C can link to, unlike `add_node<f64, f64>`, that no function
+        // in the program has already
+        let mangled = format!(
+            "{}__{}",
+            func,
+            key.1.iter().map(mangle).collect::<Vec<_>>().join("_")
+        );
+        let mut name = mangled.clone();
+        let mut n = 1;
+        while self.sigs.contains_key(&name) {
+            name = format!("{}_{}", mangled, n);
+            n += 1;
+        }
+        let mut instance = original.clone();
+        instance.name = name.clone();
+        for (param, t) in instance.params.iter_mut().zip(&key.1) {
+            param.expr_type = Some(t.clone());
+        }
+        self.sigs.insert(name.clone(), Signature::of(&instance));
+        self.instances.push(instance);
+        self.specializations.insert(key, name.clone());
+        self.changed = true;
+        Some(name)
+    }
+}
+
+/// A type in the name of a copy of a function, in characters valid in a C identifier.
+fn mangle(expr_type: &ExprType) -> String {
+    match expr_type {
+        ExprType::UnboundedArrayF64 => "ptr_f64".to_string(),
+        ExprType::UnboundedArrayI64 => "ptr_i64".to_string(),
+        ExprType::Address => "ptr".to_string(),
+        ExprType::Tuple(items) => format!(
+            "tuple_{}_",
+            items.iter().map(mangle).collect::<Vec<_>>().join("_")
+        ),
+        ExprType::Slice(elem) => format!("slice_{}", mangle(elem)),
+        t => t.to_string(),
+    }
+}
+
+fn elem_of(array_type: ExprType) -> Option<ExprType> {
+    match array_type {
+        ExprType::UnboundedArrayF64 => Some(ExprType::F64),
+        ExprType::UnboundedArrayI64 => Some(ExprType::I64),
+        ExprType::Slice(elem) => Some(*elem),
+        _ => None,
+    }
+}
+
+fn array_of(elem_type: &ExprType) -> Option<ExprType> {
+    match elem_type {
+        ExprType::F64 => Some(ExprType::UnboundedArrayF64),
+        ExprType::I64 => Some(ExprType::UnboundedArrayI64),
+        _ => None,
+    }
+}
@@ -0,0 +1,1121 @@
+//! Running programs by walking their AST, without generating any code. It
+//! follows the semantics of the code `JIT` generates, down to how structs and
+//! arrays are laid out in memory, so it can run where compiling at runtime
+//! isn't allowed, be stepped through, and check the JIT against.
+
+use crate::frontend::*;
+use crate::inference::{infer_types, resolve, Specializations};
+use crate::jit::{create_struct_map, StructDef};
+use crate::sarus_std_lib;
+use crate::sarus_value::SarusValue;
+use crate::trap::{Trap, TrapCode, TrapKind};
+use crate::typed_func::HostFn;
+use crate::validator::ExprType;
+use cranelift::prelude::Type;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem;
+use std::ptr;
+use target_lexicon::Triple;
+
+/// Like `anyhow::bail`, but also for the `Flow` results of evaluating expressions.
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return Err(anyhow::anyhow!($($arg)*).into())
+    };
+}
+
+/// A Rust function registered with `Interpreter::register_fn`, taking and
+/// returning values in 8 byte slots.
+type HostCall = Box<dyn Fn(&[u64]) -> Vec<u64>>;
+
+pub struct Interpreter {
+    /// Check indices into slices, as `JIT::bounds_checks` does
+    pub bounds_checks: bool,
+    /// Use up fuel on function entry and each loop iteration, as
+    /// `JIT::consume_fuel` does
+    pub consume_fuel: bool,
+    fuel: Cell<i64>,
+    funcs: HashMap<String, Function>,
+    specializations: Specializations,
+    structs: HashMap<String, StructDef>,
+    host_fns: HashMap<String, (Function, HostCall)>,
+    constants: HashMap<String, f64>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+/// A value while the program runs. Structs and arrays are pointers to memory
+/// laid out as the compiled code lays it out.
+#[derive(Debug, Clone)]
+enum Value {
+    Void,
+    Bool(bool),
+    F64(f64),
+    I64(i64),
+    UnboundedArrayF64(*mut u8),
+    UnboundedArrayI64(*mut u8),
+    Address(*mut u8),
+    Tuple(Vec<Value>),
+    Struct(String, *mut u8),
+    Slice(ExprType, *mut u8, i64),
+}
+
+/// Why evaluation stopped before reaching the end of an expression.
+enum Unwind {
+    Break,
+    Continue,
+    Return,
+    Error(anyhow::Error),
+}
+
+impl From<anyhow::Error> for Unwind {
+    fn from(e: anyhow::Error) -> Self {
+        Unwind::Error(e)
+    }
+}
+
+type Flow<T> = Result<T, Unwind>;
+
+/// The variables of a running function, and the memory for the structs and
+/// arrays it makes.
+struct Frame {
+    name: String,
+    variables: HashMap<String, Value>,
+    /// Memory for each place in the function that makes room for something,
+    /// reused every time it's reached, like the stack slots of compiled code
+    memory: HashMap<(*const (), usize, usize), Box<[u64]>>,
+}
+
+impl Frame {
+    fn new(name: &str) -> Self {
+        Frame {
+            name: name.to_string(),
+            variables: HashMap::new(),
+            memory: HashMap::new(),
+        }
+    }
+
+    /// Memory for the `index`th thing made at `site`, zeroed the first time.
+    fn alloc<T>(&mut self, site: &T, index: usize, size: u32) -> *mut u8 {
+        let words = (size as usize).div_ceil(8);
+        let key = (site as *const T as *const (), index, words);
+        self.memory
+            .entry(key)
+            .or_insert_with(|| vec![0; words.max(1)].into_boxed_slice())
+            .as_mut_ptr() as *mut u8
+    }
+
+    fn variable(&self, name: &str) -> anyhow::Result<Value> {
+        match self.variables.get(name) {
+            Some(v) => Ok(v.clone()),
+            None => anyhow::bail!("variable {} not found", name),
+        }
+    }
+
+    fn trap(&self, kind: TrapKind) -> anyhow::Error {
+        Trap {
+            kind,
+            function: Some(self.name.clone()),
+            offset: None,
+        }
+        .into()
+    }
+}
+
+fn arg_type(arg: &Arg) -> ExprType {
+    arg.expr_type.clone().unwrap_or(ExprType::F64)
+}
+
+fn read<T: Copy>(ptr: *const u8) -> T {
+    unsafe { ptr::read_unaligned(ptr as *const T) }
+}
+
+fn write<T>(ptr: *mut u8, value: T) {
+    unsafe { ptr::write_unaligned(ptr as *mut T, value) }
+}
+
+impl Value {
+    /// A return variable that's never assigned, zero like in compiled code.
+    fn zero(expr_type: &ExprType) -> anyhow::Result<Value> {
+        match expr_type {
+            ExprType::Slice(_) => anyhow::bail!("returning slices not supported yet"),
+            ExprType::Void => Ok(Value::Void),
+            t => Value::from_slot(t, 0),
+        }
+    }
+
+    /// The value of type `expr_type` held in an 8 byte slot.
+    fn from_slot(expr_type: &ExprType, slot: u64) -> anyhow::Result<Value> {
+        let ptr = slot as usize as *mut u8;
+        Ok(match expr_type {
+            ExprType::Bool => Value::Bool(slot != 0),
+            ExprType::F64 => Value::F64(f64::from_bits(slot)),
+            ExprType::I64 => Value::I64(slot as i64),
+            ExprType::UnboundedArrayF64 => Value::UnboundedArrayF64(ptr),
+            ExprType::UnboundedArrayI64 => Value::UnboundedArrayI64(ptr),
+            ExprType::Address => Value::Address(ptr),
+            ExprType::Struct(name) => Value::Struct(name.to_string(), ptr),
+            t => anyhow::bail!("{} isn't held in a single value", t),
+        })
+    }
+
+    /// The 8 byte slot the value is passed or stored in.
+    fn slot(&self) -> anyhow::Result<u64> {
+        Ok(match self {
+            Value::Bool(v) => *v as u64,
+            Value::F64(v) => v.to_bits(),
+            Value::I64(v) => *v as u64,
+            Value::UnboundedArrayF64(ptr)
+            | Value::UnboundedArrayI64(ptr)
+            | Value::Address(ptr)
+            | Value::Struct(_, ptr) => *ptr as usize as u64,
+            Value::Void => anyhow::bail!("void has no value"),
+            Value::Tuple(v) => anyhow::bail!("tuple {:?} isn't a single value", v),
+            Value::Slice(..) => anyhow::bail!("slice is a pointer and a length"),
+        })
+    }
+
+    fn expr_type(&self) -> ExprType {
+        match self {
+            Value::Void => ExprType::Void,
+            Value::Bool(_) => ExprType::Bool,
+            Value::F64(_) => ExprType::F64,
+            Value::I64(_) => ExprType::I64,
+            Value::UnboundedArrayF64(_) => ExprType::UnboundedArrayF64,
+            Value::UnboundedArrayI64(_) => ExprType::UnboundedArrayI64,
+            Value::Address(_) => ExprType::Address,
+            Value::Tuple(v) => ExprType::Tuple(v.iter().map(Value::expr_type).collect()),
+            Value::Struct(name, _) => ExprType::Struct(Box::new(name.to_string())),
+            Value::Slice(elem, ..) => ExprType::Slice(Box::new(elem.clone())),
+        }
+    }
+
+    fn expect_bool(&self, ctx: &str) -> anyhow::Result<bool> {
+        match self {
+            Value::Bool(v) => Ok(*v),
+            v => anyhow::bail!("incorrect type {} expected Bool {}", v.expr_type(), ctx),
+        }
+    }
+
+    fn expect_i64(&self, ctx: &str) -> anyhow::Result<i64> {
+        match self {
+            Value::I64(v) => Ok(*v),
+            v => anyhow::bail!("incorrect type {} expected Int {}", v.expr_type(), ctx),
+        }
+    }
+
+    /// What a function gives back for its returns, like a call expression.
+    fn from_returns(mut values: Vec<Value>) -> Value {
+        match values.len() {
+            0 => Value::Void,
+            1 => values.pop().unwrap(),
+            _ => Value::Tuple(values),
+        }
+    }
+}
+
+/// Like cranelift's `fcvt_to_uint`, which indices given as a float go through.
+fn float_to_index(x: f64) -> Result<i64, TrapCode> {
+    if x.is_nan() {
+        Err(TrapCode::BadConversionToInteger)
+    } else if x <= -1.0 || x >= 18446744073709551616.0 {
+        Err(TrapCode::IntegerOverflow)
+    } else {
+        Ok(x as u64 as i64)
+    }
+}
+
+fn compare<T: PartialOrd>(cmp: Cmp, a: T, b: T) -> bool {
+    match cmp {
+        Cmp::Eq => a == b,
+        Cmp::Ne => a != b,
+        Cmp::Lt => a < b,
+        Cmp::Le => a <= b,
+        Cmp::Gt => a > b,
+        Cmp::Ge => a >= b,
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter {
+            bounds_checks: true,
+            consume_fuel: false,
+            fuel: Cell::new(0),
+            funcs: HashMap::new(),
+            specializations: HashMap::new(),
+            structs: HashMap::new(),
+            host_fns: HashMap::new(),
+            constants: sarus_std_lib::get_constants(),
+        }
+    }
+
+    /// Make a Rust function callable from sarus code as `name`, like
+    /// `JIT::register_fn`.
+    pub fn register_fn<F: HostFn + 'static>(&mut self, name: &str, func: F) {
+        self.host_fns.insert(
+            name.to_string(),
+            (
+                F::declaration(name),
+                Box::new(move |slots: &[u64]| func.call_slots(slots)),
+            ),
+        );
+    }
+
+    /// Get a program ready to run, replacing the one loaded before. Unlike
+    /// `JIT::translate`, the types in function bodies aren't checked, a program
+    /// the JIT would reject fails when the offending expression is reached.
+    pub fn load(&mut self, mut prog: Vec<Declaration>) -> anyhow::Result<()> {
+        for (name, (decl, _)) in &self.host_fns {
+            for d in &prog {
+                if let Declaration::Function(func) = d {
+                    if func.name == *name {
+                        return Err(Diagnostics(vec![Diagnostic::new(
+                            func.span,
+                            format!(
+                                "{} is registered with register_fn, it can't also be declared",
+                                name
+                            ),
+                        )])
+                        .into());
+                    }
+                }
+            }
+            prog.push(Declaration::Function(decl.clone()));
+        }
+
+        // Fill in the types of parameters and returns that were left unannotated
+        self.specializations = infer_types(&mut prog)
+            .map_err(|errors| Diagnostics(errors.into_iter().map(Diagnostic::from).collect()))?;
+
+        // Memory is shared with the host, so structs are laid out for it
+        let ptr_type = Type::triple_pointer_type(&Triple::host());
+        self.structs = create_struct_map(&prog, ptr_type)?;
+        self.funcs = prog
+            .into_iter()
+            .filter_map(|d| match d {
+                Declaration::Function(func) => Some((func.name.clone(), func)),
+                _ => None,
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Layout of a struct from the loaded program, the same as `JIT::get_struct` gives.
+    pub fn get_struct(&self, struct_name: &str) -> anyhow::Result<&StructDef> {
+        match self.structs.get(struct_name) {
+            Some(struct_def) => Ok(struct_def),
+            None => anyhow::bail!("No struct {} found", struct_name),
+        }
+    }
+
+    /// Set how much fuel is left when `consume_fuel` is set. Running out
+    /// returns `TrapKind::OutOfFuel` from `call`.
+    pub fn set_fuel(&mut self, fuel: i64) {
+        self.fuel.set(fuel);
+    }
+
+    /// The fuel left, less than zero after running out.
+    pub fn fuel(&self) -> i64 {
+        self.fuel.get()
+    }
+
+    /// Call a function, taking and returning the same values as `JIT::call`.
+    /// Writes to arrays and through `self` end up in `args`, like they do there.
+    pub fn call(&self, fn_name: &str, args: &mut [SarusValue]) -> anyhow::Result<Vec<SarusValue>> {
+        let fn_name = resolve(fn_name, &self.funcs, &self.specializations, |func| {
+            SarusValue::fit(args, func)
+        });
+        let func = match self.funcs.get(fn_name) {
+            Some(func) if !func.extern_func => func,
+            Some(_) => anyhow::bail!("can't call extern function {}", fn_name),
+            None => anyhow::bail!("No function {} found", fn_name),
+        };
+        if args.len() != func.params.len() {
+            anyhow::bail!(
+                "function {} takes {} arguments but {} were given",
+                fn_name,
+                func.params.len(),
+                args.len()
+            )
+        }
+
+        let mut struct_returns = Vec::new();
+        for ret in &func.returns {
+            match arg_type(ret) {
+                ExprType::F64 | ExprType::I64 | ExprType::Bool => (),
+                ExprType::Struct(struct_name) => {
+                    let bytes = vec![0u8; self.get_struct(&struct_name)?.size as usize];
+                    struct_returns.push(SarusValue::Struct(struct_name.to_string(), bytes));
+                }
+                t => anyhow::bail!("can't return {} from {} through call", t, fn_name),
+            }
+        }
+        let struct_return_ptrs = struct_returns
+            .iter_mut()
+            .map(|ret| match ret {
+                SarusValue::Struct(_, bytes) => bytes.as_mut_ptr(),
+                _ => unreachable!(),
+            })
+            .collect();
+
+        let mut values = Vec::new();
+        for (param, arg) in func.params.iter().zip(args.iter_mut()) {
+            values.push(match (arg_type(param), arg) {
+                (ExprType::F64, SarusValue::F64(v)) => Value::F64(*v),
+                (ExprType::I64, SarusValue::I64(v)) => Value::I64(*v),
+                (ExprType::Bool, SarusValue::Bool(v)) => Value::Bool(*v),
+                (ExprType::UnboundedArrayF64, SarusValue::ArrayF64(v)) => {
+                    Value::UnboundedArrayF64(v.as_mut_ptr() as *mut u8)
+                }
+                (ExprType::UnboundedArrayI64, SarusValue::ArrayI64(v)) => {
+                    Value::UnboundedArrayI64(v.as_mut_ptr() as *mut u8)
+                }
+                (ExprType::Slice(elem), SarusValue::ArrayF64(v)) if *elem == ExprType::F64 => {
+                    Value::Slice(ExprType::F64, v.as_mut_ptr() as *mut u8, v.len() as i64)
+                }
+                (ExprType::Slice(elem), SarusValue::ArrayI64(v)) if *elem == ExprType::I64 => {
+                    Value::Slice(ExprType::I64, v.as_mut_ptr() as *mut u8, v.len() as i64)
+                }
+                (ExprType::Struct(struct_name), SarusValue::Struct(name, bytes))
+                    if *struct_name == *name =>
+                {
+                    let size = self.get_struct(name)?.size as usize;
+                    if bytes.len() != size {
+                        anyhow::bail!(
+                            "argument {} of {} should be {} bytes, found {}",
+                            param.name,
+                            fn_name,
+                            size,
+                            bytes.len()
+                        )
+                    }
+                    Value::Struct(name.to_string(), bytes.as_mut_ptr())
+                }
+                (t, arg) => anyhow::bail!(
+                    "argument {} of {} should be {}, found {}",
+                    param.name,
+                    fn_name,
+                    t,
+                    arg.type_name()
+                ),
+            });
+        }
+
+        let returns = self.call_function(func, struct_return_ptrs, values)?;
+
+        let mut struct_returns = struct_returns.into_iter();
+        let mut sarus_values = Vec::new();
+        for value in returns {
+            sarus_values.push(match value {
+                Value::F64(v) => SarusValue::F64(v),
+                Value::I64(v) => SarusValue::I64(v),
+                Value::Bool(v) => SarusValue::Bool(v),
+                _ => struct_returns.next().unwrap(),
+            });
+        }
+        Ok(sarus_values)
+    }
+
+    /// Run a sarus function. `struct_returns` is where each returned struct is
+    /// written, the values returned for them point there.
+    fn call_function(
+        &self,
+        func: &Function,
+        struct_returns: Vec<*mut u8>,
+        args: Vec<Value>,
+    ) -> anyhow::Result<Vec<Value>> {
+        let mut frame = Frame::new(&func.name);
+        self.use_fuel(&frame)?;
+        for (param, arg) in func.params.iter().zip(args) {
+            frame.variables.insert(param.name.clone(), arg);
+        }
+
+        // Returned structs are built right where the caller wants them, unless
+        // the return is also a param
+        let mut struct_returns = struct_returns.into_iter();
+        let mut return_ptrs = Vec::new();
+        for ret in &func.returns {
+            let ret_type = arg_type(ret);
+            let ptr = match ret_type {
+                ExprType::Struct(_) => struct_returns.next(),
+                _ => None,
+            };
+            return_ptrs.push(ptr);
+            if func.params.iter().any(|param| param.name == ret.name) {
+                continue;
+            }
+            let value = match (ret_type, ptr) {
+                (ExprType::Struct(name), Some(ptr)) => Value::Struct(name.to_string(), ptr),
+                (t, _) => Value::zero(&t)?,
+            };
+            frame.variables.insert(ret.name.clone(), value);
+        }
+
+        // Structs are passed by value, except `self`
+        for param in &func.params {
+            if let (ExprType::Struct(struct_name), false) = (arg_type(param), param.name == "self")
+            {
+                let src = frame.variable(&param.name)?.slot()? as usize as *mut u8;
+                let size = self.get_struct(&struct_name)?.size;
+                let dest = frame.alloc(param, 0, size);
+                unsafe { ptr::copy(src, dest, size as usize) };
+                frame.variables.insert(
+                    param.name.clone(),
+                    Value::Struct(struct_name.to_string(), dest),
+                );
+            }
+        }
+
+        for expr in &func.body {
+            match self.eval(expr, &mut frame) {
+                Ok(_) => (),
+                Err(Unwind::Return) => break,
+                Err(Unwind::Break) | Err(Unwind::Continue) => {
+                    anyhow::bail!("break or continue outside of a loop")
+                }
+                Err(Unwind::Error(e)) => return Err(e),
+            }
+        }
+
+        let mut values = Vec::new();
+        for (ret, ptr) in func.returns.iter().zip(return_ptrs) {
+            let value = frame.variable(&ret.name)?;
+            match (arg_type(ret), ptr) {
+                (ExprType::Void, _) => continue,
+                (ExprType::Struct(struct_name), Some(dest)) => {
+                    // The struct may already be in the caller's memory
+                    let src = value.slot()? as usize as *mut u8;
+                    let size = self.get_struct(&struct_name)?.size;
+                    unsafe { ptr::copy(src, dest, size as usize) };
+                    values.push(Value::Struct(struct_name.to_string(), dest));
+                }
+                _ => values.push(value),
+            }
+        }
+        Ok(values)
+    }
+
+    fn use_fuel(&self, frame: &Frame) -> anyhow::Result<()> {
+        if self.consume_fuel {
+            let fuel = self.fuel.get().wrapping_sub(1);
+            self.fuel.set(fuel);
+            if fuel < 0 {
+                return Err(frame.trap(TrapKind::OutOfFuel));
+            }
+        }
+        Ok(())
+    }
+
+    fn eval(&self, expr: &Expr, frame: &mut Frame) -> Flow<Value> {
+        // The innermost expression that failed gives the most precise location
+        self.eval_inner(expr, frame).map_err(|unwind| match unwind {
+            Unwind::Error(e) if !e.is::<Diagnostic>() && !e.is::<Trap>() => {
+                Unwind::Error(Diagnostic::new(expr.span(), e.to_string()).into())
+            }
+            unwind => unwind,
+        })
+    }
+
+    fn eval_inner(&self, expr: &Expr, frame: &mut Frame) -> Flow<Value> {
+        match expr {
+            Expr::LiteralFloat(_, literal) => match literal.parse() {
+                Ok(v) => Ok(Value::F64(v)),
+                Err(_) => bail!("invalid float {}", literal),
+            },
+            Expr::LiteralInt(_, literal) => match literal.parse() {
+                Ok(v) => Ok(Value::I64(v)),
+                Err(_) => bail!("invalid int {}", literal),
+            },
+            Expr::LiteralBool(_, b) => Ok(Value::Bool(*b)),
+            Expr::LiteralString(_, literal) => {
+                let cstr = match CString::new(literal.replace("\\n", "\n")) {
+                    Ok(cstr) => cstr,
+                    Err(_) => bail!("strings can't contain NUL"),
+                };
+                let bytes = cstr.to_bytes_with_nul();
+                let ptr = frame.alloc(expr, 0, bytes.len() as u32);
+                unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len()) };
+                Ok(Value::UnboundedArrayI64(ptr))
+            }
+            Expr::Identifier(_, name) => {
+                if name.contains('.') {
+                    let (ptr, field_type) = self.field_ptr(name, frame)?;
+                    Ok(self.load_elem(&field_type, ptr)?)
+                } else if let Some(v) = frame.variables.get(name) {
+                    Ok(v.clone())
+                } else if let Some(v) = self.constants.get(name) {
+                    Ok(Value::F64(*v))
+                } else {
+                    bail!("variable {} not found", name)
+                }
+            }
+            Expr::Binop(_, op, lhs, rhs) => {
+                let lhs = self.eval(lhs, frame)?;
+                let rhs = self.eval(rhs, frame)?;
+                Ok(self.binop(*op, lhs, rhs, frame)?)
+            }
+            Expr::Unaryop(_, op, lhs) => match (op, self.eval(lhs, frame)?) {
+                (Unaryop::Not, Value::Bool(v)) => Ok(Value::Bool(!v)),
+                (op, lhs) => bail!("operation not supported: {:?} {}", lhs, op),
+            },
+            Expr::Compare(_, cmp, lhs, rhs) => {
+                let lhs = self.eval(lhs, frame)?;
+                let rhs = self.eval(rhs, frame)?;
+                Ok(Value::Bool(match (lhs, rhs) {
+                    (Value::F64(a), Value::F64(b)) => compare(*cmp, a, b),
+                    (Value::I64(a), Value::I64(b)) => compare(*cmp, a, b),
+                    (Value::Bool(a), Value::Bool(b)) => compare(*cmp, a as i64, b as i64),
+                    (lhs, rhs) => bail!("compare not supported: {:?} {} {:?}", lhs, cmp, rhs),
+                }))
+            }
+            Expr::IfThen(_, condition, then_body) => {
+                if self.eval(condition, frame)?.expect_bool("if_then")? {
+                    self.eval_body(then_body, frame)?;
+                }
+                Ok(Value::Void)
+            }
+            Expr::IfElse(_, condition, then_body, else_body) => {
+                if self.eval(condition, frame)?.expect_bool("if_else")? {
+                    self.eval_body(then_body, frame)
+                } else {
+                    self.eval_body(else_body, frame)
+                }
+            }
+            Expr::Assign(_, names, exprs) => self.eval_assign(expr, names, exprs, frame),
+            Expr::AssignOp(_, op, name, rhs) => {
+                if name.contains('.') {
+                    let field =
+                        self.eval(&Expr::Identifier(rhs.span(), name.to_string()), frame)?;
+                    let rhs = self.eval(rhs, frame)?;
+                    let new_val = self.binop(*op, field, rhs, frame)?;
+                    let (ptr, field_type) = self.field_ptr(name, frame)?;
+                    self.store_elem(&field_type, ptr, new_val.clone())?;
+                    return Ok(new_val);
+                }
+                let rhs = self.eval(rhs, frame)?;
+                let new_val = match (frame.variable(name)?, rhs) {
+                    (lhs @ Value::F64(_), rhs @ Value::F64(_))
+                    | (lhs @ Value::I64(_), rhs @ Value::I64(_)) => {
+                        self.binop(*op, lhs, rhs, frame)?
+                    }
+                    (_, rhs) => bail!("math assign {} not supported", rhs.expr_type()),
+                };
+                frame.variables.insert(name.to_string(), new_val.clone());
+                Ok(new_val)
+            }
+            Expr::NewStruct(_, struct_name, fields) => {
+                let ptr = frame.alloc(expr, 0, self.get_struct(struct_name)?.size);
+                for field in fields {
+                    let v = self.eval(&field.expr, frame)?;
+                    let field_def = match self.structs[struct_name].fields.get(&field.field_name) {
+                        Some(field_def) => field_def,
+                        None => bail!("struct {} has no field {}", struct_name, field.field_name),
+                    };
+                    // Nested structs are stored inline, store copies them in
+                    self.store_elem(
+                        &field_def.expr_type,
+                        ptr.wrapping_add(field_def.offset as usize),
+                        v,
+                    )?;
+                }
+                Ok(Value::Struct(struct_name.to_string(), ptr))
+            }
+            Expr::WhileLoop(_, condition, loop_body) => {
+                loop {
+                    self.use_fuel(frame)?;
+                    if !self.eval(condition, frame)?.expect_bool("while_loop")? {
+                        break;
+                    }
+                    match self.eval_body(loop_body, frame) {
+                        Ok(_) | Err(Unwind::Continue) => (),
+                        Err(Unwind::Break) => break,
+                        Err(unwind) => return Err(unwind),
+                    }
+                }
+                Ok(Value::Void)
+            }
+            Expr::ForLoop(_, var, iterable, loop_body) => {
+                self.eval_for_loop(var, iterable, loop_body, frame)
+            }
+            Expr::Range(..) => bail!("ranges can only be used in a for loop"),
+            Expr::Return(_) => Err(Unwind::Return),
+            Expr::Break(_) => Err(Unwind::Break),
+            Expr::Continue(_) => Err(Unwind::Continue),
+            Expr::Block(_, body) => self.eval_body(body, frame),
+            Expr::Call(_, name, args, impl_func) => {
+                self.eval_call(expr, name, args, *impl_func, frame)
+            }
+            Expr::GlobalDataAddr(_, name) => {
+                bail!(
+                    "data addresses like *{} aren't supported by the interpreter",
+                    name
+                )
+            }
+            Expr::Parentheses(_, expr) => self.eval(expr, frame),
+            Expr::ArrayGet(_, name, idx_expr) => {
+                let (ptr, elem_type) = self.elem_ptr(name, idx_expr, frame)?;
+                Ok(self.load_elem(&elem_type, ptr)?)
+            }
+            Expr::ArraySet(_, name, idx_expr, expr) => {
+                let new_val = self.eval(expr, frame)?;
+                let (ptr, elem_type) = self.elem_ptr(name, idx_expr, frame)?;
+                self.store_elem(&elem_type, ptr, new_val)?;
+                Ok(Value::Void)
+            }
+            Expr::ArrayLiteral(_, items) => {
+                let mut values = Vec::new();
+                for item in items {
+                    values.push(self.eval(item, frame)?);
+                }
+                let elem_type = match values.first() {
+                    Some(v) => v.expr_type(),
+                    None => bail!("can't tell the type of an empty array"),
+                };
+                let stride = self.elem_size(&elem_type)?;
+                let ptr = frame.alloc(expr, 0, stride * values.len() as u32);
+                let len = values.len() as i64;
+                for (i, value) in values.into_iter().enumerate() {
+                    self.store_elem(&elem_type, ptr.wrapping_add(i * stride as usize), value)?;
+                }
+                Ok(Value::Slice(elem_type, ptr, len))
+            }
+            Expr::ArrayRepeat(_, value_expr, len) => {
+                let value = self.eval(value_expr, frame)?;
+                let elem_type = value.expr_type();
+                let stride = self.elem_size(&elem_type)?;
+                let ptr = frame.alloc(expr, 0, stride * *len as u32);
+                for i in 0..*len {
+                    self.store_elem(
+                        &elem_type,
+                        ptr.wrapping_add(i * stride as usize),
+                        value.clone(),
+                    )?;
+                }
+                Ok(Value::Slice(elem_type, ptr, *len as i64))
+            }
+            Expr::DeclareArray(_, name, elem_type, len) => {
+                let size = self.elem_size(elem_type)? * *len as u32;
+                let ptr = frame.alloc(expr, 0, size);
+                unsafe { ptr::write_bytes(ptr, 0, size as usize) };
+                frame.variables.insert(
+                    name.to_string(),
+                    Value::Slice(elem_type.clone(), ptr, *len as i64),
+                );
+                Ok(Value::Void)
+            }
+        }
+    }
+
+    /// The value of a list of statements is the value of the last one.
+    fn eval_body(&self, body: &[Expr], frame: &mut Frame) -> Flow<Value> {
+        let mut value = Value::Void;
+        for expr in body {
+            value = self.eval(expr, frame)?;
+        }
+        Ok(value)
+    }
+
+    fn binop(&self, op: Binop, lhs: Value, rhs: Value, frame: &Frame) -> anyhow::Result<Value> {
+        Ok(match (op, lhs, rhs) {
+            (Binop::Add, Value::F64(a), Value::F64(b)) => Value::F64(a + b),
+            (Binop::Sub, Value::F64(a), Value::F64(b)) => Value::F64(a - b),
+            (Binop::Mul, Value::F64(a), Value::F64(b)) => Value::F64(a * b),
+            (Binop::Div, Value::F64(a), Value::F64(b)) => Value::F64(a / b),
+            (Binop::Add, Value::I64(a), Value::I64(b)) => Value::I64(a.wrapping_add(b)),
+            (Binop::Sub, Value::I64(a), Value::I64(b)) => Value::I64(a.wrapping_sub(b)),
+            (Binop::Mul, Value::I64(a), Value::I64(b)) => Value::I64(a.wrapping_mul(b)),
+            (Binop::Div, Value::I64(a), Value::I64(b)) => {
+                if b == 0 {
+                    return Err(frame.trap(TrapKind::Code(TrapCode::IntegerDivisionByZero)));
+                }
+                match a.checked_div(b) {
+                    Some(v) => Value::I64(v),
+                    None => return Err(frame.trap(TrapKind::Code(TrapCode::IntegerOverflow))),
+                }
+            }
+            // Both sides are evaluated, like in compiled code
+            (Binop::LogicalAnd, Value::Bool(a), Value::Bool(b)) => Value::Bool(a && b),
+            (Binop::LogicalOr, Value::Bool(a), Value::Bool(b)) => Value::Bool(a || b),
+            (op, lhs, rhs) => {
+                anyhow::bail!("operation not supported: {:?} {} {:?}", lhs, op, rhs)
+            }
+        })
+    }
+
+    fn eval_assign(
+        &self,
+        expr: &Expr,
+        names: &[String],
+        exprs: &[Expr],
+        frame: &mut Frame,
+    ) -> Flow<Value> {
+        // With as many expressions as names each is assigned in turn, otherwise
+        // the first expression gives a tuple of values
+        if names.len() == exprs.len() {
+            let mut values = Vec::new();
+            for (i, (name, value_expr)) in names.iter().zip(exprs).enumerate() {
+                // Structs are values, assigning one that already exists makes a copy
+                let fresh = matches!(value_expr, Expr::NewStruct(..) | Expr::Call(..));
+                let value = self.eval(value_expr, frame)?;
+                if name.contains('.') {
+                    let (ptr, field_type) = self.field_ptr(name, frame)?;
+                    self.store_elem(&field_type, ptr, value.clone())?;
+                    values.push(value);
+                    continue;
+                }
+                let value = match value {
+                    Value::Tuple(_) => bail!("operation not supported: assign Tuple"),
+                    Value::Void => bail!("operation not supported: assign Void"),
+                    Value::Struct(struct_name, src) if !fresh => {
+                        let size = self.get_struct(&struct_name)?.size;
+                        let dest = frame.alloc(expr, i, size);
+                        unsafe { ptr::copy(src, dest, size as usize) };
+                        Value::Struct(struct_name, dest)
+                    }
+                    value => value,
+                };
+                frame.variables.insert(name.to_string(), value.clone());
+                values.push(value);
+            }
+            Ok(Value::from_returns(values))
+        } else {
+            match self.eval(&exprs[0], frame)? {
+                Value::Tuple(values) => {
+                    for (name, value) in names.iter().zip(&values) {
+                        if name.contains('.') {
+                            let (ptr, field_type) = self.field_ptr(name, frame)?;
+                            self.store_elem(&field_type, ptr, value.clone())?;
+                        } else {
+                            frame.variables.insert(name.to_string(), value.clone());
+                        }
+                    }
+                    Ok(Value::Tuple(values))
+                }
+                value => bail!(
+                    "operation not supported: assign {} to {} variables",
+                    value.expr_type(),
+                    names.len()
+                ),
+            }
+        }
+    }
+
+    /// The loop variable is an element of the array, or the index when
+    /// iterating over a range. It's only set inside the body, which can't
+    /// change the number of iterations.
+    fn eval_for_loop(
+        &self,
+        var: &str,
+        iterable: &Expr,
+        loop_body: &[Expr],
+        frame: &mut Frame,
+    ) -> Flow<Value> {
+        let (range, array) = match iterable {
+            Expr::ArrayGet(_, name, range) => (Some(&**range), Some(frame.variable(name)?)),
+            Expr::Range(..) => (Some(iterable), None),
+            slice => (None, Some(self.eval(slice, frame)?)),
+        };
+        let (start, end) = match (range, &array) {
+            (Some(Expr::Range(_, start, end)), _) => (
+                self.eval(start, frame)?.expect_i64("for_loop")?,
+                self.eval(end, frame)?.expect_i64("for_loop")?,
+            ),
+            // The whole slice
+            (None, Some(Value::Slice(_, _, len))) => (0, *len),
+            _ => bail!("can only iterate over ranges and slices"),
+        };
+        let elems = match &array {
+            Some(Value::Slice(elem, ptr, _)) => Some((*ptr, elem.clone())),
+            Some(Value::UnboundedArrayF64(ptr)) => Some((*ptr, ExprType::F64)),
+            Some(Value::UnboundedArrayI64(ptr)) => Some((*ptr, ExprType::I64)),
+            Some(v) => bail!("can't iterate over {}", v.expr_type()),
+            None => None,
+        };
+        // Only a range given in the source can go past the end of a slice
+        let check_len = match &array {
+            Some(Value::Slice(_, _, len)) if range.is_some() && self.bounds_checks => Some(*len),
+            _ => None,
+        };
+
+        let shadowed = frame.variables.remove(var);
+        let mut i = start;
+        loop {
+            self.use_fuel(frame)?;
+            if i >= end {
+                break;
+            }
+            let value = match &elems {
+                Some((ptr, elem_type)) => {
+                    if let Some(len) = check_len {
+                        self.bounds_check(i, len, frame)?;
+                    }
+                    let stride = self.elem_size(elem_type)? as i64;
+                    self.load_elem(elem_type, ptr.wrapping_add(i.wrapping_mul(stride) as usize))?
+                }
+                None => Value::I64(i),
+            };
+            frame.variables.insert(var.to_string(), value);
+            match self.eval_body(loop_body, frame) {
+                Ok(_) | Err(Unwind::Continue) => (),
+                Err(Unwind::Break) => break,
+                Err(unwind) => return Err(unwind),
+            }
+            i = i.wrapping_add(1);
+        }
+        match shadowed {
+            Some(v) => frame.variables.insert(var.to_string(), v),
+            None => frame.variables.remove(var),
+        };
+        Ok(Value::Void)
+    }
+
+    fn eval_call(
+        &self,
+        expr: &Expr,
+        name: &str,
+        args: &[Expr],
+        impl_func: bool,
+        frame: &mut Frame,
+    ) -> Flow<Value> {
+        let mut name = name.to_string();
+        if impl_func {
+            match frame.variables.get(&args[0].to_string()) {
+                Some(Value::Struct(struct_name, _)) => {
+                    name = format!("{}.{}", struct_name, name);
+                }
+                _ => bail!("{} is not a struct", args[0]),
+            }
+        }
+
+        if name == "len" && !self.funcs.contains_key(&name) {
+            return match self.eval(&args[0], frame)? {
+                Value::Slice(_, _, len) => Ok(Value::I64(len)),
+                v => bail!("incorrect type {} expected Slice len", v.expr_type()),
+            };
+        }
+
+        let func = match self.funcs.get(&name) {
+            Some(func) => func,
+            None => bail!("function {} not found", name),
+        };
+        if func.params.len() != args.len() {
+            bail!(
+                "function call to {} has {} args, but function description has {}",
+                name,
+                args.len(),
+                func.params.len()
+            )
+        }
+
+        if func.extern_func {
+            // Compiled code evaluates the arguments here for the std functions
+            // it generates inline, and a second time below for any other
+            // extern function, so they're evaluated twice here too
+            let mut values = Vec::new();
+            for arg in args {
+                values.push(match self.eval(arg, frame)? {
+                    Value::F64(v) => SarusValue::F64(v),
+                    Value::Bool(v) => SarusValue::Bool(v),
+                    // Pointers by their address
+                    Value::Slice(_, ptr, _) => SarusValue::I64(ptr as usize as i64),
+                    v => SarusValue::I64(v.slot()? as i64),
+                });
+            }
+            match sarus_std_lib::interpret_std(&name, &values) {
+                Ok(Some(SarusValue::F64(v))) => return Ok(Value::F64(v)),
+                Ok(Some(SarusValue::I64(v))) => return Ok(Value::I64(v)),
+                Ok(Some(v)) => bail!("unexpected {} from {}", v.type_name(), name),
+                Ok(None) => (),
+                Err(code) => return Err(frame.trap(TrapKind::Code(code)).into()),
+            }
+        }
+
+        // Make room for returned structs, the callee writes them here
+        let mut struct_returns = Vec::new();
+        for (i, ret) in func.returns.iter().enumerate() {
+            if let ExprType::Struct(struct_name) = arg_type(ret) {
+                let size = self.get_struct(&struct_name)?.size;
+                struct_returns.push(frame.alloc(expr, i, size));
+            }
+        }
+        let mut values = Vec::new();
+        for (param, arg) in func.params.iter().zip(args) {
+            let param_type = arg_type(param);
+            values.push(match (&param_type, self.eval(arg, frame)?) {
+                (ExprType::Slice(_), slice @ Value::Slice(..)) => slice,
+                // Passed on as a plain pointer
+                (t, Value::Slice(_, ptr, _)) => Value::from_slot(t, ptr as usize as u64)?,
+                // Pointers are whatever the param says they point to
+                (
+                    t @ (ExprType::UnboundedArrayF64
+                    | ExprType::UnboundedArrayI64
+                    | ExprType::Address
+                    | ExprType::Struct(_)),
+                    v @ (Value::UnboundedArrayF64(_)
+                    | Value::UnboundedArrayI64(_)
+                    | Value::Address(_)
+                    | Value::Struct(..)),
+                ) => Value::from_slot(t, v.slot()?)?,
+                (_, v) => v,
+            });
+        }
+
+        let returns = if let Some((_, host_call)) = self.host_fns.get(&name) {
+            let mut slots = Vec::new();
+            for value in &values {
+                match value {
+                    Value::Slice(_, ptr, len) => {
+                        slots.push(*ptr as usize as u64);
+                        slots.push(*len as u64);
+                    }
+                    v => slots.push(v.slot()?),
+                }
+            }
+            let return_slots = host_call(&slots);
+            let mut returns = Vec::new();
+            for (ret, slot) in func.returns.iter().zip(return_slots) {
+                returns.push(Value::from_slot(&arg_type(ret), slot)?);
+            }
+            returns
+        } else if func.extern_func {
+            let mut floats = Vec::new();
+            for value in &values {
+                match value {
+                    Value::F64(v) => floats.push(*v),
+                    v => bail!("incorrect type {} expected Float {}", v.expr_type(), name),
+                }
+            }
+            match sarus_std_lib::call_libm(&name, &floats) {
+                Some(v) => vec![Value::F64(v)],
+                None => bail!(
+                    "extern function {} isn't supported by the interpreter, it can be \
+                     registered with register_fn instead",
+                    name
+                ),
+            }
+        } else {
+            self.call_function(func, struct_returns, values)?
+        };
+        Ok(Value::from_returns(returns))
+    }
+
+    /// Address of the field at the end of a path like `line.a.x`. Nested
+    /// structs are stored inline, so this is an offset from the outermost struct.
+    fn field_ptr(&self, path: &str, frame: &Frame) -> anyhow::Result<(*mut u8, ExprType)> {
+        let mut parts = path.split('.');
+        let base = parts.next().unwrap();
+        let (mut ptr, mut field_type) = match frame.variable(base)? {
+            Value::Struct(struct_name, ptr) => (ptr, ExprType::Struct(Box::new(struct_name))),
+            v => anyhow::bail!("{} is {}, not a struct", base, v.expr_type()),
+        };
+        for field in parts {
+            let struct_field = match &field_type {
+                ExprType::Struct(struct_name) => {
+                    match self.get_struct(struct_name)?.fields.get(field) {
+                        Some(struct_field) => struct_field,
+                        None => anyhow::bail!("struct {} has no field {}", struct_name, field),
+                    }
+                }
+                t => anyhow::bail!("{} has no field {}", t, field),
+            };
+            ptr = ptr.wrapping_add(struct_field.offset as usize);
+            field_type = struct_field.expr_type.clone();
+        }
+        Ok((ptr, field_type))
+    }
+
+    /// Address of an element of an array along with the type of the element.
+    /// Indices into slices are checked against the length of the slice.
+    fn elem_ptr(
+        &self,
+        name: &str,
+        idx_expr: &Expr,
+        frame: &mut Frame,
+    ) -> Flow<(*mut u8, ExprType)> {
+        let (array_ptr, elem_type, len) = match frame.variable(name)? {
+            Value::UnboundedArrayF64(ptr) => (ptr, ExprType::F64, None),
+            Value::UnboundedArrayI64(ptr) => (ptr, ExprType::I64, None),
+            Value::Slice(elem, ptr, len) => (ptr, elem, Some(len)),
+            _ => bail!("{} is not an array", name),
+        };
+        let idx = match self.eval(idx_expr, frame)? {
+            Value::F64(v) => match float_to_index(v) {
+                Ok(idx) => idx,
+                Err(code) => return Err(frame.trap(TrapKind::Code(code)).into()),
+            },
+            Value::I64(v) => v,
+            _ => bail!("only int and float supported for array access"),
+        };
+        if let (Some(len), true) = (len, self.bounds_checks) {
+            self.bounds_check(idx, len, frame)?;
+        }
+        let stride = self.elem_size(&elem_type)? as i64;
+        Ok((
+            array_ptr.wrapping_add(idx.wrapping_mul(stride) as usize),
+            elem_type,
+        ))
+    }
+
+    /// Trap unless `0 <= idx < len`.
+    fn bounds_check(&self, idx: i64, len: i64, frame: &Frame) -> anyhow::Result<()> {
+        if (idx as u64) < (len as u64) {
+            Ok(())
+        } else {
+            Err(frame.trap(TrapKind::IndexOutOfBounds { index: idx, len }))
+        }
+    }
+
+    fn elem_size(&self, elem_type: &ExprType) -> anyhow::Result<u32> {
+        Ok(match elem_type {
+            ExprType::Struct(struct_name) => self.get_struct(struct_name)?.size,
+            ExprType::Bool => anyhow::bail!("arrays of bool are not supported"),
+            ExprType::F64 => mem::size_of::<f64>() as u32,
+            ExprType::I64 => mem::size_of::<i64>() as u32,
+            ExprType::UnboundedArrayF64 | ExprType::UnboundedArrayI64 | ExprType::Address => {
+                mem::size_of::<usize>() as u32
+            }
+            t => anyhow::bail!("{} can't be stored in an array", t),
+        })
+    }
+
+    /// Read a value from memory. Structs are not copied out, the value refers
+    /// to the struct in place.
+    fn load_elem(&self, expr_type: &ExprType, ptr: *mut u8) -> anyhow::Result<Value> {
+        match expr_type {
+            ExprType::Struct(struct_name) => Ok(Value::Struct(struct_name.to_string(), ptr)),
+            // Bools take up a byte in memory, like a C bool
+            ExprType::Bool => Ok(Value::Bool(read::<u8>(ptr) != 0)),
+            ExprType::UnboundedArrayF64 | ExprType::UnboundedArrayI64 | ExprType::Address => {
+                Value::from_slot(expr_type, read::<usize>(ptr) as u64)
+            }
+            t => Value::from_slot(t, read::<u64>(ptr)),
+        }
+    }
+
+    fn store_elem(&self, expr_type: &ExprType, ptr: *mut u8, value: Value) -> anyhow::Result<()> {
+        match (expr_type, value) {
+            // Structs are stored inline, copy the whole thing
+            (ExprType::Struct(struct_name), Value::Struct(_, src)) => {
+                let size = self.get_struct(struct_name)?.size;
+                unsafe { ptr::copy(src, ptr, size as usize) };
+            }
+            (ExprType::Bool, Value::Bool(v)) => write(ptr, v as u8),
+            (
+                _,
+                v @ (Value::UnboundedArrayF64(_)
+                | Value::UnboundedArrayI64(_)
+                | Value::Address(_)
+                | Value::Struct(..)),
+            ) => write(ptr, v.slot()? as usize),
+            (_, v) => write(ptr, v.slot()?),
+        }
+        Ok(())
+    }
+}
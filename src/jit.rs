@@ -1,19 +1,68 @@
+use crate::c_header;
+use crate::code_cache::{CachedFunction, Fnv1a};
 use crate::frontend::*;
+use crate::inference::{infer_types, resolve, Specializations};
 use crate::sarus_std_lib;
+use crate::sarus_struct::SarusStruct;
+use crate::sarus_value::SarusValue;
+use crate::trap;
+use crate::typed_func::{abi_types, HostFn, SarusParams, SarusReturns, TypedFunc};
 use crate::validator::validate_program;
 use crate::validator::ExprType;
 use cranelift::codegen::ir::immediates::Offset32;
-use cranelift::codegen::ir::Opcode;
+use cranelift::codegen::ir::SourceLoc;
+pub use cranelift::codegen::settings::OptLevel;
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{DataContext, Linkage, Module};
-use std::collections::HashMap;
+use cranelift_module::{DataContext, DataId, FuncId, Linkage, Module, RelocRecord};
+use cranelift_object::{ObjectBuilder, ObjectModule};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::fmt::Display;
+use std::mem;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::slice;
+use std::str::FromStr;
+use target_lexicon::Triple;
 
-/// The basic JIT class.
-pub struct JIT {
+/// What the module functions are compiled into has to do, on top of `Module`.
+pub trait Backend: Module {
+    /// Resolve the relocations of everything defined so far, so it can be used.
+    fn finalize(&mut self);
+
+    /// Where the code of a finalized function is, if it's in memory.
+    fn function_address(&self, id: FuncId) -> Option<*const u8>;
+}
+
+impl Backend for JITModule {
+    fn finalize(&mut self) {
+        self.finalize_definitions();
+    }
+
+    fn function_address(&self, id: FuncId) -> Option<*const u8> {
+        Some(self.get_finalized_function(id))
+    }
+}
+
+impl Backend for ObjectModule {
+    // Relocations are left for the linker
+    fn finalize(&mut self) {}
+
+    fn function_address(&self, _id: FuncId) -> Option<*const u8> {
+        None
+    }
+}
+
+/// Data shared between the host and compiled code, the fuel left is at `FUEL_OFFSET`.
+const CONTEXT: &str = "__sarus_context";
+const CONTEXT_SIZE: usize = 8;
+const FUEL_OFFSET: i32 = 0;
+
+/// The basic JIT class. Code is compiled into memory with the default
+/// `JITModule`, or into an object file with `JIT::new_object`.
+pub struct JIT<M: Backend = JITModule> {
     /// The function builder context, which is reused across multiple
     /// FunctionBuilder instances.
     builder_context: FunctionBuilderContext,
@@ -26,41 +75,164 @@ pub struct JIT {
     /// The data context, which is to data objects what `ctx` is to functions.
     data_ctx: DataContext,
 
-    /// The module, with the jit backend, which manages the JIT'd
-    /// functions.
-    module: JITModule,
+    /// The module, with the jit or object backend, which manages the
+    /// compiled functions.
+    module: M,
 
     //CLIF cranelift IR string, by function name
     pub clif: HashMap<String, String>,
 
     //local variables for each function
     pub variables: HashMap<String, HashMap<String, SVariable>>,
+
+    //Check indexing into slices against their length, trapping when out of bounds
+    pub bounds_checks: bool,
+
+    //Use up a unit of fuel on each function call and loop iteration, trapping once
+    //there's none left, so untrusted code can't run forever. See `set_fuel`
+    pub consume_fuel: bool,
+
+    //`bounds_checks` and `consume_fuel` as the current program was compiled with,
+    //everything is compiled again when they change
+    compiled_with: (bool, bool),
+
+    //Memory layout of each struct, the same as the equivalent #[repr(C)] struct
+    pub structs: HashMap<String, StructDef>,
+
+    //Functions of the last translated program, with their types filled in
+    pub funcs: HashMap<String, Function>,
+
+    //Copies made of functions with unannotated params for other param types, see `infer_types`
+    pub specializations: Specializations,
+
+    //Declaration of each Rust function registered with `register_fn`
+    host_fns: HashMap<String, Function>,
+
+    //Address of each registered Rust function, shared with the module so calls to them are
+    //resolved by name like any other symbol
+    host_symbols: Rc<RefCell<HashMap<String, *const u8>>>,
+
+    //Functions used by `call` to pass arguments from slots in memory, by the signature they call
+    trampolines: HashMap<Signature, *const u8>,
+
+    //Symbol of the current version of each function, they get a new one each time they're redefined
+    symbols: HashMap<String, String>,
+
+    //How many times `translate` has been called, to make the symbols of redefined functions unique
+    generation: usize,
+
+    //Where to keep the machine code of compiled functions, to skip codegen when translating the same
+    //program with the same settings again. Calls to Rust functions registered with `register_fn`
+    //are resolved by name when the code is loaded, so it stays valid from one run to the next.
+    pub cache_dir: Option<PathBuf>,
+
+    //How many functions were loaded from `cache_dir` instead of compiled
+    cache_hits: usize,
 }
 
-impl Default for JIT {
+/// How code is generated, see `JIT::with_config` and `JIT::new_object_with_config`.
+/// The defaults are Cranelift's, for the host and the CPU features it has.
+#[derive(Debug, Clone)]
+pub struct JITConfig {
+    pub opt_level: OptLevel,
+
+    /// Check the IR of every function before compiling it
+    pub enable_verifier: bool,
+
+    /// Target triple like `x86_64-unknown-linux-gnu`, the host when `None`.
+    /// Only an object can be compiled for a different target than the host.
+    pub target: Option<String>,
+
+    /// ISA flags to enable on top of the ones the host has, like `has_avx2`
+    pub cpu_features: Vec<String>,
+}
+
+impl Default for JITConfig {
     fn default() -> Self {
-        let builder = JITBuilder::new(cranelift_module::default_libcall_names());
-        let module = JITModule::new(builder);
         Self {
-            builder_context: FunctionBuilderContext::new(),
-            ctx: module.make_context(),
-            data_ctx: DataContext::new(),
-            module,
-            clif: HashMap::new(),
-            variables: HashMap::new(),
+            opt_level: OptLevel::None,
+            enable_verifier: true,
+            target: None,
+            cpu_features: Vec::new(),
         }
     }
 }
 
+impl JITConfig {
+    /// The ISA to compile for, `flag_builder` has the settings that depend on
+    /// what the code is compiled into.
+    fn isa(&self, mut flag_builder: settings::Builder) -> anyhow::Result<Box<dyn isa::TargetIsa>> {
+        flag_builder.set("opt_level", &self.opt_level.to_string())?;
+        flag_builder.set("enable_verifier", &self.enable_verifier.to_string())?;
+        let mut isa_builder = match &self.target {
+            Some(triple) => {
+                let parsed = Triple::from_str(triple)
+                    .map_err(|e| anyhow::anyhow!("target {}: {}", triple, e))?;
+                isa::lookup(parsed).map_err(|e| anyhow::anyhow!("target {}: {}", triple, e))?
+            }
+            None => cranelift_native::builder().map_err(|e| anyhow::anyhow!("{}", e))?,
+        };
+        for feature in &self.cpu_features {
+            isa_builder.enable(feature)?;
+        }
+        Ok(isa_builder.finish(settings::Flags::new(flag_builder)))
+    }
+}
+
+impl Default for JIT {
+    fn default() -> Self {
+        JIT::new(&[])
+    }
+}
+
 impl JIT {
     pub fn new(symbols: &[(&str, *const u8)]) -> Self {
-        let mut builder = JITBuilder::new(cranelift_module::default_libcall_names());
+        JIT::with_config(symbols, &JITConfig::default()).expect("the host isn't supported")
+    }
 
+    /// A JIT that generates code as `config` says. It can only compile for the host.
+    pub fn with_config(symbols: &[(&str, *const u8)], config: &JITConfig) -> anyhow::Result<Self> {
+        let mut flag_builder = settings::builder();
+        flag_builder.set("use_colocated_libcalls", "false")?;
+        flag_builder.set("is_pic", "false")?;
+        let isa = config.isa(flag_builder)?;
+        let host = cranelift_native::builder().map_err(|e| anyhow::anyhow!("{}", e))?;
+        if isa.triple() != host.triple() {
+            anyhow::bail!(
+                "can't run code for {} on {}, it can only be compiled into an object",
+                isa.triple(),
+                host.triple()
+            )
+        }
+        let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
+
+        builder.symbol(
+            sarus_std_lib::INDEX_OUT_OF_BOUNDS,
+            sarus_std_lib::index_out_of_bounds as *const u8,
+        );
         for (name, func) in symbols {
             builder.symbol(*name, *func);
         }
+        let host_symbols = Rc::new(RefCell::new(HashMap::new()));
+        let lookup = Rc::clone(&host_symbols);
+        builder.symbol_lookup_fn(Box::new(move |name| lookup.borrow().get(name).copied()));
+
+        Ok(JIT::from_module(JITModule::new(builder), host_symbols))
+    }
 
-        let module = JITModule::new(builder);
+    /// Make a Rust function callable from sarus code as `name`. Its `extern fn`
+    /// declaration is added to every program that's translated, with the
+    /// types taken from the Rust signature.
+    pub fn register_fn<F: HostFn>(&mut self, name: &str, func: F) {
+        self.host_fns.insert(name.to_string(), F::declaration(name));
+        self.host_symbols
+            .borrow_mut()
+            .insert(name.to_string(), func.ptr());
+    }
+}
+
+impl<M: Backend> JIT<M> {
+    fn from_module(module: M, host_symbols: Rc<RefCell<HashMap<String, *const u8>>>) -> Self {
         Self {
             builder_context: FunctionBuilderContext::new(),
             ctx: module.make_context(),
@@ -68,11 +240,48 @@ impl JIT {
             module,
             clif: HashMap::new(),
             variables: HashMap::new(),
+            bounds_checks: true,
+            consume_fuel: false,
+            compiled_with: (true, false),
+            structs: HashMap::new(),
+            funcs: HashMap::new(),
+            specializations: HashMap::new(),
+            host_fns: HashMap::new(),
+            host_symbols,
+            trampolines: HashMap::new(),
+            symbols: HashMap::new(),
+            generation: 0,
+            cache_dir: None,
+            cache_hits: 0,
         }
     }
 
-    /// Compile a string in the toy language into machine code.
-    pub fn translate(&mut self, prog: Vec<Declaration>) -> anyhow::Result<()> {
+    /// Compile a string in the toy language into machine code. Translating
+    /// again redefines the functions that changed, code compiled before
+    /// stays valid and keeps calling the versions it was compiled with.
+    pub fn translate(&mut self, mut prog: Vec<Declaration>) -> anyhow::Result<()> {
+        for (name, decl) in &self.host_fns {
+            for d in &prog {
+                if let Declaration::Function(func) = d {
+                    if func.name == *name {
+                        return Err(Diagnostics(vec![Diagnostic::new(
+                            func.span,
+                            format!(
+                                "{} is registered with register_fn, it can't also be declared",
+                                name
+                            ),
+                        )])
+                        .into());
+                    }
+                }
+            }
+            prog.push(Declaration::Function(decl.clone()));
+        }
+
+        // Fill in the types of parameters and returns that were left unannotated
+        let specializations = infer_types(&mut prog)
+            .map_err(|errors| Diagnostics(errors.into_iter().map(Diagnostic::from).collect()))?;
+
         //let mut return_counts = HashMap::new();
         //for func in prog.iter().filter_map(|d| match d {
         //    Declaration::Function(func) => Some(func.clone()),
@@ -91,77 +300,446 @@ impl JIT {
 
         let struct_map = create_struct_map(&prog, self.module.target_config().pointer_type())?;
 
-        // First, parse the string, producing AST nodes.
+        // Only what changed since the last program, and whatever calls it, is
+        // compiled again. Everything else keeps running the code it already has.
+        let changed = self.changed_functions(&funcs, &struct_map);
+
+        // A function that's redefined gets a new symbol, rather than replacing
+        // the old one, so code compiled earlier and pointers from `get_func` stay
+        // valid. Callers are recompiled to call the new version.
+        self.generation += 1;
+        let mut symbols = self.symbols.clone();
+        symbols.retain(|name, _| funcs.contains_key(name));
+        for name in &changed {
+            if funcs.get(name).is_none_or(|func| func.extern_func) {
+                symbols.remove(name);
+            } else if self.module.get_name(name).is_none() {
+                symbols.insert(name.clone(), name.clone());
+            } else {
+                symbols.insert(name.clone(), format!("{}@{}", name, self.generation));
+            }
+        }
+
+        if self.consume_fuel {
+            self.context_id()?;
+        }
+
+        let cache = self
+            .cache_dir
+            .as_ref()
+            .map(|dir| (dir.clone(), self.cache_key(&prog)));
+
+        // Keep going after a function fails to compile so every problem gets reported
+        let mut diagnostics = Diagnostics::default();
+
+        // First, translate the AST nodes of each function into Cranelift IR,
+        // unless its machine code is in the cache.
+        let mut compiled = Vec::new();
         for d in prog.clone() {
             match d {
                 Declaration::Function(func) => {
-                    if func.extern_func {
+                    if func.extern_func || !changed.contains(&func.name) {
                         //Don't parse contents of std func, it will be empty
                         continue;
                     }
-                    ////println!(
-                    ////    "name {:?}, params {:?}, the_return {:?}",
-                    ////    &name, &params, &the_return
-                    ////);
-                    //// Then, translate the AST nodes into Cranelift IR.
-                    self.codegen(&func, funcs.to_owned(), &prog, &struct_map)?;
-                    // Next, declare the function to jit. Functions must be declared
-                    // before they can be called, or defined.
-                    let id = self
-                        .module
-                        .declare_function(&func.name, Linkage::Export, &self.ctx.func.signature)
-                        .map_err(|e| {
-                            anyhow::anyhow!("{}:{}:{} {:?}", file!(), line!(), column!(), e)
-                        })?;
-
-                    ////println!("ID IS {}", id);
-                    // Define the function to jit. This finishes compilation, although
-                    // there may be outstanding relocations to perform. Currently, jit
-                    // cannot finish relocations until all functions to be called are
-                    // defined. For this toy demo for now, we'll just finalize the
-                    // function below.
-                    self.module
-                        .define_function(
-                            id,
-                            &mut self.ctx,
-                            &mut codegen::binemit::NullTrapSink {},
-                            &mut codegen::binemit::NullStackMapSink {},
-                        )
-                        .map_err(|e| {
-                            anyhow::anyhow!("{}:{}:{} {:?}", file!(), line!(), column!(), e)
-                        })?;
-
-                    // Now that compilation is finished, we can clear out the context state.
-                    self.module.clear_context(&mut self.ctx);
-
-                    // Finalize the functions which we just defined, which resolves any
-                    // outstanding relocations (patching in addresses, now that they're
-                    // available).
-                    self.module.finalize_definitions();
+                    if let Some(cached) = cache
+                        .as_ref()
+                        .and_then(|(dir, key)| CachedFunction::load(dir, *key, &func.name))
+                    {
+                        self.cache_hits += 1;
+                        compiled.push((func, Compiled::Cached(cached)));
+                        continue;
+                    }
+                    match self.compile_function(&func, &funcs, &prog, &struct_map, &symbols) {
+                        Ok(ir) => compiled.push((func, Compiled::Ir(Box::new(ir)))),
+                        Err(e) => {
+                            // Throw away the half built function before moving on
+                            self.module.clear_context(&mut self.ctx);
+                            self.builder_context = FunctionBuilderContext::new();
+                            diagnostics.push_error(func.span, e);
+                        }
+                    }
                 }
                 _ => continue,
             };
         }
 
+        // Nothing is defined unless everything compiled, otherwise the functions
+        // that did would call ones that don't exist. The previous version of the
+        // program stays the current one.
+        if !diagnostics.is_empty() {
+            return Err(diagnostics.into());
+        }
+        let fn_names = symbols
+            .iter()
+            .map(|(name, symbol)| (symbol.as_str(), name.as_str()))
+            .collect::<HashMap<_, _>>();
+        let mut defined = Vec::new();
+        for (func, compiled) in compiled {
+            let machine_code = match compiled {
+                Compiled::Cached(cached) => cached.to_module(&mut self.module, &symbols)?,
+                Compiled::Ir(ir) => {
+                    let machine_code = self.emit_function(&func, *ir)?;
+                    if let Some((dir, key)) = &cache {
+                        CachedFunction::new(&self.module, &machine_code, &fn_names)?.save(
+                            self.module.isa(),
+                            dir,
+                            *key,
+                            &func.name,
+                        )?;
+                    }
+                    machine_code
+                }
+            };
+            let id = self.define_function(&func, &symbols[&func.name], &machine_code)?;
+            defined.push((func.name, id, machine_code));
+        }
+
+        // Finalize the functions which we just defined, which resolves any
+        // outstanding relocations (patching in addresses, now that they're
+        // available).
+        self.module.finalize();
+
+        // So a trap can be traced back to the function it happened in
+        for (name, id, machine_code) in defined {
+            if let Some(address) = self.module.function_address(id) {
+                trap::register(address, machine_code.code.len(), &name, machine_code.traps);
+                // `get_typed` returns more than one value through a trampoline,
+                // made here since it only borrows the JIT
+                let signature = &self.module.declarations().get_function_decl(id).signature;
+                if signature.returns.len() > 1 {
+                    self.translate_trampoline(&signature.clone())?;
+                }
+            }
+        }
+
+        self.structs = struct_map;
+        self.funcs = funcs;
+        self.specializations = specializations;
+        self.symbols = symbols;
+        self.compiled_with = (self.bounds_checks, self.consume_fuel);
         Ok(())
     }
 
-    pub fn get_func(&mut self, fn_name: &str) -> anyhow::Result<*const u8> {
-        match self.module.get_name(fn_name) {
-            Some(func) => match func {
-                cranelift_module::FuncOrDataId::Func(id) => {
-                    Ok(self.module.get_finalized_function(id))
-                }
-                cranelift_module::FuncOrDataId::Data(_) => {
-                    anyhow::bail!("function {} required, data found", fn_name);
+    /// Compile a function that calls functions with `signature` by their address,
+    /// loading the arguments from and storing the returns to arrays of 8 byte
+    /// slots. Only one is made for each signature.
+    fn translate_trampoline(&mut self, signature: &Signature) -> anyhow::Result<*const u8> {
+        if let Some(trampoline) = self.trampolines.get(signature) {
+            return Ok(*trampoline);
+        }
+        let ptr_ty = self.module.target_config().pointer_type();
+        for _ in 0..3 {
+            self.ctx.func.signature.params.push(AbiParam::new(ptr_ty));
+        }
+        let mut builder = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_context);
+        let entry_block = builder.create_block();
+        builder.append_block_params_for_function_params(entry_block);
+        builder.switch_to_block(entry_block);
+        builder.seal_block(entry_block);
+        let (callee, args_ptr, returns_ptr) = match builder.block_params(entry_block) {
+            [callee, args_ptr, returns_ptr] => (*callee, *args_ptr, *returns_ptr),
+            _ => unreachable!(),
+        };
+
+        let mut args = Vec::new();
+        for (i, param) in signature.params.iter().enumerate() {
+            let offset = Offset32::new(i as i32 * 8);
+            args.push(if param.value_type == types::B1 {
+                let byte = builder
+                    .ins()
+                    .load(types::I8, MemFlags::trusted(), args_ptr, offset);
+                builder.ins().icmp_imm(IntCC::NotEqual, byte, 0)
+            } else {
+                builder
+                    .ins()
+                    .load(param.value_type, MemFlags::trusted(), args_ptr, offset)
+            });
+        }
+        let sig_ref = builder.import_signature(signature.clone());
+        let call = builder.ins().call_indirect(sig_ref, callee, &args);
+        let results = builder.inst_results(call).to_vec();
+        for (i, v) in results.into_iter().enumerate() {
+            let v = if builder.func.dfg.value_type(v) == types::B1 {
+                builder.ins().bint(types::I8, v)
+            } else {
+                v
+            };
+            builder.ins().store(
+                MemFlags::trusted(),
+                v,
+                returns_ptr,
+                Offset32::new(i as i32 * 8),
+            );
+        }
+        builder.ins().return_(&[]);
+        builder.finalize();
+
+        let name = format!("__sarus_trampoline_{}", self.trampolines.len());
+        let id = self
+            .module
+            .declare_function(&name, Linkage::Local, &self.ctx.func.signature)
+            .map_err(|e| anyhow::anyhow!("{}", e))?;
+        self.module
+            .define_function(
+                id,
+                &mut self.ctx,
+                &mut codegen::binemit::NullTrapSink {},
+                &mut codegen::binemit::NullStackMapSink {},
+            )
+            .map_err(|e| anyhow::anyhow!("failed to compile {}: {:?}", name, e))?;
+        self.module.clear_context(&mut self.ctx);
+        self.module.finalize();
+        let trampoline = self
+            .module
+            .function_address(id)
+            .ok_or_else(|| anyhow::anyhow!("{} isn't in memory", name))?;
+        self.trampolines.insert(signature.clone(), trampoline);
+        Ok(trampoline)
+    }
+
+    /// Names of the functions that have to be compiled: new ones, ones that
+    /// differ from the last translated program, and everything that calls them,
+    /// directly or not, since a call is made to a specific version of a
+    /// function. Every function depends on the layout of the structs.
+    fn changed_functions(
+        &self,
+        funcs: &HashMap<String, Function>,
+        struct_map: &HashMap<String, StructDef>,
+    ) -> HashSet<String> {
+        let structs_changed = *struct_map != self.structs;
+        let settings_changed = (self.bounds_checks, self.consume_fuel) != self.compiled_with;
+        let mut changed: HashSet<String> = funcs
+            .values()
+            .filter(|func| {
+                structs_changed
+                    || settings_changed
+                    || match self.funcs.get(&func.name) {
+                        Some(old) => old.to_string() != func.to_string(),
+                        None => true,
+                    }
+            })
+            .map(|func| func.name.clone())
+            .collect();
+        // Callers of removed functions need to be checked again as well
+        changed.extend(
+            self.funcs
+                .keys()
+                .filter(|name| !funcs.contains_key(*name))
+                .cloned(),
+        );
+
+        let calls: HashMap<&String, Vec<(&String, bool)>> = funcs
+            .values()
+            .map(|func| {
+                let mut calls = Vec::new();
+                for expr in &func.body {
+                    called_functions(expr, &mut calls);
                 }
-            },
-            None => anyhow::bail!("No function {} found", fn_name),
+                (&func.name, calls)
+            })
+            .collect();
+        loop {
+            let callers: Vec<String> = calls
+                .iter()
+                .filter(|(name, _)| !changed.contains(**name))
+                .filter(|(_, calls)| {
+                    calls.iter().any(|(callee, impl_func)| {
+                        if *impl_func {
+                            // Which struct the method is on isn't known until the
+                            // types are, so any method with that name counts
+                            let suffix = format!(".{}", callee);
+                            changed.iter().any(|name| name.ends_with(&suffix))
+                        } else {
+                            changed.contains(*callee)
+                        }
+                    })
+                })
+                .map(|(name, _)| name.to_string())
+                .collect();
+            if callers.is_empty() {
+                return changed;
+            }
+            changed.extend(callers);
         }
     }
 
-    /// Create a zero-initialized data section.
-    pub fn create_data(&mut self, name: &str, contents: Vec<u8>) -> anyhow::Result<&[u8]> {
+    fn compile_function(
+        &mut self,
+        func: &Function,
+        funcs: &HashMap<String, Function>,
+        prog: &[Declaration],
+        struct_map: &HashMap<String, StructDef>,
+        symbols: &HashMap<String, String>,
+    ) -> anyhow::Result<codegen::ir::Function> {
+        ////println!(
+        ////    "name {:?}, params {:?}, the_return {:?}",
+        ////    &name, &params, &the_return
+        ////);
+        //// Then, translate the AST nodes into Cranelift IR.
+        self.codegen(func, funcs.to_owned(), prog, struct_map, symbols)?;
+        let ir = self.ctx.func.clone();
+
+        // Now that compilation is finished, we can clear out the context state.
+        self.module.clear_context(&mut self.ctx);
+        Ok(ir)
+    }
+
+    /// Generate the machine code for the IR of `func`.
+    fn emit_function(
+        &mut self,
+        func: &Function,
+        ir: codegen::ir::Function,
+    ) -> anyhow::Result<MachineCode> {
+        self.ctx.func = ir;
+        let mut code = Vec::new();
+        let mut relocs = RelocRecords::default();
+        let mut traps = TrapRecords::default();
+        let result = self
+            .ctx
+            .compile_and_emit(
+                self.module.isa(),
+                &mut code,
+                &mut relocs,
+                &mut traps,
+                &mut codegen::binemit::NullStackMapSink {},
+            )
+            .map_err(|e| {
+                Diagnostic::new(
+                    func.span,
+                    format!("failed to compile {}: {:?}", func.name, e),
+                )
+            });
+        let signature = self.ctx.func.signature.clone();
+
+        // Now that compilation is finished, we can clear out the context state.
+        self.module.clear_context(&mut self.ctx);
+        result?;
+        Ok(MachineCode {
+            signature,
+            code,
+            relocs: relocs.0,
+            traps: traps.0,
+        })
+    }
+
+    fn define_function(
+        &mut self,
+        func: &Function,
+        symbol: &str,
+        machine_code: &MachineCode,
+    ) -> anyhow::Result<FuncId> {
+        // Next, declare the function to jit. Functions must be declared
+        // before they can be called, or defined.
+        let id = self
+            .module
+            .declare_function(symbol, Linkage::Export, &machine_code.signature)
+            .map_err(|e| Diagnostic::new(func.span, format!("{:?}", e)))?;
+
+        // Define the function to jit. There may be outstanding relocations to
+        // perform. Currently, jit cannot finish relocations until all functions
+        // to be called are defined.
+        self.module
+            .define_function_bytes(id, &machine_code.code, &machine_code.relocs)
+            .map_err(|e| {
+                Diagnostic::new(
+                    func.span,
+                    format!("failed to compile {}: {:?}", func.name, e),
+                )
+            })?;
+        Ok(id)
+    }
+
+    /// What compiled code depends on besides the program: the settings it's
+    /// generated with.
+    fn cache_key(&self, prog: &[Declaration]) -> u64 {
+        let mut hasher = Fnv1a::new();
+        // Host functions are added in no particular order
+        let mut decls = prog.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        decls.sort();
+        for decl in decls {
+            hasher.write(decl.as_bytes());
+        }
+        let isa = self.module.isa();
+        hasher.write(isa.triple().to_string().as_bytes());
+        hasher.write(isa.flags().to_string().as_bytes());
+        for flag in isa.isa_flags() {
+            hasher.write(flag.to_string().as_bytes());
+        }
+        hasher.write(&[self.bounds_checks as u8, self.consume_fuel as u8]);
+        hasher.finish()
+    }
+
+    /// The symbol `fn_name` is currently defined as in the module.
+    fn symbol<'a>(&'a self, fn_name: &'a str) -> &'a str {
+        self.symbols
+            .get(fn_name)
+            .map(String::as_str)
+            .unwrap_or(fn_name)
+    }
+
+    /// Layout of a struct from the last translated program, for sharing it with the host.
+    pub fn get_struct(&self, struct_name: &str) -> anyhow::Result<&StructDef> {
+        match self.structs.get(struct_name) {
+            Some(struct_def) => Ok(struct_def),
+            None => anyhow::bail!("No struct {} found", struct_name),
+        }
+    }
+
+    /// Check that sarus lays out the struct the same way as Rust does, so a
+    /// declaration that doesn't match the Rust struct is caught before anything
+    /// is passed across.
+    pub fn check_struct<T: SarusStruct>(&self) -> anyhow::Result<()> {
+        let decl = T::declaration();
+        let struct_def = self.get_struct(&decl.name)?;
+        let layout = T::layout();
+        if struct_def.fields.len() != layout.fields.len() {
+            anyhow::bail!(
+                "struct {} has {} fields in sarus but {} in rust",
+                decl.name,
+                struct_def.fields.len(),
+                layout.fields.len()
+            )
+        }
+        for (field_name, offset) in &layout.fields {
+            match struct_def.offset_of(field_name) {
+                Some(sarus_offset) if sarus_offset as usize == *offset => (),
+                Some(sarus_offset) => anyhow::bail!(
+                    "field {} of struct {} is at offset {} in sarus but {} in rust",
+                    field_name,
+                    decl.name,
+                    sarus_offset,
+                    offset
+                ),
+                None => anyhow::bail!("struct {} has no field {} in sarus", decl.name, field_name),
+            }
+        }
+        for field in &decl.fields {
+            let expr_type = &struct_def.fields[&field.name].expr_type;
+            if Some(expr_type) != field.expr_type.as_ref() {
+                anyhow::bail!(
+                    "field {} of struct {} is {} in sarus but {} in rust",
+                    field.name,
+                    decl.name,
+                    expr_type,
+                    field.expr_type.as_ref().unwrap()
+                )
+            }
+        }
+        if struct_def.size as usize != layout.size || struct_def.align as usize != layout.align {
+            anyhow::bail!(
+                "struct {} has size {} and align {} in sarus but size {} and align {} in rust",
+                decl.name,
+                struct_def.size,
+                struct_def.align,
+                layout.size,
+                layout.align
+            )
+        }
+        Ok(())
+    }
+
+    fn define_data(&mut self, name: &str, contents: Vec<u8>) -> anyhow::Result<DataId> {
         // The steps here are analogous to `compile`, except that data is much
         // simpler than functions.
         self.data_ctx.define(contents.into_boxed_slice());
@@ -174,10 +752,17 @@ impl JIT {
             .define_data(id, &self.data_ctx)
             .map_err(|e| anyhow::anyhow!("{}", e))?;
         self.data_ctx.clear();
-        self.module.finalize_definitions();
-        let buffer = self.module.get_finalized_data(id);
+        Ok(id)
+    }
 
-        Ok(unsafe { slice::from_raw_parts(buffer.0, buffer.1) })
+    /// The data code shares with the host, defining it the first time.
+    fn context_id(&mut self) -> anyhow::Result<DataId> {
+        if let Some(cranelift_module::FuncOrDataId::Data(id)) = self.module.get_name(CONTEXT) {
+            return Ok(id);
+        }
+        let id = self.define_data(CONTEXT, vec![0; CONTEXT_SIZE])?;
+        self.module.finalize();
+        Ok(id)
     }
 
     // Translate from toy-language AST nodes into Cranelift IR.
@@ -187,11 +772,27 @@ impl JIT {
         funcs: HashMap<String, Function>,
         env: &[Declaration],
         struct_map: &HashMap<String, StructDef>,
+        symbols: &HashMap<String, String>,
     ) -> anyhow::Result<()> {
         let float = types::F64; //self.module.target_config().pointer_type();
 
+        // Structs are returned by writing them to memory provided by the caller,
+        // a pointer for each comes before the regular parameters
+        let struct_returns = func
+            .returns
+            .iter()
+            .filter(|ret| matches!(ret.expr_type, Some(ExprType::Struct(_))))
+            .count();
+        for _ in 0..struct_returns {
+            self.ctx
+                .func
+                .signature
+                .params
+                .push(AbiParam::new(self.module.target_config().pointer_type()));
+        }
+
         for p in &func.params {
-            self.ctx.func.signature.params.push({
+            let abi_param = {
                 match &p.expr_type {
                     Some(t) => match t {
                         ExprType::F64 => AbiParam::new(types::F64),
@@ -210,14 +811,36 @@ impl JIT {
                         ExprType::Struct(_) => {
                             AbiParam::new(self.module.target_config().pointer_type())
                         }
-                        ExprType::Tuple(_) => anyhow::bail!("Tuple as parameter not supported"),
+                        ExprType::Tuple(_) => {
+                            return Err(
+                                Diagnostic::new(p.span, "Tuple as parameter not supported").into()
+                            )
+                        }
+                        ExprType::Slice(_) => {
+                            // Passed as the pointer to the first element followed by the length
+                            self.ctx
+                                .func
+                                .signature
+                                .params
+                                .push(AbiParam::new(self.module.target_config().pointer_type()));
+                            AbiParam::new(types::I64)
+                        }
                     },
                     None => AbiParam::new(float),
                 }
-            });
+            };
+            self.ctx.func.signature.params.push(abi_param);
         }
 
         for ret_arg in &func.returns {
+            if let Some(ExprType::Slice(_)) = ret_arg.expr_type {
+                return Err(
+                    Diagnostic::new(ret_arg.span, "returning slices not supported yet").into(),
+                );
+            }
+            if let Some(ExprType::Struct(_)) = ret_arg.expr_type {
+                continue;
+            }
             self.ctx.func.signature.returns.push(AbiParam::new(
                 ret_arg
                     .expr_type
@@ -248,7 +871,6 @@ impl JIT {
         // The toy language allows variables to be declared implicitly.
         // Walk the AST and declare all implicitly-declared variables.
         let variables = declare_variables(
-            float,
             &mut builder,
             &mut self.module,
             &func.params,
@@ -258,7 +880,7 @@ impl JIT {
             env,
             &funcs,
             &constant_vars,
-            &struct_map,
+            struct_map,
         )?;
 
         //Keep function vars around for later debug/print
@@ -272,60 +894,35 @@ impl JIT {
             &funcs,
             &variables,
             &constant_vars,
-            &struct_map,
-        )?;
+            struct_map,
+        )
+        .map_err(|errors| Diagnostics(errors.into_iter().map(Diagnostic::from).collect()))?;
 
         // Now translate the statements of the function body.
+        let struct_return_ptrs = builder.block_params(entry_block)[..struct_returns].to_vec();
         let mut trans = FunctionTranslator {
             builder,
             variables,
             funcs,
             struct_map,
             module: &mut self.module,
+            returns: &func.returns,
+            struct_return_ptrs,
+            symbols,
+            loops: Vec::new(),
+            bounds_checks: self.bounds_checks,
+            consume_fuel: self.consume_fuel,
         };
+        if trans.consume_fuel {
+            trans.translate_consume_fuel()?;
+        }
+        trans.copy_struct_params(&func.params)?;
         for expr in &func.body {
             trans.translate_expr(expr)?;
         }
 
-        // Set up the return variable of the function. Above, we declared a
-        // variable to hold the return value. Here, we just do a use of that
-        // variable.
-        let mut return_values = Vec::new();
-        for ret in func.returns.iter() {
-            let return_variable = trans.variables.get(&ret.name).unwrap();
-            let v = match &ret.expr_type {
-                Some(t) => match t {
-                    ExprType::F64 => trans
-                        .builder
-                        .use_var(return_variable.expect_f64("return_variable")?),
-                    ExprType::I64 => trans
-                        .builder
-                        .use_var(return_variable.expect_i64("return_variable")?),
-                    ExprType::UnboundedArrayF64 => trans
-                        .builder
-                        .use_var(return_variable.expect_unbounded_array_f64("return_variable")?),
-                    ExprType::UnboundedArrayI64 => trans
-                        .builder
-                        .use_var(return_variable.expect_unbounded_array_f64("return_variable")?),
-                    ExprType::Address => trans
-                        .builder
-                        .use_var(return_variable.expect_address("return_variable")?),
-                    ExprType::Void => continue,
-                    ExprType::Bool => trans
-                        .builder
-                        .use_var(return_variable.expect_bool("return_variable")?),
-                    ExprType::Tuple(_) => anyhow::bail!("tuple not supported in return"),
-                    ExprType::Struct(_) => anyhow::bail!("returning structs not supported yet"), //TODO support this
-                },
-                None => trans
-                    .builder
-                    .use_var(return_variable.expect_f64("return_variable")?),
-            };
-            return_values.push(v);
-        }
-
-        // Emit the return instruction.
-        trans.builder.ins().return_(&return_values);
+        // Return whatever is in the return variables once the end of the body is reached
+        trans.translate_return()?;
 
         // Tell the builder we're done with this function.
         trans.builder.finalize();
@@ -340,23 +937,355 @@ impl JIT {
 
     pub fn add_math_constants(&mut self) -> anyhow::Result<()> {
         for (name, val) in sarus_std_lib::get_constants() {
-            self.create_data(&name, val.to_ne_bytes().to_vec())?;
+            self.define_data(&name, val.to_ne_bytes().to_vec())?;
+        }
+        self.module.finalize();
+        Ok(())
+    }
+
+    pub fn print_clif(&self, show_vars: bool) {
+        for (func_name, func_clif) in &self.clif {
+            let mut func_clif = func_clif.clone();
+            if show_vars {
+                for (var_name, var) in &self.variables[func_name] {
+                    let clif_var_name = format!("v{}", var.inner().index());
+                    func_clif = func_clif
+                        .replace(&clif_var_name, &format!("{}~{}", clif_var_name, var_name));
+                }
+            }
+            println!("//{}\n{}", func_name, func_clif);
+        }
+    }
+
+    /// How many functions `translate` has loaded from `cache_dir` instead of
+    /// compiling them, over every program translated.
+    pub fn cache_hits(&self) -> usize {
+        self.cache_hits
+    }
+
+    /// The target independent settings code is generated with.
+    pub fn flags(&self) -> &settings::Flags {
+        self.module.isa().flags()
+    }
+
+    /// A C header declaring the functions and structs of the last translated
+    /// program, for calling them from C once they're compiled into an object.
+    /// It's an error if a function returns two values in a way C can't
+    /// receive on the target.
+    pub fn c_header(&self) -> anyhow::Result<String> {
+        c_header::c_header(
+            &self.funcs,
+            &self.structs,
+            &self.symbols,
+            self.module.isa().triple(),
+        )
+    }
+}
+
+impl JIT {
+    /// Get a pointer to the current version of a compiled function.
+    pub fn get_func(&self, fn_name: &str) -> anyhow::Result<*const u8> {
+        match self.module.get_name(self.symbol(fn_name)) {
+            Some(func) => match func {
+                cranelift_module::FuncOrDataId::Func(id) => {
+                    Ok(self.module.get_finalized_function(id))
+                }
+                cranelift_module::FuncOrDataId::Data(_) => {
+                    anyhow::bail!("function {} required, data found", fn_name);
+                }
+            },
+            None => anyhow::bail!("No function {} found", fn_name),
+        }
+    }
+
+    /// Get a compiled function as a `TypedFunc` that can be called directly. The
+    /// Rust types are checked against the function's params and returns, a
+    /// slice param is given as a pointer and a length, and each struct return as
+    /// a pointer to write it to ahead of the other params. For a function with
+    /// unannotated params, this is the copy of it for the Rust param types.
+    pub fn get_typed<P: SarusParams, R: SarusReturns>(
+        &self,
+        fn_name: &str,
+    ) -> anyhow::Result<TypedFunc<'_, P, R>> {
+        let fn_name = resolve(fn_name, &self.funcs, &self.specializations, |func| {
+            abi_types(func).0 == P::expr_types()
+        });
+        let ptr = self.get_func(fn_name)?;
+        let trampoline = self.trampolines.get(&self.signature(fn_name)?).copied();
+        match self.funcs.get(fn_name) {
+            Some(func) => TypedFunc::new(func, ptr, trampoline),
+            None => anyhow::bail!("No function {} found", fn_name),
+        }
+    }
+
+    /// The signature of the current version of a compiled function.
+    fn signature(&self, fn_name: &str) -> anyhow::Result<Signature> {
+        match self.module.get_name(self.symbol(fn_name)) {
+            Some(cranelift_module::FuncOrDataId::Func(id)) => Ok(self
+                .module
+                .declarations()
+                .get_function_decl(id)
+                .signature
+                .clone()),
+            _ => anyhow::bail!("No function {} found", fn_name),
+        }
+    }
+
+    /// Call a function whose signature is only known at runtime. Arrays are
+    /// passed by pointer, so anything the function writes to them ends up in
+    /// `args`. Functions work on their own copy of struct params, apart from
+    /// `self`, so only writes through `self` end up in `args`. For a function
+    /// with unannotated params, this calls the copy of it for the types of `args`.
+    pub fn call(
+        &mut self,
+        fn_name: &str,
+        args: &mut [SarusValue],
+    ) -> anyhow::Result<Vec<SarusValue>> {
+        let fn_name = resolve(fn_name, &self.funcs, &self.specializations, |func| {
+            SarusValue::fit(args, func)
+        })
+        .to_string();
+        let fn_name = fn_name.as_str();
+        let func = match self.funcs.get(fn_name) {
+            Some(func) if !func.extern_func => func.clone(),
+            Some(_) => anyhow::bail!("can't call extern function {}", fn_name),
+            None => anyhow::bail!("No function {} found", fn_name),
+        };
+        if args.len() != func.params.len() {
+            anyhow::bail!(
+                "function {} takes {} arguments but {} were given",
+                fn_name,
+                func.params.len(),
+                args.len()
+            )
         }
+        let arg_type = |arg: &Arg| arg.expr_type.clone().unwrap_or(ExprType::F64);
+
+        // Every value is passed in an 8 byte slot, in the same order as the
+        // function's params at the machine level. Returned structs come first.
+        // Structs go through buffers aligned for them, which the bytes in a
+        // `SarusValue::Struct` aren't.
+        let mut slots: Vec<u64> = Vec::new();
+        let mut struct_returns = Vec::new();
+        for ret in &func.returns {
+            match arg_type(ret) {
+                ExprType::F64 | ExprType::I64 | ExprType::Bool => (),
+                ExprType::Struct(struct_name) => {
+                    let mut buffer = struct_buffer(self.get_struct(&struct_name)?)?;
+                    slots.push(buffer.as_mut_ptr() as u64);
+                    struct_returns.push((struct_name, buffer));
+                }
+                t => anyhow::bail!("can't return {} from {} through call", t, fn_name),
+            }
+        }
+        let mut struct_args = Vec::new();
+        for (i, (param, arg)) in func.params.iter().zip(args.iter_mut()).enumerate() {
+            match (arg_type(param), arg) {
+                (ExprType::F64, SarusValue::F64(v)) => slots.push(v.to_bits()),
+                (ExprType::I64, SarusValue::I64(v)) => slots.push(*v as u64),
+                (ExprType::Bool, SarusValue::Bool(v)) => slots.push(*v as u64),
+                (ExprType::UnboundedArrayF64, SarusValue::ArrayF64(v)) => {
+                    slots.push(v.as_mut_ptr() as u64)
+                }
+                (ExprType::UnboundedArrayI64, SarusValue::ArrayI64(v)) => {
+                    slots.push(v.as_mut_ptr() as u64)
+                }
+                (ExprType::Slice(elem), SarusValue::ArrayF64(v)) if *elem == ExprType::F64 => {
+                    slots.push(v.as_mut_ptr() as u64);
+                    slots.push(v.len() as u64);
+                }
+                (ExprType::Slice(elem), SarusValue::ArrayI64(v)) if *elem == ExprType::I64 => {
+                    slots.push(v.as_mut_ptr() as u64);
+                    slots.push(v.len() as u64);
+                }
+                (ExprType::Struct(struct_name), SarusValue::Struct(name, bytes))
+                    if *struct_name == *name =>
+                {
+                    let struct_def = self.get_struct(name)?;
+                    if bytes.len() != struct_def.size as usize {
+                        anyhow::bail!(
+                            "argument {} of {} should be {} bytes, found {}",
+                            param.name,
+                            fn_name,
+                            struct_def.size,
+                            bytes.len()
+                        )
+                    }
+                    let mut buffer = struct_buffer(struct_def)?;
+                    buffer_bytes(&mut buffer, bytes.len()).copy_from_slice(bytes);
+                    slots.push(buffer.as_mut_ptr() as u64);
+                    struct_args.push((i, buffer));
+                }
+                (t, arg) => anyhow::bail!(
+                    "argument {} of {} should be {}, found {}",
+                    param.name,
+                    fn_name,
+                    t,
+                    arg.type_name()
+                ),
+            }
+        }
+
+        let func_ptr = self.get_func(fn_name)?;
+        let signature = self.signature(fn_name)?;
+        let trampoline = self.translate_trampoline(&signature)?;
+        let trampoline = unsafe {
+            mem::transmute::<*const u8, extern "C" fn(*const u8, *const u64, *mut u64)>(trampoline)
+        };
+        let mut return_slots = vec![0u64; signature.returns.len()];
+        trap::catch_traps(|| trampoline(func_ptr, slots.as_ptr(), return_slots.as_mut_ptr()))?;
+
+        for (i, mut buffer) in struct_args {
+            if let SarusValue::Struct(_, bytes) = &mut args[i] {
+                let len = bytes.len();
+                bytes.copy_from_slice(buffer_bytes(&mut buffer, len));
+            }
+        }
+        let mut return_slots = return_slots.into_iter();
+        let mut struct_returns = struct_returns.into_iter().map(|(struct_name, mut buffer)| {
+            let size = self.structs[&*struct_name].size as usize;
+            SarusValue::Struct(
+                struct_name.to_string(),
+                buffer_bytes(&mut buffer, size).to_vec(),
+            )
+        });
+        Ok(func
+            .returns
+            .iter()
+            .map(|ret| match arg_type(ret) {
+                ExprType::F64 => SarusValue::F64(f64::from_bits(return_slots.next().unwrap())),
+                ExprType::I64 => SarusValue::I64(return_slots.next().unwrap() as i64),
+                ExprType::Bool => SarusValue::Bool(return_slots.next().unwrap() != 0),
+                _ => struct_returns.next().unwrap(),
+            })
+            .collect())
+    }
+
+    /// Set how much fuel code compiled with `consume_fuel` has left. Running out
+    /// traps with `TrapKind::OutOfFuel`, which `call` and `TypedFunc::try_call`
+    /// return as an error.
+    pub fn set_fuel(&mut self, fuel: i64) -> anyhow::Result<()> {
+        let id = self.context_id()?;
+        let (context, _) = self.module.get_finalized_data(id);
+        unsafe { (context.add(FUEL_OFFSET as usize) as *mut i64).write(fuel) };
         Ok(())
     }
 
-    pub fn print_clif(&self, show_vars: bool) {
-        for (func_name, func_clif) in &self.clif {
-            let mut func_clif = func_clif.clone();
-            if show_vars {
-                for (var_name, var) in &self.variables[func_name] {
-                    let clif_var_name = format!("v{}", var.inner().index());
-                    func_clif = func_clif
-                        .replace(&clif_var_name, &format!("{}~{}", clif_var_name, var_name));
-                }
-            }
-            println!("//{}\n{}", func_name, func_clif);
-        }
+    /// The fuel left, less than zero after running out.
+    pub fn fuel(&self) -> i64 {
+        match self.module.get_name(CONTEXT) {
+            Some(cranelift_module::FuncOrDataId::Data(id)) => {
+                let (context, _) = self.module.get_finalized_data(id);
+                unsafe { (context.add(FUEL_OFFSET as usize) as *const i64).read() }
+            }
+            _ => 0,
+        }
+    }
+
+    /// Create a zero-initialized data section.
+    pub fn create_data(&mut self, name: &str, contents: Vec<u8>) -> anyhow::Result<&[u8]> {
+        let id = self.define_data(name, contents)?;
+        self.module.finalize_definitions();
+        let buffer = self.module.get_finalized_data(id);
+
+        Ok(unsafe { slice::from_raw_parts(buffer.0, buffer.1) })
+    }
+}
+
+impl JIT<ObjectModule> {
+    /// Compile into a relocatable object file for the host instead of into
+    /// memory, see `emit`. `name` is the name of the object.
+    pub fn new_object(name: &str) -> anyhow::Result<Self> {
+        JIT::new_object_with_config(name, &JITConfig::default())
+    }
+
+    /// Compile into an object file, generating code as `config` says. The
+    /// target can be something other than the host.
+    pub fn new_object_with_config(name: &str, config: &JITConfig) -> anyhow::Result<Self> {
+        let mut flag_builder = settings::builder();
+        // The object could be linked into a shared library
+        flag_builder.set("is_pic", "true")?;
+        let isa = config.isa(flag_builder)?;
+        let builder = ObjectBuilder::new(isa, name, cranelift_module::default_libcall_names())?;
+        Ok(JIT::from_module(
+            ObjectModule::new(builder),
+            Rc::new(RefCell::new(HashMap::new())),
+        ))
+    }
+
+    /// The contents of the object file, with every function that was translated
+    /// exported under its name. Slices indexed with bounds checks on call
+    /// `__sarus_index_out_of_bounds`, which whatever links the object provides.
+    pub fn emit(self) -> anyhow::Result<Vec<u8>> {
+        self.module
+            .finish()
+            .emit()
+            .map_err(|e| anyhow::anyhow!("{}", e))
+    }
+}
+
+/// A function that's ready to be defined.
+enum Compiled {
+    Ir(Box<codegen::ir::Function>),
+    /// Its machine code, from the cache
+    Cached(CachedFunction),
+}
+
+/// The machine code of a function, with what it takes to define it.
+pub(crate) struct MachineCode {
+    pub(crate) signature: Signature,
+    pub(crate) code: Vec<u8>,
+    pub(crate) relocs: Vec<RelocRecord>,
+    /// Offsets of the instructions that can trap, and why they would
+    pub(crate) traps: Vec<(codegen::binemit::CodeOffset, TrapCode)>,
+}
+
+/// Collects the relocations of a compiled function, to define it with.
+#[derive(Default)]
+struct RelocRecords(Vec<RelocRecord>);
+
+impl codegen::binemit::RelocSink for RelocRecords {
+    fn reloc_external(
+        &mut self,
+        offset: codegen::binemit::CodeOffset,
+        _srcloc: SourceLoc,
+        reloc: codegen::binemit::Reloc,
+        name: &ExternalName,
+        addend: codegen::binemit::Addend,
+    ) {
+        self.0.push(RelocRecord {
+            offset,
+            reloc,
+            name: name.clone(),
+            addend,
+        });
+    }
+
+    // Constants and jump tables are part of the code
+    fn reloc_constant(
+        &mut self,
+        _offset: codegen::binemit::CodeOffset,
+        _reloc: codegen::binemit::Reloc,
+        _constant_offset: codegen::ir::ConstantOffset,
+    ) {
+    }
+
+    fn reloc_jt(
+        &mut self,
+        _offset: codegen::binemit::CodeOffset,
+        _reloc: codegen::binemit::Reloc,
+        _jt: codegen::ir::JumpTable,
+    ) {
+    }
+}
+
+/// Collects the instructions of a compiled function that can trap.
+#[derive(Default)]
+struct TrapRecords(Vec<(codegen::binemit::CodeOffset, TrapCode)>);
+
+impl codegen::binemit::TrapSink for TrapRecords {
+    fn trap(&mut self, offset: codegen::binemit::CodeOffset, _srcloc: SourceLoc, code: TrapCode) {
+        self.0.push((offset, code));
     }
 }
 
@@ -372,6 +1301,8 @@ pub enum SValue {
     Address(Value),
     Tuple(Vec<SValue>),
     Struct(String, Value),
+    //Element type, pointer to the first element, length
+    Slice(ExprType, Value, Value),
 }
 
 impl Display for SValue {
@@ -387,6 +1318,7 @@ impl Display for SValue {
             SValue::Void => write!(f, "void"),
             SValue::Tuple(v) => write!(f, "Tuple ({})", v.len()),
             SValue::Struct(name, _) => write!(f, "Struct ({})", name),
+            SValue::Slice(elem, _, _) => write!(f, "[{}]", elem),
         }
     }
 }
@@ -403,6 +1335,7 @@ impl SValue {
             ExprType::Address => SValue::Address(value),
             ExprType::Tuple(_) => anyhow::bail!("use SValue::from_tuple"),
             ExprType::Struct(name) => SValue::Struct(name.to_string(), value),
+            ExprType::Slice(_) => anyhow::bail!("use SValue::Slice"),
         })
     }
 
@@ -418,13 +1351,27 @@ impl SValue {
             SValue::Void => anyhow::bail!("void has no inner {}", ctx),
             SValue::Tuple(v) => anyhow::bail!("inner does not support tuple {:?} {}", v, ctx),
             SValue::Struct(_, v) => Ok(*v),
+            SValue::Slice(..) => anyhow::bail!("slice is a pointer and a length {}", ctx),
         }
     }
-    fn expect_f64(&self, ctx: &str) -> anyhow::Result<Value> {
-        match self {
-            SValue::F64(v) => Ok(*v),
-            v => anyhow::bail!("incorrect type {} expected F64 {}", v, ctx),
-        }
+    fn expr_type(&self) -> anyhow::Result<ExprType> {
+        Ok(match self {
+            SValue::Void => ExprType::Void,
+            SValue::Unknown(_) => anyhow::bail!("unknown has no type"),
+            SValue::Bool(_) => ExprType::Bool,
+            SValue::F64(_) => ExprType::F64,
+            SValue::I64(_) => ExprType::I64,
+            SValue::UnboundedArrayF64(_) => ExprType::UnboundedArrayF64,
+            SValue::UnboundedArrayI64(_) => ExprType::UnboundedArrayI64,
+            SValue::Address(_) => ExprType::Address,
+            SValue::Tuple(v) => ExprType::Tuple(
+                v.iter()
+                    .map(SValue::expr_type)
+                    .collect::<anyhow::Result<Vec<_>>>()?,
+            ),
+            SValue::Struct(name, _) => ExprType::Struct(Box::new(name.to_string())),
+            SValue::Slice(elem, _, _) => ExprType::Slice(Box::new(elem.clone())),
+        })
     }
     fn expect_i64(&self, ctx: &str) -> anyhow::Result<Value> {
         match self {
@@ -438,28 +1385,10 @@ impl SValue {
             v => anyhow::bail!("incorrect type {} expected Bool {}", v, ctx),
         }
     }
-    fn expect_unbounded_array_f64(&self, ctx: &str) -> anyhow::Result<Value> {
-        match self {
-            SValue::UnboundedArrayF64(v) => Ok(*v),
-            v => anyhow::bail!("incorrect type {} expected UnboundedArrayF64 {}", v, ctx),
-        }
-    }
-    fn expect_unbounded_array_i64(&self, ctx: &str) -> anyhow::Result<Value> {
-        match self {
-            SValue::UnboundedArrayI64(v) => Ok(*v),
-            v => anyhow::bail!("incorrect type {} expected UnboundedArrayI64 {}", v, ctx),
-        }
-    }
-    fn expect_struct(&self, name: &str, ctx: &str) -> anyhow::Result<Value> {
+    fn expect_slice(&self, ctx: &str) -> anyhow::Result<(Value, Value)> {
         match self {
-            SValue::Struct(sname, v) => {
-                if sname == name {
-                    return Ok(*v);
-                } else {
-                    anyhow::bail!("incorrect type {} expected Struct {} {}", v, name, ctx)
-                }
-            }
-            v => anyhow::bail!("incorrect type {} expected Struct {} {}", v, name, ctx),
+            SValue::Slice(_, ptr, len) => Ok((*ptr, *len)),
+            v => anyhow::bail!("incorrect type {} expected Slice {}", v, ctx),
         }
     }
 }
@@ -471,37 +1400,59 @@ struct FunctionTranslator<'a> {
     variables: HashMap<String, SVariable>,
     funcs: HashMap<String, Function>,
     struct_map: &'a HashMap<String, StructDef>,
-    module: &'a mut JITModule,
+    module: &'a mut dyn Module,
+    returns: &'a [Arg],
+    // Where each struct return is written to, in the order of the returns
+    struct_return_ptrs: Vec<Value>,
+    // Symbol of the version of each function that calls go to
+    symbols: &'a HashMap<String, String>,
+    // Continue and break targets of the loops being translated, innermost last
+    loops: Vec<(Block, Block)>,
+    bounds_checks: bool,
+    consume_fuel: bool,
 }
 
 impl<'a> FunctionTranslator<'a> {
     /// When you write out instructions in Cranelift, you get back `Value`s. You
     /// can then use these references in other instructions.
     fn translate_expr(&mut self, expr: &Expr) -> anyhow::Result<SValue> {
+        // Tag instructions with their source position so they can be traced back
+        self.builder
+            .set_srcloc(SourceLoc::new(expr.span().start as u32));
+        // The innermost expression that failed gives the most precise location
+        self.translate_expr_inner(expr).map_err(|e| {
+            if e.is::<Diagnostic>() {
+                e
+            } else {
+                Diagnostic::new(expr.span(), e.to_string()).into()
+            }
+        })
+    }
+
+    fn translate_expr_inner(&mut self, expr: &Expr) -> anyhow::Result<SValue> {
         match expr {
-            Expr::LiteralFloat(literal) => Ok(SValue::F64(
+            Expr::LiteralFloat(_, literal) => Ok(SValue::F64(
                 self.builder.ins().f64const::<f64>(literal.parse().unwrap()),
             )),
-            Expr::LiteralInt(literal) => Ok(SValue::I64(
+            Expr::LiteralInt(_, literal) => Ok(SValue::I64(
                 self.builder
                     .ins()
                     .iconst::<i64>(types::I64, literal.parse().unwrap()),
             )),
-            Expr::LiteralString(literal) => self.translate_string(literal),
-            Expr::Binop(op, lhs, rhs) => self.translate_binop(*op, lhs, rhs),
-            Expr::Unaryop(op, lhs) => self.translate_unaryop(*op, lhs),
-            Expr::Compare(cmp, lhs, rhs) => self.translate_cmp(*cmp, lhs, rhs),
-            Expr::Call(name, args, impl_func) => self.translate_call(name, args, *impl_func),
-            Expr::GlobalDataAddr(name) => Ok(SValue::UnboundedArrayF64(
+            Expr::LiteralString(_, literal) => self.translate_string(literal),
+            Expr::Binop(_, op, lhs, rhs) => self.translate_binop(*op, lhs, rhs),
+            Expr::Unaryop(_, op, lhs) => self.translate_unaryop(*op, lhs),
+            Expr::Compare(_, cmp, lhs, rhs) => self.translate_cmp(*cmp, lhs, rhs),
+            Expr::Call(_, name, args, impl_func) => self.translate_call(name, args, *impl_func),
+            Expr::GlobalDataAddr(_, name) => Ok(SValue::UnboundedArrayF64(
                 self.translate_global_data_addr(self.module.target_config().pointer_type(), name),
             )),
-            Expr::Identifier(name) => {
+            Expr::Identifier(_, name) => {
                 if name.contains(".") {
                     self.translate_struct_field(name)
                 } else {
                     match self.variables.get(name) {
                         Some(var) => Ok(match var {
-                            SVariable::Unknown(_, v) => SValue::Unknown(self.builder.use_var(*v)),
                             SVariable::Bool(_, v) => SValue::Bool(self.builder.use_var(*v)),
                             SVariable::F64(_, v) => SValue::F64(self.builder.use_var(*v)),
                             SVariable::I64(_, v) => SValue::I64(self.builder.use_var(*v)),
@@ -510,11 +1461,16 @@ impl<'a> FunctionTranslator<'a> {
                                 SValue::UnboundedArrayF64(self.builder.use_var(*v))
                             }
                             SVariable::UnboundedArrayI64(_, v) => {
-                                SValue::UnboundedArrayF64(self.builder.use_var(*v))
+                                SValue::UnboundedArrayI64(self.builder.use_var(*v))
                             }
-                            SVariable::Struct(varname, structname, v) => {
+                            SVariable::Struct(_varname, structname, v) => {
                                 SValue::Struct(structname.to_string(), self.builder.use_var(*v))
                             }
+                            SVariable::Slice(_, elem, ptr, len) => SValue::Slice(
+                                elem.clone(),
+                                self.builder.use_var(*ptr),
+                                self.builder.use_var(*len),
+                            ),
                         }),
                         None => Ok(SValue::F64(
                             //TODO Don't assume this is a float (this is used for math const)
@@ -523,35 +1479,51 @@ impl<'a> FunctionTranslator<'a> {
                     }
                 }
             }
-            Expr::Assign(names, expr) => self.translate_assign(names, expr),
-            Expr::AssignOp(op, lhs, rhs) => self.translate_math_assign(*op, lhs, rhs),
-            Expr::NewStruct(struct_name, fields) => self.translate_new_struct(struct_name, fields),
-            Expr::IfThen(condition, then_body) => {
+            Expr::Assign(_, names, expr) => self.translate_assign(names, expr),
+            Expr::AssignOp(_, op, lhs, rhs) => self.translate_math_assign(*op, lhs, rhs),
+            Expr::NewStruct(_, struct_name, fields) => {
+                self.translate_new_struct(struct_name, fields)
+            }
+            Expr::IfThen(_, condition, then_body) => {
                 self.translate_if_then(condition, then_body)?;
                 Ok(SValue::Void)
             }
-            Expr::IfElse(condition, then_body, else_body) => {
+            Expr::IfElse(_, condition, then_body, else_body) => {
                 self.translate_if_else(condition, then_body, else_body)
             }
-            Expr::WhileLoop(condition, loop_body) => {
+            Expr::WhileLoop(_, condition, loop_body) => {
                 self.translate_while_loop(condition, loop_body)?;
                 Ok(SValue::Void)
             }
-            Expr::Block(b) => b
-                .into_iter()
-                .map(|e| self.translate_expr(e))
-                .last()
-                .unwrap(),
-            Expr::LiteralBool(b) => Ok(SValue::Bool(self.builder.ins().bconst(types::B1, *b))),
-            Expr::Parentheses(expr) => self.translate_expr(expr),
-            Expr::ArrayGet(name, idx_expr) => self.translate_array_get(name.to_string(), idx_expr),
-            Expr::ArraySet(name, idx_expr, expr) => {
+            Expr::ForLoop(span, var, iterable, loop_body) => {
+                self.translate_for_loop(*span, var, iterable, loop_body)
+            }
+            Expr::Range(..) => anyhow::bail!("ranges can only be used in a for loop"),
+            Expr::Return(_) => {
+                self.translate_return()?;
+                self.switch_to_unreachable_block();
+                Ok(SValue::Void)
+            }
+            Expr::Break(_) => self.translate_loop_jump(true),
+            Expr::Continue(_) => self.translate_loop_jump(false),
+            Expr::Block(_, b) => b.iter().map(|e| self.translate_expr(e)).last().unwrap(),
+            Expr::LiteralBool(_, b) => Ok(SValue::Bool(self.builder.ins().bconst(types::B1, *b))),
+            Expr::Parentheses(_, expr) => self.translate_expr(expr),
+            Expr::ArrayGet(_, name, idx_expr) => {
+                self.translate_array_get(name.to_string(), idx_expr)
+            }
+            Expr::ArraySet(_, name, idx_expr, expr) => {
                 self.translate_array_set(name.to_string(), idx_expr, expr)
             }
+            Expr::ArrayLiteral(_, items) => self.translate_array_literal(items),
+            Expr::ArrayRepeat(_, expr, len) => self.translate_array_repeat(expr, *len),
+            Expr::DeclareArray(_, name, elem_type, len) => {
+                self.translate_declare_array(name, elem_type, *len)
+            }
         }
     }
 
-    fn translate_string(&mut self, literal: &String) -> anyhow::Result<SValue> {
+    fn translate_string(&mut self, literal: &str) -> anyhow::Result<SValue> {
         let cstr = CString::new(literal.replace("\\n", "\n").to_string()).unwrap();
         let bytes = cstr.to_bytes_with_nul();
         let stack_slot = self.builder.create_stack_slot(StackSlotData::new(
@@ -598,6 +1570,7 @@ impl<'a> FunctionTranslator<'a> {
             | SValue::UnboundedArrayI64(_)
             | SValue::Address(_)
             | SValue::Struct(_, _)
+            | SValue::Slice(..)
             | SValue::Tuple(_) => {
                 anyhow::bail!("operation not supported: {:?} {} {:?}", lhs, op, rhs)
             }
@@ -624,6 +1597,7 @@ impl<'a> FunctionTranslator<'a> {
             | SValue::UnboundedArrayI64(_)
             | SValue::Address(_)
             | SValue::Struct(_, _)
+            | SValue::Slice(..)
             | SValue::Tuple(_) => {
                 anyhow::bail!("operation not supported: {:?} {}", lhs, op)
             }
@@ -647,13 +1621,25 @@ impl<'a> FunctionTranslator<'a> {
             Binop::Add => self.builder.ins().iadd(lhs, rhs),
             Binop::Sub => self.builder.ins().isub(lhs, rhs),
             Binop::Mul => self.builder.ins().imul(lhs, rhs),
-            Binop::Div => self.builder.ins().sdiv(lhs, rhs),
+            Binop::Div => self.translate_sdiv(lhs, rhs),
             Binop::LogicalAnd | Binop::LogicalOr => {
                 anyhow::bail!("operation not supported: {:?} {} {:?}", lhs, op, rhs)
             }
         })
     }
 
+    /// Signed division, trapping with `IntegerOverflow` for `i64::MIN / -1` on every
+    /// target instead of whatever the hardware does for it.
+    fn translate_sdiv(&mut self, lhs: Value, rhs: Value) -> Value {
+        let is_min = self.builder.ins().icmp_imm(IntCC::Equal, lhs, i64::MIN);
+        let is_minus_one = self.builder.ins().icmp_imm(IntCC::Equal, rhs, -1);
+        let overflow = self.builder.ins().band(is_min, is_minus_one);
+        self.builder
+            .ins()
+            .trapnz(overflow, TrapCode::IntegerOverflow);
+        self.builder.ins().sdiv(lhs, rhs)
+    }
+
     fn binop_bool(&mut self, op: Binop, lhs: Value, rhs: Value) -> anyhow::Result<Value> {
         Ok(match op {
             Binop::LogicalAnd => self.builder.ins().band(lhs, rhs),
@@ -663,8 +1649,8 @@ impl<'a> FunctionTranslator<'a> {
     }
 
     fn translate_cmp(&mut self, cmp: Cmp, lhs: &Expr, rhs: &Expr) -> anyhow::Result<SValue> {
-        let lhs = self.translate_expr(lhs).unwrap();
-        let rhs = self.translate_expr(rhs).unwrap();
+        let lhs = self.translate_expr(lhs)?;
+        let rhs = self.translate_expr(rhs)?;
         // if a or b is a float, convert to other to a float
         match lhs {
             SValue::F64(a) => match rhs {
@@ -685,6 +1671,7 @@ impl<'a> FunctionTranslator<'a> {
             | SValue::UnboundedArrayI64(_)
             | SValue::Address(_)
             | SValue::Struct(_, _)
+            | SValue::Slice(..)
             | SValue::Tuple(_) => {
                 anyhow::bail!("operation not supported: {:?} {} {:?}", lhs, cmp, rhs)
             }
@@ -763,7 +1750,14 @@ impl<'a> FunctionTranslator<'a> {
         if names.len() == expr.len() {
             let mut values = Vec::new();
             for (i, name) in names.iter().enumerate() {
+                // Structs are values, assigning one that already exists makes a copy
+                let fresh = matches!(expr[i], Expr::NewStruct(..) | Expr::Call(..));
                 let expr = self.translate_expr(expr.get(i).unwrap())?;
+                if name.contains(".") {
+                    self.translate_set_struct_field(name, expr.clone())?;
+                    values.push(expr);
+                    continue;
+                }
                 let var = match self.variables.get(name) {
                     Some(v) => v,
                     None => anyhow::bail!("variable {} not found", name),
@@ -809,16 +1803,27 @@ impl<'a> FunctionTranslator<'a> {
                         v
                     }
                     SValue::Struct(name, v) => {
+                        let var = var.expect_struct(&name, "assign")?;
+                        let v = if fresh {
+                            v
+                        } else {
+                            self.translate_struct_clone(&name, v)
+                        };
                         values.push(SValue::Struct(name.clone(), v));
-                        self.builder.def_var(var.expect_struct(&name, "assign")?, v);
+                        self.builder.def_var(var, v);
                         v
                     }
+                    SValue::Slice(elem, ptr, len) => {
+                        values.push(SValue::Slice(elem, ptr, len));
+                        let (ptr_var, len_var) = var.expect_slice("assign")?;
+                        self.builder.def_var(ptr_var, ptr);
+                        self.builder.def_var(len_var, len);
+                        ptr
+                    }
                 };
             }
             if values.len() > 1 {
-                Ok(SValue::Tuple(
-                    values.iter().map(|v| v.clone()).collect::<Vec<SValue>>(),
-                ))
+                Ok(SValue::Tuple(values.to_vec()))
             } else if values.len() == 1 {
                 Ok(values.first().unwrap().clone())
             } else {
@@ -828,6 +1833,10 @@ impl<'a> FunctionTranslator<'a> {
             match self.translate_expr(expr.first().unwrap())? {
                 SValue::Tuple(values) => {
                     for (i, name) in names.iter().enumerate() {
+                        if name.contains(".") {
+                            self.translate_set_struct_field(name, values[i].clone())?;
+                            continue;
+                        }
                         let variable = match self.variables.get(name) {
                             Some(v) => v,
                             None => anyhow::bail!("variable {} not found", name),
@@ -837,9 +1846,7 @@ impl<'a> FunctionTranslator<'a> {
                             .def_var(variable.inner(), values[i].inner("assign")?);
                     }
                     if values.len() > 1 {
-                        Ok(SValue::Tuple(
-                            values.iter().map(|v| v.clone()).collect::<Vec<SValue>>(),
-                        ))
+                        Ok(SValue::Tuple(values.to_vec()))
                     } else if values.len() == 1 {
                         Ok(values.first().unwrap().clone())
                     } else {
@@ -855,71 +1862,301 @@ impl<'a> FunctionTranslator<'a> {
                 SValue::UnboundedArrayI64(_) => anyhow::bail!("operation not supported {:?}", expr),
                 SValue::Address(_) => anyhow::bail!("operation not supported {:?}", expr),
                 SValue::Struct(_, _) => anyhow::bail!("operation not supported {:?}", expr),
+                SValue::Slice(..) => anyhow::bail!("operation not supported {:?}", expr),
             }
         }
     }
 
     fn translate_array_get(&mut self, name: String, idx_expr: &Expr) -> anyhow::Result<SValue> {
+        let (elem_ptr, elem_type) = self.translate_array_elem_ptr(&name, idx_expr)?;
+        self.translate_load_elem(&elem_type, elem_ptr)
+    }
+
+    fn translate_array_set(
+        &mut self,
+        name: String,
+        idx_expr: &Expr,
+        expr: &Expr,
+    ) -> anyhow::Result<SValue> {
+        let new_val = self.translate_expr(expr)?;
+
+        let (elem_ptr, elem_type) = self.translate_array_elem_ptr(&name, idx_expr)?;
+        self.translate_store_elem(&elem_type, elem_ptr, new_val)?;
+        Ok(SValue::Void)
+    }
+
+    fn translate_store_elem(
+        &mut self,
+        elem_type: &ExprType,
+        elem_ptr: Value,
+        new_val: SValue,
+    ) -> anyhow::Result<()> {
+        match (elem_type, new_val) {
+            // Structs are stored inline, copy the whole thing into the array
+            (ExprType::Struct(struct_name), SValue::Struct(_, src)) => {
+                self.translate_struct_copy(struct_name, elem_ptr, src, true);
+            }
+            // Bools take up a byte in memory, like a C bool
+            (ExprType::Bool, SValue::Bool(v)) => {
+                let byte = self.builder.ins().bint(types::I8, v);
+                self.builder
+                    .ins()
+                    .store(MemFlags::trusted(), byte, elem_ptr, Offset32::new(0));
+            }
+            (_, new_val) => {
+                self.builder.ins().store(
+                    MemFlags::trusted(),
+                    new_val.inner("array set")?,
+                    elem_ptr,
+                    Offset32::new(0),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate room for `len` elements on the stack, giving back the array as
+    /// a slice along with its size in bytes.
+    fn translate_stack_array(
+        &mut self,
+        elem_type: &ExprType,
+        len: usize,
+    ) -> anyhow::Result<(SValue, u32)> {
+        let size = self.elem_size(elem_type)? * len as u32;
+        let stack_slot = self
+            .builder
+            .create_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, size));
+        let stack_slot_address = self.builder.ins().stack_addr(
+            self.module.target_config().pointer_type(),
+            stack_slot,
+            Offset32::new(0),
+        );
+        let len = self.builder.ins().iconst(types::I64, len as i64);
+        Ok((
+            SValue::Slice(elem_type.clone(), stack_slot_address, len),
+            size,
+        ))
+    }
+
+    fn translate_array_literal(&mut self, items: &[Expr]) -> anyhow::Result<SValue> {
+        let mut values = Vec::new();
+        for item in items {
+            values.push(self.translate_expr(item)?);
+        }
+        let elem_type = match values.first() {
+            Some(v) => v.expr_type()?,
+            None => anyhow::bail!("can't tell the type of an empty array"),
+        };
+        let (array, _) = self.translate_stack_array(&elem_type, values.len())?;
+        let (array_ptr, _) = array.expect_slice("array_literal")?;
+        for (i, value) in values.into_iter().enumerate() {
+            let idx_val = self.builder.ins().iconst(types::I64, i as i64);
+            let elem_ptr = self.translate_elem_offset(&elem_type, array_ptr, idx_val)?;
+            self.translate_store_elem(&elem_type, elem_ptr, value)?;
+        }
+        Ok(array)
+    }
+
+    fn translate_declare_array(
+        &mut self,
+        name: &str,
+        elem_type: &ExprType,
+        len: usize,
+    ) -> anyhow::Result<SValue> {
+        let (array, size) = self.translate_stack_array(elem_type, len)?;
+        let (ptr, len) = array.expect_slice("declare_array")?;
+        self.builder.emit_small_memset(
+            self.module.target_config(),
+            ptr,
+            0,
+            size as u64,
+            1,
+            MemFlags::new(),
+        );
+        let (ptr_var, len_var) = match self.variables.get(name) {
+            Some(v) => v.expect_slice("declare_array")?,
+            None => anyhow::bail!("variable {} not found", name),
+        };
+        self.builder.def_var(ptr_var, ptr);
+        self.builder.def_var(len_var, len);
+        Ok(SValue::Void)
+    }
+
+    /// Fills the array in a loop, the value is only evaluated once.
+    fn translate_array_repeat(&mut self, expr: &Expr, len: usize) -> anyhow::Result<SValue> {
+        let value = self.translate_expr(expr)?;
+        let elem_type = value.expr_type()?;
+        let (array, _) = self.translate_stack_array(&elem_type, len)?;
+        let (array_ptr, array_len) = array.expect_slice("array_repeat")?;
+
+        // The index is passed along as a block param, there's no variable for it
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+        self.builder.append_block_param(header_block, types::I64);
+
+        let zero = self.builder.ins().iconst(types::I64, 0);
+        self.builder.ins().jump(header_block, &[zero]);
+        self.builder.switch_to_block(header_block);
+        let i = self.builder.block_params(header_block)[0];
+        let b_condition_value = self.builder.ins().icmp(IntCC::SignedLessThan, i, array_len);
+        self.builder.ins().brz(b_condition_value, exit_block, &[]);
+        self.builder.ins().jump(body_block, &[]);
+
+        self.builder.switch_to_block(body_block);
+        self.builder.seal_block(body_block);
+        let elem_ptr = self.translate_elem_offset(&elem_type, array_ptr, i)?;
+        self.translate_store_elem(&elem_type, elem_ptr, value)?;
+        let next = self.builder.ins().iadd_imm(i, 1);
+        self.builder.ins().jump(header_block, &[next]);
+
+        self.builder.switch_to_block(exit_block);
+        self.builder.seal_block(header_block);
+        self.builder.seal_block(exit_block);
+        Ok(array)
+    }
+
+    /// Address of an element of an array along with the type of the element.
+    /// Indices into slices are checked against the length of the slice.
+    fn translate_array_elem_ptr(
+        &mut self,
+        name: &str,
+        idx_expr: &Expr,
+    ) -> anyhow::Result<(Value, ExprType)> {
         let ptr_ty = self.module.target_config().pointer_type();
 
-        let variable = match self.variables.get(&name) {
-            Some(v) => v,
+        let variable = match self.variables.get(name) {
+            Some(v) => v.clone(),
             None => anyhow::bail!("variable {} not found", name),
         };
+        let elem_type = match variable.elem_type() {
+            Some(t) => t,
+            None => anyhow::bail!("{} is not an array", name),
+        };
         let array_ptr = self.builder.use_var(variable.inner());
 
-        let idx_val = self.translate_expr(idx_expr).unwrap();
+        let idx_val = self.translate_expr(idx_expr)?;
         let idx_val = match idx_val {
             SValue::F64(v) => self.builder.ins().fcvt_to_uint(ptr_ty, v),
             SValue::I64(v) => v,
             _ => anyhow::bail!("only int and float supported for array access"),
         };
-        let mult_n = self.builder.ins().iconst(ptr_ty, types::F64.bytes() as i64);
-        let idx_val = self.builder.ins().imul(mult_n, idx_val);
-        let idx_ptr = self.builder.ins().iadd(idx_val, array_ptr);
 
-        let val =
-            self.builder
-                .ins()
-                .load(types::F64, MemFlags::trusted(), idx_ptr, Offset32::new(0));
-        Ok(SValue::F64(val)) //todo, don't assume this is a float
+        if let SVariable::Slice(_, _, _, len) = variable {
+            if self.bounds_checks {
+                let len = self.builder.use_var(len);
+                self.translate_bounds_check(idx_val, len)?;
+            }
+        }
+
+        let elem_ptr = self.translate_elem_offset(&elem_type, array_ptr, idx_val)?;
+        Ok((elem_ptr, elem_type))
     }
 
-    fn translate_array_set(
+    /// `array_ptr + idx * stride`, where the stride is the size of the element type.
+    fn translate_elem_offset(
         &mut self,
-        name: String,
-        idx_expr: &Expr,
-        expr: &Expr,
+        elem_type: &ExprType,
+        array_ptr: Value,
+        idx_val: Value,
+    ) -> anyhow::Result<Value> {
+        let stride = self.elem_size(elem_type)?;
+        let offset = self.builder.ins().imul_imm(idx_val, stride as i64);
+        Ok(self.builder.ins().iadd(array_ptr, offset))
+    }
+
+    fn elem_size(&self, elem_type: &ExprType) -> anyhow::Result<u32> {
+        Ok(match elem_type {
+            ExprType::Struct(struct_name) => self.struct_map[&struct_name.to_string()].size,
+            ExprType::Bool => anyhow::bail!("arrays of bool are not supported"),
+            t => t
+                .cranelift_type(self.module.target_config().pointer_type())?
+                .bytes(),
+        })
+    }
+
+    /// Read an element of an array. Structs are not copied out of the array,
+    /// the value refers to the element in place.
+    fn translate_load_elem(
+        &mut self,
+        elem_type: &ExprType,
+        elem_ptr: Value,
     ) -> anyhow::Result<SValue> {
-        let ptr_ty = self.module.target_config().pointer_type();
+        match elem_type {
+            ExprType::Struct(struct_name) => Ok(SValue::Struct(struct_name.to_string(), elem_ptr)),
+            ExprType::Bool => {
+                let byte = self.builder.ins().load(
+                    types::I8,
+                    MemFlags::trusted(),
+                    elem_ptr,
+                    Offset32::new(0),
+                );
+                Ok(SValue::Bool(self.builder.ins().icmp_imm(
+                    IntCC::NotEqual,
+                    byte,
+                    0,
+                )))
+            }
+            t => {
+                let val = self.builder.ins().load(
+                    t.cranelift_type(self.module.target_config().pointer_type())?,
+                    MemFlags::trusted(),
+                    elem_ptr,
+                    Offset32::new(0),
+                );
+                SValue::from(t, val)
+            }
+        }
+    }
 
-        let new_val = self.translate_expr(expr)?;
+    /// Trap unless `0 <= idx < len`. The host records the index and length
+    /// before trapping, for the `Trap` to say what they were.
+    fn translate_bounds_check(&mut self, idx: Value, len: Value) -> anyhow::Result<()> {
+        let in_bounds = self.builder.ins().icmp(IntCC::UnsignedLessThan, idx, len);
 
-        let variable = self
-            .variables
-            .get(&name)
-            .unwrap()
-            .expect_unbounded_array_f64("array_set")?;
+        let in_bounds_block = self.builder.create_block();
+        let out_of_bounds_block = self.builder.create_block();
+        self.builder.ins().brnz(in_bounds, in_bounds_block, &[]);
+        self.builder.ins().jump(out_of_bounds_block, &[]);
 
-        let array_ptr = self.builder.use_var(variable);
+        self.builder.switch_to_block(out_of_bounds_block);
+        self.builder.seal_block(out_of_bounds_block);
+        let mut sig = self.module.make_signature();
+        sig.params.push(AbiParam::new(types::I64));
+        sig.params.push(AbiParam::new(types::I64));
+        let callee = self.module.declare_function(
+            sarus_std_lib::INDEX_OUT_OF_BOUNDS,
+            Linkage::Import,
+            &sig,
+        )?;
+        let local_callee = self.module.declare_func_in_func(callee, self.builder.func);
+        self.builder.ins().call(local_callee, &[idx, len]);
+        self.builder.ins().trap(TrapCode::HeapOutOfBounds);
 
-        let idx_val = self.translate_expr(idx_expr)?;
-        let idx_val = match idx_val {
-            SValue::F64(v) => self.builder.ins().fcvt_to_uint(ptr_ty, v),
-            SValue::I64(v) => v,
-            _ => anyhow::bail!("only int and float supported for array access"),
-        };
-        let mult_n = self.builder.ins().iconst(ptr_ty, types::F64.bytes() as i64);
-        let idx_val = self.builder.ins().imul(mult_n, idx_val);
-        let idx_ptr = self.builder.ins().iadd(idx_val, array_ptr);
-
-        self.builder.ins().store(
-            MemFlags::trusted(),
-            new_val.inner("array set")?,
-            idx_ptr,
-            Offset32::new(0),
-        );
-        Ok(SValue::Void)
+        self.builder.switch_to_block(in_bounds_block);
+        self.builder.seal_block(in_bounds_block);
+        Ok(())
+    }
+
+    /// Use up a unit of fuel, trapping if there was none left.
+    fn translate_consume_fuel(&mut self) -> anyhow::Result<()> {
+        let ptr_ty = self.module.target_config().pointer_type();
+        let context = self
+            .module
+            .declare_data(CONTEXT, Linkage::Export, true, false)?;
+        let context = self.module.declare_data_in_func(context, self.builder.func);
+        let context = self.builder.ins().symbol_value(ptr_ty, context);
+        let fuel = self
+            .builder
+            .ins()
+            .load(types::I64, MemFlags::trusted(), context, FUEL_OFFSET);
+        let fuel = self.builder.ins().iadd_imm(fuel, -1);
+        self.builder
+            .ins()
+            .store(MemFlags::trusted(), fuel, context, FUEL_OFFSET);
+        let out_of_fuel = self.builder.ins().icmp_imm(IntCC::SignedLessThan, fuel, 0);
+        self.builder.ins().trapnz(out_of_fuel, trap::OUT_OF_FUEL);
+        Ok(())
     }
 
     fn translate_math_assign(
@@ -928,9 +2165,15 @@ impl<'a> FunctionTranslator<'a> {
         name: &str,
         expr: &Expr,
     ) -> anyhow::Result<SValue> {
+        if name.contains(".") {
+            let field = Expr::Identifier(expr.span(), name.to_string());
+            let new_val = self.translate_binop(op, &field, expr)?;
+            self.translate_set_struct_field(name, new_val.clone())?;
+            return Ok(new_val);
+        }
         match self.translate_expr(expr)? {
             SValue::F64(v) => {
-                let orig_variable = self.variables.get(&*name).unwrap();
+                let orig_variable = self.variables.get(name).unwrap();
                 let orig_value = self
                     .builder
                     .use_var(orig_variable.expect_f64("math_assign")?);
@@ -948,21 +2191,22 @@ impl<'a> FunctionTranslator<'a> {
                 Ok(SValue::F64(added_val))
             }
             SValue::I64(v) => {
-                let orig_variable = self.variables.get(&*name).unwrap();
-                let orig_value = self
-                    .builder
-                    .use_var(orig_variable.expect_i64("math_assign")?);
+                let orig_variable = self
+                    .variables
+                    .get(name)
+                    .unwrap()
+                    .expect_i64("math_assign")?;
+                let orig_value = self.builder.use_var(orig_variable);
                 let added_val = match op {
                     Binop::Add => self.builder.ins().iadd(orig_value, v),
                     Binop::Sub => self.builder.ins().isub(orig_value, v),
                     Binop::Mul => self.builder.ins().imul(orig_value, v),
-                    Binop::Div => self.builder.ins().sdiv(orig_value, v),
+                    Binop::Div => self.translate_sdiv(orig_value, v),
                     Binop::LogicalAnd | Binop::LogicalOr => {
                         anyhow::bail!("operation not supported: {:?} {} {:?}", orig_value, op, v)
                     }
                 };
-                self.builder
-                    .def_var(orig_variable.expect_i64("math_assign")?, added_val);
+                self.builder.def_var(orig_variable, added_val);
                 Ok(SValue::I64(added_val))
             }
             SValue::Void => anyhow::bail!("math assign Void not supported"),
@@ -983,7 +2227,102 @@ impl<'a> FunctionTranslator<'a> {
                 //TODO support this for pointer math
                 anyhow::bail!("math assign Struct not supported")
             }
+            SValue::Slice(..) => anyhow::bail!("math assign Slice not supported"),
+        }
+    }
+
+    /// Return from the function with the current values of the return variables.
+    fn translate_return(&mut self) -> anyhow::Result<SValue> {
+        // Set up the return variable of the function. Above, we declared a
+        // variable to hold the return value. Here, we just do a use of that
+        // variable.
+        let mut return_values = Vec::new();
+        let mut struct_return_ptrs = self.struct_return_ptrs.clone().into_iter();
+        for ret in self.returns.iter() {
+            let return_variable = self.variables.get(&ret.name).unwrap();
+            let v = match &ret.expr_type {
+                Some(t) => match t {
+                    ExprType::F64 => self
+                        .builder
+                        .use_var(return_variable.expect_f64("return_variable")?),
+                    ExprType::I64 => self
+                        .builder
+                        .use_var(return_variable.expect_i64("return_variable")?),
+                    ExprType::UnboundedArrayF64 => self
+                        .builder
+                        .use_var(return_variable.expect_unbounded_array_f64("return_variable")?),
+                    ExprType::UnboundedArrayI64 => self
+                        .builder
+                        .use_var(return_variable.expect_unbounded_array_i64("return_variable")?),
+                    ExprType::Address => self
+                        .builder
+                        .use_var(return_variable.expect_address("return_variable")?),
+                    ExprType::Void => continue,
+                    ExprType::Bool => self
+                        .builder
+                        .use_var(return_variable.expect_bool("return_variable")?),
+                    ExprType::Tuple(_) => {
+                        return Err(
+                            Diagnostic::new(ret.span, "tuple not supported in return").into()
+                        )
+                    }
+                    ExprType::Struct(struct_name) => {
+                        // The return variable starts out pointing at the caller's memory,
+                        // so this may copy the struct onto itself
+                        let src = self.builder.use_var(
+                            return_variable.expect_struct(struct_name, "return_variable")?,
+                        );
+                        let dest = struct_return_ptrs.next().unwrap();
+                        self.translate_struct_copy(struct_name, dest, src, false);
+                        continue;
+                    }
+                    ExprType::Slice(_) => {
+                        return Err(Diagnostic::new(
+                            ret.span,
+                            "returning slices not supported yet",
+                        )
+                        .into());
+                    }
+                },
+                None => self
+                    .builder
+                    .use_var(return_variable.expect_f64("return_variable")?),
+            };
+            return_values.push(v);
+        }
+
+        // Emit the return instruction.
+        self.builder.ins().return_(&return_values);
+        Ok(SValue::Void)
+    }
+
+    /// Jump to the exit of the innermost loop, or on to its next iteration.
+    fn translate_loop_jump(&mut self, to_exit: bool) -> anyhow::Result<SValue> {
+        let (continue_block, exit_block) = match self.loops.last() {
+            Some(blocks) => *blocks,
+            None => anyhow::bail!("break or continue outside of a loop"),
+        };
+        let target = if to_exit { exit_block } else { continue_block };
+        self.builder.ins().jump(target, &[]);
+        self.switch_to_unreachable_block();
+        Ok(SValue::Void)
+    }
+
+    /// The current block was terminated early, anything that follows it in the
+    /// source goes into a fresh block that has no predecessors.
+    fn switch_to_unreachable_block(&mut self) {
+        let block = self.builder.create_block();
+        self.builder.switch_to_block(block);
+        self.builder.seal_block(block);
+    }
+
+    /// Translate a list of statements, the value of the block is the value of the last one.
+    fn translate_body(&mut self, body: &[Expr]) -> anyhow::Result<SValue> {
+        let mut value = SValue::Void;
+        for expr in body {
+            value = self.translate_expr(expr)?;
         }
+        Ok(value)
     }
 
     fn translate_if_then(
@@ -1003,9 +2342,7 @@ impl<'a> FunctionTranslator<'a> {
 
         self.builder.switch_to_block(then_block);
         self.builder.seal_block(then_block);
-        for expr in then_body {
-            self.translate_expr(expr).unwrap();
-        }
+        self.translate_body(then_body)?;
 
         // Jump to the merge block, passing it the block return value.
         self.builder.ins().jump(merge_block, &[]);
@@ -1024,62 +2361,9 @@ impl<'a> FunctionTranslator<'a> {
     ) -> anyhow::Result<SValue> {
         let b_condition_value = self.translate_expr(condition)?.expect_bool("if_else")?;
 
-        let then_block = self.builder.create_block();
-        let else_block = self.builder.create_block();
-        let merge_block = self.builder.create_block();
-
-        // If-else constructs in the toy language have a return value.
-        // In traditional SSA form, this would produce a PHI between
-        // the then and else bodies. Cranelift uses block parameters,
-        // so set up a parameter in the merge block, and we'll pass
-        // the return values to it from the branches.
-        let then_value = self.translate_expr(then_body.last().unwrap())?;
-        let then_return = match then_value.clone() {
-            SValue::Tuple(t) => {
-                let mut vals = Vec::new();
-                for v in &t {
-                    self.builder
-                        .append_block_param(merge_block, self.value_type(v.inner("then_return")?));
-                    vals.push(v.clone().inner("then_return")?);
-                }
-                vals
-            }
-            SValue::Void => vec![],
-            sv => {
-                let v = sv.inner("then_return")?;
-                self.builder
-                    .append_block_param(merge_block, self.value_type(v));
-                vec![v]
-            }
-        };
-
-        let else_value = self.translate_expr(else_body.last().unwrap())?;
-        let else_return = match else_value.clone() {
-            SValue::Tuple(t) => {
-                let mut vals = Vec::new();
-                for v in &t {
-                    vals.push(v.clone().inner("else_return")?);
-                }
-                vals
-            }
-            SValue::Void => vec![],
-            SValue::Unknown(v) => vec![v],
-            SValue::Bool(v) => vec![v],
-            SValue::F64(v) => vec![v],
-            SValue::I64(v) => vec![v],
-            SValue::UnboundedArrayF64(v) => vec![v],
-            SValue::UnboundedArrayI64(v) => vec![v],
-            SValue::Address(v) => vec![v],
-            SValue::Struct(_, v) => vec![v],
-        };
-
-        if then_value.to_string() != else_value.to_string() {
-            anyhow::bail!(
-                "if_else return types don't match {:?} {:?}",
-                then_value,
-                else_value
-            )
-        }
+        let then_block = self.builder.create_block();
+        let else_block = self.builder.create_block();
+        let merge_block = self.builder.create_block();
 
         // Test the if condition and conditionally branch.
         self.builder.ins().brz(b_condition_value, else_block, &[]);
@@ -1089,14 +2373,66 @@ impl<'a> FunctionTranslator<'a> {
         self.builder.switch_to_block(then_block);
         self.builder.seal_block(then_block);
 
-        // Jump to the merge block, passing it the block return value.
-        self.builder.ins().jump(merge_block, &then_return);
+        // If-else constructs in the toy language have a return value.
+        // In traditional SSA form, this would produce a PHI between
+        // the then and else bodies. Cranelift uses block parameters,
+        // so set up a parameter in the merge block, and we'll pass
+        // the return values to it from the branches. A branch that ends
+        // in return, break or continue never reaches the merge block.
+        let then_value = self.translate_body(then_body)?;
+        let then_diverges = then_body.last().is_some_and(Expr::diverges);
+        if !then_diverges {
+            let then_return = self.append_merge_params(merge_block, &then_value)?;
+
+            // Jump to the merge block, passing it the block return value.
+            self.builder.ins().jump(merge_block, &then_return);
+        }
 
         self.builder.switch_to_block(else_block);
         self.builder.seal_block(else_block);
 
-        // Jump to the merge block, passing it the block return value.
-        self.builder.ins().jump(merge_block, &else_return);
+        let else_value = self.translate_body(else_body)?;
+        let else_diverges = else_body.last().is_some_and(Expr::diverges);
+        if !else_diverges {
+            let else_return = if then_diverges {
+                self.append_merge_params(merge_block, &else_value)?
+            } else {
+                if then_value.to_string() != else_value.to_string() {
+                    anyhow::bail!(
+                        "if_else return types don't match {:?} {:?}",
+                        then_value,
+                        else_value
+                    )
+                }
+                match else_value.clone() {
+                    SValue::Tuple(t) => {
+                        let mut vals = Vec::new();
+                        for v in &t {
+                            vals.push(v.clone().inner("else_return")?);
+                        }
+                        vals
+                    }
+                    SValue::Void => vec![],
+                    SValue::Unknown(v) => vec![v],
+                    SValue::Bool(v) => vec![v],
+                    SValue::F64(v) => vec![v],
+                    SValue::I64(v) => vec![v],
+                    SValue::UnboundedArrayF64(v) => vec![v],
+                    SValue::UnboundedArrayI64(v) => vec![v],
+                    SValue::Address(v) => vec![v],
+                    SValue::Struct(_, v) => vec![v],
+                    SValue::Slice(_, ptr, len) => vec![ptr, len],
+                }
+            };
+
+            // Jump to the merge block, passing it the block return value.
+            self.builder.ins().jump(merge_block, &else_return);
+        }
+        let then_value = if then_diverges {
+            else_value
+        } else {
+            then_value
+        };
 
         // Switch to the merge block for subsequent statements.
         self.builder.switch_to_block(merge_block);
@@ -1108,7 +2444,9 @@ impl<'a> FunctionTranslator<'a> {
         // parameter.
         let phi = self.builder.block_params(merge_block);
 
-        if phi.len() > 1 {
+        if let SValue::Slice(elem, _, _) = then_value {
+            Ok(SValue::Slice(elem, phi[0], phi[1]))
+        } else if phi.len() > 1 {
             // TODO don't assume these are floats
             Ok(SValue::Tuple(
                 phi.iter().map(|v| SValue::F64(*v)).collect::<Vec<SValue>>(),
@@ -1129,12 +2467,46 @@ impl<'a> FunctionTranslator<'a> {
                 }
                 SValue::Address(_) => Ok(SValue::Address(*phi.first().unwrap())),
                 SValue::Struct(name, _) => Ok(SValue::Struct(name, *phi.first().unwrap())),
+                SValue::Slice(..) => unreachable!("slices are merged as two values"),
             }
         } else {
             Ok(SValue::Void)
         }
     }
 
+    /// Add parameters to the merge block of an if-else for each of the values in
+    /// `value`, returning the values to pass when jumping to it.
+    fn append_merge_params(
+        &mut self,
+        merge_block: Block,
+        value: &SValue,
+    ) -> anyhow::Result<Vec<Value>> {
+        Ok(match value.clone() {
+            SValue::Tuple(t) => {
+                let mut vals = Vec::new();
+                for v in &t {
+                    self.builder
+                        .append_block_param(merge_block, self.value_type(v.inner("then_return")?));
+                    vals.push(v.clone().inner("then_return")?);
+                }
+                vals
+            }
+            SValue::Void => vec![],
+            SValue::Slice(_, ptr, len) => {
+                self.builder
+                    .append_block_param(merge_block, self.value_type(ptr));
+                self.builder.append_block_param(merge_block, types::I64);
+                vec![ptr, len]
+            }
+            sv => {
+                let v = sv.inner("then_return")?;
+                self.builder
+                    .append_block_param(merge_block, self.value_type(v));
+                vec![v]
+            }
+        })
+    }
+
     fn translate_while_loop(
         &mut self,
         condition: &Expr,
@@ -1146,6 +2518,9 @@ impl<'a> FunctionTranslator<'a> {
 
         self.builder.ins().jump(header_block, &[]);
         self.builder.switch_to_block(header_block);
+        if self.consume_fuel {
+            self.translate_consume_fuel()?;
+        }
 
         let b_condition_value = self.translate_expr(condition)?.expect_bool("while_loop")?;
 
@@ -1155,9 +2530,119 @@ impl<'a> FunctionTranslator<'a> {
         self.builder.switch_to_block(body_block);
         self.builder.seal_block(body_block);
 
-        for expr in loop_body {
-            self.translate_expr(expr)?;
+        self.loops.push((header_block, exit_block));
+        let body = self.translate_body(loop_body);
+        self.loops.pop();
+        body?;
+        self.builder.ins().jump(header_block, &[]);
+
+        self.builder.switch_to_block(exit_block);
+
+        // We've reached the bottom of the loop, so there will be no
+        // more backedges to the header to exits to the bottom. `continue`
+        // and `break` inside the body add their edges before this point.
+        self.builder.seal_block(header_block);
+        self.builder.seal_block(exit_block);
+
+        Ok(SValue::Void)
+    }
+
+    /// Lowers to the same shape as a while loop, with a latch block that steps a
+    /// hidden counter so the body can't change the number of iterations.
+    fn translate_for_loop(
+        &mut self,
+        span: Span,
+        var: &str,
+        iterable: &Expr,
+        loop_body: &[Expr],
+    ) -> anyhow::Result<SValue> {
+        let (range, array) = match iterable {
+            Expr::ArrayGet(span, name, range) => {
+                let array = self.translate_expr(&Expr::Identifier(*span, name.to_string()))?;
+                (Some(&**range), Some(array))
+            }
+            Expr::Range(..) => (Some(iterable), None),
+            slice => (None, Some(self.translate_expr(slice)?)),
+        };
+        let counter = self.variables[&loop_counter_key(span)].inner();
+        let induction = self.variables[&induction_var_key(var, span)].clone();
+
+        let (start, end) = match (range, &array) {
+            (Some(Expr::Range(_, start, end)), _) => (
+                self.translate_expr(start)?.expect_i64("for_loop")?,
+                self.translate_expr(end)?.expect_i64("for_loop")?,
+            ),
+            // The whole slice
+            (None, Some(SValue::Slice(_, _, len))) => {
+                (self.builder.ins().iconst(types::I64, 0), *len)
+            }
+            _ => anyhow::bail!("can only iterate over ranges and slices"),
+        };
+        let (array_ptr, elem_type) = match &array {
+            Some(SValue::Slice(elem, ptr, _)) => (Some(*ptr), Some(elem.clone())),
+            Some(SValue::UnboundedArrayF64(ptr)) => (Some(*ptr), Some(ExprType::F64)),
+            Some(SValue::UnboundedArrayI64(ptr)) => (Some(*ptr), Some(ExprType::I64)),
+            Some(v) => anyhow::bail!("can't iterate over {}", v),
+            None => (None, None),
+        };
+        // Only a range given in the source can go past the end of a slice
+        let check_len = match &array {
+            Some(SValue::Slice(_, _, len)) if range.is_some() && self.bounds_checks => Some(*len),
+            _ => None,
+        };
+        self.builder.def_var(counter, start);
+
+        let header_block = self.builder.create_block();
+        let body_block = self.builder.create_block();
+        let latch_block = self.builder.create_block();
+        let exit_block = self.builder.create_block();
+
+        self.builder.ins().jump(header_block, &[]);
+        self.builder.switch_to_block(header_block);
+        if self.consume_fuel {
+            self.translate_consume_fuel()?;
         }
+
+        let i = self.builder.use_var(counter);
+        let b_condition_value = self.builder.ins().icmp(IntCC::SignedLessThan, i, end);
+        self.builder.ins().brz(b_condition_value, exit_block, &[]);
+        self.builder.ins().jump(body_block, &[]);
+
+        self.builder.switch_to_block(body_block);
+        self.builder.seal_block(body_block);
+
+        // Set the induction variable for this iteration
+        let value = match (array_ptr, elem_type) {
+            (Some(array_ptr), Some(elem_type)) => {
+                if let Some(len) = check_len {
+                    self.translate_bounds_check(i, len)?;
+                }
+                let elem_ptr = self.translate_elem_offset(&elem_type, array_ptr, i)?;
+                self.translate_load_elem(&elem_type, elem_ptr)?
+                    .inner("for_loop")?
+            }
+            _ => i,
+        };
+        self.builder.def_var(induction.inner(), value);
+
+        // The induction variable is only in scope inside the body
+        let shadowed = self.variables.insert(var.to_string(), induction);
+        self.loops.push((latch_block, exit_block));
+        let body = self.translate_body(loop_body);
+        self.loops.pop();
+        match shadowed {
+            Some(v) => self.variables.insert(var.to_string(), v),
+            None => self.variables.remove(var),
+        };
+        body?;
+        self.builder.ins().jump(latch_block, &[]);
+
+        // Every continue has been seen, so the latch can be sealed
+        self.builder.switch_to_block(latch_block);
+        self.builder.seal_block(latch_block);
+        let i = self.builder.use_var(counter);
+        let next = self.builder.ins().iadd_imm(i, 1);
+        self.builder.def_var(counter, next);
         self.builder.ins().jump(header_block, &[]);
 
         self.builder.switch_to_block(exit_block);
@@ -1178,14 +2663,11 @@ impl<'a> FunctionTranslator<'a> {
     ) -> anyhow::Result<SValue> {
         let mut name = name.to_string();
         if impl_func {
-            if let Some(self_var) = self.variables.get(&args[0].to_string()) {
-                if let SVariable::Struct(_var_name, struct_name, _var) = self_var {
+            match self.variables.get(&args[0].to_string()) {
+                Some(SVariable::Struct(_var_name, struct_name, _var)) => {
                     name = format!("{}.{}", struct_name, name);
-                } else {
-                    unreachable!("should be caught by validator");
                 }
-            } else {
-                unreachable!("should be caught by validator");
+                _ => unreachable!("should be caught by validator"),
             }
         }
 
@@ -1195,6 +2677,11 @@ impl<'a> FunctionTranslator<'a> {
 
         let name = &name;
 
+        if name == "len" && !self.funcs.contains_key(name) {
+            let (_, len) = self.translate_expr(&args[0])?.expect_slice("len")?;
+            return Ok(SValue::I64(len));
+        }
+
         if !self.funcs.contains_key(name) {
             anyhow::bail!("function {} not found", name)
         }
@@ -1208,23 +2695,48 @@ impl<'a> FunctionTranslator<'a> {
             )
         }
 
+        if func.extern_func && sarus_std_lib::INTRINSICS.contains(&name.as_str()) {
+            return self.translate_std(name, args);
+        }
         let mut arg_values = Vec::new();
-        if func.extern_func {
-            if let Some(v) = self.translate_std(name, args)? {
-                return Ok(v);
+        // Make room for returned structs, the callee writes them through pointers
+        // passed ahead of the arguments
+        let mut struct_returns = Vec::new();
+        for ret_arg in &func.returns {
+            if let Some(ExprType::Struct(struct_name)) = &ret_arg.expr_type {
+                let ptr = self.translate_stack_struct(struct_name);
+                sig.params.push(AbiParam::new(ptr_ty));
+                arg_values.push(ptr);
+                struct_returns.push(SValue::Struct(struct_name.to_string(), ptr));
             }
         }
         for (arg, expr) in func.params.iter().zip(args.iter()) {
-            sig.params.push(AbiParam::new(
-                arg.expr_type
-                    .as_ref()
-                    .unwrap_or(&ExprType::F64)
-                    .cranelift_type(ptr_ty)?,
-            ));
-            arg_values.push(self.translate_expr(expr)?.inner("translate_call")?)
+            let arg_type = arg.expr_type.as_ref().unwrap_or(&ExprType::F64);
+            match (arg_type, self.translate_expr(expr)?) {
+                (ExprType::Slice(_), SValue::Slice(_, ptr, len)) => {
+                    sig.params.push(AbiParam::new(ptr_ty));
+                    sig.params.push(AbiParam::new(types::I64));
+                    arg_values.push(ptr);
+                    arg_values.push(len);
+                }
+                // Passed on as a plain pointer
+                (_, SValue::Slice(_, ptr, _)) => {
+                    sig.params
+                        .push(AbiParam::new(arg_type.cranelift_type(ptr_ty)?));
+                    arg_values.push(ptr);
+                }
+                (_, v) => {
+                    sig.params
+                        .push(AbiParam::new(arg_type.cranelift_type(ptr_ty)?));
+                    arg_values.push(v.inner("translate_call")?);
+                }
+            }
         }
 
         for ret_arg in &func.returns {
+            if let Some(ExprType::Struct(_)) = ret_arg.expr_type {
+                continue;
+            }
             sig.returns.push(AbiParam::new(
                 ret_arg
                     .expr_type
@@ -1233,35 +2745,27 @@ impl<'a> FunctionTranslator<'a> {
                     .cranelift_type(ptr_ty)?,
             ));
         }
+        // Registered Rust functions aren't redefined, so they're imported by their own name
+        let symbol = self.symbols.get(name).unwrap_or(name);
         let callee = self
             .module
-            .declare_function(&name, Linkage::Import, &sig)
-            .expect("problem declaring function");
-        let local_callee = self
-            .module
-            .declare_func_in_func(callee, &mut self.builder.func);
+            .declare_function(symbol, Linkage::Import, &sig)
+            .map_err(|e| anyhow::anyhow!("can't call {}: {}", name, e))?;
+        let local_callee = self.module.declare_func_in_func(callee, self.builder.func);
         let call = self.builder.ins().call(local_callee, &arg_values);
-        let res = self.builder.inst_results(call);
-        if res.len() > 1 {
-            Ok(SValue::Tuple(
-                res.iter()
-                    .zip(func.returns.iter())
-                    .map(|(v, arg)| {
-                        SValue::from(arg.expr_type.as_ref().unwrap_or(&ExprType::F64), *v).unwrap()
-                    })
-                    .collect::<Vec<SValue>>(),
-            ))
-        } else if res.len() == 1 {
-            Ok(SValue::from(
-                &func
-                    .returns
-                    .first()
-                    .unwrap()
-                    .expr_type
-                    .as_ref()
-                    .unwrap_or(&ExprType::F64),
-                *res.first().unwrap(),
-            )?)
+        let mut res = self.builder.inst_results(call).to_vec().into_iter();
+        let mut struct_returns = struct_returns.into_iter();
+        let mut values = Vec::new();
+        for ret_arg in &func.returns {
+            values.push(match ret_arg.expr_type.as_ref().unwrap_or(&ExprType::F64) {
+                ExprType::Struct(_) => struct_returns.next().unwrap(),
+                t => SValue::from(t, res.next().unwrap())?,
+            });
+        }
+        if values.len() > 1 {
+            Ok(SValue::Tuple(values))
+        } else if values.len() == 1 {
+            Ok(values.pop().unwrap())
         } else {
             Ok(SValue::Void)
         }
@@ -1270,11 +2774,9 @@ impl<'a> FunctionTranslator<'a> {
     fn translate_global_data_addr(&mut self, ptr_ty: Type, name: &str) -> Value {
         let sym = self
             .module
-            .declare_data(&name, Linkage::Export, true, false)
+            .declare_data(name, Linkage::Export, true, false)
             .expect("problem declaring data object");
-        let local_id = self
-            .module
-            .declare_data_in_func(sym, &mut self.builder.func);
+        let local_id = self.module.declare_data_in_func(sym, self.builder.func);
         let global_val = self.builder.create_global_value(GlobalValueData::Load {
             base: local_id,
             offset: Offset32::new(0),
@@ -1286,7 +2788,8 @@ impl<'a> FunctionTranslator<'a> {
         self.builder.ins().global_value(ptr_ty, global_val)
     }
 
-    fn translate_std(&mut self, name: &str, args: &[Expr]) -> anyhow::Result<Option<SValue>> {
+    /// Generate the code of one of `sarus_std_lib::INTRINSICS` in place.
+    fn translate_std(&mut self, name: &str, args: &[Expr]) -> anyhow::Result<SValue> {
         let mut vargs = Vec::new();
         for arg in args {
             vargs.push(self.translate_expr(arg)?.inner("translate_std")?);
@@ -1296,71 +2799,148 @@ impl<'a> FunctionTranslator<'a> {
             &mut self.builder,
             name,
             vargs.as_slice(),
-        )
+        )?
+        .ok_or_else(|| anyhow::anyhow!("{} isn't built in", name))
     }
 
     fn value_type(&self, val: Value) -> Type {
         self.builder.func.dfg.value_type(val)
     }
 
-    fn translate_new_struct(
-        &mut self,
-        name: &str,
-        fields: &[StructAssignField],
-    ) -> anyhow::Result<SValue> {
+    /// Allocate room for a struct on the stack.
+    fn translate_stack_struct(&mut self, name: &str) -> Value {
         let stack_slot = self.builder.create_stack_slot(StackSlotData::new(
             StackSlotKind::ExplicitSlot,
             self.struct_map[name].size,
         ));
-        let stack_slot_address = self.builder.ins().stack_addr(
+        self.builder.ins().stack_addr(
             self.module.target_config().pointer_type(),
             stack_slot,
             Offset32::new(0),
+        )
+    }
+
+    fn translate_struct_copy(
+        &mut self,
+        name: &str,
+        dest: Value,
+        src: Value,
+        non_overlapping: bool,
+    ) {
+        let size = self.struct_map[name].size;
+        self.builder.emit_small_memory_copy(
+            self.module.target_config(),
+            dest,
+            src,
+            size as u64,
+            1,
+            1,
+            non_overlapping,
+            MemFlags::new(),
         );
+    }
+
+    /// Copy a struct into a new stack slot, giving back the address of the copy.
+    fn translate_struct_clone(&mut self, name: &str, src: Value) -> Value {
+        let dest = self.translate_stack_struct(name);
+        self.translate_struct_copy(name, dest, src, true);
+        dest
+    }
+
+    /// Structs are passed by value, the callee works on its own copy. `self`
+    /// is the exception, so methods can update the struct they're called on.
+    fn copy_struct_params(&mut self, params: &[Arg]) -> anyhow::Result<()> {
+        for param in params {
+            if let (Some(ExprType::Struct(struct_name)), false) =
+                (&param.expr_type, param.name == "self")
+            {
+                let var = self.variables[&param.name].expect_struct(struct_name, "params")?;
+                let src = self.builder.use_var(var);
+                let copy = self.translate_struct_clone(struct_name, src);
+                self.builder.def_var(var, copy);
+            }
+        }
+        Ok(())
+    }
+
+    fn translate_new_struct(
+        &mut self,
+        name: &str,
+        fields: &[StructAssignField],
+    ) -> anyhow::Result<SValue> {
+        let stack_slot_address = self.translate_stack_struct(name);
         for field in fields.iter() {
             let field_def = &self.struct_map[name].fields[&field.field_name];
-            let ty = field_def
-                .expr_type
-                .cranelift_type(self.module.target_config().pointer_type())?;
-            //TODO this won't work if the field is another struct
             let v = self.translate_expr(&field.expr)?;
-            self.builder.ins().Store(
-                Opcode::Store,
-                ty,
-                MemFlags::new(),
-                Offset32::new(field_def.offset as i32),
-                v.inner("new_struct")?,
-                stack_slot_address,
-            );
+            // Nested structs are stored inline, translate_store_elem copies them in
+            let field_ptr = self
+                .builder
+                .ins()
+                .iadd_imm(stack_slot_address, field_def.offset as i64);
+            self.translate_store_elem(&field_def.expr_type, field_ptr, v)?;
         }
         Ok(SValue::Struct(name.to_string(), stack_slot_address))
     }
 
-    fn translate_struct_field(&mut self, name: &str) -> anyhow::Result<SValue> {
-        let parts = name.split(".").collect::<Vec<&str>>();
-        match &self.variables[parts[0]] {
-            SVariable::Struct(_var_name, struct_name, var) => {
-                let struct_field = &self.struct_map[struct_name].fields[parts[1]];
-                let struct_var_ptr = self.builder.use_var(*var);
-
-                let val = self.builder.ins().load(
-                    struct_field
-                        .expr_type
-                        .cranelift_type(self.module.target_config().pointer_type())?,
-                    MemFlags::trusted(),
-                    struct_var_ptr,
-                    Offset32::new(struct_field.offset as i32),
-                );
-                SValue::from(&struct_field.expr_type, val)
-            }
+    /// Address of the field at the end of a path like `line.a.x`. Nested
+    /// structs are stored inline, so this is an offset from the outermost struct.
+    fn translate_field_ptr(&mut self, path: &str) -> anyhow::Result<(Value, ExprType)> {
+        let mut parts = path.split(".");
+        let base = parts.next().unwrap();
+        let (mut struct_name, var) = match &self.variables[base] {
+            SVariable::Struct(_var_name, struct_name, var) => (struct_name.to_string(), *var),
             _ => unreachable!("validator should catch this"),
-        }
+        };
+        let mut offset = 0;
+        let mut field_type = ExprType::Struct(Box::new(struct_name.clone()));
+        for field in parts {
+            if let ExprType::Struct(field_struct_name) = &field_type {
+                struct_name = field_struct_name.to_string();
+            } else {
+                unreachable!("validator should catch this")
+            }
+            let struct_field = &self.struct_map[&struct_name].fields[field];
+            offset += struct_field.offset as i64;
+            field_type = struct_field.expr_type.clone();
+        }
+        let struct_var_ptr = self.builder.use_var(var);
+        Ok((
+            self.builder.ins().iadd_imm(struct_var_ptr, offset),
+            field_type,
+        ))
+    }
+
+    fn translate_struct_field(&mut self, path: &str) -> anyhow::Result<SValue> {
+        let (field_ptr, field_type) = self.translate_field_ptr(path)?;
+        self.translate_load_elem(&field_type, field_ptr)
+    }
+
+    fn translate_set_struct_field(&mut self, path: &str, new_val: SValue) -> anyhow::Result<()> {
+        let (field_ptr, field_type) = self.translate_field_ptr(path)?;
+        self.translate_store_elem(&field_type, field_ptr, new_val)
+    }
+}
+
+/// Zeroed memory for a struct, aligned for it unlike a `Vec<u8>`.
+fn struct_buffer(struct_def: &StructDef) -> anyhow::Result<Vec<u64>> {
+    if struct_def.align > 8 {
+        anyhow::bail!(
+            "struct {} needs an alignment of {}",
+            struct_def.name,
+            struct_def.align
+        )
     }
+    Ok(vec![0u64; (struct_def.size as usize).div_ceil(8)])
+}
+
+/// The first `len` bytes of a buffer from `struct_buffer`.
+fn buffer_bytes(buffer: &mut [u64], len: usize) -> &mut [u8] {
+    assert!(len <= buffer.len() * 8);
+    unsafe { std::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut u8, len) }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum SVariable {
-    Unknown(String, Variable),
     Bool(String, Variable),
     F64(String, Variable),
     I64(String, Variable),
@@ -1368,12 +2948,13 @@ pub enum SVariable {
     UnboundedArrayI64(String, Variable),
     Address(String, Variable),
     Struct(String, String, Variable),
+    //Element type, pointer to the first element, length
+    Slice(String, ExprType, Variable, Variable),
 }
 
 impl Display for SVariable {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            SVariable::Unknown(name, _) => write!(f, "Unknown {}", name),
             SVariable::Bool(name, _) => write!(f, "Bool {}", name),
             SVariable::F64(name, _) => write!(f, "Float {}", name),
             SVariable::I64(name, _) => write!(f, "Int {}", name),
@@ -1381,6 +2962,7 @@ impl Display for SVariable {
             SVariable::UnboundedArrayI64(name, _) => write!(f, "UnboundedArrayI64 {}", name),
             SVariable::Address(name, _) => write!(f, "Address {}", name),
             SVariable::Struct(name, structname, _) => write!(f, "Struct {} {}", name, structname),
+            SVariable::Slice(name, elem, _, _) => write!(f, "Slice [{}] {}", elem, name),
         }
     }
 }
@@ -1388,7 +2970,6 @@ impl Display for SVariable {
 impl SVariable {
     fn inner(&self) -> Variable {
         match self {
-            SVariable::Unknown(_, v) => *v,
             SVariable::Bool(_, v) => *v,
             SVariable::F64(_, v) => *v,
             SVariable::I64(_, v) => *v,
@@ -1396,6 +2977,16 @@ impl SVariable {
             SVariable::UnboundedArrayI64(_, v) => *v,
             SVariable::Address(_, v) => *v,
             SVariable::Struct(_, _, v) => *v,
+            SVariable::Slice(_, _, v, _) => *v,
+        }
+    }
+    /// Type of the elements if this is an array.
+    pub(crate) fn elem_type(&self) -> Option<ExprType> {
+        match self {
+            SVariable::UnboundedArrayF64(..) => Some(ExprType::F64),
+            SVariable::UnboundedArrayI64(..) => Some(ExprType::I64),
+            SVariable::Slice(_, elem, _, _) => Some(elem.clone()),
+            _ => None,
         }
     }
     fn expect_f64(&self, ctx: &str) -> anyhow::Result<Variable> {
@@ -1428,6 +3019,12 @@ impl SVariable {
             v => anyhow::bail!("incorrect type {} expected UnboundedArrayI64 {}", v, ctx),
         }
     }
+    fn expect_slice(&self, ctx: &str) -> anyhow::Result<(Variable, Variable)> {
+        match self {
+            SVariable::Slice(_, _, ptr, len) => Ok((*ptr, *len)),
+            v => anyhow::bail!("incorrect type {} expected Slice {}", v, ctx),
+        }
+    }
     fn expect_address(&self, ctx: &str) -> anyhow::Result<Variable> {
         match self {
             SVariable::Address(_, v) => Ok(*v),
@@ -1438,7 +3035,7 @@ impl SVariable {
         match self {
             SVariable::Struct(varname, sname, v) => {
                 if sname == name {
-                    return Ok(*v);
+                    Ok(*v)
                 } else {
                     anyhow::bail!(
                         "incorrect type {} expected Struct {} {}",
@@ -1453,8 +3050,8 @@ impl SVariable {
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn declare_variables(
-    float: types::Type,
     builder: &mut FunctionBuilder,
     module: &mut dyn Module,
     params: &[Arg],
@@ -1470,41 +3067,85 @@ fn declare_variables(
     let mut variables: HashMap<String, SVariable> = HashMap::new();
     let mut index = 0;
 
-    for (i, arg) in params.iter().enumerate() {
-        let val = builder.block_params(entry_block)[i];
+    // Pointers to write returned structs to come first, slices take up two block params
+    let mut struct_return_index = 0;
+    let mut param_index = returns
+        .iter()
+        .filter(|ret| matches!(ret.expr_type, Some(ExprType::Struct(_))))
+        .count();
+    for arg in params {
+        let val = builder.block_params(entry_block)[param_index];
         let var = declare_variable(module, builder, &mut variables, &mut index, arg);
-        if let Some(var) = var {
-            builder.def_var(var.inner(), val);
+        match var {
+            Some(SVariable::Slice(_, _, ptr, len)) => {
+                let len_val = builder.block_params(entry_block)[param_index + 1];
+                builder.def_var(ptr, val);
+                builder.def_var(len, len_val);
+                param_index += 2;
+            }
+            Some(var) => {
+                builder.def_var(var.inner(), val);
+                param_index += 1;
+            }
+            None => (),
         }
     }
 
     for arg in returns {
-        declare_variable(module, builder, &mut variables, &mut index, arg);
+        let var = declare_variable(module, builder, &mut variables, &mut index, arg);
+        if let Some(ExprType::Struct(_)) = arg.expr_type {
+            // A return that is also a param keeps the param's value
+            if let Some(SVariable::Struct(_, _, var)) = var {
+                let val = builder.block_params(entry_block)[struct_return_index];
+                builder.def_var(var, val);
+            }
+            struct_return_index += 1;
+        }
     }
 
     for expr in stmts {
         declare_variables_in_stmt(
             module.target_config().pointer_type(),
-            float,
             builder,
             &mut variables,
             &mut index,
             expr,
             env,
             funcs,
-            &constant_vars,
-            &struct_map,
+            constant_vars,
+            struct_map,
         )?;
     }
 
     Ok(variables)
 }
 
+/// Key the induction variable of a for loop is declared under. It is only in
+/// scope inside the loop body, where it is made available under its own name.
+pub(crate) fn induction_var_key(name: &str, span: Span) -> String {
+    format!("{}@{}", name, span.start)
+}
+
+/// Key of the hidden counter driving a for loop.
+fn loop_counter_key(span: Span) -> String {
+    format!("@{}", span.start)
+}
+
+/// Every function called in `expr`, and whether it's called as a method.
+fn called_functions<'a>(expr: &'a Expr, calls: &mut Vec<(&'a String, bool)>) {
+    if let Expr::Call(_, name, _, impl_func) = expr {
+        calls.push((name, *impl_func));
+    }
+    for child in expr.children() {
+        called_functions(child, calls);
+    }
+}
+
 /// Recursively descend through the AST, translating all implicit
 /// variable declarations.
+#[allow(clippy::too_many_arguments)]
 fn declare_variables_in_stmt(
     ptr_type: types::Type,
-    ty: types::Type,
     builder: &mut FunctionBuilder,
     variables: &mut HashMap<String, SVariable>,
     index: &mut usize,
@@ -1514,8 +3155,38 @@ fn declare_variables_in_stmt(
     constant_vars: &HashMap<String, f64>,
     struct_map: &HashMap<String, StructDef>,
 ) -> anyhow::Result<()> {
+    // Variables can also be assigned in the branches of an if else producing a value
+    let mut values = match expr {
+        Expr::IfThen(_, cond, _) | Expr::IfElse(_, cond, ..) | Expr::WhileLoop(_, cond, _) => {
+            vec![&**cond]
+        }
+        Expr::ForLoop(_, _, iterable, _) => vec![&**iterable],
+        Expr::Assign(_, _, exprs) => exprs.iter().collect(),
+        expr => expr.children(),
+    };
+    while let Some(value) = values.pop() {
+        match value {
+            Expr::IfElse(_, cond, then_body, else_body) => {
+                values.push(cond);
+                for stmt in then_body.iter().chain(else_body.iter()) {
+                    declare_variables_in_stmt(
+                        ptr_type,
+                        builder,
+                        variables,
+                        index,
+                        stmt,
+                        env,
+                        funcs,
+                        constant_vars,
+                        struct_map,
+                    )?;
+                }
+            }
+            value => values.extend(value.children()),
+        }
+    }
     match *expr {
-        Expr::Assign(ref names, ref exprs) => {
+        Expr::Assign(_, ref names, ref exprs) => {
             if exprs.len() == names.len() {
                 for (name, expr) in names.iter().zip(exprs.iter()) {
                     declare_variable_from_expr(
@@ -1550,15 +3221,14 @@ fn declare_variables_in_stmt(
                 )?;
             }
         }
-        Expr::IfElse(ref _condition, ref then_body, ref else_body) => {
+        Expr::IfElse(_, ref _condition, ref then_body, ref else_body) => {
             for stmt in then_body {
                 declare_variables_in_stmt(
                     ptr_type,
-                    ty,
                     builder,
                     variables,
                     index,
-                    &stmt,
+                    stmt,
                     env,
                     funcs,
                     constant_vars,
@@ -1568,11 +3238,10 @@ fn declare_variables_in_stmt(
             for stmt in else_body {
                 declare_variables_in_stmt(
                     ptr_type,
-                    ty,
                     builder,
                     variables,
                     index,
-                    &stmt,
+                    stmt,
                     env,
                     funcs,
                     constant_vars,
@@ -1580,15 +3249,82 @@ fn declare_variables_in_stmt(
                 )?;
             }
         }
-        Expr::WhileLoop(ref _condition, ref loop_body) => {
+        Expr::ForLoop(span, ref var, ref iterable, ref loop_body) => {
+            // Not iterable if there's no element type, validate_program reports it
+            let var_type = match &**iterable {
+                Expr::Range(..) => Some(ExprType::I64),
+                Expr::ArrayGet(_, name, _) => variables.get(name).and_then(SVariable::elem_type),
+                e => match ExprType::of(e, env, funcs, variables, constant_vars, struct_map) {
+                    Ok(ExprType::Slice(elem)) => Some(*elem),
+                    _ => None,
+                },
+            };
+            let counter_key = loop_counter_key(span);
+            let induction_key = induction_var_key(var, span);
+            declare_variable_from_type(
+                ptr_type,
+                &ExprType::I64,
+                builder,
+                variables,
+                index,
+                &[&counter_key],
+            )?;
+            if let Some(var_type) = var_type {
+                declare_variable_from_type(
+                    ptr_type,
+                    &var_type,
+                    builder,
+                    variables,
+                    index,
+                    &[&induction_key],
+                )?;
+            }
+
+            // Make the induction variable visible under its own name while in the body
+            let shadowed = match variables.get(&induction_key) {
+                Some(v) => {
+                    let v = v.clone();
+                    variables.insert(var.to_string(), v)
+                }
+                None => variables.remove(var),
+            };
             for stmt in loop_body {
                 declare_variables_in_stmt(
                     ptr_type,
-                    ty,
                     builder,
                     variables,
                     index,
-                    &stmt,
+                    stmt,
+                    env,
+                    funcs,
+                    constant_vars,
+                    struct_map,
+                )?;
+            }
+            match shadowed {
+                Some(v) => variables.insert(var.to_string(), v),
+                None => variables.remove(var),
+            };
+        }
+        Expr::DeclareArray(_, ref var, ref elem, _) => {
+            declare_variable_from_type(
+                ptr_type,
+                &ExprType::Slice(Box::new(elem.clone())),
+                builder,
+                variables,
+                index,
+                &[var],
+            )?;
+        }
+        Expr::IfThen(_, ref _condition, ref body)
+        | Expr::WhileLoop(_, ref _condition, ref body) => {
+            for stmt in body {
+                declare_variables_in_stmt(
+                    ptr_type,
+                    builder,
+                    variables,
+                    index,
+                    stmt,
                     env,
                     funcs,
                     constant_vars,
@@ -1602,6 +3338,7 @@ fn declare_variables_in_stmt(
 }
 
 /// Declare a single variable declaration.
+#[allow(clippy::too_many_arguments)]
 fn declare_variable_from_expr(
     ptr_type: Type,
     expr: &Expr,
@@ -1615,7 +3352,7 @@ fn declare_variable_from_expr(
     struct_map: &HashMap<String, StructDef>,
 ) -> anyhow::Result<()> {
     match expr {
-        Expr::IfElse(_condition, then_body, _else_body) => {
+        Expr::IfElse(_, _condition, then_body, _else_body) => {
             //TODO make sure then & else returns match
             declare_variable_from_expr(
                 ptr_type,
@@ -1631,10 +3368,14 @@ fn declare_variable_from_expr(
             )?;
         }
         expr => {
-            let expr_type = ExprType::of(expr, &env, funcs, variables, constant_vars, struct_map)?;
-            declare_variable_from_type(
-                ptr_type, &expr_type, builder, variables, index, names, env,
-            )?;
+            // Leave the variable undeclared if its type can't be worked out,
+            // validate_program checks the statement again and reports the error
+            let expr_type =
+                match ExprType::of(expr, env, funcs, variables, constant_vars, struct_map) {
+                    Ok(expr_type) => expr_type,
+                    Err(_) => return Ok(()),
+                };
+            declare_variable_from_type(ptr_type, &expr_type, builder, variables, index, names)?;
         }
     };
     Ok(())
@@ -1647,9 +3388,12 @@ fn declare_variable_from_type(
     variables: &mut HashMap<String, SVariable>,
     index: &mut usize,
     names: &[&str],
-    env: &[Declaration],
 ) -> anyhow::Result<()> {
     let name = *names.first().unwrap();
+    if name.contains(".") && !matches!(expr_type, ExprType::Tuple(_)) {
+        // Struct fields are stored in the struct, not in a variable
+        return Ok(());
+    }
     match expr_type {
         ExprType::Void => anyhow::bail!("can't assign void type to {}", name),
         ExprType::Bool => {
@@ -1712,7 +3456,6 @@ fn declare_variable_from_type(
                             variables,
                             index,
                             &[sname],
-                            env,
                         )?
                     }
                     return Ok(());
@@ -1726,7 +3469,6 @@ fn declare_variable_from_type(
                     variables,
                     index,
                     &[sname],
-                    env,
                 )?
             }
         }
@@ -1741,6 +3483,19 @@ fn declare_variable_from_type(
                 *index += 1;
             }
         }
+        ExprType::Slice(elem) => {
+            if !variables.contains_key(name) {
+                let ptr = Variable::new(*index);
+                let len = Variable::new(*index + 1);
+                variables.insert(
+                    name.into(),
+                    SVariable::Slice(name.into(), (**elem).clone(), ptr, len),
+                );
+                builder.declare_var(ptr, ptr_type);
+                builder.declare_var(len, types::I64);
+                *index += 2;
+            }
+        }
     }
     Ok(())
 }
@@ -1754,6 +3509,16 @@ fn declare_variable(
 ) -> Option<SVariable> {
     let ptr_ty = module.target_config().pointer_type();
     if !variables.contains_key(&arg.name) {
+        if let Some(ExprType::Slice(elem)) = &arg.expr_type {
+            let ptr = Variable::new(*index);
+            let len = Variable::new(*index + 1);
+            let var = SVariable::Slice(arg.name.clone(), (**elem).clone(), ptr, len);
+            variables.insert(arg.name.clone(), var.clone());
+            builder.declare_var(ptr, ptr_ty);
+            builder.declare_var(len, types::I64);
+            *index += 2;
+            return Some(var);
+        }
         let (var, ty) = match &arg.expr_type {
             Some(t) => match t {
                 ExprType::F64 => (
@@ -1790,6 +3555,7 @@ fn declare_variable(
                     ),
                     ptr_ty,
                 ),
+                ExprType::Slice(_) => unreachable!("declared above"),
             },
             None => (
                 SVariable::F64(arg.name.clone(), Variable::new(*index)),
@@ -1805,14 +3571,21 @@ fn declare_variable(
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct StructDef {
     pub size: u32,
+    pub align: u32,
     pub name: String,
     pub fields: HashMap<String, StructField>,
 }
 
-#[derive(Debug)]
+impl StructDef {
+    pub fn offset_of(&self, field_name: &str) -> Option<u32> {
+        self.fields.get(field_name).map(|field| field.offset)
+    }
+}
+
+#[derive(Debug, PartialEq)]
 pub struct StructField {
     pub offset: u32,
     pub size: u32,
@@ -1820,7 +3593,11 @@ pub struct StructField {
     pub expr_type: ExprType,
 }
 
-fn create_struct_map(
+fn align_to(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
+
+pub(crate) fn create_struct_map(
     prog: &Vec<Declaration>,
     ptr_type: types::Type,
 ) -> anyhow::Result<HashMap<String, StructDef>> {
@@ -1834,40 +3611,51 @@ fn create_struct_map(
 
     let mut structs: HashMap<String, StructDef> = HashMap::new();
 
+    // Lay out fields like C does, each one aligned to its own size (or the largest
+    // alignment inside a nested struct), with the total size padded to a multiple
+    // of the struct's alignment so arrays of it stay aligned
     for struct_name in structs_order {
         let mut fields = HashMap::new();
         let mut struct_size = 0u32;
+        let mut struct_align = 1u32;
         for field in in_structs[&struct_name].fields.iter() {
-            let size = match &field.expr_type {
+            let (size, align) = match &field.expr_type {
                 Some(t) => match t {
-                    ExprType::Void => 0u32,
-                    ExprType::Bool => types::B1.bytes(),
-                    ExprType::F64 => types::F64.bytes(),
-                    ExprType::I64 => types::I64.bytes(),
-                    ExprType::UnboundedArrayF64 => ptr_type.bytes(),
-                    ExprType::UnboundedArrayI64 => ptr_type.bytes(),
-                    ExprType::Address => ptr_type.bytes(),
+                    ExprType::Void => (0u32, 1u32),
+                    ExprType::Bool => (1, 1),
+                    ExprType::F64 => (types::F64.bytes(), types::F64.bytes()),
+                    ExprType::I64 => (types::I64.bytes(), types::I64.bytes()),
+                    ExprType::UnboundedArrayF64
+                    | ExprType::UnboundedArrayI64
+                    | ExprType::Address => (ptr_type.bytes(), ptr_type.bytes()),
                     ExprType::Tuple(_) => anyhow::bail!("Tuple in struct not supported"),
-                    ExprType::Struct(name) => structs[&name.to_string()].size,
+                    ExprType::Slice(_) => anyhow::bail!("Slice in struct not supported"),
+                    ExprType::Struct(name) => {
+                        let inner = &structs[&name.to_string()];
+                        (inner.size, inner.align)
+                    }
                 },
-                None => types::F64.bytes(),
+                None => (types::F64.bytes(), types::F64.bytes()),
             };
+            let offset = align_to(struct_size, align);
             fields.insert(
                 field.name.to_string(),
                 StructField {
-                    offset: struct_size,
+                    offset,
                     size,
                     name: field.name.to_string(),
                     expr_type: field.expr_type.as_ref().unwrap_or(&ExprType::F64).clone(),
                 },
             );
-            struct_size += size;
+            struct_size = offset + size;
+            struct_align = struct_align.max(align);
         }
 
         structs.insert(
             struct_name.to_string(),
             StructDef {
-                size: struct_size,
+                size: align_to(struct_size, struct_align),
+                align: struct_align,
                 name: struct_name.to_string(),
                 fields,
             },
@@ -1893,7 +3681,8 @@ fn order_structs(in_structs: &HashMap<String, &Struct>) -> anyhow::Result<Vec<St
                         | ExprType::UnboundedArrayF64
                         | ExprType::UnboundedArrayI64
                         | ExprType::Address
-                        | ExprType::Tuple(_) => continue,
+                        | ExprType::Tuple(_)
+                        | ExprType::Slice(_) => continue,
                         ExprType::Struct(field_struct_name) => {
                             if !in_structs.contains_key(&field_struct_name.to_string()) {
                                 anyhow::bail!(
@@ -1,9 +1,18 @@
 pub use crate::frontend::parser;
+pub use sarus_derive::SarusStruct;
 
+pub mod c_header;
+mod code_cache;
 pub mod frontend;
 pub mod graph;
+pub mod inference;
+pub mod interpreter;
 pub mod jit;
 pub mod sarus_std_lib;
+pub mod sarus_struct;
+pub mod sarus_value;
+pub mod trap;
+pub mod typed_func;
 pub mod validator;
 
 #[macro_export]
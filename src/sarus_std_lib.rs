@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 
+use cranelift::codegen::ir::TrapCode;
 use cranelift::frontend::FunctionBuilder;
-use cranelift::prelude::{types, InstBuilder, Value};
+use cranelift::prelude::{types, InstBuilder, IntCC, Value};
 
-use crate::frontend::Arg;
+use crate::frontend::{Arg, Span};
 use crate::hashmap;
 use crate::jit::SValue;
+use crate::sarus_value::SarusValue;
+use crate::trap;
 use crate::{
     frontend::{Declaration, Function},
     validator::ExprType,
@@ -19,6 +22,7 @@ fn decl(name: &str, params: Vec<(&str, ExprType)>, returns: Vec<(&str, ExprType)
             .map(|(name, expr)| Arg {
                 name: name.to_string(),
                 expr_type: Some(expr),
+                span: Span::default(),
             })
             .collect(),
         returns: returns
@@ -26,10 +30,12 @@ fn decl(name: &str, params: Vec<(&str, ExprType)>, returns: Vec<(&str, ExprType)
             .map(|(name, expr)| Arg {
                 name: name.to_string(),
                 expr_type: Some(expr),
+                span: Span::default(),
             })
             .collect(),
         body: vec![],
         extern_func: true,
+        span: Span::default(),
     })
 }
 
@@ -105,6 +111,11 @@ pub fn append_std_funcs(mut prog: Vec<Declaration>) -> Vec<Declaration> {
     prog
 }
 
+/// The std functions `translate_std` generates code for in place, instead of calling them.
+pub(crate) const INTRINSICS: [&str; 12] = [
+    "trunc", "floor", "ceil", "fract", "abs", "round", "int", "float", "min", "max", "imin", "imax",
+];
+
 pub(crate) fn translate_std(
     _ptr_ty: cranelift::prelude::Type,
     builder: &mut FunctionBuilder,
@@ -130,12 +141,132 @@ pub(crate) fn translate_std(
         ))),
         "min" => Ok(Some(SValue::F64(builder.ins().fmin(args[0], args[1])))),
         "max" => Ok(Some(SValue::F64(builder.ins().fmax(args[0], args[1])))),
-        "imin" => Ok(Some(SValue::I64(builder.ins().imin(args[0], args[1])))),
-        "imax" => Ok(Some(SValue::I64(builder.ins().imax(args[0], args[1])))),
+        // The x64 backend only has `imin` and `imax` for vectors
+        // https://github.com/bytecodealliance/wasmtime/issues/3370
+        "imin" => {
+            let lt = builder.ins().icmp(IntCC::SignedLessThan, args[0], args[1]);
+            Ok(Some(SValue::I64(
+                builder.ins().select(lt, args[0], args[1]),
+            )))
+        }
+        "imax" => {
+            let gt = builder
+                .ins()
+                .icmp(IntCC::SignedGreaterThan, args[0], args[1]);
+            Ok(Some(SValue::I64(
+                builder.ins().select(gt, args[0], args[1]),
+            )))
+        }
         _ => Ok(None),
     }
 }
 
+/// What `translate_std` generates code for, worked out directly by `Interpreter`.
+/// `None` if `name` isn't one of those functions.
+pub(crate) fn interpret_std(
+    name: &str,
+    args: &[SarusValue],
+) -> Result<Option<SarusValue>, TrapCode> {
+    use SarusValue::{F64, I64};
+    Ok(Some(match (name, args) {
+        ("trunc", [F64(x)]) => F64(x.trunc()),
+        ("floor", [F64(x)]) => F64(x.floor()),
+        ("ceil", [F64(x)]) => F64(x.ceil()),
+        ("fract", [F64(x)]) => F64(x - x.trunc()),
+        ("abs", [F64(x)]) => F64(x.abs()),
+        ("round", [F64(x)]) => F64(nearest(*x)),
+        ("int", [F64(x)]) => I64(float_to_int(*x)?),
+        ("float", [I64(x)]) => F64(*x as f64),
+        ("min", [F64(x), F64(y)]) => F64(fmin(*x, *y)),
+        ("max", [F64(x), F64(y)]) => F64(fmax(*x, *y)),
+        ("imin", [I64(x), I64(y)]) => I64(*x.min(y)),
+        ("imax", [I64(x), I64(y)]) => I64(*x.max(y)),
+        _ => return Ok(None),
+    }))
+}
+
+/// The std functions compiled code calls in libc, for `Interpreter`. The Rust
+/// methods call the same functions, except for `exp10` which Rust doesn't have.
+pub(crate) fn call_libm(name: &str, args: &[f64]) -> Option<f64> {
+    Some(match (name, args) {
+        ("sin", [x]) => x.sin(),
+        ("cos", [x]) => x.cos(),
+        ("tan", [x]) => x.tan(),
+        ("asin", [x]) => x.asin(),
+        ("acos", [x]) => x.acos(),
+        ("atan", [x]) => x.atan(),
+        ("exp", [x]) => x.exp(),
+        ("log", [x]) => x.ln(),
+        ("log10", [x]) => x.log10(),
+        ("sqrt", [x]) => x.sqrt(),
+        ("sinh", [x]) => x.sinh(),
+        ("cosh", [x]) => x.cosh(),
+        ("exp10", [x]) => 10f64.powf(*x),
+        ("tanh", [x]) => x.tanh(),
+        ("atan2", [y, x]) => y.atan2(*x),
+        ("pow", [x, y]) => x.powf(*y),
+        _ => return None,
+    })
+}
+
+/// Round to the nearest integer, ties to even, like cranelift's `nearest`.
+fn nearest(x: f64) -> f64 {
+    let rounded = x.round();
+    if (rounded - x).abs() == 0.5 {
+        2.0 * (x / 2.0).round()
+    } else {
+        rounded
+    }
+}
+
+/// Like cranelift's `fcvt_to_sint`, which traps instead of saturating.
+fn float_to_int(x: f64) -> Result<i64, TrapCode> {
+    if x.is_nan() {
+        Err(TrapCode::BadConversionToInteger)
+    } else if !(-9223372036854775808.0..9223372036854775808.0).contains(&x) {
+        Err(TrapCode::IntegerOverflow)
+    } else {
+        Ok(x as i64)
+    }
+}
+
+/// Like cranelift's `fmin`, NaN if either is, and -0.0 is less than 0.0.
+fn fmin(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        f64::NAN
+    } else if x == y {
+        if x.is_sign_negative() {
+            x
+        } else {
+            y
+        }
+    } else {
+        x.min(y)
+    }
+}
+
+/// Like cranelift's `fmax`, NaN if either is, and 0.0 is greater than -0.0.
+fn fmax(x: f64, y: f64) -> f64 {
+    if x.is_nan() || y.is_nan() {
+        f64::NAN
+    } else if x == y {
+        if x.is_sign_positive() {
+            x
+        } else {
+            y
+        }
+    } else {
+        x.max(y)
+    }
+}
+
+/// Symbol of the function compiled code calls before trapping on an out of bounds index.
+pub(crate) const INDEX_OUT_OF_BOUNDS: &str = "__sarus_index_out_of_bounds";
+
+pub(crate) extern "C" fn index_out_of_bounds(index: i64, len: i64) {
+    trap::record_out_of_bounds(index, len);
+}
+
 pub fn get_constants() -> HashMap<String, f64> {
     hashmap!(
         "E".into() => std::f64::consts::E,
@@ -0,0 +1,102 @@
+//! Sharing `#[repr(C)]` Rust structs with sarus code. `#[derive(SarusStruct)]`
+//! generates the matching `extern struct` declaration, and fails to compile if
+//! Rust lays the struct out differently than sarus would. `JIT::check_struct`
+//! compares the layout of a declaration written in sarus with the Rust one.
+
+use crate::frontend::{Declaration, Struct};
+use crate::validator::ExprType;
+
+/// A Rust type that can be used as the field of a struct shared with sarus.
+pub trait SarusType {
+    /// Size of the type in sarus
+    const SIZE: usize;
+    /// Alignment of the type in sarus
+    const ALIGN: usize;
+
+    fn expr_type() -> ExprType;
+}
+
+impl SarusType for f64 {
+    const SIZE: usize = 8;
+    const ALIGN: usize = 8;
+    fn expr_type() -> ExprType {
+        ExprType::F64
+    }
+}
+
+impl SarusType for i64 {
+    const SIZE: usize = 8;
+    const ALIGN: usize = 8;
+    fn expr_type() -> ExprType {
+        ExprType::I64
+    }
+}
+
+impl SarusType for bool {
+    const SIZE: usize = 1;
+    const ALIGN: usize = 1;
+    fn expr_type() -> ExprType {
+        ExprType::Bool
+    }
+}
+
+/// A `#[repr(C)]` struct with an equivalent sarus `extern struct`, usually
+/// implemented with `#[derive(SarusStruct)]`.
+pub trait SarusStruct: SarusType {
+    /// The `extern struct` declaration, with the fields in the same order as in Rust.
+    fn declaration() -> Struct;
+
+    /// How Rust lays out the struct in memory.
+    fn layout() -> StructLayout;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructLayout {
+    pub size: usize,
+    pub align: usize,
+    /// Byte offset of each field, in declaration order.
+    pub fields: Vec<(String, usize)>,
+}
+
+/// Add the `extern struct` declaration for `T` to a parsed program, instead of
+/// writing it out by hand in the source.
+pub fn append_struct<T: SarusStruct>(mut prog: Vec<Declaration>) -> Vec<Declaration> {
+    prog.push(Declaration::Struct(T::declaration()));
+    prog
+}
+
+/// Offset sarus gives the field at `index` of a struct, from the size and
+/// alignment of each field. With `index` past the last field, it's the size
+/// of the struct. The layout is the same as C's, see `create_struct_map`.
+#[doc(hidden)]
+pub const fn c_offset(fields: &[(usize, usize)], index: usize) -> usize {
+    let mut offset = 0;
+    let mut i = 0;
+    while i < fields.len() && i < index {
+        offset = align_to(offset, fields[i].1) + fields[i].0;
+        i += 1;
+    }
+    if index < fields.len() {
+        align_to(offset, fields[index].1)
+    } else {
+        align_to(offset, c_align(fields))
+    }
+}
+
+/// Alignment sarus gives a struct, from the size and alignment of each field.
+#[doc(hidden)]
+pub const fn c_align(fields: &[(usize, usize)]) -> usize {
+    let mut align = 1;
+    let mut i = 0;
+    while i < fields.len() {
+        if fields[i].1 > align {
+            align = fields[i].1;
+        }
+        i += 1;
+    }
+    align
+}
+
+const fn align_to(offset: usize, align: usize) -> usize {
+    offset.div_ceil(align) * align
+}
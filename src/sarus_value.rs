@@ -0,0 +1,55 @@
+//! Values for calling compiled functions whose signature is only known at
+//! runtime, see `JIT::call`.
+
+use crate::frontend::Function;
+use crate::validator::ExprType;
+
+/// An argument to or a return from `JIT::call`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SarusValue {
+    F64(f64),
+    I64(i64),
+    Bool(bool),
+    /// For `&[f64]` and `[f64]` params, the function can write to it
+    ArrayF64(Vec<f64>),
+    /// For `&[i64]` and `[i64]` params, the function can write to it
+    ArrayI64(Vec<i64>),
+    /// The name of the struct and its bytes, laid out as in `JIT::get_struct`
+    Struct(String, Vec<u8>),
+}
+
+impl SarusValue {
+    pub fn type_name(&self) -> String {
+        match self {
+            SarusValue::F64(_) => "f64".to_string(),
+            SarusValue::I64(_) => "i64".to_string(),
+            SarusValue::Bool(_) => "bool".to_string(),
+            SarusValue::ArrayF64(_) => "[f64]".to_string(),
+            SarusValue::ArrayI64(_) => "[i64]".to_string(),
+            SarusValue::Struct(name, _) => name.to_string(),
+        }
+    }
+
+    /// Whether the value can be passed to a param of `expr_type`.
+    pub(crate) fn fits(&self, expr_type: &ExprType) -> bool {
+        match (self, expr_type) {
+            (SarusValue::F64(_), ExprType::F64)
+            | (SarusValue::I64(_), ExprType::I64)
+            | (SarusValue::Bool(_), ExprType::Bool)
+            | (SarusValue::ArrayF64(_), ExprType::UnboundedArrayF64)
+            | (SarusValue::ArrayI64(_), ExprType::UnboundedArrayI64) => true,
+            (SarusValue::ArrayF64(_), ExprType::Slice(elem)) => **elem == ExprType::F64,
+            (SarusValue::ArrayI64(_), ExprType::Slice(elem)) => **elem == ExprType::I64,
+            (SarusValue::Struct(name, _), ExprType::Struct(struct_name)) => **struct_name == *name,
+            _ => false,
+        }
+    }
+
+    /// Whether the values can be passed as the params of `func`.
+    pub(crate) fn fit(values: &[SarusValue], func: &Function) -> bool {
+        values.len() == func.params.len()
+            && values.iter().zip(&func.params).all(|(value, param)| {
+                value.fits(param.expr_type.as_ref().unwrap_or(&ExprType::F64))
+            })
+    }
+}
@@ -0,0 +1,400 @@
+//! Turning faults in compiled code into errors, instead of the process crashing.
+//! While a guarded call is running, a signal handler jumps back out of it with
+//! `siglongjmp`, and the address that faulted is looked up in the trap codes
+//! recorded when each function was compiled.
+
+pub use cranelift::codegen::ir::TrapCode;
+use std::cell::Cell;
+use std::fmt;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::Mutex;
+
+/// A fault in compiled code, returned instead of crashing the process.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    /// The sarus function that faulted, if it was in compiled code or run
+    /// by `Interpreter`
+    pub function: Option<String>,
+    /// Where in the machine code of `function` the fault was, `None` when it
+    /// was interpreted
+    pub offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    /// A trap cranelift put in the code, like `int_divz` for an integer
+    /// division by zero or `heap_oob` for an index out of bounds
+    Code(TrapCode),
+    /// Code compiled with `JIT::consume_fuel`, or run by an `Interpreter`
+    /// with `consume_fuel` set, used up the fuel it was given
+    OutOfFuel,
+    /// An array or slice was indexed outside of it, caught by a bounds check
+    IndexOutOfBounds { index: i64, len: i64 },
+    /// Any other fault, by the signal it raised, like a stray pointer
+    /// causing `SIGSEGV`
+    Signal(i32),
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.function, self.offset) {
+            (Some(function), Some(offset)) => {
+                write!(f, "{} in {} at offset {}", self.kind, function, offset)
+            }
+            (Some(function), None) => write!(f, "{} in {}", self.kind, function),
+            _ => write!(f, "{} outside of compiled code", self.kind),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrapKind::Code(code) => write!(f, "trap {}", code),
+            TrapKind::OutOfFuel => write!(f, "out of fuel"),
+            TrapKind::IndexOutOfBounds { index, len } => write!(
+                f,
+                "index out of bounds: the len is {} but the index is {}",
+                len, index
+            ),
+            TrapKind::Signal(signal) => write!(f, "signal {}", signal),
+        }
+    }
+}
+
+/// What running out of fuel traps with.
+pub(crate) const OUT_OF_FUEL: TrapCode = TrapCode::Interrupt;
+
+thread_local! {
+    /// The index and length of a failed bounds check, set by compiled code
+    /// right before it traps with `heap_oob`
+    static OUT_OF_BOUNDS: Cell<Option<(i64, i64)>> = const { Cell::new(None) };
+}
+
+/// Remember the index and length of a failed bounds check on this thread,
+/// for the trap that follows it.
+pub(crate) fn record_out_of_bounds(index: i64, len: i64) {
+    OUT_OF_BOUNDS.with(|oob| oob.set(Some((index, len))));
+}
+
+/// The machine code of a compiled function, and the trap codes in it.
+struct CodeRange {
+    start: usize,
+    len: usize,
+    function: String,
+    traps: Vec<(u32, TrapCode)>,
+}
+
+/// Every function compiled into memory. Code is never freed, so neither are
+/// these, even after the JIT that compiled them is dropped.
+static CODE: Mutex<Vec<CodeRange>> = Mutex::new(Vec::new());
+
+/// Remember where the code of `function` is, to say which function a trap
+/// happened in and why.
+pub(crate) fn register(start: *const u8, len: usize, function: &str, traps: Vec<(u32, TrapCode)>) {
+    let mut code = CODE.lock().unwrap();
+    code.push(CodeRange {
+        start: start as usize,
+        len,
+        function: function.to_string(),
+        traps,
+    });
+    // Only one thread adds to the list at a time, while holding the lock
+    let node = Box::new(CodeSpan {
+        start: start as usize,
+        len,
+        next: SPANS.load(Ordering::Acquire),
+    });
+    SPANS.store(Box::into_raw(node), Ordering::Release);
+}
+
+/// Where a function compiled into memory is, in a list the signal handler
+/// can read without taking a lock. Nodes are never freed, like the code.
+struct CodeSpan {
+    start: usize,
+    len: usize,
+    next: *const CodeSpan,
+}
+
+/// The most recently registered function, the others follow from it.
+static SPANS: AtomicPtr<CodeSpan> = AtomicPtr::new(ptr::null_mut());
+
+/// Whether `pc` is in compiled code.
+#[cfg_attr(not(unix), allow(dead_code))]
+fn in_compiled_code(pc: usize) -> bool {
+    let mut span = SPANS.load(Ordering::Acquire) as *const CodeSpan;
+    while let Some(s) = unsafe { span.as_ref() } {
+        if s.start <= pc && pc < s.start + s.len {
+            return true;
+        }
+        span = s.next;
+    }
+    false
+}
+
+impl Trap {
+    fn new(signal: i32, pc: Option<usize>) -> Self {
+        let code = CODE.lock().unwrap();
+        // Later versions of a function are registered after earlier ones
+        let range = pc.and_then(|pc| {
+            code.iter()
+                .rev()
+                .find(|range| range.start <= pc && pc < range.start + range.len)
+                .map(|range| (range, (pc - range.start) as u32))
+        });
+        match range {
+            Some((range, offset)) => Trap {
+                kind: range
+                    .traps
+                    .iter()
+                    .find(|(trap_offset, _)| *trap_offset == offset)
+                    .map_or(TrapKind::Signal(signal), |(_, code)| match *code {
+                        OUT_OF_FUEL => TrapKind::OutOfFuel,
+                        TrapCode::HeapOutOfBounds => match OUT_OF_BOUNDS.with(Cell::take) {
+                            Some((index, len)) => TrapKind::IndexOutOfBounds { index, len },
+                            None => TrapKind::Code(TrapCode::HeapOutOfBounds),
+                        },
+                        code => TrapKind::Code(code),
+                    }),
+                function: Some(range.function.clone()),
+                offset: Some(offset),
+            },
+            None => Trap {
+                kind: TrapKind::Signal(signal),
+                function: None,
+                offset: None,
+            },
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn catch_traps(f: impl FnOnce()) -> Result<(), Trap> {
+    f();
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) use self::unix::catch_traps;
+
+#[cfg(unix)]
+mod unix {
+    use super::{in_compiled_code, Trap};
+    use std::cell::{Cell, RefCell};
+    use std::sync::OnceLock;
+
+    /// Big enough for a `sigjmp_buf` on every platform supported.
+    #[repr(C, align(16))]
+    struct JmpBuf([u64; 64]);
+
+    extern "C" {
+        // `sigsetjmp` is a macro in glibc
+        #[cfg_attr(target_env = "gnu", link_name = "__sigsetjmp")]
+        fn sigsetjmp(env: *mut JmpBuf, savemask: libc::c_int) -> libc::c_int;
+        fn siglongjmp(env: *mut JmpBuf, val: libc::c_int) -> !;
+    }
+
+    thread_local! {
+        /// Where to jump back to if the guarded call on this thread faults
+        static JMP_BUF: Cell<*mut JmpBuf> = const { Cell::new(std::ptr::null_mut()) };
+        /// The signal and the address that faulted, set before jumping back
+        static FAULT: Cell<Option<(i32, Option<usize>)>> = const { Cell::new(None) };
+        /// The stack signals are handled on, if the thread didn't have one
+        static ALT_STACK: RefCell<Option<AltStack>> = const { RefCell::new(None) };
+    }
+
+    const SIGNALS: [libc::c_int; 4] = [libc::SIGILL, libc::SIGFPE, libc::SIGSEGV, libc::SIGBUS];
+
+    /// What handled each of `SIGNALS` before, for faults outside guarded calls.
+    static PREVIOUS_HANDLERS: OnceLock<[Option<libc::sigaction>; 4]> = OnceLock::new();
+
+    /// Call `f`, which runs compiled code, returning the trap if the compiled
+    /// code faults. The frames between here and the fault are skipped without
+    /// being dropped, so `f` shouldn't own anything that needs to be. Faults in
+    /// Rust functions the code calls, like ones registered with `register_fn`,
+    /// are left to the handler from before, which usually ends the process.
+    pub(crate) fn catch_traps(f: impl FnOnce()) -> Result<(), Trap> {
+        install_handlers();
+        AltStack::install();
+        let mut jmp_buf = JmpBuf([0; 64]);
+        let previous = JMP_BUF.with(|buf| buf.replace(&mut jmp_buf));
+        let mut f = Some(f);
+        let returned = unsafe { call_with_jmp_buf(&mut jmp_buf, &mut || (f.take().unwrap())()) };
+        JMP_BUF.with(|buf| buf.set(previous));
+        if returned {
+            Ok(())
+        } else {
+            let (signal, pc) = FAULT.with(|fault| fault.take()).unwrap();
+            Err(Trap::new(signal, pc))
+        }
+    }
+
+    /// False if `f` was jumped out of. Kept out of line so nothing from after
+    /// `sigsetjmp` returns the second time is shared with the code before it.
+    #[inline(never)]
+    unsafe fn call_with_jmp_buf(jmp_buf: *mut JmpBuf, f: &mut dyn FnMut()) -> bool {
+        if sigsetjmp(jmp_buf, 1) == 0 {
+            f();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn install_handlers() {
+        PREVIOUS_HANDLERS.get_or_init(|| unsafe {
+            let mut previous_handlers = [None; 4];
+            for (i, signal) in SIGNALS.iter().enumerate() {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = handle_signal as *const () as libc::sighandler_t;
+                // The alternate stack lets a stack overflow be handled too
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+                let mut previous: libc::sigaction = std::mem::zeroed();
+                if libc::sigaction(*signal, &action, &mut previous) == 0 {
+                    previous_handlers[i] = Some(previous);
+                }
+            }
+            previous_handlers
+        });
+    }
+
+    /// An alternate signal stack for a thread that has none, so a stack
+    /// overflow in compiled code can be handled. The Rust runtime gives its
+    /// own threads one, but threads started elsewhere, like by an audio host,
+    /// may not have it.
+    struct AltStack {
+        stack: *mut libc::c_void,
+        size: usize,
+    }
+
+    impl AltStack {
+        /// Give the current thread an alternate stack for as long as it
+        /// runs, unless it already has one.
+        fn install() {
+            ALT_STACK.with(|alt_stack| {
+                if alt_stack.borrow().is_some() {
+                    return;
+                }
+                unsafe {
+                    let mut current: libc::stack_t = std::mem::zeroed();
+                    libc::sigaltstack(std::ptr::null(), &mut current);
+                    if current.ss_flags & libc::SS_DISABLE == 0 {
+                        return;
+                    }
+                    let size = libc::SIGSTKSZ.max(64 * 1024);
+                    let stack = libc::mmap(
+                        std::ptr::null_mut(),
+                        size,
+                        libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_PRIVATE | libc::MAP_ANON,
+                        -1,
+                        0,
+                    );
+                    if stack == libc::MAP_FAILED {
+                        return;
+                    }
+                    let new = libc::stack_t {
+                        ss_sp: stack,
+                        ss_flags: 0,
+                        ss_size: size,
+                    };
+                    if libc::sigaltstack(&new, std::ptr::null_mut()) != 0 {
+                        libc::munmap(stack, size);
+                        return;
+                    }
+                    *alt_stack.borrow_mut() = Some(AltStack { stack, size });
+                }
+            });
+        }
+    }
+
+    impl Drop for AltStack {
+        fn drop(&mut self) {
+            unsafe {
+                let disable = libc::stack_t {
+                    ss_sp: std::ptr::null_mut(),
+                    ss_flags: libc::SS_DISABLE,
+                    ss_size: 0,
+                };
+                libc::sigaltstack(&disable, std::ptr::null_mut());
+                libc::munmap(self.stack, self.size);
+            }
+        }
+    }
+
+    unsafe extern "C" fn handle_signal(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        let jmp_buf = JMP_BUF.with(|buf| buf.get());
+        let pc = program_counter(context);
+        // Jumping out of Rust code could skip releasing a lock it holds
+        if jmp_buf.is_null() || !pc.is_some_and(in_compiled_code) {
+            return call_previous_handler(signal, info, context);
+        }
+        FAULT.with(|fault| fault.set(Some((signal, pc))));
+        siglongjmp(jmp_buf, 1);
+    }
+
+    /// Leave faults outside of guarded calls to whatever handled them before,
+    /// like the stack overflow handler of the Rust runtime.
+    unsafe fn call_previous_handler(
+        signal: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        let previous = SIGNALS
+            .iter()
+            .position(|s| *s == signal)
+            .and_then(|i| PREVIOUS_HANDLERS.get()?[i]);
+        match previous {
+            Some(previous)
+                if previous.sa_sigaction != libc::SIG_DFL
+                    && previous.sa_sigaction != libc::SIG_IGN =>
+            {
+                if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler = std::mem::transmute::<
+                        usize,
+                        extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void),
+                    >(previous.sa_sigaction);
+                    handler(signal, info, context);
+                } else {
+                    let handler = std::mem::transmute::<usize, extern "C" fn(libc::c_int)>(
+                        previous.sa_sigaction,
+                    );
+                    handler(signal);
+                }
+            }
+            // Put the default action back, which happens when the faulting
+            // instruction runs again after returning
+            Some(previous) => {
+                libc::sigaction(signal, &previous, std::ptr::null_mut());
+            }
+            None => {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+    }
+
+    /// The address of the instruction that faulted.
+    #[allow(unused_variables)]
+    unsafe fn program_counter(context: *mut libc::c_void) -> Option<usize> {
+        let context = &*(context as *const libc::ucontext_t);
+        #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+        return Some(context.uc_mcontext.gregs[libc::REG_RIP as usize] as usize);
+        #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+        return Some(context.uc_mcontext.pc as usize);
+        #[cfg(all(target_os = "macos", target_arch = "x86_64"))]
+        return Some((*context.uc_mcontext).__ss.__rip as usize);
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        return Some((*context.uc_mcontext).__ss.__pc as usize);
+        #[allow(unreachable_code)]
+        None
+    }
+}
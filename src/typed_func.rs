@@ -0,0 +1,467 @@
+//! Calling compiled functions through a Rust signature that has been checked
+//! against the sarus one, instead of transmuting the pointer from `get_func`,
+//! and the other way around, declaring Rust functions from their signature.
+
+use crate::frontend::{Arg, Function, Span};
+use crate::jit::JIT;
+use crate::sarus_struct::SarusStruct;
+use crate::trap::{catch_traps, Trap};
+use crate::validator::ExprType;
+use std::marker::PhantomData;
+use std::mem;
+
+/// A Rust type that can be passed to or returned from a sarus function.
+pub trait SarusParam: Copy {
+    fn expr_type() -> ExprType;
+
+    /// The value in the 8 byte slot it's passed in, as `Interpreter` calls
+    /// registered functions.
+    fn to_slot(self) -> u64;
+    fn from_slot(slot: u64) -> Self;
+}
+
+impl SarusParam for f64 {
+    fn expr_type() -> ExprType {
+        ExprType::F64
+    }
+    fn to_slot(self) -> u64 {
+        self.to_bits()
+    }
+    fn from_slot(slot: u64) -> Self {
+        f64::from_bits(slot)
+    }
+}
+
+impl SarusParam for i64 {
+    fn expr_type() -> ExprType {
+        ExprType::I64
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as i64
+    }
+}
+
+impl SarusParam for bool {
+    fn expr_type() -> ExprType {
+        ExprType::Bool
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot != 0
+    }
+}
+
+impl SarusParam for *mut f64 {
+    fn expr_type() -> ExprType {
+        ExprType::UnboundedArrayF64
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *mut f64
+    }
+}
+
+impl SarusParam for *const f64 {
+    fn expr_type() -> ExprType {
+        ExprType::UnboundedArrayF64
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *const f64
+    }
+}
+
+impl SarusParam for *mut i64 {
+    fn expr_type() -> ExprType {
+        ExprType::UnboundedArrayI64
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *mut i64
+    }
+}
+
+impl SarusParam for *const i64 {
+    fn expr_type() -> ExprType {
+        ExprType::UnboundedArrayI64
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *const i64
+    }
+}
+
+// Strings and other untyped memory
+impl SarusParam for *const i8 {
+    fn expr_type() -> ExprType {
+        ExprType::Address
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *const i8
+    }
+}
+
+impl SarusParam for *const u8 {
+    fn expr_type() -> ExprType {
+        ExprType::Address
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *const u8
+    }
+}
+
+// Structs are always passed as a pointer
+impl<T: SarusStruct> SarusParam for *mut T {
+    fn expr_type() -> ExprType {
+        ExprType::Struct(Box::new(T::declaration().name))
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *mut T
+    }
+}
+
+impl<T: SarusStruct> SarusParam for *const T {
+    fn expr_type() -> ExprType {
+        ExprType::Struct(Box::new(T::declaration().name))
+    }
+    fn to_slot(self) -> u64 {
+        self as u64
+    }
+    fn from_slot(slot: u64) -> Self {
+        slot as *const T
+    }
+}
+
+/// A tuple of the parameters of a function, like `(f64, i64)`.
+pub trait SarusParams {
+    fn expr_types() -> Vec<ExprType>;
+}
+
+/// A tuple of the returns of a function, at most two values.
+pub trait SarusReturns {
+    /// What the function returns at the machine level, when it returns at most
+    /// one value. Two values are returned through a trampoline instead.
+    type Abi;
+    fn expr_types() -> Vec<ExprType>;
+    fn from_abi(abi: Self::Abi) -> Self;
+    /// The returns from the 8 byte slots a trampoline stores them in.
+    fn from_slots(slots: &[u64]) -> Self;
+}
+
+impl SarusReturns for () {
+    type Abi = ();
+    fn expr_types() -> Vec<ExprType> {
+        vec![]
+    }
+    fn from_abi(_abi: ()) -> Self {}
+    fn from_slots(_slots: &[u64]) -> Self {}
+}
+
+impl<A: SarusParam> SarusReturns for (A,) {
+    type Abi = A;
+    fn expr_types() -> Vec<ExprType> {
+        vec![A::expr_type()]
+    }
+    fn from_abi(abi: A) -> Self {
+        (abi,)
+    }
+    fn from_slots(slots: &[u64]) -> Self {
+        (A::from_slot(slots[0]),)
+    }
+}
+
+// Cranelift returns two values in the first two return registers for their
+// class, which no Rust type is returned in on every target.
+impl<A: SarusParam, B: SarusParam> SarusReturns for (A, B) {
+    type Abi = ();
+    fn expr_types() -> Vec<ExprType> {
+        vec![A::expr_type(), B::expr_type()]
+    }
+    fn from_abi(_abi: ()) -> Self {
+        unreachable!("two values are returned through a trampoline")
+    }
+    fn from_slots(slots: &[u64]) -> Self {
+        (A::from_slot(slots[0]), B::from_slot(slots[1]))
+    }
+}
+
+/// A param passed by value, that compiled code can't reach memory through.
+/// Functions that only take these can be called with `TypedFunc::call`.
+pub trait ScalarParam: SarusParam {}
+
+impl ScalarParam for f64 {}
+impl ScalarParam for i64 {}
+impl ScalarParam for bool {}
+
+/// What a Rust function registered with `JIT::register_fn` returns, nothing or a single value.
+pub trait HostReturn {
+    fn expr_types() -> Vec<ExprType>;
+    fn to_slots(self) -> Vec<u64>;
+}
+
+impl HostReturn for () {
+    fn expr_types() -> Vec<ExprType> {
+        vec![]
+    }
+    fn to_slots(self) -> Vec<u64> {
+        vec![]
+    }
+}
+
+impl<T: SarusParam> HostReturn for T {
+    fn expr_types() -> Vec<ExprType> {
+        vec![T::expr_type()]
+    }
+    fn to_slots(self) -> Vec<u64> {
+        vec![self.to_slot()]
+    }
+}
+
+/// An `extern "C"` Rust function that sarus code can call, see `JIT::register_fn`.
+pub trait HostFn: Copy {
+    fn params() -> Vec<ExprType>;
+    fn returns() -> Vec<ExprType>;
+    fn ptr(self) -> *const u8;
+
+    /// Call the function with its arguments in 8 byte slots, giving back the
+    /// returns the same way. This is how `Interpreter` calls it.
+    fn call_slots(self, slots: &[u64]) -> Vec<u64>;
+
+    /// The `extern fn` declaration sarus code needs to call the function as `name`.
+    fn declaration(name: &str) -> Function {
+        let arg = |name: String, expr_type: ExprType| Arg {
+            name,
+            expr_type: Some(expr_type),
+            span: Span::default(),
+        };
+        Function {
+            name: name.to_string(),
+            params: Self::params()
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| arg(format!("arg{}", i), t))
+                .collect(),
+            returns: Self::returns()
+                .into_iter()
+                .map(|t| arg("ret".to_string(), t))
+                .collect(),
+            body: vec![],
+            extern_func: true,
+            span: Span::default(),
+        }
+    }
+}
+
+/// A compiled function with a known signature, from `JIT::get_typed`. It
+/// borrows the JIT it came from, which owns the code.
+pub struct TypedFunc<'jit, P, R> {
+    ptr: *const u8,
+    /// Calls the function with its arguments and returns in 8 byte slots, for
+    /// functions that return more than one value.
+    trampoline: Option<*const u8>,
+    _signature: PhantomData<fn(P) -> R>,
+    _jit: PhantomData<&'jit JIT>,
+}
+
+impl<'jit, P, R> Clone for TypedFunc<'jit, P, R> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'jit, P, R> Copy for TypedFunc<'jit, P, R> {}
+
+impl<'jit, P: SarusParams, R: SarusReturns> TypedFunc<'jit, P, R> {
+    /// Check the Rust signature against the parameters and returns of `func`.
+    /// `trampoline` is needed if it returns more than one value.
+    pub(crate) fn new(
+        func: &Function,
+        ptr: *const u8,
+        trampoline: Option<*const u8>,
+    ) -> anyhow::Result<Self> {
+        let (params, returns) = abi_types(func);
+        let (rust_params, rust_returns) = (P::expr_types(), R::expr_types());
+        if params != rust_params || returns != rust_returns {
+            anyhow::bail!(
+                "function {} is ({}) -> ({}) but was used as ({}) -> ({})",
+                func.name,
+                type_list(&params),
+                type_list(&returns),
+                type_list(&rust_params),
+                type_list(&rust_returns),
+            )
+        }
+        let trampoline = if returns.len() > 1 {
+            match trampoline {
+                Some(trampoline) => Some(trampoline),
+                None => anyhow::bail!("function {} has no trampoline to return through", func.name),
+            }
+        } else {
+            None
+        };
+        Ok(TypedFunc {
+            ptr,
+            trampoline,
+            _signature: PhantomData,
+            _jit: PhantomData,
+        })
+    }
+
+    pub fn ptr(&self) -> *const u8 {
+        self.ptr
+    }
+}
+
+macro_rules! impl_typed_func {
+    ($($arg:ident: $param:ident),*) => {
+        impl<$($param: SarusParam),*> SarusParams for ($($param,)*) {
+            fn expr_types() -> Vec<ExprType> {
+                vec![$($param::expr_type()),*]
+            }
+        }
+
+        impl<'jit, $($param: SarusParam,)* R: SarusReturns> TypedFunc<'jit, ($($param,)*), R> {
+            /// Call a function that takes pointers.
+            ///
+            /// # Safety
+            ///
+            /// Compiled code doesn't check what it reads and writes through a
+            /// pointer. Each one has to point to memory that's valid for
+            /// everything the function does with it, an unbounded array for
+            /// as far as it's indexed and a struct for its whole layout.
+            #[allow(clippy::too_many_arguments)]
+            pub unsafe fn call_unchecked(&self, $($arg: $param),*) -> R {
+                match self.trampoline {
+                    None => {
+                        let func = mem::transmute::<*const u8, extern "C" fn($($param),*) -> R::Abi>(self.ptr);
+                        R::from_abi(func($($arg),*))
+                    }
+                    Some(trampoline) => {
+                        let trampoline = mem::transmute::<
+                            *const u8,
+                            extern "C" fn(*const u8, *const u64, *mut u64),
+                        >(trampoline);
+                        let slots: &[u64] = &[$($arg.to_slot()),*];
+                        let mut return_slots = [0u64; 2];
+                        trampoline(self.ptr, slots.as_ptr(), return_slots.as_mut_ptr());
+                        R::from_slots(&return_slots)
+                    }
+                }
+            }
+
+            /// Like `call_unchecked`, but a trap in the function is returned
+            /// instead of crashing the process.
+            ///
+            /// # Safety
+            ///
+            /// The same as for `call_unchecked`.
+            #[allow(clippy::too_many_arguments)]
+            pub unsafe fn try_call_unchecked(&self, $($arg: $param),*) -> Result<R, Trap> {
+                let mut ret = None;
+                catch_traps(|| ret = Some(self.call_unchecked($($arg),*)))?;
+                Ok(ret.unwrap())
+            }
+        }
+
+        impl<'jit, $($param: ScalarParam,)* R: SarusReturns> TypedFunc<'jit, ($($param,)*), R> {
+            #[allow(clippy::too_many_arguments)]
+            pub fn call(&self, $($arg: $param),*) -> R {
+                unsafe { self.call_unchecked($($arg),*) }
+            }
+
+            /// Like `call`, but a trap in the function, like an integer division
+            /// by zero, is returned instead of crashing the process.
+            #[allow(clippy::too_many_arguments)]
+            pub fn try_call(&self, $($arg: $param),*) -> Result<R, Trap> {
+                unsafe { self.try_call_unchecked($($arg),*) }
+            }
+        }
+
+        impl<$($param: SarusParam,)* R: HostReturn> HostFn for extern "C" fn($($param),*) -> R {
+            fn params() -> Vec<ExprType> {
+                vec![$($param::expr_type()),*]
+            }
+            fn returns() -> Vec<ExprType> {
+                R::expr_types()
+            }
+            fn ptr(self) -> *const u8 {
+                self as *const u8
+            }
+            #[allow(unused_mut, unused_variables)]
+            fn call_slots(self, slots: &[u64]) -> Vec<u64> {
+                let mut slots = slots.iter();
+                $(let $arg = $param::from_slot(*slots.next().unwrap());)*
+                self($($arg),*).to_slots()
+            }
+        }
+    };
+}
+
+impl_typed_func!();
+impl_typed_func!(a: A);
+impl_typed_func!(a: A, b: B);
+impl_typed_func!(a: A, b: B, c: C);
+impl_typed_func!(a: A, b: B, c: C, d: D);
+impl_typed_func!(a: A, b: B, c: C, d: D, e: E);
+impl_typed_func!(a: A, b: B, c: C, d: D, e: E, f: F);
+impl_typed_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G);
+impl_typed_func!(a: A, b: B, c: C, d: D, e: E, f: F, g: G, h: H);
+
+/// Parameters and returns of `func` as they are passed at the machine level.
+/// A slice is a pointer followed by its length, and each returned struct is
+/// written to memory given by a pointer ahead of the other parameters.
+pub(crate) fn abi_types(func: &Function) -> (Vec<ExprType>, Vec<ExprType>) {
+    let arg_type = |arg: &Arg| arg.expr_type.clone().unwrap_or(ExprType::F64);
+    let mut params = Vec::new();
+    let mut returns = Vec::new();
+    for ret in &func.returns {
+        match arg_type(ret) {
+            t @ ExprType::Struct(_) => params.push(t),
+            t => returns.push(t),
+        }
+    }
+    for param in &func.params {
+        match arg_type(param) {
+            ExprType::Slice(elem) => {
+                params.push(match *elem {
+                    ExprType::F64 => ExprType::UnboundedArrayF64,
+                    ExprType::I64 => ExprType::UnboundedArrayI64,
+                    t => t,
+                });
+                params.push(ExprType::I64);
+            }
+            t => params.push(t),
+        }
+    }
+    (params, returns)
+}
+
+fn type_list(types: &[ExprType]) -> String {
+    types
+        .iter()
+        .map(|t| t.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -1,8 +1,12 @@
+// TypeError carries spans and types for diagnostics; it is only built on the
+// error path, so its size doesn't matter
+#![allow(clippy::result_large_err)]
+
 use std::{collections::HashMap, fmt::Display};
 
 use crate::{
-    frontend::{Declaration, Expr, Function},
-    jit::{SVariable, StructDef},
+    frontend::{Declaration, Diagnostic, Expr, Function, Span},
+    jit::{induction_var_key, SVariable, StructDef},
 };
 use thiserror::Error;
 
@@ -10,24 +14,68 @@ use thiserror::Error;
 pub enum TypeError {
     #[error("Type mismatch; expected {expected}, found {actual}")]
     TypeMismatch {
+        span: Span,
         expected: ExprType,
         actual: ExprType,
     },
     #[error("Type mismatch; {s}")]
-    TypeMismatchSpecific { s: String },
+    TypeMismatchSpecific { span: Span, s: String },
     #[error("Tuple length mismatch; expected {expected} found {actual}")]
-    TupleLengthMismatch { expected: usize, actual: usize },
-    #[error("Function \"{0}\" does not exist")]
-    UnknownFunction(String),
-    #[error("Variable \"{0}\" does not exist")]
-    UnknownVariable(String),
-    #[error("Struct \"{0}\" does not exist")]
-    UnknownStruct(String),
-    #[error("Struct \"{0}\" does not have field \"{1}\"")]
-    UnknownField(String, String),
+    TupleLengthMismatch {
+        span: Span,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("Function \"{1}\" does not exist")]
+    UnknownFunction(Span, String),
+    #[error("Variable \"{1}\" does not exist")]
+    UnknownVariable(Span, String),
+    #[error("Struct \"{1}\" does not exist")]
+    UnknownStruct(Span, String),
+    #[error("Struct \"{1}\" does not have field \"{2}\"")]
+    UnknownField(Span, String, String),
+    #[error("Struct \"{1}\" is missing field \"{2}\"")]
+    MissingField(Span, String, String),
+    #[error("Field \"{2}\" of struct \"{1}\" is specified more than once")]
+    DuplicateField(Span, String, String),
+    #[error("\"{1}\" outside of a loop")]
+    OutsideLoop(Span, String),
+    #[error("Conflicting types for \"{name}\" in \"{func}\"; inferred {first} at {first_span}, found {second}")]
+    InferenceConflict {
+        span: Span,
+        func: String,
+        name: String,
+        first: ExprType,
+        first_span: Span,
+        second: ExprType,
+    },
+}
+
+impl TypeError {
+    pub fn span(&self) -> Span {
+        match self {
+            TypeError::TypeMismatch { span, .. }
+            | TypeError::TypeMismatchSpecific { span, .. }
+            | TypeError::TupleLengthMismatch { span, .. }
+            | TypeError::UnknownFunction(span, ..)
+            | TypeError::UnknownVariable(span, ..)
+            | TypeError::UnknownStruct(span, ..)
+            | TypeError::UnknownField(span, ..)
+            | TypeError::MissingField(span, ..)
+            | TypeError::DuplicateField(span, ..)
+            | TypeError::OutsideLoop(span, ..)
+            | TypeError::InferenceConflict { span, .. } => *span,
+        }
+    }
+}
+
+impl From<TypeError> for Diagnostic {
+    fn from(err: TypeError) -> Self {
+        Diagnostic::new(err.span(), err.to_string())
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum ExprType {
     Void,
     Bool,
@@ -38,6 +86,8 @@ pub enum ExprType {
     Address,
     Tuple(Vec<ExprType>),
     Struct(Box<String>),
+    //Pointer to the first element and the number of elements
+    Slice(Box<ExprType>),
 }
 
 impl Display for ExprType {
@@ -59,11 +109,27 @@ impl Display for ExprType {
                 write!(f, ")")
             }
             ExprType::Struct(s) => write!(f, "{}", s),
+            ExprType::Slice(elem) => write!(f, "[{}]", elem),
         }
     }
 }
 
 impl ExprType {
+    fn of_variable(var: &SVariable) -> ExprType {
+        match var {
+            SVariable::Bool(_, _) => ExprType::Bool,
+            SVariable::F64(_, _) => ExprType::F64,
+            SVariable::I64(_, _) => ExprType::I64,
+            SVariable::UnboundedArrayF64(_, _) => ExprType::UnboundedArrayF64,
+            SVariable::UnboundedArrayI64(_, _) => ExprType::UnboundedArrayI64,
+            SVariable::Address(_, _) => ExprType::Address,
+            SVariable::Struct(_, structname, _) => {
+                ExprType::Struct(Box::new(structname.to_string()))
+            }
+            SVariable::Slice(_, elem, _, _) => ExprType::Slice(Box::new(elem.clone())),
+        }
+    }
+
     pub fn of(
         expr: &Expr,
         env: &[Declaration],
@@ -71,181 +137,324 @@ impl ExprType {
         variables: &HashMap<String, SVariable>,
         constant_vars: &HashMap<String, f64>,
         struct_map: &HashMap<String, StructDef>,
+    ) -> Result<ExprType, TypeError> {
+        let mut errors = Vec::new();
+        ExprType::of_collect(
+            expr,
+            env,
+            funcs,
+            variables,
+            constant_vars,
+            struct_map,
+            &mut errors,
+        )
+    }
+
+    /// Like `of`, but every statement in the bodies of ifs, loops and blocks
+    /// is checked. The first error is returned, the others in them are pushed
+    /// to `errors`.
+    fn of_collect(
+        expr: &Expr,
+        env: &[Declaration],
+        funcs: &HashMap<String, Function>,
+        variables: &HashMap<String, SVariable>,
+        constant_vars: &HashMap<String, f64>,
+        struct_map: &HashMap<String, StructDef>,
+        errors: &mut Vec<TypeError>,
     ) -> Result<ExprType, TypeError> {
         let res = match expr {
-            //TODO don't assume all identifiers are floats
-            Expr::Identifier(id_name) => {
+            Expr::Identifier(span, id_name) => {
                 if id_name.contains(".") {
-                    let parts = id_name.split(".").collect::<Vec<&str>>();
-                    if variables.contains_key(parts[0]) {
-                        match &variables[parts[0]] {
-                            SVariable::Struct(_var_name, struct_name, _var) => {
-                                let struct_name = struct_name.to_string();
-                                if struct_map.contains_key(&struct_name) {
-                                    if struct_map[&struct_name].fields.contains_key(parts[1]) {
-                                        struct_map[&struct_name].fields[parts[1]].expr_type.clone()
-                                    } else {
-                                        return Err(TypeError::UnknownField(
-                                            struct_name.to_string(),
-                                            parts[1].to_string(),
-                                        ));
-                                    }
-                                } else {
-                                    return Err(TypeError::UnknownStruct(struct_name.to_string()));
-                                }
-                            }
-                            _v => {
-                                return Err(TypeError::TypeMismatchSpecific {
-                                    s: format!("{} is not a Struct", id_name),
-                                })
-                            }
-                        }
-                    } else {
-                        return Err(TypeError::UnknownVariable(id_name.to_string()));
-                    }
+                    field_path_type(*span, id_name, variables, struct_map)?
                 } else if variables.contains_key(id_name) {
-                    match &variables[id_name] {
-                        SVariable::Unknown(_, _) => ExprType::F64, //TODO
-                        SVariable::Bool(_, _) => ExprType::Bool,
-                        SVariable::F64(_, _) => ExprType::F64,
-                        SVariable::I64(_, _) => ExprType::I64,
-                        SVariable::UnboundedArrayF64(_, _) => ExprType::UnboundedArrayF64,
-                        SVariable::UnboundedArrayI64(_, _) => ExprType::UnboundedArrayI64,
-                        SVariable::Address(_, _) => ExprType::Address,
-                        SVariable::Struct(_, structname, _) => {
-                            ExprType::Struct(Box::new(structname.to_string()))
-                        }
-                    }
+                    ExprType::of_variable(&variables[id_name])
                 } else if constant_vars.contains_key(id_name) {
                     ExprType::F64 //All constants are currently math like PI, TAU...
                 } else {
-                    return Err(TypeError::UnknownVariable(id_name.to_string()));
+                    return Err(TypeError::UnknownVariable(*span, id_name.to_string()));
                 }
             }
-            Expr::LiteralFloat(_) => ExprType::F64,
-            Expr::LiteralInt(_) => ExprType::I64,
-            Expr::LiteralBool(_) => ExprType::Bool,
-            Expr::LiteralString(_) => ExprType::UnboundedArrayI64, //TODO change to char
-            Expr::Binop(_, l, r) => {
+            Expr::LiteralFloat(..) => ExprType::F64,
+            Expr::LiteralInt(..) => ExprType::I64,
+            Expr::LiteralBool(..) => ExprType::Bool,
+            Expr::LiteralString(..) => ExprType::UnboundedArrayI64, //TODO change to char
+            Expr::Binop(span, _, l, r) => {
                 let lt = ExprType::of(l, env, funcs, variables, constant_vars, struct_map)?;
                 let rt = ExprType::of(r, env, funcs, variables, constant_vars, struct_map)?;
                 if lt == rt {
                     lt
                 } else {
                     return Err(TypeError::TypeMismatch {
+                        span: *span,
                         expected: lt,
                         actual: rt,
                     });
                 }
             }
-            Expr::Unaryop(_, l) => {
-                ExprType::of(l, env, funcs, variables, constant_vars, struct_map)?
+            Expr::Unaryop(_, _, l) => {
+                // Not is the only unary operator
+                let lt = ExprType::of(l, env, funcs, variables, constant_vars, struct_map)?;
+                expect_type(l.span(), &ExprType::Bool, &lt)?;
+                lt
             }
-            Expr::Compare(_, _, _) => ExprType::Bool,
-            Expr::IfThen(econd, _) => {
+            Expr::Compare(span, _, l, r) => {
+                let lt = ExprType::of(l, env, funcs, variables, constant_vars, struct_map)?;
+                let rt = ExprType::of(r, env, funcs, variables, constant_vars, struct_map)?;
+                expect_type(*span, &lt, &rt)?;
+                ExprType::Bool
+            }
+            Expr::IfThen(_, econd, then_body) => {
                 let tcond = ExprType::of(econd, env, funcs, variables, constant_vars, struct_map)?;
-                if tcond != ExprType::Bool {
-                    return Err(TypeError::TypeMismatch {
-                        expected: ExprType::Bool,
-                        actual: tcond,
-                    });
-                }
+                expect_type(econd.span(), &ExprType::Bool, &tcond)?;
+                body_type(
+                    then_body,
+                    env,
+                    funcs,
+                    variables,
+                    constant_vars,
+                    struct_map,
+                    errors,
+                )?;
                 ExprType::Void
             }
-            Expr::IfElse(econd, etrue, efalse) => {
+            Expr::IfElse(span, econd, etrue, efalse) => {
                 let tcond = ExprType::of(econd, env, funcs, variables, constant_vars, struct_map)?;
                 if tcond != ExprType::Bool {
                     return Err(TypeError::TypeMismatch {
+                        span: econd.span(),
                         expected: ExprType::Bool,
                         actual: tcond,
                     });
                 }
 
-                let ttrue = etrue
-                    .iter()
-                    .map(|e| ExprType::of(e, env, funcs, variables, constant_vars, struct_map))
-                    .collect::<Result<Vec<_>, _>>()?
-                    .last()
-                    .cloned()
-                    .unwrap_or(ExprType::Void);
-                let tfalse = efalse
-                    .iter()
-                    .map(|e| ExprType::of(e, env, funcs, variables, constant_vars, struct_map))
-                    .collect::<Result<Vec<_>, _>>()?
-                    .last()
-                    .cloned()
-                    .unwrap_or(ExprType::Void);
+                let ttrue = body_type(
+                    etrue,
+                    env,
+                    funcs,
+                    variables,
+                    constant_vars,
+                    struct_map,
+                    errors,
+                );
+                let tfalse = body_type(
+                    efalse,
+                    env,
+                    funcs,
+                    variables,
+                    constant_vars,
+                    struct_map,
+                    errors,
+                );
+                let (ttrue, tfalse) = match (ttrue, tfalse) {
+                    (Ok(ttrue), Ok(tfalse)) => (ttrue, tfalse),
+                    (Err(err), Ok(_)) | (Ok(_), Err(err)) => return Err(err),
+                    (Err(err), Err(other)) => {
+                        errors.push(other);
+                        return Err(err);
+                    }
+                };
 
-                if ttrue == tfalse {
+                // A branch that returns or breaks doesn't produce a value
+                if etrue.last().is_some_and(Expr::diverges) {
+                    tfalse
+                } else if efalse.last().is_some_and(Expr::diverges) || ttrue == tfalse {
                     ttrue
                 } else {
                     return Err(TypeError::TypeMismatch {
+                        span: *span,
                         expected: ttrue,
                         actual: tfalse,
                     });
                 }
             }
-            Expr::Assign(vars, e) => {
-                let tlen = match e.len().into() {
-                    1 => ExprType::of(&e[0], env, funcs, variables, constant_vars, struct_map)?
-                        .tuple_size(),
-                    n => n,
+            Expr::Assign(span, vars, e) => {
+                // The values may be ifs or blocks, with errors of their own
+                let texprs = e
+                    .iter()
+                    .map(|e| {
+                        ExprType::of_collect(
+                            e,
+                            env,
+                            funcs,
+                            variables,
+                            constant_vars,
+                            struct_map,
+                            errors,
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                // A single expression on the right may produce several values
+                let tvalues = match texprs.as_slice() {
+                    [ExprType::Tuple(items)] => items.clone(),
+                    [ExprType::Void] => vec![],
+                    _ => texprs.clone(),
                 };
-                if usize::from(vars.len()) != tlen {
+                if usize::from(vars.len()) != tvalues.len() {
                     return Err(TypeError::TupleLengthMismatch {
+                        span: *span,
                         actual: usize::from(vars.len()),
-                        expected: tlen,
+                        expected: tvalues.len(),
                     });
                 }
-                ExprType::Tuple(
-                    e.iter()
-                        .map(|e| ExprType::of(e, env, funcs, variables, constant_vars, struct_map))
-                        .collect::<Result<Vec<_>, _>>()?,
-                )
+                // Variables are typed by their declaration or first assignment,
+                // every later assignment has to agree with that
+                for (var, tvalue) in vars.iter().zip(tvalues.iter()) {
+                    if let Some(tvar) = existing_var_type(
+                        *span,
+                        var,
+                        env,
+                        funcs,
+                        variables,
+                        constant_vars,
+                        struct_map,
+                    )? {
+                        expect_type(*span, &tvar, tvalue)?;
+                    }
+                }
+                ExprType::Tuple(texprs)
+            }
+            Expr::AssignOp(span, _, var, e) => {
+                let te = ExprType::of(e, env, funcs, variables, constant_vars, struct_map)?;
+                match existing_var_type(
+                    *span,
+                    var,
+                    env,
+                    funcs,
+                    variables,
+                    constant_vars,
+                    struct_map,
+                )? {
+                    Some(tvar) => expect_type(*span, &tvar, &te)?,
+                    None => return Err(TypeError::UnknownVariable(*span, var.to_string())),
+                }
+                te
+            }
+            Expr::WhileLoop(_, econd, loop_body) => {
+                let tcond = ExprType::of(econd, env, funcs, variables, constant_vars, struct_map)?;
+                expect_type(econd.span(), &ExprType::Bool, &tcond)?;
+                body_type(
+                    loop_body,
+                    env,
+                    funcs,
+                    variables,
+                    constant_vars,
+                    struct_map,
+                    errors,
+                )?;
+                ExprType::Void
+            }
+            Expr::ForLoop(span, var, iterable, loop_body) => {
+                let range = match &**iterable {
+                    Expr::Range(..) => Some(iterable),
+                    Expr::ArrayGet(span, id_name, idx) if matches!(**idx, Expr::Range(..)) => {
+                        array_elem_type(*span, id_name, variables)?;
+                        Some(idx)
+                    }
+                    e => match ExprType::of(e, env, funcs, variables, constant_vars, struct_map)? {
+                        // Slices know their length, so they can be iterated over directly
+                        ExprType::Slice(_) => None,
+                        t => {
+                            return Err(TypeError::TypeMismatchSpecific {
+                                span: e.span(),
+                                s: format!(
+                                    "can't iterate over {}, use a range like 0..n or arr[0..n]",
+                                    t
+                                ),
+                            })
+                        }
+                    },
+                };
+                if let Some(Expr::Range(_, start, end)) = range.map(|r| &**r) {
+                    for bound in [start, end] {
+                        let t =
+                            ExprType::of(bound, env, funcs, variables, constant_vars, struct_map)?;
+                        expect_type(bound.span(), &ExprType::I64, &t)?;
+                    }
+                }
+                // The induction variable is only in scope inside the body
+                let mut body_variables = variables.clone();
+                if let Some(v) = variables.get(&induction_var_key(var, *span)) {
+                    body_variables.insert(var.to_string(), v.clone());
+                }
+                body_type(
+                    loop_body,
+                    env,
+                    funcs,
+                    &body_variables,
+                    constant_vars,
+                    struct_map,
+                    errors,
+                )?;
+                ExprType::Void
+            }
+            Expr::Range(span, start, end) => {
+                // Only valid as the bounds of a for loop, checked there
+                return Err(TypeError::TypeMismatchSpecific {
+                    span: *span,
+                    s: format!("{}..{} can only be used in a for loop", start, end),
+                });
             }
-            Expr::AssignOp(_, _, e) => {
-                ExprType::of(e, env, funcs, variables, constant_vars, struct_map)?
+            Expr::Block(_, b) => {
+                body_type(b, env, funcs, variables, constant_vars, struct_map, errors)?
             }
-            Expr::WhileLoop(_, _) => ExprType::Void,
-            Expr::Block(b) => b
-                .iter()
-                .map(|e| ExprType::of(e, env, funcs, variables, constant_vars, struct_map))
-                .last()
-                .map(Result::unwrap)
-                .unwrap_or(ExprType::Void),
-            Expr::Call(fn_name, args, impl_func) => {
+            Expr::Call(span, fn_name, args, impl_func) => {
                 if *impl_func {
                     if let Some(self_var) = variables.get(&args[0].to_string()) {
                         if let SVariable::Struct(_var_name, struct_name, _var) = self_var {
                             let e = Expr::Call(
+                                *span,
                                 format!("{}.{}", struct_name, fn_name),
                                 args.to_vec(),
                                 false,
                             );
-                            return Ok(ExprType::of(
+                            return ExprType::of(
                                 &e,
                                 env,
                                 funcs,
                                 variables,
                                 constant_vars,
                                 struct_map,
-                            )?);
+                            );
                         } else {
                             return Err(TypeError::TypeMismatchSpecific {
+                                span: *span,
                                 s: format!("{} is not a Struct", self_var),
                             });
                         }
                     } else {
-                        return Err(TypeError::UnknownVariable(args[0].to_string()));
+                        return Err(TypeError::UnknownVariable(*span, args[0].to_string()));
                     }
                 }
+                if fn_name == "len" && !funcs.contains_key(fn_name) {
+                    return len_type(
+                        *span,
+                        args,
+                        env,
+                        funcs,
+                        variables,
+                        constant_vars,
+                        struct_map,
+                    );
+                }
                 if let Some(d) = funcs.get(fn_name) {
                     if d.params.len() == args.len() {
-                        //TODO make sure types match too
                         let targs: Result<Vec<_>, _> = args
                             .iter()
-                            .map(|e| {
-                                ExprType::of(e, env, funcs, variables, constant_vars, struct_map)
+                            .zip(d.params.iter())
+                            .map(|(e, param)| {
+                                let targ = ExprType::of(
+                                    e,
+                                    env,
+                                    funcs,
+                                    variables,
+                                    constant_vars,
+                                    struct_map,
+                                )?;
+                                expect_type(
+                                    e.span(),
+                                    param.expr_type.as_ref().unwrap_or(&ExprType::F64),
+                                    &targ,
+                                )
                             })
                             .collect();
                         match targs {
@@ -269,39 +478,121 @@ impl ExprType {
                         }
                     } else {
                         return Err(TypeError::TupleLengthMismatch {
+                            span: *span,
                             expected: d.params.len(),
                             actual: args.len(),
                         });
                     }
                 } else {
-                    return Err(TypeError::UnknownFunction(fn_name.to_string()));
+                    return Err(TypeError::UnknownFunction(*span, fn_name.to_string()));
                 }
             }
-            Expr::GlobalDataAddr(_) => ExprType::F64,
-            Expr::Parentheses(expr) => {
+            Expr::GlobalDataAddr(..) => ExprType::F64,
+            Expr::Parentheses(_, expr) => {
                 ExprType::of(expr, env, funcs, variables, constant_vars, struct_map)?
             }
-            Expr::ArraySet(_, _, e) => {
-                ExprType::of(e, env, funcs, variables, constant_vars, struct_map)?
+            Expr::ArraySet(span, id_name, idx, e) => {
+                let telem = array_elem_type(*span, id_name, variables)?;
+                let tidx = ExprType::of(idx, env, funcs, variables, constant_vars, struct_map)?;
+                expect_index_type(idx.span(), &tidx)?;
+                let te = ExprType::of(e, env, funcs, variables, constant_vars, struct_map)?;
+                expect_type(e.span(), &telem, &te)?;
+                te
+            }
+            Expr::ArrayGet(span, id_name, idx) => {
+                let telem = array_elem_type(*span, id_name, variables)?;
+                let tidx = ExprType::of(idx, env, funcs, variables, constant_vars, struct_map)?;
+                expect_index_type(idx.span(), &tidx)?;
+                telem
             }
-            Expr::ArrayGet(id_name, _) => {
-                if variables.contains_key(id_name) {
-                    match &variables[id_name] {
-                        SVariable::UnboundedArrayF64(_, _) => Ok(ExprType::F64),
-                        SVariable::UnboundedArrayI64(_, _) => Ok(ExprType::I64),
-                        _ => Err(TypeError::TypeMismatchSpecific {
-                            s: format!("{} is not an array", id_name),
-                        }),
+            Expr::ArrayLiteral(span, items) => {
+                let telem = match items.first() {
+                    Some(first) => {
+                        ExprType::of(first, env, funcs, variables, constant_vars, struct_map)?
                     }
-                } else {
-                    return Err(TypeError::UnknownVariable(id_name.to_string()));
-                }?
+                    None => {
+                        return Err(TypeError::TypeMismatchSpecific {
+                            span: *span,
+                            s: "can't tell the type of an empty array".to_string(),
+                        })
+                    }
+                };
+                expect_elem_type(*span, &telem)?;
+                for item in items.iter().skip(1) {
+                    let t = ExprType::of(item, env, funcs, variables, constant_vars, struct_map)?;
+                    expect_type(item.span(), &telem, &t)?;
+                }
+                ExprType::Slice(Box::new(telem))
             }
-            Expr::NewStruct(struct_name, _fields) => {
-                if struct_map.contains_key(struct_name) {
-                    //Need to check field types
-                } else {
-                    return Err(TypeError::UnknownStruct(struct_name.to_string()));
+            Expr::ArrayRepeat(span, e, _) => {
+                let telem = ExprType::of(e, env, funcs, variables, constant_vars, struct_map)?;
+                expect_elem_type(*span, &telem)?;
+                ExprType::Slice(Box::new(telem))
+            }
+            Expr::DeclareArray(span, var, elem, _) => {
+                if let ExprType::Struct(struct_name) = elem {
+                    if !struct_map.contains_key(&struct_name.to_string()) {
+                        return Err(TypeError::UnknownStruct(*span, struct_name.to_string()));
+                    }
+                }
+                expect_elem_type(*span, elem)?;
+                let tarray = ExprType::Slice(Box::new(elem.clone()));
+                if let Some(tvar) =
+                    existing_var_type(*span, var, env, funcs, variables, constant_vars, struct_map)?
+                {
+                    expect_type(*span, &tvar, &tarray)?;
+                }
+                ExprType::Void
+            }
+            Expr::Return(..) | Expr::Break(..) | Expr::Continue(..) => ExprType::Void,
+            Expr::NewStruct(span, struct_name, fields) => {
+                let def = match struct_map.get(struct_name) {
+                    Some(def) => def,
+                    None => return Err(TypeError::UnknownStruct(*span, struct_name.to_string())),
+                };
+                let mut seen = Vec::new();
+                for field in fields {
+                    let def_field = match def.fields.get(&field.field_name) {
+                        Some(def_field) => def_field,
+                        None => {
+                            return Err(TypeError::UnknownField(
+                                field.span,
+                                struct_name.to_string(),
+                                field.field_name.to_string(),
+                            ))
+                        }
+                    };
+                    if seen.contains(&&field.field_name) {
+                        return Err(TypeError::DuplicateField(
+                            field.span,
+                            struct_name.to_string(),
+                            field.field_name.to_string(),
+                        ));
+                    }
+                    seen.push(&field.field_name);
+                    let tfield = ExprType::of(
+                        &field.expr,
+                        env,
+                        funcs,
+                        variables,
+                        constant_vars,
+                        struct_map,
+                    )?;
+                    expect_type(field.expr.span(), &def_field.expr_type, &tfield)?;
+                }
+                // Report missing fields in a stable order
+                let mut missing = def
+                    .fields
+                    .keys()
+                    .filter(|name| !seen.contains(name))
+                    .collect::<Vec<_>>();
+                missing.sort();
+                if let Some(name) = missing.first() {
+                    return Err(TypeError::MissingField(
+                        *span,
+                        struct_name.to_string(),
+                        name.to_string(),
+                    ));
                 }
                 ExprType::Struct(Box::new(struct_name.to_string()))
             }
@@ -309,6 +600,25 @@ impl ExprType {
         Ok(res)
     }
 
+    /// Whether a value of type `actual` can be used where `self` is expected.
+    pub fn accepts(&self, actual: &ExprType) -> bool {
+        // A raw address accepts anything that is passed around as a pointer
+        let is_pointer = matches!(
+            actual,
+            ExprType::UnboundedArrayF64
+                | ExprType::UnboundedArrayI64
+                | ExprType::Address
+                | ExprType::Struct(_)
+                | ExprType::Slice(_)
+        );
+        // A slice can be passed on without its length
+        let is_slice_of = |elem: ExprType| *actual == ExprType::Slice(Box::new(elem));
+        self == actual
+            || (*self == ExprType::Address && is_pointer)
+            || (*self == ExprType::UnboundedArrayF64 && is_slice_of(ExprType::F64))
+            || (*self == ExprType::UnboundedArrayI64 && is_slice_of(ExprType::I64))
+    }
+
     pub fn tuple_size(&self) -> usize {
         match self {
             ExprType::Void => 0,
@@ -318,7 +628,8 @@ impl ExprType {
             | ExprType::Address
             | ExprType::Struct(_)
             | ExprType::UnboundedArrayF64
-            | ExprType::UnboundedArrayI64 => 1,
+            | ExprType::UnboundedArrayI64
+            | ExprType::Slice(_) => 1,
             ExprType::Tuple(v) => v.len(),
         }
     }
@@ -329,6 +640,7 @@ impl ExprType {
     ) -> Result<cranelift::prelude::Type, TypeError> {
         match self {
             ExprType::Void => Err(TypeError::TypeMismatchSpecific {
+                span: Span::default(),
                 s: "Void has no cranelift analog".to_string(),
             }),
             ExprType::Bool => Ok(cranelift::prelude::types::B1),
@@ -339,22 +651,228 @@ impl ExprType {
             ExprType::Address => Ok(ptr_type),
             ExprType::Struct(_) => Ok(ptr_type),
             ExprType::Tuple(_) => Err(TypeError::TypeMismatchSpecific {
+                span: Span::default(),
                 s: "Tuple has no cranelift analog".to_string(),
             }),
+            ExprType::Slice(_) => Err(TypeError::TypeMismatchSpecific {
+                span: Span::default(),
+                s: "Slice is a pointer and a length, it has no single cranelift analog".to_string(),
+            }),
         }
     }
 }
 
+fn expect_type(span: Span, expected: &ExprType, actual: &ExprType) -> Result<(), TypeError> {
+    if expected.accepts(actual) {
+        Ok(())
+    } else {
+        Err(TypeError::TypeMismatch {
+            span,
+            expected: expected.clone(),
+            actual: actual.clone(),
+        })
+    }
+}
+
+/// Array indices can be ints or floats, floats are truncated.
+fn expect_index_type(span: Span, actual: &ExprType) -> Result<(), TypeError> {
+    match actual {
+        ExprType::I64 | ExprType::F64 => Ok(()),
+        _ => Err(TypeError::TypeMismatchSpecific {
+            span,
+            s: format!("array index must be i64 or f64, found {}", actual),
+        }),
+    }
+}
+
+/// Arrays can hold numbers and structs.
+fn expect_elem_type(span: Span, elem: &ExprType) -> Result<(), TypeError> {
+    match elem {
+        ExprType::F64 | ExprType::I64 | ExprType::Struct(_) => Ok(()),
+        t => Err(TypeError::TypeMismatchSpecific {
+            span,
+            s: format!("arrays of {} are not supported", t),
+        }),
+    }
+}
+
+fn array_elem_type(
+    span: Span,
+    id_name: &str,
+    variables: &HashMap<String, SVariable>,
+) -> Result<ExprType, TypeError> {
+    match variables.get(id_name).map(SVariable::elem_type) {
+        Some(Some(elem_type)) => Ok(elem_type),
+        Some(None) => Err(TypeError::TypeMismatchSpecific {
+            span,
+            s: format!("{} is not an array", id_name),
+        }),
+        None => Err(TypeError::UnknownVariable(span, id_name.to_string())),
+    }
+}
+
+/// `len(arr)` is built in, it gives the number of elements in a slice.
+fn len_type(
+    span: Span,
+    args: &[Expr],
+    env: &[Declaration],
+    funcs: &HashMap<String, Function>,
+    variables: &HashMap<String, SVariable>,
+    constant_vars: &HashMap<String, f64>,
+    struct_map: &HashMap<String, StructDef>,
+) -> Result<ExprType, TypeError> {
+    if args.len() != 1 {
+        return Err(TypeError::TupleLengthMismatch {
+            span,
+            expected: 1,
+            actual: args.len(),
+        });
+    }
+    match ExprType::of(&args[0], env, funcs, variables, constant_vars, struct_map)? {
+        ExprType::Slice(_) => Ok(ExprType::I64),
+        t => Err(TypeError::TypeMismatchSpecific {
+            span: args[0].span(),
+            s: format!("len expects a slice like [f64], found {}", t),
+        }),
+    }
+}
+
+/// Type of a struct field path like `line.a.x`, everything before the last
+/// field has to be a struct.
+fn field_path_type(
+    span: Span,
+    path: &str,
+    variables: &HashMap<String, SVariable>,
+    struct_map: &HashMap<String, StructDef>,
+) -> Result<ExprType, TypeError> {
+    let mut parts = path.split(".");
+    let base = parts.next().unwrap();
+    let mut expr_type = match variables.get(base) {
+        Some(var) => ExprType::of_variable(var),
+        None => return Err(TypeError::UnknownVariable(span, path.to_string())),
+    };
+    let mut prefix = base.to_string();
+    for field in parts {
+        let struct_name = match &expr_type {
+            ExprType::Struct(struct_name) => struct_name.to_string(),
+            _ => {
+                return Err(TypeError::TypeMismatchSpecific {
+                    span,
+                    s: format!("{} is not a Struct", prefix),
+                })
+            }
+        };
+        let struct_def = struct_map
+            .get(&struct_name)
+            .ok_or_else(|| TypeError::UnknownStruct(span, struct_name.clone()))?;
+        expr_type = match struct_def.fields.get(field) {
+            Some(struct_field) => struct_field.expr_type.clone(),
+            None => {
+                return Err(TypeError::UnknownField(
+                    span,
+                    struct_name,
+                    field.to_string(),
+                ))
+            }
+        };
+        prefix = format!("{}.{}", prefix, field);
+    }
+    Ok(expr_type)
+}
+
+/// Type of an assignment target that is already declared, `None` if this assignment
+/// introduces it. Struct fields like `a.b` must always exist.
+fn existing_var_type(
+    span: Span,
+    name: &str,
+    env: &[Declaration],
+    funcs: &HashMap<String, Function>,
+    variables: &HashMap<String, SVariable>,
+    constant_vars: &HashMap<String, f64>,
+    struct_map: &HashMap<String, StructDef>,
+) -> Result<Option<ExprType>, TypeError> {
+    if !name.contains(".") && !variables.contains_key(name) {
+        return Ok(None);
+    }
+    let target = Expr::Identifier(span, name.to_string());
+    ExprType::of(&target, env, funcs, variables, constant_vars, struct_map).map(Some)
+}
+
+/// Check every statement, carrying on past failures so all of them are reported.
 pub fn validate_program(
-    stmts: &Vec<Expr>,
+    stmts: &[Expr],
+    env: &[Declaration],
+    funcs: &HashMap<String, Function>,
+    variables: &HashMap<String, SVariable>,
+    constant_vars: &HashMap<String, f64>,
+    struct_map: &HashMap<String, StructDef>,
+) -> Result<(), Vec<TypeError>> {
+    let mut errors = Vec::new();
+    if let Err(err) = body_type(
+        stmts,
+        env,
+        funcs,
+        variables,
+        constant_vars,
+        struct_map,
+        &mut errors,
+    ) {
+        errors.push(err);
+    }
+    check_loop_control(stmts, false, &mut errors);
+    errors.sort_by_key(|e| e.span().start);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Type of the last statement in `body`, `Void` if it's empty. Checking
+/// goes on after a statement with an error, the first is returned and the
+/// others are pushed to `errors`.
+fn body_type(
+    body: &[Expr],
     env: &[Declaration],
     funcs: &HashMap<String, Function>,
     variables: &HashMap<String, SVariable>,
     constant_vars: &HashMap<String, f64>,
     struct_map: &HashMap<String, StructDef>,
-) -> Result<(), TypeError> {
+    errors: &mut Vec<TypeError>,
+) -> Result<ExprType, TypeError> {
+    let mut first_err = None;
+    let mut last = ExprType::Void;
+    for e in body {
+        match ExprType::of_collect(e, env, funcs, variables, constant_vars, struct_map, errors) {
+            Ok(t) => last = t,
+            Err(err) if first_err.is_none() => first_err = Some(err),
+            Err(err) => errors.push(err),
+        }
+    }
+    match first_err {
+        Some(err) => Err(err),
+        None => Ok(last),
+    }
+}
+
+/// `break` and `continue` are only allowed inside of a loop.
+fn check_loop_control(stmts: &[Expr], in_loop: bool, errors: &mut Vec<TypeError>) {
     for expr in stmts {
-        ExprType::of(expr, env, funcs, variables, constant_vars, struct_map)?;
+        match expr {
+            Expr::Break(span) | Expr::Continue(span) if !in_loop => {
+                errors.push(TypeError::OutsideLoop(*span, expr.to_string()))
+            }
+            Expr::WhileLoop(_, _, body) | Expr::ForLoop(_, _, _, body) => {
+                check_loop_control(body, true, errors)
+            }
+            Expr::IfThen(_, _, body) | Expr::Block(_, body) => {
+                check_loop_control(body, in_loop, errors)
+            }
+            Expr::IfElse(_, _, then_body, else_body) => {
+                check_loop_control(then_body, in_loop, errors);
+                check_loop_control(else_body, in_loop, errors);
+            }
+            _ => (),
+        }
     }
-    Ok(())
 }
@@ -0,0 +1,781 @@
+//! Differential fuzzing of the parser, validator and JIT. Random well-typed
+//! programs are generated as ASTs, printed and parsed back, and what the
+//! compiled functions return is compared with interpreting the generated AST.
+//!
+//! Runs `PROGRAMS` programs starting from `SEED`, which `SARUS_FUZZ_SEED` and
+//! `SARUS_FUZZ_PROGRAMS` override to search further.
+
+use sarus::{
+    frontend::{make_nonempty, Arg, Binop, Cmp, Declaration, Expr, Function, Span, Unaryop},
+    interpreter::Interpreter,
+    jit::JIT,
+    parser, sarus_std_lib,
+    sarus_value::SarusValue,
+    trap::Trap,
+    validator::ExprType,
+};
+
+const SEED: u64 = 0x5a12_05f0_22ed_1e55;
+const PROGRAMS: u64 = 200;
+const FUEL: i64 = 100_000;
+
+/// Float literals as the grammar takes them, always with a fractional part.
+const FLOATS: &[&str] = &[
+    "0.0",
+    "1.0",
+    "-1.0",
+    "0.5",
+    "2.5",
+    "-3.25",
+    "100.0",
+    "0.1",
+    "1000000.0",
+    "-0.0",
+];
+const INTS: &[&str] = &[
+    "0",
+    "1",
+    "-1",
+    "2",
+    "3",
+    "7",
+    "-13",
+    "1000",
+    "9223372036854775807",
+    "-9223372036854775808",
+];
+const ARG_FLOATS: &[f64] = &[0.0, -0.0, 1.5, -2.25, 7.0, 1e300, -1e-300, f64::NAN];
+const ARG_INTS: &[i64] = &[0, 1, -1, 5, -6, 100, i64::MAX, i64::MIN];
+
+/// xorshift64*, so a seed gives the same programs everywhere.
+struct Rng(u64);
+
+impl Rng {
+    /// Consecutive seeds are scrambled, as xorshift would start out alike for them.
+    fn new(seed: u64) -> Self {
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Rng((z ^ (z >> 31)).max(1))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+fn span() -> Span {
+    Span::default()
+}
+
+fn bx(expr: Expr) -> Box<Expr> {
+    Box::new(expr)
+}
+
+/// How tightly an expression binds when printed. An operand that binds less
+/// tightly than its operator needs parentheses to parse back the same way.
+fn precedence(expr: &Expr) -> u8 {
+    match expr {
+        // `!` takes the whole expression after it, and `if` isn't an operand
+        Expr::Unaryop(..) | Expr::IfElse(..) => 0,
+        Expr::Binop(_, Binop::LogicalAnd | Binop::LogicalOr, ..) => 1,
+        Expr::Compare(..) => 2,
+        Expr::Binop(_, Binop::Add | Binop::Sub, ..)
+        | Expr::AssignOp(_, Binop::Add | Binop::Sub, ..) => 3,
+        Expr::Binop(_, Binop::Mul | Binop::Div, ..) | Expr::AssignOp(..) => 4,
+        _ => 5,
+    }
+}
+
+#[derive(Clone)]
+struct Var {
+    name: String,
+    expr_type: ExprType,
+    /// Loop counters aren't assigned to, so loops end
+    assignable: bool,
+}
+
+#[derive(Clone)]
+struct FixedArray {
+    name: String,
+    elem: ExprType,
+    len: usize,
+}
+
+/// What's visible at the statement being generated.
+#[derive(Clone, Default)]
+struct Scope {
+    vars: Vec<Var>,
+    arrays: Vec<FixedArray>,
+    in_loop: bool,
+}
+
+struct Signature {
+    name: String,
+    params: Vec<ExprType>,
+    returns: Vec<ExprType>,
+}
+
+struct Generator {
+    rng: Rng,
+    /// Functions generated so far, which later ones can call
+    funcs: Vec<Signature>,
+    next_name: usize,
+}
+
+impl Generator {
+    fn new(seed: u64) -> Self {
+        Generator {
+            rng: Rng::new(seed),
+            funcs: Vec::new(),
+            next_name: 0,
+        }
+    }
+
+    fn name(&mut self, prefix: &str) -> String {
+        self.next_name += 1;
+        format!("{}{}", prefix, self.next_name)
+    }
+
+    fn scalar_type(&mut self) -> ExprType {
+        self.rng
+            .pick(&[ExprType::F64, ExprType::I64, ExprType::Bool])
+            .clone()
+    }
+
+    fn program(&mut self) -> Vec<Declaration> {
+        let count = 1 + self.rng.below(4);
+        (0..count)
+            .map(|_| Declaration::Function(self.function()))
+            .collect()
+    }
+
+    fn function(&mut self) -> Function {
+        let name = self.name("f");
+        let mut scope = Scope::default();
+        let mut params = Vec::new();
+        for _ in 0..self.rng.below(4) {
+            let arg = self.arg("p", &mut scope);
+            params.push(arg);
+        }
+        let mut returns = Vec::new();
+        let mut body = Vec::new();
+        for _ in 0..1 + self.rng.below(2) {
+            let arg = self.arg("r", &mut Scope::default());
+            let expr_type = arg.expr_type.clone().unwrap();
+            let value = self.expr(&scope, &expr_type, 2);
+            body.push(assign(vec![arg.name.clone()], vec![value]));
+            scope.vars.push(Var {
+                name: arg.name.clone(),
+                expr_type,
+                assignable: true,
+            });
+            returns.push(arg);
+        }
+        for _ in 0..1 + self.rng.below(6) {
+            body.extend(self.statement(&mut scope, 2));
+        }
+        self.funcs.push(Signature {
+            name: name.clone(),
+            params: params
+                .iter()
+                .map(|p| p.expr_type.clone().unwrap())
+                .collect(),
+            returns: returns
+                .iter()
+                .map(|r| r.expr_type.clone().unwrap())
+                .collect(),
+        });
+        Function {
+            name,
+            params,
+            returns,
+            body,
+            extern_func: false,
+            span: span(),
+        }
+    }
+
+    fn arg(&mut self, prefix: &str, scope: &mut Scope) -> Arg {
+        let name = self.name(prefix);
+        let expr_type = self.scalar_type();
+        scope.vars.push(Var {
+            name: name.clone(),
+            expr_type: expr_type.clone(),
+            assignable: true,
+        });
+        Arg {
+            name,
+            expr_type: Some(expr_type),
+            span: span(),
+        }
+    }
+
+    /// A block of statements, anything declared in it goes out of scope after.
+    fn block(&mut self, scope: &Scope, depth: usize) -> Vec<Expr> {
+        let mut scope = scope.clone();
+        let mut body = Vec::new();
+        for _ in 0..1 + self.rng.below(3) {
+            body.extend(self.statement(&mut scope, depth));
+        }
+        if scope.in_loop && self.rng.chance(15) {
+            let jump = if self.rng.chance(50) {
+                Expr::Break(span())
+            } else {
+                Expr::Continue(span())
+            };
+            let cond = self.expr(&scope, &ExprType::Bool, 2);
+            body.push(Expr::IfThen(span(), bx(cond), vec![jump]));
+        } else if self.rng.chance(5) {
+            let cond = self.expr(&scope, &ExprType::Bool, 2);
+            body.push(Expr::IfThen(span(), bx(cond), vec![Expr::Return(span())]));
+        }
+        body
+    }
+
+    /// Statements ending with an expression, for the value of an if else.
+    fn value_block(&mut self, scope: &Scope, expr_type: &ExprType, depth: usize) -> Vec<Expr> {
+        let mut scope = Scope {
+            in_loop: false,
+            ..scope.clone()
+        };
+        let mut body = Vec::new();
+        for _ in 0..self.rng.below(3) {
+            body.extend(self.statement(&mut scope, 0));
+        }
+        body.push(self.expr(&scope, expr_type, depth));
+        body
+    }
+
+    fn statement(&mut self, scope: &mut Scope, depth: usize) -> Vec<Expr> {
+        let choice = if depth == 0 {
+            self.rng.below(4)
+        } else {
+            self.rng.below(9)
+        };
+        match choice {
+            0 | 1 => vec![self.assignment(scope)],
+            2 => match self.assign_op(scope) {
+                Some(stmt) => vec![stmt],
+                None => vec![self.assignment(scope)],
+            },
+            3 => self.array_statement(scope),
+            4 => {
+                let cond = self.expr(scope, &ExprType::Bool, 2);
+                let then_body = self.block(scope, depth - 1);
+                vec![Expr::IfThen(span(), bx(cond), then_body)]
+            }
+            5 => {
+                let cond = self.expr(scope, &ExprType::Bool, 2);
+                let mut then_body = self.block(scope, depth - 1);
+                let mut else_body = self.block(scope, depth - 1);
+                // Even as a statement the branches have to agree on the type of
+                // their last statement, so have both end without a value
+                for body in [&mut then_body, &mut else_body] {
+                    if !matches!(body.last(), Some(stmt) if is_void(stmt)) {
+                        let cond = self.expr(scope, &ExprType::Bool, 2);
+                        let inner = self.block(scope, 0);
+                        body.push(Expr::IfThen(span(), bx(cond), inner));
+                    }
+                }
+                vec![Expr::IfElse(span(), bx(cond), then_body, else_body)]
+            }
+            6 => self.while_loop(scope, depth),
+            7 => vec![self.for_loop(scope, depth)],
+            _ => match self.multi_return_call(scope) {
+                Some(stmt) => vec![stmt],
+                None => vec![self.assignment(scope)],
+            },
+        }
+    }
+
+    /// Assign to new variables or to existing ones of the same type.
+    fn assignment(&mut self, scope: &mut Scope) -> Expr {
+        let count = if self.rng.chance(20) { 2 } else { 1 };
+        let mut names = Vec::new();
+        let mut values = Vec::new();
+        let mut new_vars = Vec::new();
+        for _ in 0..count {
+            let existing = scope
+                .vars
+                .iter()
+                .filter(|v| v.assignable && !names.contains(&v.name))
+                .cloned()
+                .collect::<Vec<_>>();
+            let var = if !existing.is_empty() && self.rng.chance(50) {
+                self.rng.pick(&existing).clone()
+            } else {
+                let var = Var {
+                    name: self.name("v"),
+                    expr_type: self.scalar_type(),
+                    assignable: true,
+                };
+                new_vars.push(var.clone());
+                var
+            };
+            values.push(self.expr(scope, &var.expr_type, 2));
+            names.push(var.name);
+        }
+        scope.vars.extend(new_vars);
+        assign(names, values)
+    }
+
+    fn assign_op(&mut self, scope: &Scope) -> Option<Expr> {
+        let numbers = scope
+            .vars
+            .iter()
+            .filter(|v| v.assignable && v.expr_type != ExprType::Bool)
+            .cloned()
+            .collect::<Vec<_>>();
+        if numbers.is_empty() {
+            return None;
+        }
+        let var = self.rng.pick(&numbers).clone();
+        let op = *self
+            .rng
+            .pick(&[Binop::Add, Binop::Sub, Binop::Mul, Binop::Div]);
+        let value = self.expr(scope, &var.expr_type, 2);
+        // The value takes operators as tight as the assignment's own, so
+        // `x -= a - b` subtracts `a - b` but `x *= a + b` needs parentheses
+        let min = match op {
+            Binop::Add | Binop::Sub => 3,
+            _ => 4,
+        };
+        let value = self.operand(value, min);
+        Some(Expr::AssignOp(span(), op, Box::new(var.name), bx(value)))
+    }
+
+    /// Declare a fixed size array or set an element of one.
+    fn array_statement(&mut self, scope: &mut Scope) -> Vec<Expr> {
+        if scope.arrays.is_empty() || self.rng.chance(30) {
+            let array = FixedArray {
+                name: self.name("a"),
+                elem: self.rng.pick(&[ExprType::F64, ExprType::I64]).clone(),
+                len: 1 + self.rng.below(6),
+            };
+            let declare = if self.rng.chance(50) {
+                Expr::DeclareArray(span(), array.name.clone(), array.elem.clone(), array.len)
+            } else {
+                let value = self.expr(scope, &array.elem, 1);
+                let repeat = Expr::ArrayRepeat(span(), bx(value), array.len);
+                assign(vec![array.name.clone()], vec![repeat])
+            };
+            scope.arrays.push(array);
+            vec![declare]
+        } else {
+            let array = self.rng.pick(&scope.arrays).clone();
+            let index = self.index(scope, &array);
+            let value = self.expr(scope, &array.elem, 2);
+            vec![Expr::ArraySet(span(), array.name, bx(index), bx(value))]
+        }
+    }
+
+    /// Usually in bounds, going out of bounds traps.
+    fn index(&mut self, scope: &Scope, array: &FixedArray) -> Expr {
+        let counters = scope
+            .vars
+            .iter()
+            .filter(|v| !v.assignable)
+            .collect::<Vec<_>>();
+        match self.rng.below(10) {
+            0 => self.expr(scope, &ExprType::I64, 2),
+            1 | 2 if !counters.is_empty() => {
+                Expr::Identifier(span(), self.rng.pick(&counters).name.clone())
+            }
+            _ => Expr::LiteralInt(span(), self.rng.below(array.len).to_string()),
+        }
+    }
+
+    /// A loop counted up front, so `continue` can't skip the increment.
+    fn while_loop(&mut self, scope: &mut Scope, depth: usize) -> Vec<Expr> {
+        let counter = self.name("w");
+        let limit = 1 + self.rng.below(6);
+        let init = assign(
+            vec![counter.clone()],
+            vec![Expr::LiteralInt(span(), "0".to_string())],
+        );
+        let cond = Expr::Compare(
+            span(),
+            Cmp::Lt,
+            bx(Expr::Identifier(span(), counter.clone())),
+            bx(Expr::LiteralInt(span(), limit.to_string())),
+        );
+        let increment = Expr::AssignOp(
+            span(),
+            Binop::Add,
+            Box::new(counter.clone()),
+            bx(Expr::LiteralInt(span(), "1".to_string())),
+        );
+        scope.vars.push(Var {
+            name: counter,
+            expr_type: ExprType::I64,
+            assignable: false,
+        });
+        let mut loop_scope = scope.clone();
+        loop_scope.in_loop = true;
+        let mut body = vec![increment];
+        body.extend(self.block(&loop_scope, depth - 1));
+        vec![init, Expr::WhileLoop(span(), bx(cond), body)]
+    }
+
+    /// Only goes over a few elements, so nested loops don't use up all of the fuel.
+    fn for_loop(&mut self, scope: &Scope, depth: usize) -> Expr {
+        let var = self.name("i");
+        let start = Expr::LiteralInt(span(), self.rng.below(3).to_string());
+        let end = if self.rng.chance(50) {
+            Expr::LiteralInt(span(), self.rng.below(6).to_string())
+        } else {
+            let bound = self.expr(scope, &ExprType::I64, 1);
+            Expr::Call(
+                span(),
+                "imin".to_string(),
+                vec![bound, Expr::LiteralInt(span(), "5".to_string())],
+                false,
+            )
+        };
+        let mut loop_scope = scope.clone();
+        loop_scope.in_loop = true;
+        loop_scope.vars.push(Var {
+            name: var.clone(),
+            expr_type: ExprType::I64,
+            assignable: false,
+        });
+        let body = self.block(&loop_scope, depth - 1);
+        Expr::ForLoop(
+            span(),
+            var,
+            bx(Expr::Range(span(), bx(start), bx(end))),
+            body,
+        )
+    }
+
+    /// Assign every value returned by a function returning more than one.
+    fn multi_return_call(&mut self, scope: &mut Scope) -> Option<Expr> {
+        let candidates = (0..self.funcs.len())
+            .filter(|i| self.funcs[*i].returns.len() > 1)
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None;
+        }
+        let func = *self.rng.pick(&candidates);
+        let call = self.call(scope, func);
+        let mut names = Vec::new();
+        for expr_type in self.funcs[func].returns.clone() {
+            let existing = scope
+                .vars
+                .iter()
+                .filter(|v| v.assignable && v.expr_type == expr_type && !names.contains(&v.name))
+                .map(|v| v.name.clone())
+                .collect::<Vec<_>>();
+            if !existing.is_empty() && self.rng.chance(50) {
+                names.push(self.rng.pick(&existing).clone());
+            } else {
+                let name = self.name("v");
+                scope.vars.push(Var {
+                    name: name.clone(),
+                    expr_type,
+                    assignable: true,
+                });
+                names.push(name);
+            }
+        }
+        Some(assign(names, vec![call]))
+    }
+
+    fn call(&mut self, scope: &Scope, func: usize) -> Expr {
+        let args = self.funcs[func]
+            .params
+            .clone()
+            .iter()
+            .map(|expr_type| self.expr(scope, expr_type, 1))
+            .collect();
+        Expr::Call(span(), self.funcs[func].name.clone(), args, false)
+    }
+
+    fn literal(&mut self, expr_type: &ExprType) -> Expr {
+        match expr_type {
+            ExprType::F64 => Expr::LiteralFloat(span(), self.rng.pick(FLOATS).to_string()),
+            ExprType::I64 => Expr::LiteralInt(span(), self.rng.pick(INTS).to_string()),
+            _ => Expr::LiteralBool(span(), self.rng.chance(50)),
+        }
+    }
+
+    /// Put `expr` in parentheses if it binds less tightly than `min`, and
+    /// sometimes when it doesn't need them.
+    fn operand(&mut self, expr: Expr, min: u8) -> Expr {
+        if precedence(&expr) < min || self.rng.chance(5) {
+            Expr::Parentheses(span(), bx(expr))
+        } else {
+            expr
+        }
+    }
+
+    fn binop(&mut self, op: Binop, a: Expr, b: Expr) -> Expr {
+        let level = precedence(&Expr::Binop(span(), op, bx(a.clone()), bx(b.clone())));
+        let a = self.operand(a, level);
+        let b = self.operand(b, level + 1);
+        Expr::Binop(span(), op, bx(a), bx(b))
+    }
+
+    fn compare(&mut self, cmp: Cmp, a: Expr, b: Expr) -> Expr {
+        let a = self.operand(a, 2);
+        let b = self.operand(b, 3);
+        Expr::Compare(span(), cmp, bx(a), bx(b))
+    }
+
+    /// An expression of type `expr_type`, nested at most `depth` deep.
+    fn expr(&mut self, scope: &Scope, expr_type: &ExprType, depth: usize) -> Expr {
+        let vars = scope
+            .vars
+            .iter()
+            .filter(|v| v.expr_type == *expr_type)
+            .collect::<Vec<_>>();
+        if depth == 0 || self.rng.chance(20) {
+            return if !vars.is_empty() && self.rng.chance(60) {
+                Expr::Identifier(span(), self.rng.pick(&vars).name.clone())
+            } else {
+                self.literal(expr_type)
+            };
+        }
+        let depth = depth - 1;
+        let funcs = (0..self.funcs.len())
+            .filter(|i| self.funcs[*i].returns == [expr_type.clone()])
+            .collect::<Vec<_>>();
+        let arrays = scope
+            .arrays
+            .iter()
+            .filter(|a| a.elem == *expr_type)
+            .cloned()
+            .collect::<Vec<_>>();
+        match (expr_type, self.rng.below(10)) {
+            (_, 9) if self.rng.chance(30) => {
+                let cond = self.expr(scope, &ExprType::Bool, depth);
+                let then_body = self.value_block(scope, expr_type, depth);
+                let else_body = self.value_block(scope, expr_type, depth);
+                Expr::IfElse(span(), bx(cond), then_body, else_body)
+            }
+            (_, 0) if !funcs.is_empty() => {
+                let func = *self.rng.pick(&funcs);
+                self.call(scope, func)
+            }
+            (_, 1) if !arrays.is_empty() => {
+                let array = self.rng.pick(&arrays).clone();
+                let index = self.index(scope, &array);
+                Expr::ArrayGet(span(), array.name, bx(index))
+            }
+            (_, 2) => {
+                let inner = self.expr(scope, expr_type, depth);
+                Expr::Parentheses(span(), bx(inner))
+            }
+            (ExprType::F64, 3) => {
+                let name = *self
+                    .rng
+                    .pick(&["floor", "ceil", "trunc", "fract", "abs", "round"]);
+                let x = self.expr(scope, &ExprType::F64, depth);
+                Expr::Call(span(), name.to_string(), vec![x], false)
+            }
+            (ExprType::F64, 4) => {
+                let name = *self.rng.pick(&["min", "max"]);
+                let x = self.expr(scope, &ExprType::F64, depth);
+                let y = self.expr(scope, &ExprType::F64, depth);
+                Expr::Call(span(), name.to_string(), vec![x, y], false)
+            }
+            (ExprType::F64, 5) => {
+                let x = self.expr(scope, &ExprType::I64, depth);
+                Expr::Call(span(), "float".to_string(), vec![x], false)
+            }
+            (ExprType::I64, 3) => {
+                let x = self.expr(scope, &ExprType::F64, depth);
+                Expr::Call(span(), "int".to_string(), vec![x], false)
+            }
+            (ExprType::I64, 4) => {
+                let name = *self.rng.pick(&["imin", "imax"]);
+                let x = self.expr(scope, &ExprType::I64, depth);
+                let y = self.expr(scope, &ExprType::I64, depth);
+                Expr::Call(span(), name.to_string(), vec![x, y], false)
+            }
+            (ExprType::F64, _) | (ExprType::I64, _) => {
+                // Integer division by zero traps, keep it rare enough for
+                // most programs to run to the end
+                let op = match self.rng.below(10) {
+                    0..=2 => Binop::Add,
+                    3..=5 => Binop::Sub,
+                    6..=8 => Binop::Mul,
+                    _ => Binop::Div,
+                };
+                let a = self.expr(scope, expr_type, depth);
+                let b = self.expr(scope, expr_type, depth);
+                self.binop(op, a, b)
+            }
+            (_, 3) | (_, 4) => {
+                let operand_type = self.rng.pick(&[ExprType::F64, ExprType::I64]).clone();
+                let cmp = *self
+                    .rng
+                    .pick(&[Cmp::Eq, Cmp::Ne, Cmp::Lt, Cmp::Le, Cmp::Gt, Cmp::Ge]);
+                let a = self.expr(scope, &operand_type, depth);
+                let b = self.expr(scope, &operand_type, depth);
+                self.compare(cmp, a, b)
+            }
+            (_, 5) => {
+                let cmp = *self.rng.pick(&[Cmp::Eq, Cmp::Ne]);
+                let a = self.expr(scope, &ExprType::Bool, depth);
+                let b = self.expr(scope, &ExprType::Bool, depth);
+                self.compare(cmp, a, b)
+            }
+            (_, 6) => {
+                let inner = self.expr(scope, &ExprType::Bool, depth);
+                Expr::Unaryop(span(), Unaryop::Not, bx(inner))
+            }
+            _ => {
+                let op = *self.rng.pick(&[Binop::LogicalAnd, Binop::LogicalOr]);
+                let a = self.expr(scope, &ExprType::Bool, depth);
+                let b = self.expr(scope, &ExprType::Bool, depth);
+                self.binop(op, a, b)
+            }
+        }
+    }
+
+    fn args(&mut self, func: &Function) -> Vec<SarusValue> {
+        func.params
+            .iter()
+            .map(|param| match param.expr_type.as_ref().unwrap() {
+                ExprType::F64 => SarusValue::F64(*self.rng.pick(ARG_FLOATS)),
+                ExprType::I64 => SarusValue::I64(*self.rng.pick(ARG_INTS)),
+                _ => SarusValue::Bool(self.rng.chance(50)),
+            })
+            .collect()
+    }
+}
+
+/// Whether the validator types `stmt` as having no value.
+fn is_void(stmt: &Expr) -> bool {
+    matches!(
+        stmt,
+        Expr::IfThen(..)
+            | Expr::IfElse(..)
+            | Expr::WhileLoop(..)
+            | Expr::ForLoop(..)
+            | Expr::DeclareArray(..)
+    )
+}
+
+fn assign(names: Vec<String>, values: Vec<Expr>) -> Expr {
+    Expr::Assign(
+        span(),
+        make_nonempty(names).unwrap(),
+        make_nonempty(values).unwrap(),
+    )
+}
+
+fn print(ast: &[Declaration]) -> String {
+    ast.iter().map(|decl| format!("{}\n", decl)).collect()
+}
+
+fn same_value(a: &SarusValue, b: &SarusValue) -> bool {
+    match (a, b) {
+        (SarusValue::F64(a), SarusValue::F64(b)) => {
+            a.to_bits() == b.to_bits() || (a.is_nan() && b.is_nan())
+        }
+        (SarusValue::I64(a), SarusValue::I64(b)) => a == b,
+        (SarusValue::Bool(a), SarusValue::Bool(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} should be a number", name)),
+        Err(_) => default,
+    }
+}
+
+/// Generate the program for `seed`, check it prints and parses back the same,
+/// and that the JIT and the interpreter agree on what each function returns.
+fn check_program(seed: u64) {
+    let mut generator = Generator::new(seed);
+    let generated = generator.program();
+    let code = print(&generated);
+    let context = format!("seed {}, program:\n{}", seed, code);
+
+    let parsed =
+        parser::program(&code).unwrap_or_else(|e| panic!("{}\ndoesn't parse, {}", context, e));
+    assert_eq!(code, print(&parsed), "printing parsed {}", context);
+
+    let mut jit = JIT::default();
+    jit.consume_fuel = true;
+    jit.translate(sarus_std_lib::append_std_funcs(parsed))
+        .unwrap_or_else(|e| panic!("{}\ndoesn't translate, {}", context, e));
+    let mut interpreter = Interpreter::default();
+    interpreter.consume_fuel = true;
+    interpreter
+        .load(sarus_std_lib::append_std_funcs(generated.clone()))
+        .unwrap_or_else(|e| panic!("{}\ndoesn't load, {}", context, e));
+
+    for decl in &generated {
+        let func = match decl {
+            Declaration::Function(func) => func,
+            _ => continue,
+        };
+        let args = generator.args(func);
+        let context = format!("{}({:?}) with {}", func.name, args, context);
+
+        interpreter.set_fuel(FUEL);
+        let interpreted = interpreter.call(&func.name, &mut args.clone());
+        jit.set_fuel(FUEL).unwrap();
+        let compiled = jit.call(&func.name, &mut args.clone());
+        match (&compiled, &interpreted) {
+            (Ok(compiled), Ok(interpreted)) => {
+                assert_eq!(compiled.len(), interpreted.len(), "{}", context);
+                for (a, b) in compiled.iter().zip(interpreted) {
+                    assert!(same_value(a, b), "{:?} != {:?} from {}", a, b, context);
+                }
+            }
+            (Err(compiled), Err(interpreted)) => assert_eq!(
+                compiled.downcast_ref::<Trap>().map(|t| &t.kind),
+                interpreted.downcast_ref::<Trap>().map(|t| &t.kind),
+                "{} != {} from {}",
+                compiled,
+                interpreted,
+                context
+            ),
+            _ => panic!(
+                "{:?} != {:?} from {}",
+                compiled.as_ref().map_err(|e| e.to_string()),
+                interpreted.as_ref().map_err(|e| e.to_string()),
+                context
+            ),
+        }
+        assert_eq!(
+            jit.fuel(),
+            interpreter.fuel(),
+            "fuel left after {}",
+            context
+        );
+    }
+}
+
+#[test]
+fn fuzz_jit_against_interpreter() {
+    let seed = env_or("SARUS_FUZZ_SEED", SEED);
+    let programs = env_or("SARUS_FUZZ_PROGRAMS", PROGRAMS);
+    for i in 0..programs {
+        check_program(seed.wrapping_add(i));
+    }
+}
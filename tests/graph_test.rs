@@ -122,7 +122,7 @@ fn sin_node(a) -> (c) {
     ];
 
     //initialize graph, will arrange graph, generate graph code, and compile
-    let mut graph = Graph::new(code.to_string(), nodes, connections, STEP_SIZE)?;
+    let graph = Graph::new(code.to_string(), nodes, connections, STEP_SIZE)?;
 
     //print out the resulting code for fun
     for d in graph.ast {
@@ -135,27 +135,24 @@ fn sin_node(a) -> (c) {
     const STEPS: usize = 48000 / STEP_SIZE;
     let mut output_arr = [[0.0f64; STEP_SIZE]; STEPS];
     let mut n = 0;
-    for i in 0..STEPS {
+    for output in output_arr.iter_mut() {
         let mut audio_buffer = [0.0f64; STEP_SIZE];
-        for j in 0..STEP_SIZE {
-            audio_buffer[j] = ((n as f64).powi(2) * 0.000001).sin(); //sound source is sine sweep
+        for sample in audio_buffer.iter_mut() {
+            *sample = ((n as f64).powi(2) * 0.000001).sin(); //sound source is sine sweep
             n += 1;
         }
 
         let func_ptr = graph.jit.get_func("graph")?;
-        let func = unsafe { mem::transmute::<_, extern "C" fn(&mut [f64; STEP_SIZE])>(func_ptr) };
+        let func =
+            unsafe { mem::transmute::<*const u8, extern "C" fn(&mut [f64; STEP_SIZE])>(func_ptr) };
         func(&mut audio_buffer);
 
         //Collect output audio
-        output_arr[i] = audio_buffer;
+        *output = audio_buffer;
     }
 
     //Flatten output audio chunks for saving as wav
-    let flat = output_arr
-        .iter()
-        .flatten()
-        .map(|x| *x)
-        .collect::<Vec<f64>>();
+    let flat = output_arr.iter().flatten().copied().collect::<Vec<f64>>();
     write_wav(&flat, "graph_test.wav");
     dbg!(flat.iter().sum::<f64>());
 
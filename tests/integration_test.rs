@@ -1,5 +1,5 @@
 use serde::Deserialize;
-use std::{collections::HashMap, f64::consts::*, ffi::CStr, mem};
+use std::{collections::HashMap, convert::TryInto, f64::consts::*, ffi::CStr, mem};
 
 use sarus::*;
 
@@ -14,15 +14,71 @@ fn main(a, b) -> (c) {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(a * (a - b) * (a * (2.0 + b)), func(a, b));
     Ok(())
 }
 
+#[test]
+fn left_associative_operators() -> anyhow::Result<()> {
+    use sarus_value::SarusValue::*;
+    let code = r#"
+fn main(a: i64, b: f64) -> (c: i64, d: f64, e: bool) {
+    c = a - 3 - 2
+    d = b / 4.0 / 2.0
+    e = 1 < 2 == false
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    assert_eq!(
+        jit.call("main", &mut [I64(10), F64(8.0)])?,
+        vec![I64(5), F64(1.0), Bool(false)]
+    );
+    Ok(())
+}
+
+#[test]
+fn expression_starting_next_line() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    c = if a > 0.0 {
+        t = true
+        -1.0
+    } else {
+        b = a
+        (b - 1.0) * 2.0
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
+    assert_eq!(func(5.0), -1.0);
+    assert_eq!(func(-5.0), -12.0);
+
+    // No binary operator continues the line before it
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    c = a
+    + 1.0
+}
+"#;
+    let err = parser::program(code).unwrap_err();
+    assert_eq!(err[0].span.line, 4);
+    assert_eq!(err[0].span.col, 5);
+    Ok(())
+}
+
 #[test]
 fn libc_math() -> anyhow::Result<()> {
     let code = r#"
@@ -90,11 +146,11 @@ fn nums() -> (r) {
     let epsilon = 0.00000000000001;
     let mut jit = jit::JIT::default();
     jit.add_math_constants()?;
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     let result = func(a, b);
     assert!(result >= c - epsilon && result <= c + epsilon);
     Ok(())
@@ -111,11 +167,11 @@ fn main(a, b) -> (c) {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(
         a.ceil() * b.floor() * a.trunc() * (a * b * -1.234).fract() * 1.5f64.round(),
         func(a, b)
@@ -138,7 +194,7 @@ fn minmax() -> anyhow::Result<()> {
     let mut jit = jit::JIT::default();
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(a, func(a, b));
 
     let mut jit = jit::JIT::default();
@@ -152,7 +208,7 @@ fn minmax() -> anyhow::Result<()> {
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(b, func(a, b));
 
     Ok(())
@@ -195,11 +251,11 @@ fn foodd(a, b) -> (c) {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(601.0, func(a, b));
     Ok(())
 }
@@ -241,15 +297,39 @@ fn multiple_returns() -> anyhow::Result<()> {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(6893909.333333333, func(a, b));
     Ok(())
 }
 
+#[test]
+fn print_multiple_returns() -> anyhow::Result<()> {
+    let code = r#"
+fn stuff(a, b: i64) -> (c, d: i64) {
+    c = a + 1.0
+    d = b
+}
+"#;
+    let print = |ast: &[frontend::Declaration]| {
+        ast.iter()
+            .map(|d| d.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let printed = print(&parser::program(code)?);
+    let reparsed = parser::program(&printed)?;
+    match &reparsed[0] {
+        frontend::Declaration::Function(f) => assert_eq!(f.returns.len(), 2),
+        d => panic!("expected a function, got {}", d),
+    }
+    assert_eq!(print(&reparsed), printed);
+    Ok(())
+}
+
 #[test]
 fn bools() -> anyhow::Result<()> {
     let code = r#"
@@ -267,11 +347,11 @@ fn bools() -> anyhow::Result<()> {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(20000.0, func(a, b));
     Ok(())
 }
@@ -290,15 +370,44 @@ fn ifelse_assign() -> anyhow::Result<()> {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(20000.0, func(a, b));
     Ok(())
 }
 
+#[test]
+fn assign_in_if_branches() -> anyhow::Result<()> {
+    let code = r#"
+    fn main(a, b) -> (c) {
+        if a < b {
+            d = a * 2.0
+            a = d
+        }
+        c = if a < b {
+            e = a + 1.0
+            e * b
+        } else {
+            f = b + 1.0
+            f
+        }
+    }
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    assert_eq!(21.0 * 100.0, func(10.0, 100.0));
+    assert_eq!(101.0, func(60.0, 100.0));
+    assert_eq!(3.0, func(3.0, 2.0));
+    Ok(())
+}
+
 #[test]
 fn order() -> anyhow::Result<()> {
     let code = r#"
@@ -309,11 +418,11 @@ fn order() -> anyhow::Result<()> {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(100.0, func(a, b));
     Ok(())
 }
@@ -332,11 +441,11 @@ fn main(arr: &[f64], b) -> () {
     let mut arr = [1.0, 2.0, 3.0, 4.0];
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(*mut f64, f64)>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(*mut f64, f64)>(func_ptr) };
     func(arr.as_mut_ptr(), b);
     assert_eq!([200.0, 400.0, 600.0, 800.0], arr);
     Ok(())
@@ -351,11 +460,11 @@ fn negative() -> anyhow::Result<()> {
 "#;
     let a = -100.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
     assert_eq!(-101.0, func(a));
     Ok(())
 }
@@ -392,21 +501,23 @@ fn compiled_graph() -> anyhow::Result<()> {
 
     let mut audio = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("graph")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(&mut [f64; 8])>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(&mut [f64; 8])>(func_ptr) };
     dbg!(func(&mut audio));
     Ok(())
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct Metadata {
     description: Option<String>,
     inputs: HashMap<String, MetadataInput>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct MetadataInput {
     default: Option<f64>,
@@ -457,7 +568,7 @@ fn metadata() -> anyhow::Result<()> {
 "#;
 
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
 
@@ -465,7 +576,7 @@ fn metadata() -> anyhow::Result<()> {
         frontend::Declaration::Metadata(head, body) => {
             if let Some(head) = head.first() {
                 if head == "add_node" {
-                    Some(toml::from_str(&body).unwrap())
+                    Some(toml::from_str(body).unwrap())
                 } else {
                     None
                 }
@@ -480,7 +591,7 @@ fn metadata() -> anyhow::Result<()> {
 
     let mut audio = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0];
     let func_ptr = jit.get_func("graph")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(&mut [f64; 8])>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(&mut [f64; 8])>(func_ptr) };
     dbg!(func(&mut audio));
     //assert_eq!([200.0, 400.0, 600.0, 800.0], arr);
     Ok(())
@@ -502,11 +613,11 @@ fn int_while_loop() -> anyhow::Result<()> {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(2048.0, func(a, b));
     Ok(())
 }
@@ -523,11 +634,11 @@ fn int_to_float() -> anyhow::Result<()> {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(80000.0, func(a, b));
     Ok(())
 }
@@ -548,11 +659,11 @@ fn float_conversion() -> anyhow::Result<()> {
     let b = 200.0f64;
 
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(100.0, func(a, b));
     Ok(())
 }
@@ -574,11 +685,11 @@ fn float_as_bool_error() -> anyhow::Result<()> {
     let b = 200.0f64;
 
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(1.0, func(a, b));
     Ok(())
 }
@@ -601,11 +712,12 @@ fn main(arr1: &[f64], arr2: &[f64], b) -> () {
     let mut arr2 = [10.0, 20.0, 30.0, 40.0];
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(*mut f64, *mut f64, f64)>(func_ptr) };
+    let func =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(*mut f64, *mut f64, f64)>(func_ptr) };
     func(arr1.as_mut_ptr(), arr2.as_mut_ptr(), b);
     assert_eq!(200.0, arr2[0]);
     Ok(())
@@ -626,11 +738,11 @@ fn var_type_consistency() -> anyhow::Result<()> {
     let b = 200.0f64;
 
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(1.0, func(a, b));
     Ok(())
 }
@@ -648,11 +760,12 @@ fn three_inputs() -> anyhow::Result<()> {
     let c = 300.0f64;
 
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64, f64) -> f64>(func_ptr) };
+    let func =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64, f64) -> f64>(func_ptr) };
     assert_eq!(600.0, func(a, b, c));
     Ok(())
 }
@@ -667,11 +780,11 @@ fn main(a: f64, b: f64) -> (c: f64) {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(a * (a - b) * (a * (2.0 + b)), func(a, b));
     Ok(())
 }
@@ -686,11 +799,11 @@ fn main(a: f64, b: i64) -> (c: i64) {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, i64) -> i64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, i64) -> i64>(func_ptr) };
     assert_eq!((a * (a - b) * (a * (2.0 + b))) as i64, func(a, b as i64));
     Ok(())
 }
@@ -709,11 +822,11 @@ fn foo(a: f64, b: i64, c: i64) -> (d: i64) {
     let a = 100.0f64;
     let b = 200.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, i64) -> i64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, i64) -> i64>(func_ptr) };
     assert_eq!(302, func(a, b as i64));
     Ok(())
 }
@@ -731,11 +844,11 @@ fn main(a: f64, b: bool) -> (c: f64) {
 "#;
     let a = 100.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, bool) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, bool) -> f64>(func_ptr) };
     assert_eq!(a, func(a, true));
     assert_eq!(-a, func(a, false));
     Ok(())
@@ -797,60 +910,83 @@ fn parenassign() -> (c: bool) {
 }
 "#;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("and")?) };
-    assert_eq!(true, f(true, true));
-    assert_eq!(false, f(true, false));
-    assert_eq!(false, f(false, true));
-    assert_eq!(false, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("or")?) };
-    assert_eq!(true, f(true, true));
-    assert_eq!(true, f(true, false));
-    assert_eq!(true, f(false, true));
-    assert_eq!(false, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("gt")?) };
-    assert_eq!(false, f(true, true));
-    assert_eq!(true, f(true, false));
-    assert_eq!(false, f(false, true));
-    assert_eq!(false, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("ge")?) };
-    assert_eq!(true, f(true, true));
-    assert_eq!(true, f(true, false));
-    assert_eq!(false, f(false, true));
-    assert_eq!(true, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("lt")?) };
-    assert_eq!(false, f(true, true));
-    assert_eq!(false, f(true, false));
-    assert_eq!(true, f(false, true));
-    assert_eq!(false, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("le")?) };
-    assert_eq!(true, f(true, true));
-    assert_eq!(false, f(true, false));
-    assert_eq!(true, f(false, true));
-    assert_eq!(true, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("eq")?) };
-    assert_eq!(true, f(true, true));
-    assert_eq!(false, f(true, false));
-    assert_eq!(false, f(false, true));
-    assert_eq!(true, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool, bool) -> bool>(jit.get_func("ne")?) };
-    assert_eq!(false, f(true, true));
-    assert_eq!(true, f(true, false));
-    assert_eq!(true, f(false, true));
-    assert_eq!(false, f(false, false));
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthen")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthen2")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthenparen")?) };
-    assert_eq!(true, f());
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("and")?)
+    };
+    assert!(f(true, true));
+    assert!(!f(true, false));
+    assert!(!f(false, true));
+    assert!(!f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("or")?)
+    };
+    assert!(f(true, true));
+    assert!(f(true, false));
+    assert!(f(false, true));
+    assert!(!f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("gt")?)
+    };
+    assert!(!f(true, true));
+    assert!(f(true, false));
+    assert!(!f(false, true));
+    assert!(!f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("ge")?)
+    };
+    assert!(f(true, true));
+    assert!(f(true, false));
+    assert!(!f(false, true));
+    assert!(f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("lt")?)
+    };
+    assert!(!f(true, true));
+    assert!(!f(true, false));
+    assert!(f(false, true));
+    assert!(!f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("le")?)
+    };
+    assert!(f(true, true));
+    assert!(!f(true, false));
+    assert!(f(false, true));
+    assert!(f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("eq")?)
+    };
+    assert!(f(true, true));
+    assert!(!f(true, false));
+    assert!(!f(false, true));
+    assert!(f(false, false));
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(bool, bool) -> bool>(jit.get_func("ne")?)
+    };
+    assert!(!f(true, true));
+    assert!(f(true, false));
+    assert!(f(false, true));
+    assert!(!f(false, false));
     let f =
-        unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthennestedparen")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("parenassign")?) };
-    assert_eq!(true, f());
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthen")?) };
+    assert!(f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthen2")?) };
+    assert!(f());
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthenparen")?)
+    };
+    assert!(f());
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthennestedparen")?)
+    };
+    assert!(f());
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("parenassign")?)
+    };
+    assert!(f());
     Ok(())
 }
 
@@ -895,25 +1031,34 @@ fn parenassign() -> (c: bool) {
 }
 "#;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("direct")?) };
-    assert_eq!(false, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("direct2")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("direct3")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn(bool) -> bool>(jit.get_func("not2")?) };
-    assert_eq!(false, f(true));
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthen")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthen2")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("ifthen3")?) };
-    assert_eq!(true, f());
-    let f = unsafe { mem::transmute::<_, extern "C" fn() -> bool>(jit.get_func("parenassign")?) };
-    assert_eq!(false, f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("direct")?) };
+    assert!(!f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("direct2")?) };
+    assert!(f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("direct3")?) };
+    assert!(f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(bool) -> bool>(jit.get_func("not2")?) };
+    assert!(!f(true));
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthen")?) };
+    assert!(f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthen2")?) };
+    assert!(f());
+    let f =
+        unsafe { mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("ifthen3")?) };
+    assert!(f());
+    let f = unsafe {
+        mem::transmute::<*const u8, extern "C" fn() -> bool>(jit.get_func("parenassign")?)
+    };
+    assert!(!f());
     Ok(())
 }
 
@@ -939,11 +1084,11 @@ fn main(a: f64, b: f64) -> (c: f64) {
     let a = 100.0f64;
     let b = 100.0f64;
     let mut jit = jit::JIT::new(&[("mult", mult as *const u8), ("dbg", dbg as *const u8)]);
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     assert_eq!(mult(a, b), func(a, b));
     Ok(())
 }
@@ -954,6 +1099,109 @@ extern "C" fn prt2(s: *const i8) {
     }
 }
 
+extern "C" fn count_calls(n: i64, reset: bool) -> i64 {
+    static CALLS: std::sync::atomic::AtomicI64 = std::sync::atomic::AtomicI64::new(0);
+    if reset {
+        CALLS.store(0, std::sync::atomic::Ordering::SeqCst);
+    }
+    CALLS.fetch_add(n, std::sync::atomic::Ordering::SeqCst) + n
+}
+
+#[test]
+fn register_fn() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64, b: f64) -> (c: f64) {
+    c = mult(a, b)
+    dbg(a)
+}
+fn count() -> (n: i64) {
+    count_calls(1, true)
+    count_calls(2, false)
+    n = count_calls(3, false)
+}
+fn nested() -> (n: i64) {
+    count_calls(1, true)
+    n = count_calls(count_calls(1, false), false)
+}
+fn sum_first(arr: [f64]) -> (c: f64) {
+    c = first_two(arr)
+}
+"#;
+    extern "C" fn first_two(arr: *const f64) -> f64 {
+        unsafe { *arr + *arr.add(1) }
+    }
+    let mut jit = jit::JIT::default();
+    jit.register_fn("mult", mult as extern "C" fn(f64, f64) -> f64);
+    jit.register_fn("dbg", dbg as extern "C" fn(f64));
+    jit.register_fn(
+        "count_calls",
+        count_calls as extern "C" fn(i64, bool) -> i64,
+    );
+    jit.register_fn("first_two", first_two as extern "C" fn(*const f64) -> f64);
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let main = jit.get_typed::<(f64, f64), (f64,)>("main")?;
+    assert_eq!(main.call(3.0, 4.0), (12.0,));
+    let count = jit.get_typed::<(), (i64,)>("count")?;
+    assert_eq!(count.call(), (6,));
+    // The inner call only happens once
+    let nested = jit.get_typed::<(), (i64,)>("nested")?;
+    assert_eq!(nested.call(), (4,));
+    // A slice is passed on as a pointer to its elements
+    assert_eq!(
+        jit.call(
+            "sum_first",
+            &mut [sarus_value::SarusValue::ArrayF64(vec![1.0, 2.0, 4.0])]
+        )?,
+        vec![sarus_value::SarusValue::F64(3.0)]
+    );
+
+    // A function registered again with another signature can't be called by
+    // code compiled into the same module
+    jit.register_fn("mult", count_calls as extern "C" fn(i64, bool) -> i64);
+    let code = r#"
+fn again() -> (n: i64) {
+    n = mult(1, true)
+}
+"#;
+    let err = jit.translate(parser::program(code)?).unwrap_err();
+    assert!(err.to_string().starts_with("3:9: can't call mult: "));
+
+    // The declaration comes from the Rust signature, so the source can't disagree with it
+    let code = r#"
+fn main(a: f64, b: f64) -> (c: f64) {
+    c = mult(a, 2)
+}
+"#;
+    let mut jit = jit::JIT::default();
+    jit.register_fn("mult", mult as extern "C" fn(f64, f64) -> f64);
+    let ast = parser::program(code)?;
+    let err = jit.translate(ast).unwrap_err();
+    let diag = &err.downcast_ref::<frontend::Diagnostics>().unwrap()[0];
+    assert_eq!(
+        diag.to_string(),
+        "3:17: Type mismatch; expected f64, found i64"
+    );
+
+    let code = r#"
+extern fn mult(a: f64, b: f64) -> (c: f64) {}
+fn main(a: f64, b: f64) -> (c: f64) {
+    c = mult(a, b)
+}
+"#;
+    let mut jit = jit::JIT::default();
+    jit.register_fn("mult", mult as extern "C" fn(f64, f64) -> f64);
+    let ast = parser::program(code)?;
+    let err = jit.translate(ast).unwrap_err();
+    let diag = &err.downcast_ref::<frontend::Diagnostics>().unwrap()[0];
+    assert_eq!(
+        diag.to_string(),
+        "2:1: mult is registered with register_fn, it can't also be declared"
+    );
+    Ok(())
+}
+
 #[test]
 fn create_string() -> anyhow::Result<()> {
     let code = r#"
@@ -969,11 +1217,11 @@ extern fn print(s: &) -> () {}
     let a = 100.0f64;
     let b = 100.0f64;
     let mut jit = jit::JIT::new(&[("print", prt2 as *const u8)]);
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64, f64) -> f64>(func_ptr) };
     func(a, b);
 
     Ok(())
@@ -998,11 +1246,11 @@ fn main(a: f64) -> (c: f64) {
 "#;
     let a = 100.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
     assert_eq!(600.0, func(a));
     Ok(())
 }
@@ -1022,6 +1270,13 @@ struct Point {
 fn length(self: Point) -> (r: f64) {
     r = sqrt(pow(self.x, 2.0) + pow(self.y, 2.0) + pow(self.z, 2.0))
 }
+fn length(self: Line) -> (r: f64) {
+    d = self.b
+    d.x -= self.a.x
+    d.y -= self.a.y
+    d.z -= self.a.z
+    r = d.length()
+}
 fn main(a: f64) -> (c: f64) {
     p = Point {
         x: a,
@@ -1030,38 +1285,2495 @@ fn main(a: f64) -> (c: f64) {
     }
     c = p.length()
 }
+fn line(a: f64) -> (c: f64) {
+    p = Point {
+        x: a,
+        y: 200.0,
+        z: 300.0,
+    }
+    line = Line {
+        a: Point {
+            x: 1.0,
+            y: 2.0,
+            z: 3.0,
+        },
+        b: p,
+    }
+    p.x = 0.0
+    line.b.y += 2.0
+    line.b.z += line.a.z
+    c = line.length()
+}
+fn origin(line: Line) -> (c: f64) {
+    line.b = line.a
+    line.a = Point {
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    }
+    c = line.length()
+}
 "#;
+    #[repr(C)]
+    struct Point {
+        x: f64,
+        y: f64,
+        z: f64,
+    }
+    #[repr(C)]
+    struct Line {
+        a: Point,
+        b: Point,
+    }
     let a = 100.0f64;
     let mut jit = jit::JIT::default();
-    let ast = parser::program(&code)?;
+    let ast = parser::program(code)?;
     let ast = sarus_std_lib::append_std_funcs(ast);
     jit.translate(ast.clone())?;
     let func_ptr = jit.get_func("main")?;
-    let func = unsafe { mem::transmute::<_, extern "C" fn(f64) -> f64>(func_ptr) };
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
     assert_eq!(374.16573867739413, func(a));
+    // The line's copy of p isn't affected by changing p afterwards
+    let func_ptr = jit.get_func("line")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
+    assert_eq!(374.16573867739413, func(a + 1.0));
+    let func_ptr = jit.get_func("origin")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Line) -> f64>(func_ptr) };
+    let mut line = Line {
+        a: Point {
+            x: 2.0,
+            y: 3.0,
+            z: 6.0,
+        },
+        b: Point {
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        },
+    };
+    assert_eq!(func(&mut line), 7.0);
+    assert_eq!(line.a.x, 2.0);
+    Ok(())
+}
+
+#[test]
+fn redefine_functions() -> anyhow::Result<()> {
+    let translate = |jit: &mut jit::JIT, code: &str| -> anyhow::Result<()> {
+        let ast = parser::program(code)?;
+        let ast = sarus_std_lib::append_std_funcs(ast);
+        jit.translate(ast)
+    };
+    let mut jit = jit::JIT::default();
+    translate(
+        &mut jit,
+        r#"
+fn gain(x: f64) -> (y: f64) {
+    y = x * 2.0
+}
+fn main(x: f64) -> (y: f64) {
+    y = gain(x) + 1.0
+}
+fn other(x: f64) -> (y: f64) {
+    y = x - 1.0
+}
+"#,
+    )?;
+    // A `TypedFunc` borrows the JIT, old versions are kept as pointers
+    let main_v1 = jit.get_func("main")?;
+    let main_v1 = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(main_v1) };
+    let other_v1 = jit.get_func("other")?;
+    assert_eq!(main_v1(5.0), 11.0);
+
+    // main calls gain, so it's recompiled to call the new version
+    translate(
+        &mut jit,
+        r#"
+fn gain(x: f64) -> (y: f64) {
+    y = x * 3.0
+}
+fn main(x: f64) -> (y: f64) {
+    y = gain(x) + 1.0
+}
+fn other(x: f64) -> (y: f64) {
+    y = x - 1.0
+}
+"#,
+    )?;
+    let main_v2 = jit.get_func("main")?;
+    let main_v2 = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(main_v2) };
+    assert_eq!(main_v2(5.0), 16.0);
+    // Code from before keeps working, and calling what it was compiled with
+    assert_eq!(main_v1(5.0), 11.0);
+    // Functions that didn't change aren't compiled again
+    assert_eq!(jit.get_func("other")?, other_v1);
+
+    // The signature changes along with the caller
+    translate(
+        &mut jit,
+        r#"
+fn gain(x: f64, k: f64) -> (y: f64) {
+    y = x * k
+}
+fn main(x: f64) -> (y: f64) {
+    y = gain(x, 4.0) + 1.0
+}
+fn other(x: f64) -> (y: f64) {
+    y = x - 1.0
+}
+"#,
+    )?;
+    let main_v3 = jit.get_typed::<(f64,), (f64,)>("main")?;
+    assert_eq!(main_v3.call(5.0), (21.0,));
+    let gain = jit.get_typed::<(f64, f64), (f64,)>("gain")?;
+    assert_eq!(gain.call(5.0, 5.0), (25.0,));
+    assert_eq!(main_v2(5.0), 16.0);
+
+    // Callers are checked against the new signature, a failed translate keeps
+    // the previous program
+    let err = translate(
+        &mut jit,
+        r#"
+fn gain(x: f64) -> (y: f64) {
+    y = x * 5.0
+}
+fn main(x: f64) -> (y: f64) {
+    y = gain(x, 4.0) + 1.0
+}
+"#,
+    )
+    .unwrap_err();
+    assert!(err.downcast_ref::<frontend::Diagnostics>().is_some());
+    let main = jit.get_typed::<(f64,), (f64,)>("main")?;
+    assert_eq!(main.call(5.0), (21.0,));
+    assert_eq!(jit.get_func("other")?, other_v1);
+    Ok(())
+}
+
+// `sum` returns two values, which C only receives as a struct on System V x86-64
+#[test]
+#[cfg(all(unix, target_arch = "x86_64"))]
+fn compile_object() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+struct Line {
+    a: Point,
+    b: Point,
+}
+fn length(self: Line) -> (r: f64) {
+    r = sqrt(pow(self.b.x - self.a.x, 2.0) + pow(self.b.y - self.a.y, 2.0))
+}
+fn midpoint(line: Line) -> (p: Point) {
+    p = Point {
+        x: (line.a.x + line.b.x) * 0.5,
+        y: (line.a.y + line.b.y) * 0.5,
+    }
+}
+fn sum(arr: [f64]) -> (total: f64, n: i64) {
+    total = 0.0
+    n = 0
+    for x in arr {
+        total += x
+        n += 1
+    }
+}
+fn is_positive(a: i64) -> (b: bool) {
+    b = a > 0
+}
+"#;
+    let mut jit = jit::JIT::new_object("geometry")?;
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast)?;
+    let header = jit.c_header()?;
+    for decl in &[
+        "typedef struct Line {\n    Point a;\n    Point b;\n} Line;",
+        "double Line_length(Line* self) __asm__(\"Line.length\");",
+        "void midpoint(Point* p, Line* line);",
+        "typedef struct { double total; int64_t n; } sum_returns;",
+        "sum_returns sum(double* arr, int64_t arr_len);",
+        "bool is_positive(int64_t a);",
+    ] {
+        assert!(header.contains(decl), "{} not in\n{}", decl, header);
+    }
+
+    let dir = std::env::temp_dir().join(format!("sarus_object_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("geometry.o"), jit.emit()?)?;
+    std::fs::write(dir.join("geometry.h"), header)?;
+    std::fs::write(
+        dir.join("main.c"),
+        r#"
+#include <stdio.h>
+#include "geometry.h"
+
+void __sarus_index_out_of_bounds(int64_t index, int64_t len) {}
+
+int main(void) {
+    Line line = {{1.0, 2.0}, {4.0, 6.0}};
+    Point mid;
+    midpoint(&mid, &line);
+    double values[] = {1.0, 2.0, 3.5};
+    sum_returns s = sum(values, 3);
+    printf("%g %g %g %g %lld %d %d\n", Line_length(&line), mid.x, mid.y, s.total,
+           (long long)s.n, is_positive(3), is_positive(-1));
+    return 0;
+}
+"#,
+    )?;
+    let status = std::process::Command::new("cc")
+        .current_dir(&dir)
+        .args(["main.c", "geometry.o", "-o", "main", "-lm"])
+        .status()?;
+    assert!(status.success());
+    let output = std::process::Command::new(dir.join("main")).output()?;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(String::from_utf8(output.stdout)?, "5 2.5 4 6.5 3 1 0\n");
+    Ok(())
+}
+
+#[test]
+fn c_header_pair_returns() -> anyhow::Result<()> {
+    let code = r#"
+fn both(a: bool) -> (b: bool, c: bool) {
+    b = a
+    c = true
+}
+"#;
+    let mut jit = jit::JIT::new_object("pair")?;
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast)?;
+    // Two bools would be returned in the same register as a struct
+    let err = jit.c_header().unwrap_err().to_string();
+    assert!(
+        err.starts_with("both returns (bool, bool), which C can't receive on "),
+        "{}",
+        err
+    );
+
+    if cfg!(target_arch = "x86_64") {
+        let code = r#"
+fn pair(a: i64) -> (b: i64, c: f64) {
+    b = a
+    c = 1.0
+}
+"#;
+        let config = jit::JITConfig {
+            target: Some("x86_64-pc-windows-msvc".to_string()),
+            ..Default::default()
+        };
+        let mut jit = jit::JIT::new_object_with_config("pair", &config)?;
+        let ast = parser::program(code)?;
+        let ast = sarus_std_lib::append_std_funcs(ast);
+        jit.translate(ast)?;
+        assert_eq!(
+            jit.c_header().unwrap_err().to_string(),
+            "pair returns (int64_t, double), which C can't receive on x86_64-pc-windows-msvc"
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn jit_config() -> anyhow::Result<()> {
+    let code = r#"
+fn main(n: i64) -> (c: f64) {
+    c = 0.0
+    for i in 0..n {
+        c += float(i) * 0.5
+    }
+}
+"#;
+    let jit = jit::JIT::default();
+    assert_eq!(jit.flags().opt_level(), jit::OptLevel::None);
+    assert!(jit.flags().enable_verifier());
+
+    let config = jit::JITConfig {
+        opt_level: jit::OptLevel::Speed,
+        enable_verifier: false,
+        ..Default::default()
+    };
+    let mut jit = jit::JIT::with_config(&[], &config)?;
+    assert_eq!(jit.flags().opt_level(), jit::OptLevel::Speed);
+    assert!(!jit.flags().enable_verifier());
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast)?;
+    let main = jit.get_typed::<(i64,), (f64,)>("main")?;
+    assert_eq!(main.call(4), (3.0,));
+
+    let jit = jit::JIT::new_object_with_config("config", &config)?;
+    assert_eq!(jit.flags().opt_level(), jit::OptLevel::Speed);
+
+    let config = jit::JITConfig {
+        cpu_features: vec!["has_nonsense".to_string()],
+        ..Default::default()
+    };
+    let err = jit::JIT::with_config(&[], &config).err().unwrap();
+    assert_eq!(err.to_string(), "No existing setting named 'has_nonsense'");
+
+    let config = jit::JITConfig {
+        target: Some("not-a-target".to_string()),
+        ..Default::default()
+    };
+    assert!(jit::JIT::with_config(&[], &config).is_err());
+    assert!(jit::JIT::new_object_with_config("config", &config).is_err());
+    Ok(())
+}
+
+#[test]
+fn code_cache() -> anyhow::Result<()> {
+    let mut code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn length(self: Point) -> (r: f64) {
+    r = sqrt(self.x * self.x + self.y * self.y)
+}
+fn main(arr: [f64], i: i64) -> (c: f64, p: Point) {
+    p = Point {
+        x: arr[i],
+        y: sin(PI * 0.5),
+    }
+    c = p.length() + step_99(1.0)
+}
+fn fact(n: i64) -> (r: i64) {
+    if n <= 1 {
+        r = 1
+    } else {
+        r = n * fact(n - 1)
+    }
+}
+"#
+    .to_string();
+    // Enough functions for codegen to take a while
+    code.push_str("fn step_0(x: f64) -> (y: f64) {\n    y = x\n}\n");
+    for i in 1..100 {
+        code.push_str(&format!(
+            "fn step_{}(x: f64) -> (y: f64) {{\n    y = step_{}(x) * 1.5 + {}.0\n}}\n",
+            i,
+            i - 1,
+            i
+        ));
+    }
+    let dir = std::env::temp_dir().join(format!("sarus_cache_{}", std::process::id()));
+
+    // The second time everything is loaded from the cache. The third time the
+    // cache is corrupt, which is the same as it being empty.
+    let mut results = Vec::new();
+    for run in 0..3 {
+        if run == 2 {
+            for entry in std::fs::read_dir(&dir)? {
+                let path = entry?.path();
+                let mut bytes = std::fs::read(&path)?;
+                bytes.truncate(bytes.len() / 2);
+                bytes.extend_from_slice("\u{e9}z".as_bytes());
+                std::fs::write(&path, bytes)?;
+            }
+        }
+        let mut jit = jit::JIT::default();
+        jit.cache_dir = Some(dir.clone());
+        jit.add_math_constants()?;
+        let ast = parser::program(&code)?;
+        let ast = sarus_std_lib::append_std_funcs(ast);
+        jit.translate(ast)?;
+        let arr = [3.0, 4.0];
+        let mut values = jit.call(
+            "main",
+            &mut [
+                sarus_value::SarusValue::ArrayF64(arr.to_vec()),
+                sarus_value::SarusValue::I64(1),
+            ],
+        )?;
+        values.push(sarus_value::SarusValue::F64(
+            jit.get_typed::<(f64,), (f64,)>("step_99")?.call(2.0).0,
+        ));
+        values.push(sarus_value::SarusValue::I64(
+            jit.get_typed::<(i64,), (i64,)>("fact")?.call(5).0,
+        ));
+        results.push((values, jit.clif.len(), jit.cache_hits()));
+    }
+    // Only finished files are left behind
+    let mut files = std::fs::read_dir(&dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    files.sort();
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(files.len(), 103);
+    assert!(files.iter().all(|name| name.ends_with(".o")), "{:?}", files);
+
+    // Codegen only ran the first time and when the cache was corrupt
+    assert_eq!((results[0].1, results[0].2), (103, 0));
+    assert_eq!((results[1].1, results[1].2), (0, 103));
+    assert_eq!((results[2].1, results[2].2), (103, 0));
+    assert_eq!(results[0].0[3], sarus_value::SarusValue::I64(120));
+    assert_eq!(results[0].0, results[1].0);
+    assert_eq!(results[0].0, results[2].0);
+    Ok(())
+}
+
+#[test]
+fn code_cache_host_fn() -> anyhow::Result<()> {
+    extern "C" fn add(a: f64, b: f64) -> f64 {
+        a + b
+    }
+    let code = r#"
+fn main(a: f64, b: f64) -> (c: f64) {
+    c = combine(a, b) * 2.0
+}
+"#;
+    let dir = std::env::temp_dir().join(format!("sarus_cache_host_fn_{}", std::process::id()));
+
+    // In a later run the Rust function is somewhere else, the cached code has
+    // to call it there
+    let mut results = Vec::new();
+    for func in [mult as extern "C" fn(f64, f64) -> f64, add] {
+        let mut jit = jit::JIT::default();
+        jit.cache_dir = Some(dir.clone());
+        jit.register_fn("combine", func);
+        jit.translate(parser::program(code)?)?;
+        let main = jit.get_typed::<(f64, f64), (f64,)>("main")?;
+        results.push((main.call(3.0, 4.0), jit.clif.len()));
+    }
+    std::fs::remove_dir_all(&dir)?;
+
+    assert_eq!(results, vec![((24.0,), 1), ((14.0,), 0)]);
+    Ok(())
+}
+
+#[test]
+fn trap_handling() -> anyhow::Result<()> {
+    let code = r#"
+fn divide(a: i64, b: i64) -> (c: i64) {
+    c = a / b
+}
+fn divide_assign(a: i64, b: i64) -> (c: i64) {
+    c = a
+    c /= b
+}
+fn get(arr: [f64], i: i64) -> (x: f64) {
+    x = arr[i]
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+
+    let divide = jit.get_typed::<(i64, i64), (i64,)>("divide")?;
+    assert_eq!(divide.try_call(7, 2)?, (3,));
+    // Trapping more than once works, the signal isn't left blocked
+    for _ in 0..2 {
+        let trap = divide.try_call(7, 0).unwrap_err();
+        assert_eq!(
+            trap.kind,
+            trap::TrapKind::Code(trap::TrapCode::IntegerDivisionByZero)
+        );
+        assert_eq!(trap.function.as_deref(), Some("divide"));
+        assert!(trap
+            .to_string()
+            .starts_with("trap int_divz in divide at offset "));
+    }
+    // The result doesn't fit in an i64, which traps the same way on every target
+    let trap = divide.try_call(i64::MIN, -1).unwrap_err();
+    assert_eq!(
+        trap.kind,
+        trap::TrapKind::Code(trap::TrapCode::IntegerOverflow)
+    );
+    let divide_assign = jit.get_typed::<(i64, i64), (i64,)>("divide_assign")?;
+    assert_eq!(divide_assign.try_call(i64::MIN, 1)?, (i64::MIN,));
+    let trap = divide_assign.try_call(i64::MIN, -1).unwrap_err();
+    assert_eq!(
+        trap.kind,
+        trap::TrapKind::Code(trap::TrapCode::IntegerOverflow)
+    );
+
+    let mut args = [
+        sarus_value::SarusValue::ArrayF64(vec![1.0, 2.0]),
+        sarus_value::SarusValue::I64(5),
+    ];
+    let err = jit.call("get", &mut args).unwrap_err();
+    let trap = err.downcast_ref::<trap::Trap>().unwrap();
+    assert_eq!(
+        trap.kind,
+        trap::TrapKind::IndexOutOfBounds { index: 5, len: 2 }
+    );
+    assert_eq!(trap.function.as_deref(), Some("get"));
+    assert!(trap
+        .to_string()
+        .starts_with("index out of bounds: the len is 2 but the index is 5 in get at offset "));
+    args[1] = sarus_value::SarusValue::I64(1);
+    assert_eq!(
+        jit.call("get", &mut args)?,
+        vec![sarus_value::SarusValue::F64(2.0)]
+    );
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn fault_in_host_fn() -> anyhow::Result<()> {
+    use std::os::unix::process::ExitStatusExt;
+    extern "C" fn read(addr: i64) -> i64 {
+        unsafe { std::ptr::read_volatile(addr as *const i64) }
+    }
+    // Faults in Rust code aren't caught, so this ends the process it runs in
+    if std::env::var_os("SARUS_FAULT_IN_HOST_FN").is_some() {
+        let code = r#"
+fn main(a: i64) -> (c: i64) {
+    c = read(a)
+}
+"#;
+        let mut jit = jit::JIT::default();
+        jit.register_fn("read", read as extern "C" fn(i64) -> i64);
+        jit.translate(parser::program(code)?)?;
+        let main = jit.get_typed::<(i64,), (i64,)>("main")?;
+        // Not null, which debug builds check for before reading
+        let _ = main.try_call(8);
+        return Ok(());
+    }
+    let output = std::process::Command::new(std::env::current_exe()?)
+        .args(["--exact", "fault_in_host_fn"])
+        .env("SARUS_FAULT_IN_HOST_FN", "1")
+        .output()?;
+    assert_eq!(output.status.signal(), Some(libc::SIGSEGV));
     Ok(())
 }
 
-//#[test]
-//fn int_min_max() -> anyhow::Result<()> {
-//    //Not currently working: Unsupported type for imin instruction: i64
-//    //https://github.com/bytecodealliance/wasmtime/issues/3370
-//    let code = r#"
-//    fn main() -> (e) {
-//        c = imin(1, 2)
-//        //d = imax(3, 4)
-//        //f = c * d
-//        e = float(c)
-//    }
-//"#;
-//    let a = 100.0f64;
-//    let b = 100.0f64;
-//    let mut jit = jit::JIT::new(&[("print", prt2 as *const u8)]);
-//    let ast = parser::program(&code)?;
-//    let ast = sarus_std_lib::append_std_funcs(ast);
-//    jit.translate(ast.clone())?;
-//    let func_ptr = jit.get_func("main")?;
-//    let func = unsafe { mem::transmute::<_, extern "C" fn(f64, f64) -> f64>(func_ptr) };
-//    func(a, b);
-//    Ok(())
-//}
+#[cfg(unix)]
+#[test]
+fn stack_overflow() -> anyhow::Result<()> {
+    let code = r#"
+fn deep(n: i64) -> (c: i64) {
+    c = deep(n + 1) + 1
+}
+"#;
+    let thread = std::thread::spawn(move || -> anyhow::Result<trap::Trap> {
+        // Like a thread started outside of Rust, which has no alternate stack
+        // to handle signals on
+        unsafe {
+            let disable = libc::stack_t {
+                ss_sp: std::ptr::null_mut(),
+                ss_flags: libc::SS_DISABLE,
+                ss_size: 0,
+            };
+            libc::sigaltstack(&disable, std::ptr::null_mut());
+        }
+        let mut jit = jit::JIT::default();
+        jit.translate(parser::program(code)?)?;
+        let deep = jit.get_typed::<(i64,), (i64,)>("deep")?;
+        Ok(deep.try_call(0).unwrap_err())
+    });
+    let trap = thread.join().unwrap()?;
+    assert!(matches!(
+        trap.kind,
+        trap::TrapKind::Code(trap::TrapCode::StackOverflow)
+            | trap::TrapKind::Signal(libc::SIGSEGV)
+            | trap::TrapKind::Signal(libc::SIGBUS)
+    ));
+    assert_eq!(trap.function.as_deref(), Some("deep"));
+    Ok(())
+}
+
+#[test]
+fn execution_fuel() -> anyhow::Result<()> {
+    let code = r#"
+fn spin(n: i64) -> (c: i64) {
+    c = 0
+    while c < n {
+        c += 1
+    }
+}
+fn count(n: i64) -> (c: i64) {
+    c = 0
+    for i in 0..n {
+        c += 1
+    }
+}
+fn forever() -> () {
+    for i in 0..9223372036854775807 {}
+}
+fn fib(n: i64) -> (c: i64) {
+    c = if n < 2 {
+        n
+    } else {
+        fib(n - 1) + fib(n - 2)
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    jit.consume_fuel = true;
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+
+    jit.set_fuel(100)?;
+    let spin = jit.get_typed::<(i64,), (i64,)>("spin")?;
+    // The call, and checking the condition 11 times
+    assert_eq!(spin.try_call(10)?, (10,));
+    assert_eq!(jit.fuel(), 88);
+    let trap = spin.try_call(1_000_000_000).unwrap_err();
+    assert_eq!(trap.kind, trap::TrapKind::OutOfFuel);
+    assert_eq!(trap.function.as_deref(), Some("spin"));
+    assert_eq!(jit.fuel(), -1);
+
+    // For loops use fuel the same way
+    jit.set_fuel(100)?;
+    let count = jit.get_typed::<(i64,), (i64,)>("count")?;
+    assert_eq!(count.try_call(10)?, (10,));
+    assert_eq!(jit.fuel(), 88);
+    let forever = jit.get_typed::<(), ()>("forever")?;
+    let trap = forever.try_call().unwrap_err();
+    assert_eq!(trap.kind, trap::TrapKind::OutOfFuel);
+    assert_eq!(jit.fuel(), -1);
+
+    jit.set_fuel(1000)?;
+    let err = jit
+        .call("fib", &mut [sarus_value::SarusValue::I64(30)])
+        .unwrap_err();
+    assert_eq!(err.to_string().split(" in ").next(), Some("out of fuel"));
+    jit.set_fuel(1000)?;
+    assert_eq!(
+        jit.call("fib", &mut [sarus_value::SarusValue::I64(10)])?,
+        vec![sarus_value::SarusValue::I64(55)]
+    );
+    assert_eq!(jit.fuel(), 1000 - 177);
+
+    // Turning it off compiles everything again, without the instrumentation
+    jit.consume_fuel = false;
+    jit.translate(ast.clone())?;
+    let spin = jit.get_typed::<(i64,), (i64,)>("spin")?;
+    assert_eq!(spin.call(1_000_000), (1_000_000,));
+    assert_eq!(jit.fuel(), 1000 - 177);
+    Ok(())
+}
+
+#[test]
+fn interpreter() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn scale(self: Point, s: f64) -> () {
+    self.x *= s
+    self.y *= s
+}
+fn midpoint(a: Point, b: Point) -> (c: Point) {
+    c = Point {
+        x: (a.x + b.x) / 2.0,
+        y: (a.y + b.y) / 2.0,
+    }
+    a.x = 100.0
+}
+fn main(n: i64) -> (c: f64, p: Point) {
+    a = Point {
+        x: 1.0,
+        y: 2.0,
+    }
+    b = Point {
+        x: 3.0,
+        y: 4.0,
+    }
+    p = midpoint(a, b)
+    c = a.x
+    p.scale(2.0)
+    for i in 0..n {
+        c += mult(float(i), 2.0)
+    }
+}
+fn divide(a: i64, b: i64) -> (c: i64) {
+    c = a / b
+}
+fn spin(n: i64) -> (c: i64) {
+    c = 0
+    while c < n {
+        c += 1
+    }
+}
+fn forever() -> () {
+    for i in 0..9223372036854775807 {}
+}
+fn get(arr: [f64], i: i64) -> (x: f64) {
+    x = arr[i]
+}
+"#;
+    let mut interpreter = interpreter::Interpreter::default();
+    interpreter.register_fn("mult", mult as extern "C" fn(f64, f64) -> f64);
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    interpreter.load(ast)?;
+
+    let returns = interpreter.call("main", &mut [sarus_value::SarusValue::I64(4)])?;
+    // `midpoint` changed its own copy of `a`
+    assert_eq!(returns[0], sarus_value::SarusValue::F64(13.0));
+    let point = interpreter.get_struct("Point")?;
+    let bytes = match &returns[1] {
+        sarus_value::SarusValue::Struct(name, bytes) if name == "Point" => bytes,
+        v => panic!("expected a Point, found {:?}", v),
+    };
+    let field = |name: &str| {
+        let offset = point.offset_of(name).unwrap() as usize;
+        f64::from_ne_bytes(bytes[offset..offset + 8].try_into().unwrap())
+    };
+    assert_eq!((field("x"), field("y")), (4.0, 6.0));
+
+    let err = interpreter
+        .call(
+            "divide",
+            &mut [
+                sarus_value::SarusValue::I64(1),
+                sarus_value::SarusValue::I64(0),
+            ],
+        )
+        .unwrap_err();
+    let trap = err.downcast_ref::<trap::Trap>().unwrap();
+    assert_eq!(
+        trap.kind,
+        trap::TrapKind::Code(trap::TrapCode::IntegerDivisionByZero)
+    );
+    assert_eq!(trap.to_string(), "trap int_divz in divide");
+    let err = interpreter
+        .call(
+            "divide",
+            &mut [
+                sarus_value::SarusValue::I64(i64::MIN),
+                sarus_value::SarusValue::I64(-1),
+            ],
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<trap::Trap>().unwrap().kind,
+        trap::TrapKind::Code(trap::TrapCode::IntegerOverflow)
+    );
+    let err = interpreter
+        .call(
+            "get",
+            &mut [
+                sarus_value::SarusValue::ArrayF64(vec![1.0, 2.0]),
+                sarus_value::SarusValue::I64(-1),
+            ],
+        )
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<trap::Trap>().unwrap().to_string(),
+        "index out of bounds: the len is 2 but the index is -1 in get"
+    );
+
+    interpreter.consume_fuel = true;
+    interpreter.set_fuel(100);
+    interpreter.call("spin", &mut [sarus_value::SarusValue::I64(10)])?;
+    assert_eq!(interpreter.fuel(), 88);
+    let err = interpreter
+        .call("spin", &mut [sarus_value::SarusValue::I64(1000)])
+        .unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<trap::Trap>().unwrap().kind,
+        trap::TrapKind::OutOfFuel
+    );
+    interpreter.set_fuel(100);
+    let err = interpreter.call("forever", &mut []).unwrap_err();
+    assert_eq!(
+        err.downcast_ref::<trap::Trap>().unwrap().kind,
+        trap::TrapKind::OutOfFuel
+    );
+    Ok(())
+}
+
+/// The size of each struct, and the offset, size and type of its fields.
+type Layouts = HashMap<String, (u32, Vec<(u32, u32, validator::ExprType)>)>;
+
+/// Whether two results are the same, allowing for the last bits of floats
+/// calculated by different libm functions.
+fn same_value(a: &sarus_value::SarusValue, b: &sarus_value::SarusValue, structs: &Layouts) -> bool {
+    use sarus_value::SarusValue;
+    let same_f64 = |a: f64, b: f64| {
+        a == b || (a.is_nan() && b.is_nan()) || (a - b).abs() <= 1e-12 * a.abs().max(b.abs())
+    };
+    match (a, b) {
+        (SarusValue::F64(a), SarusValue::F64(b)) => same_f64(*a, *b),
+        (SarusValue::ArrayF64(a), SarusValue::ArrayF64(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_f64(*a, *b))
+        }
+        // Padding between fields isn't compared, it's left uninitialized
+        (SarusValue::Struct(a_name, a), SarusValue::Struct(b_name, b)) => {
+            a_name == b_name && same_struct(a_name, a, b, structs)
+        }
+        (a, b) => a == b,
+    }
+}
+
+fn same_struct(name: &str, a: &[u8], b: &[u8], structs: &Layouts) -> bool {
+    structs[name].1.iter().all(|(offset, size, expr_type)| {
+        let range = *offset as usize..(offset + size) as usize;
+        match expr_type {
+            validator::ExprType::Struct(inner) => {
+                same_struct(inner, &a[range.clone()], &b[range], structs)
+            }
+            validator::ExprType::F64 => {
+                let a = f64::from_ne_bytes(a[range.clone()].try_into().unwrap());
+                let b = f64::from_ne_bytes(b[range].try_into().unwrap());
+                same_value(
+                    &sarus_value::SarusValue::F64(a),
+                    &sarus_value::SarusValue::F64(b),
+                    structs,
+                )
+            }
+            validator::ExprType::Bool => (a[range.start] != 0) == (b[range.start] != 0),
+            _ => a[range.clone()] == b[range],
+        }
+    })
+}
+
+/// Only structs of plain values can be made up as arguments.
+fn plain_struct(name: &str, structs: &Layouts) -> bool {
+    structs[name]
+        .1
+        .iter()
+        .all(|(_, _, expr_type)| match expr_type {
+            validator::ExprType::F64 | validator::ExprType::I64 | validator::ExprType::Bool => true,
+            validator::ExprType::Struct(inner) => plain_struct(inner, structs),
+            _ => false,
+        })
+}
+
+/// Functions the programs in this file get from the host.
+const HOST_FNS: [&str; 7] = [
+    "mult",
+    "dbg",
+    "count_calls",
+    "first_two",
+    "combine",
+    "read",
+    "print",
+];
+
+/// Stand-ins for the functions in `HOST_FNS` that don't print, read anywhere
+/// or keep count across tests.
+fn register_host_fns(jit: &mut jit::JIT, interpreter: &mut interpreter::Interpreter) {
+    extern "C" fn quiet(_: f64) {}
+    extern "C" fn count(n: i64, reset: bool) -> i64 {
+        n + reset as i64
+    }
+    extern "C" fn first_two(arr: *const f64) -> f64 {
+        unsafe { *arr + *arr.add(1) }
+    }
+    extern "C" fn read(addr: i64) -> i64 {
+        addr * 2
+    }
+    extern "C" fn print(_: *const i8) {}
+    macro_rules! register {
+        ($($name:literal => $func:expr),*) => {
+            $(
+                jit.register_fn($name, $func);
+                interpreter.register_fn($name, $func);
+            )*
+        };
+    }
+    register!(
+        "mult" => mult as extern "C" fn(f64, f64) -> f64,
+        "dbg" => quiet as extern "C" fn(f64),
+        "count_calls" => count as extern "C" fn(i64, bool) -> i64,
+        "first_two" => first_two as extern "C" fn(*const f64) -> f64,
+        "combine" => mult as extern "C" fn(f64, f64) -> f64,
+        "read" => read as extern "C" fn(i64) -> i64,
+        "print" => print as extern "C" fn(*const i8)
+    );
+}
+
+/// The first error of each program in this file that isn't meant to compile
+/// on its own.
+const EXPECTED_ERRORS: [&str; 20] = [
+    // Calls host functions with the wrong types
+    "3:14: Type mismatch; expected f64, found i64",
+    "3:17: Type mismatch; expected f64, found i64",
+    "6:9: Tuple length mismatch; expected 1 found 2",
+    // A graph, not a program
+    "2:1: expected one of \"@\", \"extern\", \"fn\", \"struct\"",
+    // Completed by the code cache test
+    "14:22: Function \"step_99\" does not exist",
+    // Diagnostics
+    "4:5: expected one of \"!\", \"(\", \"*\", \"*=\", \"+=\", \",\", \"-=\", \"/=\", \"=\", \"[\", \"\\\"\", \"break\", \"continue\", \"false\", \"for\", \"if\", \"return\", \"true\", \"while\", \"{\", \"}\", ['-'], ['0'..='9'], identifier",
+    "4:1: expected one of \"!\", \"(\", \"*\", \"[\", \"\\\"\", \"false\", \"true\", ['-'], ['0'..='9'], identifier",
+    "4:13: Type mismatch; expected f64, found i64",
+    "3:13: expected one of \"!\", \"(\", \"*\", \"[\", \"\\\"\", \"false\", \"true\", ['-'], ['0'..='9'], identifier",
+    "3:9: Type mismatch; expected f64, found i64",
+    "6:19: Type mismatch; expected f64, found i64",
+    "8:12: Type mismatch; expected f64, found i64",
+    "3:5: Type mismatch; expected f64, found i64",
+    "5:9: \"break\" outside of a loop",
+    "4:14: Type mismatch; can't iterate over &[f64], use a range like 0..n or arr[0..n]",
+    "7:9: Type mismatch; expected i64, found f64",
+    "4:15: Conflicting types for \"a\" in \"scale\"; inferred i64 at 3:9, found f64",
+    "3:13: Type mismatch; len expects a slice like [f64], found &[f64]",
+    "11:5: Struct \"Point\" does not have field \"z\"",
+    "3:9: Type mismatch; can't tell the type of an empty array",
+];
+
+/// Run every program in this file with both the JIT and the interpreter,
+/// calling each function that can be given made up arguments, and check they
+/// return the same values, write the same to their arguments, trap the same
+/// way, and use the same amount of fuel.
+#[test]
+fn interpreter_matches_jit() {
+    // Deep recursion takes a lot more stack when interpreted
+    std::thread::Builder::new()
+        .stack_size(1 << 29)
+        .spawn(compare_backends)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+fn compare_backends() {
+    use sarus_value::SarusValue;
+    use validator::ExprType;
+    const FUEL: i64 = 10_000;
+
+    let source = include_str!("integration_test.rs");
+    let mut compared = 0;
+    let mut errors = Vec::new();
+    let mut expect_error = |error: &str, code: &str| {
+        let first = error.lines().next().unwrap_or_default().to_string();
+        assert!(
+            EXPECTED_ERRORS.contains(&first.as_str()) && !errors.contains(&first),
+            "unexpected error {:?} in\n{}",
+            error,
+            code
+        );
+        errors.push(first);
+    };
+    for code in source.split("r#\"").skip(1) {
+        let code = match code.find("\"#") {
+            Some(end) => &code[..end],
+            None => continue,
+        };
+        let ast = match parser::program(code) {
+            Ok(ast) => ast,
+            Err(e) => {
+                expect_error(&e.to_string(), code);
+                continue;
+            }
+        };
+        // Host functions are registered on both backends instead of declared
+        let mut ast = ast
+            .into_iter()
+            .filter(|d| {
+                !matches!(d, frontend::Declaration::Function(f)
+                    if f.extern_func && HOST_FNS.contains(&f.name.as_str()))
+            })
+            .collect::<Vec<_>>();
+        // Structs derived in Rust are only appended by the tests using them
+        let declared = |name: &str| {
+            ast.iter()
+                .any(|d| matches!(d, frontend::Declaration::Struct(s) if s.name == name))
+        };
+        if !declared("Settings") && !declared("Voice") {
+            ast = sarus_struct::append_struct::<Settings>(ast);
+            ast = sarus_struct::append_struct::<Voice>(ast);
+        }
+        let ast = sarus_std_lib::append_std_funcs(ast);
+        let mut jit = jit::JIT::default();
+        jit.consume_fuel = true;
+        jit.add_math_constants().unwrap();
+        let mut interpreter = interpreter::Interpreter::default();
+        interpreter.consume_fuel = true;
+        register_host_fns(&mut jit, &mut interpreter);
+        if let Err(e) = jit.translate(ast.clone()) {
+            expect_error(&e.to_string(), code);
+            continue;
+        }
+        interpreter.load(ast.clone()).unwrap();
+
+        let mut funcs = ast
+            .iter()
+            .filter_map(|d| match d {
+                frontend::Declaration::Function(f) if !f.extern_func => Some(f.name.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        funcs.sort();
+        let struct_names = ast.iter().filter_map(|d| match d {
+            frontend::Declaration::Struct(s) => Some(s.name.clone()),
+            _ => None,
+        });
+        let structs = struct_names
+            .map(|name| {
+                let struct_def = jit.get_struct(&name).unwrap();
+                let fields = struct_def
+                    .fields
+                    .values()
+                    .map(|f| (f.offset, f.size, f.expr_type.clone()))
+                    .collect();
+                (name, (struct_def.size, fields))
+            })
+            .collect::<Layouts>();
+
+        'funcs: for fn_name in funcs {
+            // The JIT fills in the types that were left out
+            let mut ast = ast.clone();
+            inference::infer_types(&mut ast).unwrap();
+            let func = ast
+                .iter()
+                .find_map(|d| match d {
+                    frontend::Declaration::Function(f) if f.name == fn_name => Some(f.clone()),
+                    _ => None,
+                })
+                .unwrap();
+            let mut args = Vec::new();
+            for (i, param) in func.params.iter().enumerate() {
+                args.push(match param.expr_type.as_ref().unwrap() {
+                    ExprType::F64 => SarusValue::F64(2.5 + i as f64),
+                    ExprType::I64 => SarusValue::I64(3 + i as i64),
+                    ExprType::Bool => SarusValue::Bool(i % 2 == 0),
+                    ExprType::Slice(elem) if **elem == ExprType::F64 => {
+                        SarusValue::ArrayF64((1..=8).map(|x| x as f64 * 0.5).collect())
+                    }
+                    ExprType::Slice(elem) if **elem == ExprType::I64 => {
+                        SarusValue::ArrayI64((1..=8).collect())
+                    }
+                    ExprType::Struct(name) if plain_struct(name, &structs) => SarusValue::Struct(
+                        name.to_string(),
+                        vec![0; structs[&name.to_string()].0 as usize],
+                    ),
+                    _ => continue 'funcs,
+                });
+            }
+            for ret in &func.returns {
+                match ret.expr_type.as_ref().unwrap() {
+                    ExprType::F64 | ExprType::I64 | ExprType::Bool | ExprType::Struct(_) => (),
+                    _ => continue 'funcs,
+                }
+            }
+
+            let mut interpreted_args = args.clone();
+            interpreter.set_fuel(FUEL);
+            let interpreted = interpreter.call(&fn_name, &mut interpreted_args);
+            let mut compiled_args = args.clone();
+            jit.set_fuel(FUEL).unwrap();
+            let compiled = jit.call(&fn_name, &mut compiled_args);
+
+            let context = format!("{} in\n{}", fn_name, code);
+            match (&compiled, &interpreted) {
+                (Ok(compiled), Ok(interpreted)) => {
+                    assert_eq!(compiled.len(), interpreted.len(), "{}", context);
+                    for (a, b) in compiled.iter().zip(interpreted) {
+                        assert!(
+                            same_value(a, b, &structs),
+                            "{:?} != {:?} from {}",
+                            a,
+                            b,
+                            context
+                        );
+                    }
+                }
+                (Err(compiled), Err(interpreted)) => assert_eq!(
+                    compiled.downcast_ref::<trap::Trap>().map(|t| &t.kind),
+                    interpreted.downcast_ref::<trap::Trap>().map(|t| &t.kind),
+                    "{} != {} from {}",
+                    compiled,
+                    interpreted,
+                    context
+                ),
+                _ => panic!(
+                    "{:?} != {:?} from {}",
+                    compiled.map_err(|e| e.to_string()),
+                    interpreted.map_err(|e| e.to_string()),
+                    context
+                ),
+            }
+            for (a, b) in compiled_args.iter().zip(&interpreted_args) {
+                assert!(
+                    same_value(a, b, &structs),
+                    "argument {:?} != {:?} after {}",
+                    a,
+                    b,
+                    context
+                );
+            }
+            assert_eq!(
+                jit.fuel(),
+                interpreter.fuel(),
+                "fuel left after {}",
+                context
+            );
+            compared += 1;
+        }
+    }
+    assert_eq!(
+        errors.len(),
+        EXPECTED_ERRORS.len(),
+        "expected errors not seen"
+    );
+    // Every function that can be given made up arguments
+    assert!(compared >= 141, "only {} functions were compared", compared);
+}
+
+#[test]
+fn parse_error_location() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    c = a +
+}
+"#;
+    let err = parser::program(code).unwrap_err();
+    assert_eq!(err.len(), 1);
+    assert_eq!(err[0].span.line, 4);
+    assert_eq!(err[0].span.col, 1);
+    Ok(())
+}
+
+#[test]
+fn type_error_location() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    b = 2.0
+    c = a + b * 1
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    let err = jit.translate(ast.clone()).unwrap_err();
+    let diag = &err.downcast_ref::<frontend::Diagnostics>().unwrap()[0];
+    assert_eq!(diag.span.line, 4);
+    assert_eq!(diag.span.col, 13);
+    assert_eq!(
+        diag.render(code),
+        "error: Type mismatch; expected f64, found i64
+ --> 4:13
+  |
+4 |     c = a + b * 1
+  |             ^^^^^
+"
+    );
+    Ok(())
+}
+
+#[test]
+fn multiple_parse_errors() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    b = a * )
+    if a > 1.0 {
+        c = ( b
+    }
+    c = b
+}
+fn other(a: f64 -> (c: f64) {
+    c = a
+}
+fn last(a: f64) -> (c: f64) {
+    c = a ++ 1.0
+}
+"#;
+    let err = parser::program(code).unwrap_err();
+    let lines = err.iter().map(|d| d.span.line).collect::<Vec<_>>();
+    assert_eq!(lines, vec![3, 6, 9, 13]);
+    Ok(())
+}
+
+#[test]
+fn multiple_type_errors() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    b = a + 1
+    c = a + true
+}
+fn other(a: f64) -> (c: f64) {
+    c = missing(a)
+}
+fn bodies(a: f64) -> (c: f64) {
+    c = a
+    while c < 10.0 {
+        c = c + 1
+        c = c * true
+    }
+    if c > 1.0 {
+        c = missing(c)
+    } else {
+        c = c + 2
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    let err = jit.translate(ast.clone()).unwrap_err();
+    let err = err.downcast_ref::<frontend::Diagnostics>().unwrap();
+    let lines = err.iter().map(|d| d.span.line).collect::<Vec<_>>();
+    // Every statement in a body is checked, not just up to the first error
+    assert_eq!(lines, vec![3, 4, 7, 12, 13, 16, 18]);
+    Ok(())
+}
+
+fn type_errors(code: &str) -> Vec<String> {
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code).unwrap();
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    let err = jit.translate(ast).unwrap_err();
+    err.downcast_ref::<frontend::Diagnostics>()
+        .unwrap()
+        .iter()
+        .map(|d| d.to_string())
+        .collect()
+}
+
+#[test]
+fn call_arg_types() -> anyhow::Result<()> {
+    let code = r#"
+fn takes_f64(a: f64, b: i64) -> (c: f64) {
+    c = a
+}
+fn main(a: f64) -> (c: f64) {
+    c = takes_f64(1, 2)
+    d = takes_f64(a, 2.0)
+    e = sin(1)
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "6:19: Type mismatch; expected f64, found i64",
+            "7:22: Type mismatch; expected i64, found f64",
+            "8:13: Type mismatch; expected f64, found i64",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn struct_literal_fields() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn main(a: f64) -> (c: f64) {
+    p1 = Point {
+        x: 1,
+        y: a,
+    }
+    p2 = Point {
+        x: a,
+    }
+    p3 = Point {
+        x: a,
+        y: a,
+        z: a,
+    }
+    p4 = Point {
+        x: a,
+        x: a,
+        y: a,
+    }
+    c = a
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "8:12: Type mismatch; expected f64, found i64",
+            "11:10: Struct \"Point\" is missing field \"y\"",
+            "17:9: Struct \"Point\" does not have field \"z\"",
+            "21:9: Field \"x\" of struct \"Point\" is specified more than once",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn assignment_types() -> anyhow::Result<()> {
+    let code = r#"
+fn main(arr: &[f64], i: i64) -> (c: f64) {
+    c = 1
+    arr[0] = i
+    d = true
+    d = 1.0
+    if d {
+        e = arr[i] + i
+    }
+    c += i
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "3:5: Type mismatch; expected f64, found i64",
+            "4:14: Type mismatch; expected f64, found i64",
+            "6:5: Type mismatch; expected bool, found f64",
+            "8:13: Type mismatch; expected f64, found i64",
+            "10:5: Type mismatch; expected f64, found i64",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn infer_types() -> anyhow::Result<()> {
+    let code = r#"
+fn add_node(a, b) -> (c) {
+    c = a + b
+}
+fn scale(x, amount) -> (y) {
+    y = x * amount
+}
+fn main(a: i64, b: f64) -> (c, d) {
+    c = add_node(a, 2)
+    d = scale(b, float(add_node(1, 3)))
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("add_node")?;
+    let add_node = unsafe { mem::transmute::<*const u8, extern "C" fn(i64, i64) -> i64>(func_ptr) };
+    assert_eq!(add_node(3, 4), 7);
+    let func_ptr = jit.get_func("main")?;
+    let func =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(i64, f64) -> (i64, f64)>(func_ptr) };
+    assert_eq!(func(5, 2.0), (7, 8.0));
+    Ok(())
+}
+
+#[test]
+fn typed_funcs() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn add_node(a, b) -> (c) {
+    c = a + b
+}
+fn main(a: i64, b: f64) -> (c, d) {
+    c = add_node(a, 2)
+    d = b * 2.0
+}
+fn sum(arr: [f64]) -> (c: f64) {
+    c = 0.0
+    for x in arr {
+        c += x
+    }
+}
+fn new(x: f64, y: f64) -> (p: Point) {
+    p = Point {
+        x: x,
+        y: y,
+    }
+}
+fn is_origin(p: Point) -> (b: bool) {
+    b = p.x == 0.0 && p.y == 0.0
+}
+fn both(a: bool) -> (b: bool, c: bool) {
+    b = a
+    c = true
+}
+"#;
+    #[repr(C)]
+    #[derive(SarusStruct)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let add_node = jit.get_typed::<(i64, i64), (i64,)>("add_node")?;
+    assert_eq!(add_node.call(3, 4), (7,));
+    let main = jit.get_typed::<(i64, f64), (i64, f64)>("main")?;
+    assert_eq!(main.call(5, 2.0), (7, 4.0));
+    let both = jit.get_typed::<(bool,), (bool, bool)>("both")?;
+    assert_eq!(both.call(false), (false, true));
+    assert_eq!(both.try_call(true)?, (true, true));
+    let sum = jit.get_typed::<(*const f64, i64), (f64,)>("sum")?;
+    let arr = [1.0, 2.0, 3.0];
+    assert_eq!(
+        unsafe { sum.call_unchecked(arr.as_ptr(), arr.len() as i64) },
+        (6.0,)
+    );
+    let new = jit.get_typed::<(*mut Point, f64, f64), ()>("new")?;
+    let mut p = Point { x: 0.0, y: 0.0 };
+    unsafe { new.call_unchecked(&mut p, 1.0, 2.0) };
+    assert_eq!((p.x, p.y), (1.0, 2.0));
+    let is_origin = jit.get_typed::<(*const Point,), (bool,)>("is_origin")?;
+    assert_eq!(unsafe { is_origin.try_call_unchecked(&p)? }, (false,));
+
+    let err = |e: anyhow::Error| e.to_string();
+    assert_eq!(
+        jit.get_typed::<(f64, f64), (f64,)>("add_node")
+            .map(|_| ())
+            .map_err(err),
+        Err("function add_node is (i64, i64) -> (i64) but was used as (f64, f64) -> (f64)".into())
+    );
+    assert_eq!(
+        jit.get_typed::<(i64, f64), (i64,)>("main")
+            .map(|_| ())
+            .map_err(err),
+        Err("function main is (i64, f64) -> (i64, f64) but was used as (i64, f64) -> (i64)".into())
+    );
+    assert_eq!(
+        jit.get_typed::<(*const f64,), (f64,)>("sum")
+            .map(|_| ())
+            .map_err(err),
+        Err("function sum is (&[f64], i64) -> (f64) but was used as (&[f64]) -> (f64)".into())
+    );
+    assert_eq!(
+        jit.get_typed::<(f64, f64), ()>("new")
+            .map(|_| ())
+            .map_err(err),
+        Err("function new is (Point, f64, f64) -> () but was used as (f64, f64) -> ()".into())
+    );
+    assert!(jit.get_typed::<(), ()>("missing").is_err());
+    Ok(())
+}
+
+#[test]
+fn dynamic_call() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn main(a: i64, b: f64, c: bool) -> (d: i64, e: f64) {
+    d = a * 2
+    e = b
+    if c {
+        e = 0.0 - b
+    }
+}
+fn not(a: bool) -> (b: bool) {
+    b = !a
+}
+fn scale(arr: [f64], amount: f64) -> () {
+    for i in 0..len(arr) {
+        arr[i] = arr[i] * amount
+    }
+}
+fn fill(arr: &[i64], n: i64) -> () {
+    i = 0
+    while i < n {
+        arr[i] = i
+        i += 1
+    }
+}
+fn swap(p: Point) -> (q: Point) {
+    q = Point {
+        x: p.y,
+        y: p.x,
+    }
+}
+fn shift(self: Point, dx: f64) -> () {
+    self.x += dx
+}
+fn shifted(p: Point, dx: f64) -> (x: f64) {
+    p.x += dx
+    x = p.x
+}
+"#;
+    use sarus_value::SarusValue::*;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    assert_eq!(
+        jit.call("main", &mut [I64(3), F64(1.5), Bool(true)])?,
+        vec![I64(6), F64(-1.5)]
+    );
+    assert_eq!(
+        jit.call("main", &mut [I64(3), F64(1.5), Bool(false)])?,
+        vec![I64(6), F64(1.5)]
+    );
+    assert_eq!(jit.call("not", &mut [Bool(true)])?, vec![Bool(false)]);
+    assert_eq!(jit.call("not", &mut [Bool(false)])?, vec![Bool(true)]);
+
+    let mut args = [ArrayF64(vec![1.0, 2.0, 3.0]), F64(2.0)];
+    assert_eq!(jit.call("scale", &mut args)?, vec![]);
+    assert_eq!(args[0], ArrayF64(vec![2.0, 4.0, 6.0]));
+    let mut args = [ArrayI64(vec![9; 4]), I64(3)];
+    jit.call("fill", &mut args)?;
+    assert_eq!(args[0], ArrayI64(vec![0, 1, 2, 9]));
+
+    let point = |x: f64, y: f64| {
+        let mut bytes = x.to_ne_bytes().to_vec();
+        bytes.extend_from_slice(&y.to_ne_bytes());
+        Struct("Point".to_string(), bytes)
+    };
+    assert_eq!(
+        jit.call("swap", &mut [point(1.0, 2.0)])?,
+        vec![point(2.0, 1.0)]
+    );
+    // Only writes through self make it back to the caller
+    let mut args = [point(1.0, 2.0), F64(3.0)];
+    jit.call("Point.shift", &mut args)?;
+    assert_eq!(args[0], point(4.0, 2.0));
+    let mut args = [point(1.0, 2.0), F64(3.0)];
+    assert_eq!(jit.call("shifted", &mut args)?, vec![F64(4.0)]);
+    assert_eq!(args[0], point(1.0, 2.0));
+
+    let err = |r: anyhow::Result<Vec<sarus_value::SarusValue>>| r.unwrap_err().to_string();
+    assert_eq!(
+        err(jit.call("main", &mut [I64(3), F64(1.5)])),
+        "function main takes 3 arguments but 2 were given"
+    );
+    assert_eq!(
+        err(jit.call("main", &mut [F64(3.0), F64(1.5), Bool(true)])),
+        "argument a of main should be i64, found f64"
+    );
+    assert_eq!(
+        err(jit.call("scale", &mut [ArrayI64(vec![1]), F64(2.0)])),
+        "argument arr of scale should be [f64], found [i64]"
+    );
+    assert_eq!(
+        err(jit.call("swap", &mut [Struct("Point".to_string(), vec![0; 4])])),
+        "argument p of swap should be 16 bytes, found 4"
+    );
+    assert_eq!(
+        err(jit.call("sqrt", &mut [F64(4.0)])),
+        "can't call extern function sqrt"
+    );
+    assert_eq!(
+        err(jit.call("missing", &mut [])),
+        "No function missing found"
+    );
+    Ok(())
+}
+
+#[test]
+fn early_return() -> anyhow::Result<()> {
+    let code = r#"
+fn find(arr: &[f64], n: i64, x: f64) -> (idx: i64) {
+    idx = 0 - 1
+    i = 0
+    while i < n {
+        if arr[i] == x {
+            idx = i
+            return
+        }
+        i += 1
+    }
+}
+fn sign(a: f64) -> (c: f64) {
+    c = 1.0
+    if a < 0.0 {
+        c = 0.0 - 1.0
+        return
+    } else {
+        c = 1.0
+    }
+    c = 0.0
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("find")?;
+    let find =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(&[f64; 4], i64, f64) -> i64>(func_ptr) };
+    let arr = [1.0, 2.0, 3.0, 4.0];
+    assert_eq!(find(&arr, 4, 3.0), 2);
+    assert_eq!(find(&arr, 4, 5.0), -1);
+    let func_ptr = jit.get_func("sign")?;
+    let sign = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
+    assert_eq!(sign(-2.0), -1.0);
+    assert_eq!(sign(2.0), 0.0);
+    Ok(())
+}
+
+#[test]
+fn break_continue() -> anyhow::Result<()> {
+    let code = r#"
+fn main(n: i64) -> (c: i64) {
+    c = 0
+    i = 0
+    while true {
+        i += 1
+        if i > n {
+            break
+        }
+        if i == 3 {
+            continue
+        }
+        j = 0
+        while true {
+            j += 1
+            if j > i {
+                break
+            }
+            c += 1
+        }
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(i64) -> i64>(func_ptr) };
+    assert_eq!(func(5), 1 + 2 + 4 + 5);
+    Ok(())
+}
+
+#[test]
+fn break_outside_loop() -> anyhow::Result<()> {
+    let code = r#"
+fn main(a: f64) -> (c: f64) {
+    c = a
+    if a > 1.0 {
+        break
+    }
+    while a > 1.0 {
+        continue
+    }
+}
+"#;
+    assert_eq!(type_errors(code), vec!["5:9: \"break\" outside of a loop"]);
+    Ok(())
+}
+
+#[test]
+fn for_loops() -> anyhow::Result<()> {
+    let code = r#"
+fn sum_range(n: i64) -> (c: i64) {
+    c = 0
+    i = 100
+    for i in 0..n {
+        if i == 2 {
+            continue
+        }
+        for j in i..n {
+            c += j
+            i = 0
+        }
+    }
+    c += i
+}
+fn sum_array(arr: &[f64], n: i64) -> (c: f64) {
+    c = 0.0
+    for x in arr[1..n] {
+        if x > 4.0 {
+            break
+        }
+        c += x
+    }
+}
+fn sum_ints(arr: &[i64]) -> (c: i64) {
+    c = 0
+    for x in arr[0..3] {
+        c += x
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("sum_range")?;
+    let sum_range = unsafe { mem::transmute::<*const u8, extern "C" fn(i64) -> i64>(func_ptr) };
+    // (0+1+2+3) + (1+2+3) + (3) + 100
+    assert_eq!(sum_range(4), 6 + 6 + 3 + 100);
+    let func_ptr = jit.get_func("sum_array")?;
+    let sum_array =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(&[f64; 6], i64) -> f64>(func_ptr) };
+    assert_eq!(
+        sum_array(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0], 6),
+        2.0 + 3.0 + 4.0
+    );
+    let func_ptr = jit.get_func("sum_ints")?;
+    let sum_ints =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(&[i64; 3]) -> i64>(func_ptr) };
+    assert_eq!(sum_ints(&[1, 2, 3]), 6);
+    Ok(())
+}
+
+#[test]
+fn return_unbounded_array() -> anyhow::Result<()> {
+    let code = r#"
+fn same_f64(arr: &[f64]) -> (out: &[f64]) {
+    out = arr
+}
+fn same_i64(arr: &[i64]) -> (out: &[i64]) {
+    out = arr
+}
+fn third_f64(arr: &[f64]) -> (x: f64) {
+    floats = same_f64(arr)
+    x = floats[2]
+}
+fn third_i64(arr: &[i64]) -> (n: i64) {
+    ints = same_i64(arr)
+    n = ints[2]
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    jit.translate(ast)?;
+    let func_ptr = jit.get_func("same_i64")?;
+    let same_i64 =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(*const i64) -> *const i64>(func_ptr) };
+    let ints = [1, 2, 3];
+    assert_eq!(same_i64(ints.as_ptr()), ints.as_ptr());
+    let func_ptr = jit.get_func("third_f64")?;
+    let third_f64 =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(&[f64; 3]) -> f64>(func_ptr) };
+    assert_eq!(third_f64(&[1.0, 2.0, 3.0]), 3.0);
+    let func_ptr = jit.get_func("third_i64")?;
+    let third_i64 =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(&[i64; 3]) -> i64>(func_ptr) };
+    assert_eq!(third_i64(&ints), 3);
+    Ok(())
+}
+
+#[test]
+fn for_loop_errors() -> anyhow::Result<()> {
+    let code = r#"
+fn main(arr: &[f64], n: i64) -> (c: f64) {
+    c = 0.0
+    for x in arr {
+        c += x
+    }
+    for i in 0.0..n {
+        c += 1.0
+    }
+    for i in 0..n {
+        c += 1.0
+    }
+    c += i
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "4:14: Type mismatch; can't iterate over &[f64], use a range like 0..n or arr[0..n]",
+            "7:14: Type mismatch; expected i64, found f64",
+            "13:10: Variable \"i\" does not exist",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn infer_types_per_call() -> anyhow::Result<()> {
+    use sarus_value::SarusValue::*;
+    let code = r#"
+fn add_node(a, b) -> (c) {
+    c = a + b
+}
+fn twice(x) -> (y) {
+    y = add_node(x, x)
+}
+fn main(a: i64, b: f64) -> (c: i64, d: f64, e: f64) {
+    c = add_node(a, 2)
+    d = add_node(b, 2.0)
+    e = twice(b)
+}
+fn add_node__f64_f64(a: f64) -> (c: f64) {
+    c = a
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    assert_eq!(
+        jit.call("main", &mut [I64(5), F64(0.5)])?,
+        vec![I64(7), F64(2.5), F64(1.0)]
+    );
+    // The first call decides the types of the function under its own name
+    let add_node = jit.get_typed::<(i64, i64), (i64,)>("add_node")?;
+    assert_eq!(add_node.call(3, 4), (7,));
+    // Copies for other types are found by their params, their names are only
+    // made unique
+    let add_node = jit.get_typed::<(f64, f64), (f64,)>("add_node")?;
+    assert_eq!(add_node.call(0.5, 0.25), (0.75,));
+    assert_eq!(
+        jit.call("add_node", &mut [F64(0.5), F64(0.5)])?,
+        vec![F64(1.0)]
+    );
+    assert_eq!(
+        jit.specializations["add_node"],
+        vec![(
+            vec![validator::ExprType::F64, validator::ExprType::F64],
+            "add_node__f64_f64_1".to_string()
+        )]
+    );
+    let add_node = jit.get_typed::<(f64, f64), (f64,)>("add_node__f64_f64_1")?;
+    assert_eq!(add_node.call(1.0, 2.0), (3.0,));
+    assert!(jit.get_typed::<(bool, bool), (f64,)>("add_node").is_err());
+    Ok(())
+}
+
+#[test]
+fn infer_local_types() -> anyhow::Result<()> {
+    let code = r#"
+fn id(a) -> (b) {
+    b = a
+}
+fn main() -> (c: f64) {
+    x = id(3)
+    c = x * 2.0
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec!["7:9: Type mismatch; expected i64, found f64"]
+    );
+    Ok(())
+}
+
+#[test]
+fn infer_types_conflict() -> anyhow::Result<()> {
+    let code = r#"
+fn scale(a) -> (c) {
+    b = a + 1
+    c = 1.5 * a
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec!["4:15: Conflicting types for \"a\" in \"scale\"; inferred i64 at 3:9, found f64"]
+    );
+    Ok(())
+}
+
+#[test]
+fn slices() -> anyhow::Result<()> {
+    let code = r#"
+fn sum(arr: [f64]) -> (c: f64) {
+    c = 0.0
+    for x in arr {
+        c += x
+    }
+}
+fn first(arr: &[f64]) -> (c: f64) {
+    c = arr[0]
+}
+fn lens(arr: [f64], ints: [i64]) -> (n: i64) {
+    n = len(arr) + ints[1]
+}
+fn main(arr: [f64]) -> (c: f64) {
+    arr[len(arr) - 1] = 10.0
+    c = sum(arr) + first(arr)
+    for x in arr[1..3] {
+        c += x
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+
+    let func_ptr = jit.get_func("lens")?;
+    let lens = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(*const f64, i64, *const i64, i64) -> i64>(
+            func_ptr,
+        )
+    };
+    let arr = [1.0, 2.0, 3.0];
+    let ints = [5, 6];
+    assert_eq!(
+        lens(
+            arr.as_ptr(),
+            arr.len() as i64,
+            ints.as_ptr(),
+            ints.len() as i64
+        ),
+        3 + 6
+    );
+
+    let func_ptr = jit.get_func("main")?;
+    let main =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(*mut f64, i64) -> f64>(func_ptr) };
+    let mut arr = [1.0, 2.0, 3.0, 4.0];
+    let c = main(arr.as_mut_ptr(), arr.len() as i64);
+    assert_eq!(arr, [1.0, 2.0, 3.0, 10.0]);
+    assert_eq!(c, 16.0 + 1.0 + 2.0 + 3.0);
+    Ok(())
+}
+
+#[test]
+fn slice_bounds_checks() -> anyhow::Result<()> {
+    let code = r#"
+fn get(arr: [f64], i: i64) -> (c: f64) {
+    c = arr[i]
+}
+"#;
+    let ast = sarus_std_lib::append_std_funcs(parser::program(code)?);
+
+    let mut jit = jit::JIT::default();
+    jit.translate(ast.clone())?;
+    assert!(jit.clif["get"].contains("trap heap_oob"));
+    let func_ptr = jit.get_func("get")?;
+    let get = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(*const f64, i64, i64) -> f64>(func_ptr)
+    };
+    let arr = [1.0, 2.0, 3.0];
+    assert_eq!(get(arr.as_ptr(), arr.len() as i64, 2), 3.0);
+
+    let mut jit = jit::JIT::default();
+    jit.bounds_checks = false;
+    jit.translate(ast.clone())?;
+    assert!(!jit.clif["get"].contains("heap_oob"));
+    Ok(())
+}
+
+#[test]
+fn slice_errors() -> anyhow::Result<()> {
+    let code = r#"
+fn main(arr: &[f64], s: [i64]) -> (c: i64) {
+    c = len(arr)
+    c = s[0] + len(s)
+    takes_floats(s)
+}
+fn takes_floats(arr: [f64]) -> () {
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "3:13: Type mismatch; len expects a slice like [f64], found &[f64]",
+            "5:18: Type mismatch; expected [f64], found [i64]",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn int_arrays() -> anyhow::Result<()> {
+    let code = r#"
+fn main(arr: &[i64], idx: [i64]) -> (c: i64) {
+    b = arr
+    b[1] = b[0] * 10
+    c = 0
+    for i in idx {
+        c += arr[i]
+    }
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(*mut i64, *const i64, i64) -> i64>(func_ptr)
+    };
+    let mut arr = [3, 0, 5];
+    let idx = [0, 1, 1, 2];
+    assert_eq!(
+        func(arr.as_mut_ptr(), idx.as_ptr(), idx.len() as i64),
+        3 + 30 + 30 + 5
+    );
+    assert_eq!(arr, [3, 30, 5]);
+    Ok(())
+}
+
+#[test]
+fn struct_arrays() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn main(points: [Point]) -> (c: f64) {
+    c = 0.0
+    for p in points {
+        c += p.x * p.y
+    }
+    last = points[len(points) - 1]
+    points[0] = Point {
+        x: last.y,
+        y: c,
+    }
+}
+"#;
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Point, i64) -> f64>(func_ptr) };
+    let mut points = [
+        Point { x: 1.0, y: 2.0 },
+        Point { x: 3.0, y: 4.0 },
+        Point { x: 5.0, y: 6.0 },
+    ];
+    assert_eq!(
+        func(points.as_mut_ptr(), points.len() as i64),
+        2.0 + 12.0 + 30.0
+    );
+    assert_eq!(points[0], Point { x: 6.0, y: 44.0 });
+    assert_eq!(points[2], Point { x: 5.0, y: 6.0 });
+    Ok(())
+}
+
+#[test]
+fn struct_returns() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn new(x: f64, y: f64) -> (p: Point) {
+    p = Point {
+        x: x,
+        y: y,
+    }
+}
+fn swap(p: Point) -> (q: Point, d: f64) {
+    q = new(p.y, p.x)
+    d = q.x - q.y
+}
+fn pass(p: Point) -> (p: Point) {
+}
+fn main(points: [Point]) -> (c: f64) {
+    a = points[0]
+    points[0] = new(10.0, 20.0)
+    b, d = swap(pass(points[0]))
+    c = a.x + a.y + b.x + b.y + d
+}
+"#;
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("new")?;
+    let new = unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Point, f64, f64)>(func_ptr) };
+    let mut p = Point { x: 0.0, y: 0.0 };
+    new(&mut p, 1.0, 2.0);
+    assert_eq!(p, Point { x: 1.0, y: 2.0 });
+    let func_ptr = jit.get_func("main")?;
+    let func =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Point, i64) -> f64>(func_ptr) };
+    let mut points = [Point { x: 1.0, y: 2.0 }];
+    // a is a copy, replacing points[0] afterwards doesn't change it
+    assert_eq!(
+        func(points.as_mut_ptr(), points.len() as i64),
+        1.0 + 2.0 + 20.0 + 10.0 + 10.0
+    );
+    assert_eq!(points[0], Point { x: 10.0, y: 20.0 });
+    Ok(())
+}
+
+#[test]
+fn struct_field_assign() -> anyhow::Result<()> {
+    let code = r#"
+struct Filter {
+    ic1eq: f64,
+    ic2eq: f64,
+}
+struct Point {
+    x: f64,
+    y: f64,
+}
+struct Line {
+    a: Point,
+    b: Point,
+    steps: i64,
+}
+fn filter(self: Filter, audio: f64, a1: f64, a2: f64, a3: f64) -> (audio: f64) {
+    v3 = audio - self.ic2eq
+    v1 = a1 * self.ic1eq + a2 * v3
+    v2 = self.ic2eq + a2 * self.ic1eq + a3 * v3
+    self.ic1eq = 2.0 * v1 - self.ic1eq
+    self.ic2eq = 2.0 * v2 - self.ic2eq
+    audio = v2
+}
+fn length(line: Line) -> (d: f64) {
+    line.b.x -= line.a.x
+    line.b.y -= line.a.y
+    d = sqrt(line.b.x * line.b.x + line.b.y * line.b.y)
+}
+fn main(state: Filter, lines: [Line]) -> (c: f64) {
+    line = lines[0]
+    line.a.x = 3.0
+    line.a.y, line.steps = 4.0, 2
+    line.steps += 1
+    line.b = line.a
+    line.b.x *= 2.0
+    line.b.y *= 2.0
+    lines[0] = line
+    c = length(line) + state.filter(1.0, 0.5, 0.25, 0.125)
+}
+"#;
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Filter {
+        ic1eq: f64,
+        ic2eq: f64,
+    }
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Point {
+        x: f64,
+        y: f64,
+    }
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Line {
+        a: Point,
+        b: Point,
+        steps: i64,
+    }
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(*mut Filter, *mut Line, i64) -> f64>(func_ptr)
+    };
+    let mut filter = Filter {
+        ic1eq: 1.0,
+        ic2eq: 2.0,
+    };
+    let mut lines = [Line {
+        a: Point { x: 0.0, y: 0.0 },
+        b: Point { x: 0.0, y: 0.0 },
+        steps: 0,
+    }];
+    // main passes a copy of the filter state, only a method call updates it in place
+    assert_eq!(func(&mut filter, lines.as_mut_ptr(), 1), 5.0 + 2.125);
+    assert_eq!(
+        filter,
+        Filter {
+            ic1eq: 1.0,
+            ic2eq: 2.0
+        }
+    );
+    let func_ptr = jit.get_func("Filter.filter")?;
+    let filter_fn = unsafe {
+        mem::transmute::<*const u8, extern "C" fn(*mut Filter, f64, f64, f64, f64) -> f64>(func_ptr)
+    };
+    assert_eq!(filter_fn(&mut filter, 1.0, 0.5, 0.25, 0.125), 2.125);
+    assert_eq!(
+        filter,
+        Filter {
+            ic1eq: -0.5,
+            ic2eq: 2.25
+        }
+    );
+    // length works on its own copy of the line
+    assert_eq!(
+        lines[0],
+        Line {
+            a: Point { x: 3.0, y: 4.0 },
+            b: Point { x: 6.0, y: 8.0 },
+            steps: 3,
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn struct_field_assign_errors() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+struct Line {
+    a: Point,
+    b: Point,
+}
+fn main(line: Line) -> (c: f64) {
+    line.a.z = 1.0
+    line.a.x.y = 1.0
+    line.a.x = 1
+    line.b += 1.0
+    c = 0.0
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "11:5: Struct \"Point\" does not have field \"z\"",
+            "12:5: Type mismatch; line.a.x is not a Struct",
+            "13:5: Type mismatch; expected f64, found i64",
+            "14:5: Type mismatch; expected Point, found f64",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn struct_c_layout() -> anyhow::Result<()> {
+    let code = r#"
+extern struct Settings {
+    enabled: bool,
+    gain: f64,
+    muted: bool,
+    steps: i64,
+}
+struct Voice {
+    active: bool,
+    settings: Settings,
+    held: bool,
+}
+fn main(voices: [Voice]) -> (c: f64) {
+    c = 0.0
+    for voice in voices {
+        if voice.active && voice.settings.enabled && !voice.settings.muted {
+            c += voice.settings.gain * float(voice.settings.steps)
+        }
+    }
+}
+fn toggle(self: Voice) -> () {
+    self.held = true
+    self.settings.muted = !self.settings.muted
+}
+"#;
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Settings {
+        enabled: bool,
+        gain: f64,
+        muted: bool,
+        steps: i64,
+    }
+    #[repr(C)]
+    #[derive(Debug, PartialEq)]
+    struct Voice {
+        active: bool,
+        settings: Settings,
+        held: bool,
+    }
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+
+    let settings = jit.get_struct("Settings")?;
+    assert_eq!(settings.size as usize, mem::size_of::<Settings>());
+    assert_eq!(settings.align as usize, mem::align_of::<Settings>());
+    assert_eq!(settings.offset_of("enabled"), Some(0));
+    assert_eq!(settings.offset_of("gain"), Some(8));
+    assert_eq!(settings.offset_of("muted"), Some(16));
+    assert_eq!(settings.offset_of("steps"), Some(24));
+    assert_eq!(settings.offset_of("volume"), None);
+    let voice = jit.get_struct("Voice")?;
+    assert_eq!(voice.size as usize, mem::size_of::<Voice>());
+    assert_eq!(voice.offset_of("settings"), Some(8));
+    assert_eq!(voice.offset_of("held"), Some(40));
+    assert!(jit.get_struct("Chord").is_err());
+
+    let func_ptr = jit.get_func("main")?;
+    let func =
+        unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Voice, i64) -> f64>(func_ptr) };
+    let voice = |active, enabled, muted| Voice {
+        active,
+        settings: Settings {
+            enabled,
+            gain: 0.5,
+            muted,
+            steps: 3,
+        },
+        held: false,
+    };
+    let mut voices = [
+        voice(true, true, false),
+        voice(true, true, true),
+        voice(false, true, false),
+        voice(true, false, false),
+        voice(true, true, false),
+    ];
+    assert_eq!(func(voices.as_mut_ptr(), voices.len() as i64), 3.0);
+    let func_ptr = jit.get_func("Voice.toggle")?;
+    let toggle = unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Voice)>(func_ptr) };
+    toggle(&mut voices[1]);
+    assert!(voices[1].held);
+    assert!(!voices[1].settings.muted);
+    assert_eq!(voices[1].settings.steps, 3);
+    assert_eq!(voices[0], voice(true, true, false));
+    assert_eq!(voices[2], voice(false, true, false));
+    Ok(())
+}
+
+#[repr(C)]
+#[derive(SarusStruct)]
+struct Settings {
+    enabled: bool,
+    gain: f64,
+    steps: i64,
+}
+
+#[repr(C)]
+#[derive(SarusStruct)]
+struct Voice {
+    active: bool,
+    settings: Settings,
+}
+
+#[test]
+fn derive_sarus_struct() -> anyhow::Result<()> {
+    let code = r#"
+fn main(voice: Voice) -> (c: f64) {
+    c = 0.0
+    if voice.active && voice.settings.enabled {
+        c = voice.settings.gain * float(voice.settings.steps)
+    }
+}
+"#;
+    assert_eq!(
+        <Settings as sarus_struct::SarusStruct>::declaration().to_string(),
+        "extern struct Settings {enabled: bool,\ngain: f64,\nsteps: i64,\n}\n"
+    );
+    assert_eq!(<Settings as sarus_struct::SarusType>::SIZE, 24);
+    assert_eq!(<Settings as sarus_struct::SarusType>::ALIGN, 8);
+    assert_eq!(<Voice as sarus_struct::SarusType>::SIZE, 32);
+    assert_eq!(sarus_struct::c_offset(&[(1, 1), (24, 8)], 1), 8);
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_struct::append_struct::<Settings>(ast);
+    let ast = sarus_struct::append_struct::<Voice>(ast);
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    jit.check_struct::<Settings>()?;
+    jit.check_struct::<Voice>()?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(*mut Voice) -> f64>(func_ptr) };
+    let mut voice = Voice {
+        active: true,
+        settings: Settings {
+            enabled: true,
+            gain: 0.5,
+            steps: 3,
+        },
+    };
+    assert_eq!(func(&mut voice), 1.5);
+
+    // A hand written declaration that has drifted from the Rust struct
+    let code = r#"
+extern struct Settings {
+    enabled: bool,
+    steps: i64,
+    gain: f64,
+}
+fn main(settings: Settings) -> (c: f64) {
+    c = settings.gain
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    assert_eq!(
+        jit.check_struct::<Settings>().unwrap_err().to_string(),
+        "field gain of struct Settings is at offset 16 in sarus but 8 in rust"
+    );
+    assert!(jit.check_struct::<Voice>().is_err());
+    Ok(())
+}
+
+#[test]
+fn fixed_arrays() -> anyhow::Result<()> {
+    let code = r#"
+struct Point {
+    x: f64,
+    y: f64,
+}
+fn sum(arr: [f64]) -> (c: f64) {
+    c = 0.0
+    for x in arr {
+        c += x
+    }
+}
+fn main(x: f64) -> (c: f64) {
+    buf: [f64; 64]
+    buf[3] = x
+    taps = [1.0, 2.0, 3.0]
+    ones = [1; 16]
+    points = [Point { x: 1.0, y: x, }; 4]
+    c = sum(buf)
+    for t in taps {
+        c += t * x
+    }
+    for p in points {
+        c += p.x + p.y
+    }
+    for i in [10, 20] {
+        c += float(i)
+    }
+    c += float(len(ones) + ones[15])
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let func_ptr = jit.get_func("main")?;
+    let func = unsafe { mem::transmute::<*const u8, extern "C" fn(f64) -> f64>(func_ptr) };
+    assert_eq!(
+        func(2.0),
+        2.0 + (1.0 + 2.0 + 3.0) * 2.0 + 4.0 * (1.0 + 2.0) + 30.0 + 17.0
+    );
+    Ok(())
+}
+
+#[test]
+fn fixed_array_errors() -> anyhow::Result<()> {
+    let code = r#"
+fn main() -> (c: f64) {
+    a = []
+    b = [1.0, 2]
+    d = [true; 2]
+    e: [f64; 4]
+    e: [i64; 4]
+    c = 0.0
+}
+"#;
+    assert_eq!(
+        type_errors(code),
+        vec![
+            "3:9: Type mismatch; can't tell the type of an empty array",
+            "4:15: Type mismatch; expected f64, found i64",
+            "5:9: Type mismatch; arrays of bool are not supported",
+            "7:5: Type mismatch; expected [f64], found [i64]",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn int_min_max() -> anyhow::Result<()> {
+    use sarus_value::SarusValue::*;
+    let code = r#"
+fn main(a: i64, b: i64) -> (c: i64, d: i64) {
+    c = imin(a, b)
+    d = imax(a, b)
+}
+"#;
+    let mut jit = jit::JIT::default();
+    let ast = parser::program(code)?;
+    let ast = sarus_std_lib::append_std_funcs(ast);
+    jit.translate(ast.clone())?;
+    let mut min_max = |a, b| jit.call("main", &mut [I64(a), I64(b)]);
+    assert_eq!(min_max(1, 2)?, vec![I64(1), I64(2)]);
+    assert_eq!(min_max(4, -3)?, vec![I64(-3), I64(4)]);
+    assert_eq!(
+        min_max(i64::MIN, i64::MAX)?,
+        vec![I64(i64::MIN), I64(i64::MAX)]
+    );
+    Ok(())
+}